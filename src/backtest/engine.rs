@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use chrono::Duration;
+use polars::prelude::DataFrame;
+
+use crate::{config::{TracingMMConfig, TracingMMHooks}, client::types::{TradeRecord, KLines}, order_types::OrderType, data_structure::float_exp::FloatExp, utils::{tracingmm_utils::{tracing_price_df, TracingPriceResult, plan_orders, OrderSizing, TracingMMOrder, TracingMMOrderKind, MAPPING_SIZE}, reserved_orders::{ReservedOrdersManager, ReservedOrder}, strategy_utils::next_asset_status, time::UnixTimeMs}};
+
+use super::matching::{SimExchange, FeeModel, SimFill};
+
+/// 実運用のread_klineが要求する足の数
+const MIN_KLINE_LEN: usize = 200;
+
+pub struct BacktestData {
+    /// timestamp順
+    pub trades: Vec<TradeRecord>,
+    pub klines: KLines,
+    pub ref_klines: KLines,
+    /// hooksでSFDを使う場合の現物
+    pub spot_klines: Option<KLines>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BacktestParams {
+    pub initial_collateral: f64,
+    pub fee: FeeModel,
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestFill {
+    pub fill: SimFill,
+    pub order_type: OrderType,
+    pub kind: Option<TracingMMOrderKind>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EquityPoint {
+    pub opentime: UnixTimeMs,
+    pub collateral: f64,
+    pub unrealized_pnl: f64,
+    pub equity: f64,
+    pub long_pos: f64,
+    pub short_pos: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BacktestSummary {
+    pub final_equity: f64,
+    pub total_pnl: f64,
+    pub realized_pnl: f64,
+    pub fees: f64,
+    pub num_fills: usize,
+    pub num_closes: usize,
+    pub win_rate: f64,
+    pub max_drawdown: f64,
+    pub max_drawdown_rate: f64,
+    /// 足ごとのequityの変化から年率換算
+    pub sharpe: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestResult {
+    pub fills: Vec<BacktestFill>,
+    pub equity: Vec<EquityPoint>,
+    pub summary: BacktestSummary,
+}
+
+/// 発注済みの注文、reserved orderがどのTracingMMOrderから来たか
+struct OrderMeta {
+    kind: TracingMMOrderKind,
+}
+
+/// 記録されたklinesと約定履歴でtracing mmを再生する
+/// 足の切り替わりで全注文を取り消し、一つ前の足までのklinesで注文を出すのは実運用と同じ
+/// fire_sourceがorderbookでも約定履歴で発火を判定する
+pub fn run_backtest(config: &TracingMMConfig, data: &BacktestData, params: &BacktestParams) -> anyhow::Result<BacktestResult> {
+    let hooks = config.hooks();
+    let timeframe: Duration = config.timeframe.into();
+    let tf_ms = timeframe.num_milliseconds();
+    let symbol = config.symbol;
+
+    let df = tracing_price_df(data.klines.df.clone(), data.ref_klines.df.clone(), MAPPING_SIZE, config.atr_period, &config.beta, &config.gamma)?;
+    let opentimes = opentime_ms(&df)?;
+    let closes = df["close"].f64()?.into_iter().collect::<Vec<_>>();
+    let volume_1d = rolling_sum(&df["volume"].f64()?.into_iter().map(|v| v.unwrap_or(0.)).collect::<Vec<_>>(), (Duration::days(1).num_milliseconds() / tf_ms) as usize);
+    let spot_closes = match (&hooks.sfd, &data.spot_klines) {
+        (Some(_), Some(spot_klines)) => Some(opentime_ms(&spot_klines.df)?.into_iter().zip(spot_klines.df["close"].f64()?).collect::<HashMap<_, _>>()),
+        (Some(_), None) => anyhow::bail!("spot_klines is required for sfd"),
+        _ => None,
+    };
+
+    let mut sim = SimExchange::new(symbol, params.initial_collateral, params.fee, hooks.long_only);
+    let mut reserved = ReservedOrdersManager::new(symbol.price_precision());
+    let mut order_meta: HashMap<String, OrderMeta> = HashMap::new();
+    let mut fixed_margin = 0.;
+    let mut sizing = None;
+    let mut last_asset_hour = None;
+
+    let mut fills = vec![];
+    let mut equity = vec![];
+    let mut trade_idx = data.trades.partition_point(|t| t.timestamp < opentimes.get(MIN_KLINE_LEN).copied().unwrap_or(i64::MAX));
+
+    for i in MIN_KLINE_LEN..opentimes.len() {
+        let opentime = opentimes[i];
+
+        // 足の切り替わりで全て取り消す
+        sim.cancel_all();
+        reserved.cancel_all_orders();
+        order_meta.clear();
+
+        // 1時間ごとに注文量を更新する
+        let hour = opentime / Duration::hours(1).num_milliseconds();
+        if last_asset_hour != Some(hour) {
+            let status = next_asset_status(fixed_margin, config, sim.collateral_for_sizing(), volume_1d[i - 1]);
+            fixed_margin = status.fixed_margin;
+            sizing = Some(OrderSizing { available_quote: status.available_quote, liquidity_limited_base: status.liquidity_limited_base });
+            last_asset_hour = Some(hour);
+        }
+
+        if let Ok(prices) = TracingPriceResult::from_row(&df, i - 1) {
+            let sfd = match &spot_closes {
+                Some(spot_closes) => get_sfd(closes[i - 1], spot_closes.get(&opentimes[i - 1]).copied().flatten()),
+                None => None,
+            };
+            let orders = plan_orders(config, &hooks, &sim.position(), &prices, sfd, sizing)?;
+            for order in orders {
+                send_order(&hooks, &mut sim, &mut reserved, &mut order_meta, order, opentime);
+            }
+        }
+
+        // 足の中の約定履歴を流す
+        while trade_idx < data.trades.len() && data.trades[trade_idx].timestamp < opentime + tf_ms {
+            let trade = &data.trades[trade_idx];
+            trade_idx += 1;
+            for fill in sim.on_trade(trade) {
+                // 実運用のon_private_eventと同じく、決済指値が約定した分だけペアのロスカットを減らす
+                if let Some(order_id) = &fill.order_id {
+                    reserved.execution_handler(order_id, fill.amount);
+                }
+                let kind = fill.order_id.as_ref().and_then(|id| order_meta.get(id)).map(|m| m.kind);
+                fills.push(BacktestFill { fill, order_type: OrderType::Limit, kind });
+            }
+            for rsv in reserved.trades_handler(&vec![trade.clone()]) {
+                let kind = order_meta.get(&rsv.id.to_string()).map(|m| m.kind);
+                if let Some(fill) = fire_reserved_order(&mut sim, &mut reserved, rsv.clone(), trade.timestamp) {
+                    fills.push(BacktestFill { fill, order_type: rsv.order_type, kind });
+                }
+            }
+        }
+
+        let mark = sim.last_price().or_else(|| closes[i].map(|c| FloatExp::from_f64(c, symbol.price_precision())));
+        let pos = sim.position();
+        let unrealized_pnl = mark.map(|m| sim.unrealized_pnl(m)).unwrap_or(0.);
+        equity.push(EquityPoint {
+            opentime,
+            collateral: sim.collateral(),
+            unrealized_pnl,
+            equity: sim.collateral() + unrealized_pnl,
+            long_pos: pos[0].pos.to_f64(),
+            short_pos: pos[1].pos.to_f64(),
+        });
+    }
+
+    let summary = summarize(&fills, &equity, params.initial_collateral, timeframe);
+    Ok(BacktestResult { fills, equity, summary })
+}
+
+fn send_order(hooks: &TracingMMHooks, sim: &mut SimExchange, reserved: &mut ReservedOrdersManager, order_meta: &mut HashMap<String, OrderMeta>, order: TracingMMOrder, timestamp: UnixTimeMs) {
+    let TracingMMOrder { kind, side, price, amount, losscut_price } = order;
+    let pos_side = match kind {
+        TracingMMOrderKind::Open => side.to_pos(),
+        TracingMMOrderKind::Close => side.inv().to_pos(),
+    };
+    let (order_id, rsv_order_id) = if hooks.reserve_limit_orders {
+        let rid = reserved.add_reserved_order(OrderType::Limit, side, pos_side, price, amount, None);
+        order_meta.insert(rid.to_string(), OrderMeta { kind });
+        (None, Some(rid))
+    } else {
+        let id = sim.place_limit(side, price, amount, timestamp);
+        order_meta.insert(id.clone(), OrderMeta { kind });
+        (Some(id), None)
+    };
+    if let Some(losscut_price) = losscut_price {
+        let losscut_id = reserved.add_reserved_order(OrderType::Stop, side, pos_side, losscut_price, amount, order_id);
+        reserved.get_mut(&losscut_id).unwrap().pair_rsv_order_id = rsv_order_id;
+    }
+}
+
+/// 実運用のfire_reserved_orderと同じく、ペアの注文を取り消してから発注する
+/// 指値は発火した価格でtakerとして約定したとみなす
+fn fire_reserved_order(sim: &mut SimExchange, reserved: &mut ReservedOrdersManager, rsv: ReservedOrder, timestamp: UnixTimeMs) -> Option<SimFill> {
+    if let Some(pair_rsv_order_id) = rsv.pair_rsv_order_id {
+        reserved.remove(&pair_rsv_order_id);
+    }
+    if let Some(pair_order_id) = &rsv.pair_order_id {
+        sim.cancel(pair_order_id);
+    }
+    match rsv.order_type {
+        OrderType::Limit | OrderType::StopLimit => sim.take(rsv.side, rsv.price, rsv.amount, timestamp),
+        OrderType::Market | OrderType::Stop => sim.market(rsv.side, rsv.amount, timestamp),
+    }
+}
+
+fn get_sfd(close: Option<f64>, spot_close: Option<f64>) -> Option<f64> {
+    Some(close? / spot_close? - 1.0)
+}
+
+fn opentime_ms(df: &DataFrame) -> anyhow::Result<Vec<UnixTimeMs>> {
+    df.column("opentime")?.datetime()?.as_datetime_iter()
+        .map(|t| t.map(|t| t.timestamp_millis()).context("opentime is null"))
+        .collect()
+}
+
+/// 直近window個の合計。windowに満たない間は先頭からの合計
+fn rolling_sum(values: &[f64], window: usize) -> Vec<f64> {
+    let mut ret = Vec::with_capacity(values.len());
+    let mut sum = 0.;
+    for (i, v) in values.iter().enumerate() {
+        sum += v;
+        if i >= window {
+            sum -= values[i - window];
+        }
+        ret.push(sum);
+    }
+    ret
+}
+
+fn summarize(fills: &[BacktestFill], equity: &[EquityPoint], initial_collateral: f64, timeframe: Duration) -> BacktestSummary {
+    let final_equity = equity.last().map(|e| e.equity).unwrap_or(initial_collateral);
+    let closes = fills.iter().filter(|f| f.fill.realized_pnl != 0.).collect::<Vec<_>>();
+    let wins = closes.iter().filter(|f| f.fill.realized_pnl > 0.).count();
+
+    let mut peak = initial_collateral;
+    let mut max_drawdown = 0f64;
+    let mut max_drawdown_rate = 0f64;
+    for e in equity {
+        peak = peak.max(e.equity);
+        max_drawdown = max_drawdown.max(peak - e.equity);
+        if peak > 0. {
+            max_drawdown_rate = max_drawdown_rate.max((peak - e.equity) / peak);
+        }
+    }
+
+    let returns = equity.windows(2).map(|w| w[1].equity - w[0].equity).collect::<Vec<_>>();
+    let sharpe = if returns.len() < 2 {
+        0.
+    } else {
+        let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+        let var = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (returns.len() - 1) as f64;
+        let bars_per_year = Duration::days(365).num_seconds() as f64 / timeframe.num_seconds() as f64;
+        if var > 0. { mean / var.sqrt() * bars_per_year.sqrt() } else { 0. }
+    };
+
+    BacktestSummary {
+        final_equity,
+        total_pnl: final_equity - initial_collateral,
+        realized_pnl: fills.iter().map(|f| f.fill.realized_pnl).sum(),
+        fees: fills.iter().map(|f| f.fill.fee).sum(),
+        num_fills: fills.len(),
+        num_closes: closes.len(),
+        win_rate: if closes.is_empty() { 0. } else { wins as f64 / closes.len() as f64 },
+        max_drawdown,
+        max_drawdown_rate,
+        sharpe,
+    }
+}
+
+#[test]
+fn test_rolling_sum() {
+    assert_eq!(rolling_sum(&[1., 2., 3., 4.], 2), vec![1., 3., 5., 7.]);
+}
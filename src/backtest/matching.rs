@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+
+use crate::{symbol::{Symbol, SymbolType}, order_types::{Side, PosSide, OrderType}, data_structure::float_exp::FloatExp, client::{types::TradeRecord, exchange::{OrderId, OpenOrder, Position}}, utils::{tracingmm_utils::TracingMMPosition, time::{UnixTimeMs, datetime_utc_from_timestamp, UnixTimeUnit}}};
+
+/// 手数料とスリッページ。いずれも約定代金に対する率
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeeModel {
+    pub maker: f64,
+    pub taker: f64,
+    /// 成行・逆指値の約定価格を不利な方向にずらす
+    pub slippage: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimOrder {
+    pub id: OrderId,
+    pub side: Side,
+    pub price: FloatExp,
+    /// 未約定の数量
+    pub amount: FloatExp,
+    pub created_at: UnixTimeMs,
+    /// 同じ時刻の注文は出した順に約定させる
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimFill {
+    pub timestamp: UnixTimeMs,
+    pub order_id: Option<OrderId>,
+    pub side: Side,
+    pub price: FloatExp,
+    pub amount: FloatExp,
+    pub liquidity: Liquidity,
+    pub fee: f64,
+    pub realized_pnl: f64,
+}
+
+/// 約定履歴から約定を判定する取引所シミュレータ
+/// 指値は約定価格が指値を厳密に跨いだときだけ約定させる（同値では約定しない）
+#[derive(Debug, Clone)]
+pub struct SimExchange {
+    pub symbol: Symbol,
+    pub fee: FeeModel,
+    /// 現物などLongのみ。保有量を超える売りは約定させない
+    pub long_only: bool,
+    orders: BTreeMap<OrderId, SimOrder>,
+    next_id: u64,
+    pos: [TracingMMPosition; 2],
+    /// 初期資産 + 実現損益 - 手数料
+    collateral: f64,
+    last_price: Option<FloatExp>,
+}
+
+impl SimExchange {
+    pub fn new(symbol: Symbol, initial_collateral: f64, fee: FeeModel, long_only: bool) -> Self {
+        Self {
+            symbol,
+            fee,
+            long_only,
+            orders: BTreeMap::new(),
+            next_id: 0,
+            pos: [
+                TracingMMPosition::new(symbol.price_precision(), symbol.amount_precision()),
+                TracingMMPosition::new(symbol.price_precision(), symbol.amount_precision()),
+            ],
+            collateral: initial_collateral,
+            last_price: None,
+        }
+    }
+
+    /// 保存しておいた資産と建玉から再開する
+    pub fn restore(&mut self, collateral: f64, pos: [TracingMMPosition; 2]) {
+        self.collateral = collateral;
+        self.pos = pos;
+    }
+
+    pub fn position(&self) -> [TracingMMPosition; 2] {
+        self.pos.clone()
+    }
+
+    pub fn positions(&self) -> Vec<Position> {
+        self.pos.iter().enumerate().filter(|(_, p)| !p.pos.is_zero()).map(|(i, p)| Position {
+            symbol: self.symbol,
+            pos_side: if i == 0 { PosSide::Long } else { PosSide::Short },
+            amount: p.pos,
+            price: p.entry_price,
+        }).collect()
+    }
+
+    pub fn open_orders(&self) -> Vec<OpenOrder> {
+        self.orders.values().map(|o| OpenOrder {
+            id: o.id.clone(),
+            symbol: self.symbol,
+            side: o.side,
+            order_type: OrderType::Limit,
+            price: Some(o.price),
+            amount: o.amount,
+            created_at: datetime_utc_from_timestamp(o.created_at, UnixTimeUnit::MilliSecond),
+        }).collect()
+    }
+
+    pub fn last_price(&self) -> Option<FloatExp> {
+        self.last_price
+    }
+
+    pub fn collateral(&self) -> f64 {
+        self.collateral
+    }
+
+    /// 注文量の計算に使う資産
+    /// 現物では建玉に使った分を除いたquoteの残高
+    pub fn collateral_for_sizing(&self) -> f64 {
+        match self.symbol.r#type {
+            SymbolType::Spot => self.collateral - self.pos[0].init_notional.to_f64(),
+            SymbolType::Perp => self.collateral,
+        }
+    }
+
+    pub fn unrealized_pnl(&self, mark: FloatExp) -> f64 {
+        let mark = mark.to_f64();
+        self.pos.iter().enumerate().map(|(i, p)| {
+            let sign = if i == 0 { 1. } else { -1. };
+            (mark * p.pos.to_f64() - p.init_notional.to_f64()) * sign
+        }).sum()
+    }
+
+    pub fn equity(&self, mark: FloatExp) -> f64 {
+        self.collateral + self.unrealized_pnl(mark)
+    }
+
+    pub fn place_limit(&mut self, side: Side, price: FloatExp, amount: FloatExp, timestamp: UnixTimeMs) -> OrderId {
+        self.next_id += 1;
+        let id = format!("sim-{}", self.next_id);
+        self.orders.insert(id.clone(), SimOrder {
+            id: id.clone(),
+            side,
+            price: price.round(self.symbol.price_precision()),
+            amount: amount.round(self.symbol.amount_precision()),
+            created_at: timestamp,
+            seq: self.next_id,
+        });
+        id
+    }
+
+    pub fn cancel(&mut self, id: &OrderId) -> bool {
+        self.orders.remove(id).is_some()
+    }
+
+    pub fn cancel_all(&mut self) {
+        self.orders.clear();
+    }
+
+    /// 直近の約定価格にスリッページを乗せて成行で約定させる
+    pub fn market(&mut self, side: Side, amount: FloatExp, timestamp: UnixTimeMs) -> Option<SimFill> {
+        let last_price = self.last_price?;
+        let slip = match side {
+            Side::Buy => 1. + self.fee.slippage,
+            Side::Sell => 1. - self.fee.slippage,
+        };
+        let price = FloatExp::from_f64(last_price.to_f64() * slip, self.symbol.price_precision());
+        self.fill(None, side, price, amount, Liquidity::Taker, timestamp)
+    }
+
+    /// 指定価格でtakerとして約定させる
+    pub fn take(&mut self, side: Side, price: FloatExp, amount: FloatExp, timestamp: UnixTimeMs) -> Option<SimFill> {
+        self.fill(None, side, price, amount, Liquidity::Taker, timestamp)
+    }
+
+    /// 約定履歴1件を処理し、指値の約定を返す
+    /// 跨いだ指値は価格の良い順（買いは高い順、売りは安い順）、同じ価格なら出した順に約定させる
+    pub fn on_trade(&mut self, trade: &TradeRecord) -> Vec<SimFill> {
+        let trade_price = FloatExp::from_f64(trade.price, self.symbol.price_precision());
+        self.last_price = Some(trade_price);
+        let mut remaining = FloatExp::from_f64(trade.amount, self.symbol.amount_precision());
+        let mut crossed = self.orders.values().filter(|o| match o.side {
+            Side::Buy => trade_price < o.price,
+            Side::Sell => trade_price > o.price,
+        }).collect::<Vec<_>>();
+        crossed.sort_by(|a, b| {
+            let by_price = match a.side {
+                Side::Buy => b.price.cmp(&a.price),
+                Side::Sell => a.price.cmp(&b.price),
+            };
+            by_price.then(a.created_at.cmp(&b.created_at)).then(a.seq.cmp(&b.seq))
+        });
+        let crossed = crossed.into_iter().map(|o| o.id.clone()).collect::<Vec<_>>();
+        let mut ret = vec![];
+        for id in crossed {
+            if remaining.is_zero() {
+                break;
+            }
+            let order = self.orders.get(&id).unwrap().clone();
+            // long_onlyの売りは保有量までしか約定しないので、実際に約定した分だけ減らす
+            let filled = match self.fill(Some(id.clone()), order.side, order.price, order.amount.min(remaining), Liquidity::Maker, trade.timestamp) {
+                Some(fill) => {
+                    let amount = fill.amount;
+                    ret.push(fill);
+                    amount
+                },
+                None => continue,
+            };
+            remaining -= filled;
+            let order = self.orders.get_mut(&id).unwrap();
+            order.amount -= filled;
+            if order.amount.is_zero() {
+                self.orders.remove(&id);
+            }
+        }
+        ret
+    }
+
+    fn fill(&mut self, order_id: Option<OrderId>, side: Side, price: FloatExp, amount: FloatExp, liquidity: Liquidity, timestamp: UnixTimeMs) -> Option<SimFill> {
+        let price_exp = self.symbol.price_precision();
+        let amount_exp = self.symbol.amount_precision();
+        let price = price.round(price_exp);
+        let mut amount = amount.round(amount_exp);
+        if self.long_only && side == Side::Sell {
+            amount = amount.min(self.pos[0].pos);
+        }
+        if amount.is_zero() {
+            return None;
+        }
+
+        // 反対側の建玉から決済し、残りを新規に建てる
+        let realized_pnl = TracingMMPosition::apply_execution(&mut self.pos, side, price, amount);
+
+        let rate = match liquidity {
+            Liquidity::Maker => self.fee.maker,
+            Liquidity::Taker => self.fee.taker,
+        };
+        let fee = price.to_f64() * amount.to_f64() * rate;
+        self.collateral += realized_pnl - fee;
+        Some(SimFill {
+            timestamp,
+            order_id,
+            side,
+            price,
+            amount,
+            liquidity,
+            fee,
+            realized_pnl,
+        })
+    }
+}
+
+#[test]
+fn test_sim_exchange() {
+    use crate::symbol::{Currency, Exchange};
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let mut sim = SimExchange::new(symbol, 1_000_000., FeeModel { maker: 0., taker: 0.001, slippage: 0. }, false);
+    let trade = |price: f64, amount: f64| TradeRecord::new(symbol, 0, price, amount, Side::Buy);
+
+    let id = sim.place_limit(Side::Buy, FloatExp::new(4_000_000, 0), FloatExp::new(2, -2), 0);
+    // 同値では約定しない
+    assert!(sim.on_trade(&trade(4_000_000., 1.)).is_empty());
+    // 約定量は約定履歴の数量まで
+    let fills = sim.on_trade(&trade(3_999_999., 0.01));
+    assert_eq!(fills.len(), 1);
+    assert_eq!(fills[0].amount, FloatExp::new(1, -2));
+    assert_eq!(sim.open_orders()[0].amount, FloatExp::new(1, -2));
+    sim.on_trade(&trade(3_999_999., 1.));
+    assert!(!sim.cancel(&id));
+    assert_eq!(sim.position()[0].pos, FloatExp::new(2, -2));
+    assert_eq!(sim.position()[0].entry_price, FloatExp::new(4_000_000, 0));
+
+    // ドテン
+    sim.on_trade(&trade(4_100_000., 1.));
+    let fill = sim.market(Side::Sell, FloatExp::new(3, -2), 0).unwrap();
+    assert!((fill.realized_pnl - 2000.).abs() < 1e-6);
+    assert_eq!(sim.position()[0].pos, FloatExp::new(0, -2));
+    assert_eq!(sim.position()[1].pos, FloatExp::new(1, -2));
+    assert!((sim.collateral() - (1_000_000. + 2000. - 4_100_000. * 0.03 * 0.001)).abs() < 1e-6);
+    assert!((sim.unrealized_pnl(FloatExp::new(4_000_000, 0)) - 1000.).abs() < 1e-6);
+}
+
+#[test]
+fn test_sim_exchange_priority() {
+    use crate::symbol::{Currency, Exchange};
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Spot, Exchange::Bitflyer);
+    let mut sim = SimExchange::new(symbol, 1_000_000., FeeModel::default(), true);
+    let trade = |price: f64, amount: f64| TradeRecord::new(symbol, 0, price, amount, Side::Buy);
+
+    // idの文字列順（sim-10がsim-2より前）ではなく価格、時刻の順に約定させる
+    let ids = (0..10).map(|i| sim.place_limit(Side::Buy, FloatExp::new(4_000_000 - i, 0), FloatExp::new(1, -2), i)).collect::<Vec<_>>();
+    let best = sim.place_limit(Side::Buy, FloatExp::new(4_000_001, 0), FloatExp::new(1, -2), 10);
+    let fills = sim.on_trade(&trade(3_999_000., 0.02));
+    assert_eq!(fills.iter().map(|f| f.order_id.clone().unwrap()).collect::<Vec<_>>(), vec![best, ids[0].clone()]);
+    sim.cancel_all();
+
+    // 現物では保有量までしか売れず、残りは注文に残る
+    let id = sim.place_limit(Side::Sell, FloatExp::new(4_100_000, 0), FloatExp::new(3, -2), 0);
+    let fills = sim.on_trade(&trade(4_100_001., 1.));
+    assert_eq!(fills.len(), 1);
+    assert_eq!(fills[0].amount, FloatExp::new(2, -2));
+    assert_eq!(sim.open_orders().iter().find(|o| o.id == id).unwrap().amount, FloatExp::new(1, -2));
+    assert!(sim.position()[0].pos.is_zero());
+}
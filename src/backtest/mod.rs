@@ -0,0 +1,5 @@
+pub mod matching;
+pub mod replay;
+pub mod engine;
+pub mod report;
+pub mod optimizer;
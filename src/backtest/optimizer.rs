@@ -0,0 +1,427 @@
+use std::{sync::atomic::{AtomicUsize, Ordering}, collections::HashSet};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use polars::prelude::{DataFrame, NamedFrom};
+use polars::series::Series;
+use serde::Deserialize;
+use serde_yaml::Value;
+use strum::{EnumIter, IntoEnumIterator};
+
+use crate::config::TracingMMConfig;
+
+use super::{engine::{BacktestData, BacktestParams, BacktestSummary, run_backtest}, report::summary_df};
+
+/// 探索するパラメータ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
+pub enum Param {
+    AtrPeriod,
+    BetaIn,
+    BetaOut,
+    GammaIn,
+    GammaOut,
+    LosscutRate,
+    ExitMeanFrame,
+}
+
+impl Param {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Param::AtrPeriod => "atr_period",
+            Param::BetaIn => "beta_in",
+            Param::BetaOut => "beta_out",
+            Param::GammaIn => "gamma_in",
+            Param::GammaOut => "gamma_out",
+            Param::LosscutRate => "losscut_rate",
+            Param::ExitMeanFrame => "exit_mean_frame",
+        }
+    }
+
+    fn is_int(&self) -> bool {
+        matches!(self, Param::AtrPeriod | Param::ExitMeanFrame)
+    }
+
+    fn apply(&self, config: &mut TracingMMConfig, value: f64) {
+        match self {
+            Param::AtrPeriod => config.atr_period = value.round() as i64,
+            Param::BetaIn => config.beta.r#in = value,
+            Param::BetaOut => config.beta.out = value,
+            Param::GammaIn => config.gamma.r#in = value,
+            Param::GammaOut => config.gamma.out = value,
+            Param::LosscutRate => config.losscut_rate = Some(value),
+            Param::ExitMeanFrame => config.exit_mean_frame = value.round() as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ParamRange {
+    pub min: f64,
+    pub max: f64,
+    /// gridでの分割数
+    #[serde(default = "grid_default")]
+    pub grid: usize,
+    /// 対数スケールで探索する
+    #[serde(default)]
+    pub log: bool,
+}
+
+fn grid_default() -> usize {
+    5
+}
+
+impl ParamRange {
+    pub const fn new(min: f64, max: f64) -> Self {
+        Self { min, max, grid: 5, log: false }
+    }
+
+    /// [0, 1] -> [min, max]
+    fn denormalize(&self, u: f64) -> f64 {
+        let u = u.clamp(0., 1.);
+        if self.log {
+            (self.min.ln() + (self.max.ln() - self.min.ln()) * u).exp()
+        } else {
+            self.min + (self.max - self.min) * u
+        }
+    }
+
+    fn normalize(&self, x: f64) -> f64 {
+        if self.max == self.min {
+            return 0.;
+        }
+        if self.log {
+            (x.ln() - self.min.ln()) / (self.max.ln() - self.min.ln())
+        } else {
+            (x - self.min) / (self.max - self.min)
+        }
+    }
+}
+
+/// 省略したパラメータは元の設定のまま
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchSpace {
+    #[serde(default)]
+    pub atr_period: Option<ParamRange>,
+    #[serde(default)]
+    pub beta_in: Option<ParamRange>,
+    #[serde(default)]
+    pub beta_out: Option<ParamRange>,
+    #[serde(default)]
+    pub gamma_in: Option<ParamRange>,
+    #[serde(default)]
+    pub gamma_out: Option<ParamRange>,
+    #[serde(default)]
+    pub losscut_rate: Option<ParamRange>,
+    #[serde(default)]
+    pub exit_mean_frame: Option<ParamRange>,
+    /// beta, gammaのoutをinと同じ値にする（ドテン）
+    #[serde(default)]
+    pub tie_in_out: bool,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        Self {
+            atr_period: Some(ParamRange::new(10., 40.)),
+            beta_in: Some(ParamRange::new(0.5, 3.0)),
+            beta_out: Some(ParamRange::new(0.5, 3.0)),
+            gamma_in: Some(ParamRange::new(0.5, 20.0)),
+            gamma_out: Some(ParamRange::new(0.5, 20.0)),
+            losscut_rate: Some(ParamRange::new(0.02, 0.15)),
+            exit_mean_frame: Some(ParamRange::new(20., 120.)),
+            tie_in_out: false,
+        }
+    }
+}
+
+impl SearchSpace {
+    fn range(&self, param: Param) -> Option<ParamRange> {
+        match param {
+            Param::AtrPeriod => self.atr_period,
+            Param::BetaIn => self.beta_in,
+            Param::BetaOut if self.tie_in_out => None,
+            Param::BetaOut => self.beta_out,
+            Param::GammaIn => self.gamma_in,
+            Param::GammaOut if self.tie_in_out => None,
+            Param::GammaOut => self.gamma_out,
+            Param::LosscutRate => self.losscut_rate,
+            Param::ExitMeanFrame => self.exit_mean_frame,
+        }
+    }
+
+    /// 探索する次元
+    pub fn dims(&self) -> Vec<(Param, ParamRange)> {
+        Param::iter().filter_map(|p| self.range(p).map(|r| (p, r))).collect()
+    }
+
+    pub fn apply(&self, base: &TracingMMConfig, values: &[f64]) -> TracingMMConfig {
+        let mut config = base.clone();
+        for ((param, _), &value) in self.dims().iter().zip(values) {
+            param.apply(&mut config, value);
+        }
+        if self.tie_in_out {
+            config.beta.out = config.beta.r#in;
+            config.gamma.out = config.gamma.r#in;
+        }
+        config
+    }
+
+    fn round(&self, values: Vec<f64>) -> Vec<f64> {
+        self.dims().iter().zip(values).map(|((param, _), v)| if param.is_int() { v.round() } else { v }).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchMethod {
+    Grid,
+    Random,
+    Tpe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Objective {
+    TotalPnl,
+    Sharpe,
+    /// total_pnl / max_drawdown
+    Calmar,
+}
+
+impl Objective {
+    pub fn score(&self, summary: &BacktestSummary) -> f64 {
+        match self {
+            Objective::TotalPnl => summary.total_pnl,
+            Objective::Sharpe => summary.sharpe,
+            Objective::Calmar => if summary.max_drawdown > 0. { summary.total_pnl / summary.max_drawdown } else { summary.total_pnl },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trial {
+    pub values: Vec<f64>,
+    pub summary: BacktestSummary,
+    pub score: f64,
+}
+
+pub struct Optimizer<'a> {
+    pub base: &'a TracingMMConfig,
+    pub space: SearchSpace,
+    pub data: &'a BacktestData,
+    pub params: BacktestParams,
+    pub objective: Objective,
+    pub threads: usize,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn run(&self, method: SearchMethod, trials: usize, seed: u64) -> Vec<Trial> {
+        let mut rng = SplitMix64::new(seed);
+        let dims = self.space.dims();
+        let mut ret = match method {
+            SearchMethod::Grid => self.evaluate(grid(&dims).into_iter().map(|v| self.space.round(v)).collect()),
+            SearchMethod::Random => self.evaluate((0..trials).map(|_| self.space.round(random_point(&dims, &mut rng))).collect()),
+            SearchMethod::Tpe => {
+                // 最初はランダムに探索し、以降はスレッド数ずつ提案する
+                let n_startup = (trials / 4).max(self.threads).min(trials);
+                let mut history = self.evaluate((0..n_startup).map(|_| self.space.round(random_point(&dims, &mut rng))).collect());
+                while history.len() < trials {
+                    let batch = self.threads.min(trials - history.len());
+                    let candidates = (0..batch).map(|_| self.space.round(tpe_suggest(&dims, &history, &mut rng))).collect();
+                    let results = self.evaluate(candidates);
+                    if results.is_empty() {
+                        break;
+                    }
+                    history.extend(results);
+                    info!("tpe {}/{} best: {:?}", history.len(), trials, history.iter().map(|t| t.score).fold(f64::NEG_INFINITY, f64::max));
+                }
+                history
+            },
+        };
+        ret.sort_by(|a, b| b.score.total_cmp(&a.score));
+        ret
+    }
+
+    /// 並列にバックテストを実行する。失敗したものは除く
+    pub fn evaluate(&self, candidates: Vec<Vec<f64>>) -> Vec<Trial> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![]);
+        std::thread::scope(|s| {
+            for _ in 0..self.threads.max(1) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(values) = candidates.get(i) else { break };
+                    let config = self.space.apply(self.base, values);
+                    match run_backtest(&config, self.data, &self.params) {
+                        Ok(result) => {
+                            let score = self.objective.score(&result.summary);
+                            results.lock().push(Trial { values: values.clone(), summary: result.summary, score });
+                        },
+                        Err(e) => warn!("backtest failed. values: {:?}, error: {:?}", values, e),
+                    }
+                });
+            }
+        });
+        results.into_inner()
+    }
+}
+
+fn grid(dims: &[(Param, ParamRange)]) -> Vec<Vec<f64>> {
+    let mut ret = vec![vec![]];
+    for (param, range) in dims {
+        let n = range.grid.max(1);
+        let mut axis = (0..n).map(|i| range.denormalize(if n == 1 { 0.5 } else { i as f64 / (n - 1) as f64 })).collect::<Vec<_>>();
+        if param.is_int() {
+            let mut seen = HashSet::new();
+            axis = axis.into_iter().map(f64::round).filter(|v| seen.insert(*v as i64)).collect();
+        }
+        ret = ret.into_iter().flat_map(|prefix| axis.iter().map(move |&v| {
+            let mut p = prefix.clone();
+            p.push(v);
+            p
+        })).collect();
+    }
+    ret
+}
+
+fn random_point(dims: &[(Param, ParamRange)], rng: &mut SplitMix64) -> Vec<f64> {
+    dims.iter().map(|(_, range)| range.denormalize(rng.next_f64())).collect()
+}
+
+/// TPEの簡易版
+/// 上位25%とそれ以外で次元ごとにParzen推定し、l(x)/g(x)が最大の候補を返す
+fn tpe_suggest(dims: &[(Param, ParamRange)], history: &[Trial], rng: &mut SplitMix64) -> Vec<f64> {
+    const N_CANDIDATES: usize = 24;
+    if history.is_empty() {
+        return random_point(dims, rng);
+    }
+    let mut sorted = history.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let n_good = ((sorted.len() as f64 * 0.25).ceil() as usize).max(1);
+    let normalized = |t: &Trial| dims.iter().zip(&t.values).map(|((_, r), &v)| r.normalize(v)).collect::<Vec<_>>();
+    let good = sorted[..n_good].iter().map(|t| normalized(t)).collect::<Vec<_>>();
+    let bad = sorted[n_good..].iter().map(|t| normalized(t)).collect::<Vec<_>>();
+    let bw_good = bandwidth(good.len());
+    let bw_bad = bandwidth(bad.len());
+
+    let mut best = None;
+    let mut best_score = f64::NEG_INFINITY;
+    for _ in 0..N_CANDIDATES {
+        let center = &good[(rng.next_u64() % good.len() as u64) as usize];
+        let candidate = center.iter().map(|&c| (c + rng.next_gaussian() * bw_good).clamp(0., 1.)).collect::<Vec<_>>();
+        let score = (0..dims.len()).map(|d| {
+            parzen(candidate[d], good.iter().map(|x| x[d]), bw_good).ln() - parzen(candidate[d], bad.iter().map(|x| x[d]), bw_bad).ln()
+        }).sum::<f64>();
+        if score > best_score {
+            best_score = score;
+            best = Some(candidate);
+        }
+    }
+    dims.iter().zip(best.unwrap()).map(|((_, r), u)| r.denormalize(u)).collect()
+}
+
+fn bandwidth(n: usize) -> f64 {
+    (0.5 * (n.max(1) as f64).powf(-0.2)).max(0.05)
+}
+
+/// [0, 1]の一様分布を事前分布として混ぜたカーネル密度
+fn parzen(x: f64, points: impl Iterator<Item = f64>, bw: f64) -> f64 {
+    let mut sum = 1.;
+    let mut n = 1.;
+    for p in points {
+        let z = (x - p) / bw;
+        sum += (-0.5 * z * z).exp() / (bw * (2. * std::f64::consts::PI).sqrt());
+        n += 1.;
+    }
+    sum / n
+}
+
+/// 再現性のための小さな乱数生成器
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn next_gaussian(&mut self) -> f64 {
+        let u1 = self.next_f64().max(f64::MIN_POSITIVE);
+        let u2 = self.next_f64();
+        (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+    }
+}
+
+/// パラメータとバックテスト結果をscoreの降順に並べた表
+pub fn trials_df(space: &SearchSpace, trials: &[Trial]) -> anyhow::Result<DataFrame> {
+    let mut columns = space.dims().iter().enumerate().map(|(d, (param, _))| {
+        Series::new(param.name(), trials.iter().map(|t| t.values[d]).collect::<Vec<_>>())
+    }).collect::<Vec<_>>();
+    columns.push(Series::new("score", trials.iter().map(|t| t.score).collect::<Vec<_>>()));
+    let summaries = summary_df(&trials.iter().map(|t| t.summary.clone()).collect::<Vec<_>>())?;
+    let df = DataFrame::new(columns)?.hstack(summaries.get_columns())?;
+    Ok(df.sort(vec!["score"], true)?)
+}
+
+/// config.bot.yamlの元のエントリをbestの値で書き換えた断片
+pub fn config_fragment(name: &str, entry: &Value, best: &TracingMMConfig) -> anyhow::Result<String> {
+    let mut entry = entry.clone();
+    entry["atr_period"] = serde_yaml::to_value(best.atr_period)?;
+    entry["beta"]["in"] = serde_yaml::to_value(best.beta.r#in)?;
+    entry["beta"]["out"] = serde_yaml::to_value(best.beta.out)?;
+    entry["gamma"]["in"] = serde_yaml::to_value(best.gamma.r#in)?;
+    entry["gamma"]["out"] = serde_yaml::to_value(best.gamma.out)?;
+    if let Some(losscut_rate) = best.losscut_rate {
+        entry["losscut_rate"] = serde_yaml::to_value(losscut_rate)?;
+    }
+    entry["exit_mean_frame"] = serde_yaml::to_value(best.exit_mean_frame)?;
+    let mut root = serde_yaml::Mapping::new();
+    root.insert(Value::from(name), entry);
+    Ok(serde_yaml::to_string(&root)?)
+}
+
+#[test]
+fn test_grid() {
+    let space = SearchSpace {
+        atr_period: Some(ParamRange { min: 10., max: 12., grid: 5, log: false }),
+        beta_in: Some(ParamRange { min: 1., max: 2., grid: 2, log: false }),
+        beta_out: None,
+        gamma_in: None,
+        gamma_out: None,
+        losscut_rate: None,
+        exit_mean_frame: None,
+        tie_in_out: true,
+    };
+    // 整数は重複を除く
+    let points = grid(&space.dims());
+    assert_eq!(points.len(), 6);
+    assert_eq!(points[0], vec![10., 1.]);
+    assert_eq!(points[5], vec![12., 2.]);
+}
+
+#[test]
+fn test_tpe_suggest_in_range() {
+    let dims = SearchSpace::default().dims();
+    let mut rng = SplitMix64::new(0);
+    let history = (0..20).map(|i| Trial {
+        values: random_point(&dims, &mut rng),
+        summary: BacktestSummary::default(),
+        score: i as f64,
+    }).collect::<Vec<_>>();
+    for _ in 0..10 {
+        let suggested = tpe_suggest(&dims, &history, &mut rng);
+        for ((_, range), v) in dims.iter().zip(suggested) {
+            assert!(range.min <= v && v <= range.max);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{collections::BTreeMap, fs::File, io::{BufReader, BufRead, ErrorKind}, path::{Path, PathBuf}};
+
+use anyhow::Context;
+use chrono::{NaiveDate, Duration, DateTime, Utc};
+use log::info;
+use polars::prelude::{ParquetReader, SerReader, IntoLazy};
+use polars::lazy::dsl::col;
+use serde::Deserialize;
+
+use crate::{config::TracingMMConfig, symbol::Symbol, client::types::{TradeRecord, MpackTradeRecord, trades_time_fn, KLines}, order_types::Side, utils::{record_writer::SerialRecordWriter, time::{parse_format_time_utc, UnixTimeUnit, floor_time_sec, datetime_utc_from_timestamp}}};
+
+use super::{engine::{BacktestData, BacktestParams}, matching::FeeModel};
+
+/// [since, until]の日付。SerialRecordWriterのファイルはJSTの日付で分かれている
+pub fn date_range(since: NaiveDate, until: NaiveDate) -> Vec<NaiveDate> {
+    let mut ret = vec![];
+    let mut day = since;
+    while day <= until {
+        ret.push(day);
+        day += Duration::days(1);
+    }
+    ret
+}
+
+fn trades_file_name(symbol: &Symbol, day: NaiveDate) -> String {
+    SerialRecordWriter::<MpackTradeRecord>::new("marketTrades", symbol, "msgpack", Box::new(trades_time_fn)).file_name(day)
+}
+
+fn klines_file_name(symbol: &Symbol, day: NaiveDate) -> String {
+    SerialRecordWriter::<()>::new("klines", symbol, "log", Box::new(|_| None)).file_name(day)
+}
+
+/// crawlerが書き出したmarketTradesを読み込む。timestamp順
+pub fn load_trades(dir: &Path, symbol: &Symbol, days: &[NaiveDate]) -> anyhow::Result<Vec<TradeRecord>> {
+    let mut ret = vec![];
+    for &day in days {
+        let path = dir.join(trades_file_name(symbol, day));
+        if !path.exists() {
+            info!("trades file not found: {}", path.display());
+            continue;
+        }
+        let mut rd = BufReader::new(File::open(&path)?);
+        loop {
+            // MpackTradeRecordの形式 (price, amount, timestamp, is_sell)
+            match rmp_serde::from_read::<_, (f64, f64, i64, bool)>(&mut rd) {
+                Ok((price, amount, timestamp, is_sell)) => {
+                    ret.push(TradeRecord::new(*symbol, timestamp, price, amount, if is_sell { Side::Sell } else { Side::Buy }));
+                },
+                Err(rmp_serde::decode::Error::InvalidMarkerRead(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+            }
+        }
+    }
+    // 日付を跨いで書き込まれることがあるので並べ直す
+    ret.sort_by_key(|t| t.timestamp);
+    Ok(ret)
+}
+
+#[derive(Debug, Deserialize)]
+struct KLineLogRow {
+    opentime: String,
+    open: Option<f64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    close: Option<f64>,
+    volume: Option<f64>,
+}
+
+/// crawlerが書き出したklinesを読み込む。ファイルがなければNone
+pub fn load_kline_logs(dir: &Path, symbol: &Symbol, days: &[NaiveDate]) -> anyhow::Result<Option<KLines>> {
+    // 同じopentimeが複数回書き込まれていれば後のものを使う
+    let mut rows = BTreeMap::new();
+    for &day in days {
+        let path = dir.join(klines_file_name(symbol, day));
+        if !path.exists() {
+            continue;
+        }
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let row: KLineLogRow = serde_json::from_str(&line)?;
+            let opentime = parse_format_time_utc(&row.opentime)?.timestamp();
+            rows.insert(opentime, vec![Some(opentime as f64), row.open, row.high, row.low, row.close, row.volume]);
+        }
+    }
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(KLines::new_options(&rows.into_values().collect(), UnixTimeUnit::Second)?))
+}
+
+/// opentime, open, high, low, close, volumeの列を持つparquet
+pub fn load_kline_parquet(path: &Path) -> anyhow::Result<KLines> {
+    let mut file = File::open(path)?;
+    let df = ParquetReader::new(&mut file).finish()?;
+    let df = df.lazy().select(vec![
+        col("opentime"),
+        col("open"),
+        col("high"),
+        col("low"),
+        col("close"),
+        col("volume"),
+    ]).collect()?;
+    KLines::from(df).sorted()
+}
+
+/// 約定履歴からklinesを作る
+pub fn klines_from_trades(trades: &[TradeRecord], timeframe: Duration) -> anyhow::Result<KLines> {
+    let mut rows: Vec<Vec<f64>> = vec![];
+    for trade in trades {
+        let opentime = floor_time_sec(datetime_utc_from_timestamp(trade.timestamp, UnixTimeUnit::MilliSecond), timeframe, 0) as f64;
+        match rows.last_mut() {
+            Some(row) if row[0] == opentime => {
+                row[2] = row[2].max(trade.price);
+                row[3] = row[3].min(trade.price);
+                row[4] = trade.price;
+                row[5] += trade.amount;
+            },
+            _ => rows.push(vec![opentime, trade.price, trade.price, trade.price, trade.price, trade.amount]),
+        }
+    }
+    KLines::new(&rows, UnixTimeUnit::Second)
+}
+
+/// timeframeが元のklinesの整数倍でない場合、opentimeで切り捨てた足に含めるので近似になる
+pub fn resample(klines: &KLines, timeframe: Duration) -> anyhow::Result<KLines> {
+    let df = &klines.df;
+    let opentime = df.column("opentime")?.datetime()?.as_datetime_iter().collect::<Vec<_>>();
+    let open = df.column("open")?.f64()?.into_iter().collect::<Vec<_>>();
+    let high = df.column("high")?.f64()?.into_iter().collect::<Vec<_>>();
+    let low = df.column("low")?.f64()?.into_iter().collect::<Vec<_>>();
+    let close = df.column("close")?.f64()?.into_iter().collect::<Vec<_>>();
+    let volume = df.column("volume")?.f64()?.into_iter().collect::<Vec<_>>();
+    let mut rows: Vec<Vec<Option<f64>>> = vec![];
+    for (i, t) in opentime.into_iter().enumerate() {
+        let t = DateTime::<Utc>::from_utc(t.context("opentime is null")?, Utc);
+        let bar = floor_time_sec(t, timeframe, 0) as f64;
+        let row = [open[i], high[i], low[i], close[i], volume[i]];
+        match rows.last_mut() {
+            Some(last) if last[0] == Some(bar) => {
+                last[1] = last[1].or(row[0]);
+                last[2] = max_option(last[2], row[1]);
+                last[3] = min_option(last[3], row[2]);
+                last[4] = row[3].or(last[4]);
+                last[5] = Some(last[5].unwrap_or(0.) + row[4].unwrap_or(0.));
+            },
+            _ => rows.push(vec![Some(bar), row[0], row[1], row[2], row[3], row[4]]),
+        }
+    }
+    KLines::new_options(&rows, UnixTimeUnit::Second)
+}
+
+fn max_option(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn min_option(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// klinesのログがあればそれを、なければ約定履歴から作ったklinesを使う
+/// 欠けている足はreindexで埋める
+pub fn load_klines(dir: &Path, symbol: &Symbol, days: &[NaiveDate], timeframe: Duration, trades: Option<&[TradeRecord]>) -> anyhow::Result<KLines> {
+    let klines = match load_kline_logs(dir, symbol, days)? {
+        Some(klines) => resample(&klines, timeframe)?,
+        None => {
+            let loaded;
+            let trades = match trades {
+                Some(trades) => trades,
+                None => {
+                    loaded = load_trades(dir, symbol, days)?;
+                    &loaded
+                },
+            };
+            if trades.is_empty() {
+                anyhow::bail!("neither klines nor trades found for {:?} in {}", symbol, dir.display());
+            }
+            klines_from_trades(trades, timeframe)?
+        },
+    };
+    fill_klines(klines, timeframe)
+}
+
+/// 最後の足までの欠けている足を埋める
+pub fn fill_klines(klines: KLines, timeframe: Duration) -> anyhow::Result<KLines> {
+    let last = klines.df.column("opentime")?.datetime()?.as_datetime_iter().last().flatten().context("klines is empty")?;
+    let until = DateTime::<Utc>::from_utc(last, Utc) + timeframe;
+    klines.reindex(until, timeframe)
+}
+
+/// backtest, optimizeで共通の引数
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReplayArgs {
+    /// YYYYMMDD (JST)
+    #[clap(long, value_parser = parse_date)]
+    pub since: NaiveDate,
+    /// YYYYMMDD (JST)
+    #[clap(long, value_parser = parse_date)]
+    pub until: NaiveDate,
+    #[clap(long, default_value = "market")]
+    pub market_dir: PathBuf,
+    /// market_dirのklinesの代わりに使うparquet
+    #[clap(long)]
+    pub klines: Option<PathBuf>,
+    #[clap(long)]
+    pub ref_klines: Option<PathBuf>,
+    #[clap(long)]
+    pub spot_klines: Option<PathBuf>,
+    #[clap(long, default_value_t = 1_000_000.)]
+    pub initial_collateral: f64,
+    /// 省略するとinstruments.yamlの手数料
+    #[clap(long)]
+    pub maker_fee: Option<f64>,
+    #[clap(long)]
+    pub taker_fee: Option<f64>,
+    #[clap(long, default_value_t = 0.)]
+    pub slippage: f64,
+}
+
+impl ReplayArgs {
+    pub fn source(&self) -> ReplaySource {
+        ReplaySource {
+            market_dir: self.market_dir.clone(),
+            since: self.since,
+            until: self.until,
+            klines: self.klines.clone(),
+            ref_klines: self.ref_klines.clone(),
+            spot_klines: self.spot_klines.clone(),
+        }
+    }
+
+    pub fn params(&self, symbol: &Symbol) -> anyhow::Result<BacktestParams> {
+        let instrument = symbol.instrument()?;
+        Ok(BacktestParams {
+            initial_collateral: self.initial_collateral,
+            fee: FeeModel {
+                maker: self.maker_fee.unwrap_or(instrument.maker_fee),
+                taker: self.taker_fee.unwrap_or(instrument.taker_fee),
+                slippage: self.slippage,
+            },
+        })
+    }
+
+    /// 出力先のディレクトリ名
+    pub fn run_name(&self, name: &str) -> String {
+        format!("{}_{}_{}", name, self.since.format("%Y%m%d"), self.until.format("%Y%m%d"))
+    }
+}
+
+fn parse_date(s: &str) -> anyhow::Result<NaiveDate> {
+    Ok(NaiveDate::parse_from_str(s, "%Y%m%d")?)
+}
+
+/// バックテストに使うファイル
+#[derive(Debug, Clone)]
+pub struct ReplaySource {
+    /// SerialRecordWriterの書き出し先
+    pub market_dir: PathBuf,
+    pub since: NaiveDate,
+    pub until: NaiveDate,
+    /// 指定すればmarket_dirのklinesの代わりに使うparquet
+    pub klines: Option<PathBuf>,
+    pub ref_klines: Option<PathBuf>,
+    pub spot_klines: Option<PathBuf>,
+}
+
+impl ReplaySource {
+    fn klines(&self, path: &Option<PathBuf>, symbol: &Symbol, timeframe: Duration, trades: Option<&[TradeRecord]>) -> anyhow::Result<KLines> {
+        let days = date_range(self.since, self.until);
+        match path {
+            Some(path) => fill_klines(resample(&load_kline_parquet(path)?, timeframe)?, timeframe),
+            None => load_klines(&self.market_dir, symbol, &days, timeframe, trades),
+        }
+    }
+
+    pub fn load(&self, config: &TracingMMConfig) -> anyhow::Result<BacktestData> {
+        let timeframe: Duration = config.timeframe.into();
+        let trades = load_trades(&self.market_dir, &config.symbol, &date_range(self.since, self.until))?;
+        if trades.is_empty() {
+            anyhow::bail!("no trades found for {:?} in {}", config.symbol, self.market_dir.display());
+        }
+        let klines = self.klines(&self.klines, &config.symbol, timeframe, Some(&trades))?;
+        let ref_klines = self.klines(&self.ref_klines, &config.ref_symbol, timeframe, None)?;
+        let spot_klines = match config.hooks().sfd {
+            Some(sfd) => Some(self.klines(&self.spot_klines, &sfd.spot_symbol, timeframe, None)?),
+            None => None,
+        };
+        info!("loaded trades: {}, klines: {}, ref_klines: {}", trades.len(), klines.df.height(), ref_klines.df.height());
+        Ok(BacktestData { trades, klines, ref_klines, spot_klines })
+    }
+}
+
+#[test]
+fn test_klines_from_trades() {
+    use crate::symbol::{Currency, SymbolType, Exchange};
+    use crate::utils::time::datetime_utc;
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Spot, Exchange::Coincheck);
+    let t0 = datetime_utc(2023, 1, 1, 0, 0, 0).timestamp_millis();
+    let trades = vec![
+        TradeRecord::new(symbol, t0, 100., 1., Side::Buy),
+        TradeRecord::new(symbol, t0 + 30_000, 120., 1., Side::Buy),
+        TradeRecord::new(symbol, t0 + 59_000, 90., 2., Side::Sell),
+        TradeRecord::new(symbol, t0 + 180_000, 110., 1., Side::Buy),
+    ];
+    let klines = fill_klines(klines_from_trades(&trades, Duration::seconds(60)).unwrap(), Duration::seconds(60)).unwrap();
+    assert_eq!(klines.df.height(), 4);
+    assert_eq!(klines.at(datetime_utc(2023, 1, 1, 0, 0, 0), "high").unwrap(), Some(120.));
+    assert_eq!(klines.at(datetime_utc(2023, 1, 1, 0, 0, 0), "volume").unwrap(), Some(4.));
+    // 約定のない足はcloseで埋まる
+    assert_eq!(klines.at(datetime_utc(2023, 1, 1, 0, 1, 0), "open").unwrap(), Some(90.));
+
+    let resampled = resample(&klines, Duration::seconds(120)).unwrap();
+    assert_eq!(resampled.df.height(), 2);
+    assert_eq!(resampled.at(datetime_utc(2023, 1, 1, 0, 2, 0), "close").unwrap(), Some(110.));
+}
@@ -0,0 +1,69 @@
+use std::{fs::File, path::Path};
+
+use polars::prelude::{DataFrame, NamedFrom, ParquetWriter, ChunkedArray, TimeUnit};
+use polars::series::{Series, IntoSeries};
+
+use super::engine::{BacktestResult, BacktestSummary};
+
+fn datetime_series_ms(name: &str, values: Vec<i64>) -> Series {
+    ChunkedArray::from_vec(name, values)
+        .into_datetime(TimeUnit::Milliseconds, Some("UTC".to_string()))
+        .into_series()
+}
+
+pub fn fills_df(result: &BacktestResult) -> anyhow::Result<DataFrame> {
+    let fills = &result.fills;
+    Ok(DataFrame::new(vec![
+        datetime_series_ms("timestamp", fills.iter().map(|f| f.fill.timestamp).collect()),
+        Series::new("side", fills.iter().map(|f| format!("{:?}", f.fill.side)).collect::<Vec<_>>()),
+        Series::new("order_type", fills.iter().map(|f| format!("{:?}", f.order_type)).collect::<Vec<_>>()),
+        Series::new("kind", fills.iter().map(|f| f.kind.map(|k| format!("{:?}", k))).collect::<Vec<_>>()),
+        Series::new("liquidity", fills.iter().map(|f| format!("{:?}", f.fill.liquidity)).collect::<Vec<_>>()),
+        Series::new("price", fills.iter().map(|f| f.fill.price.to_f64()).collect::<Vec<_>>()),
+        Series::new("amount", fills.iter().map(|f| f.fill.amount.to_f64()).collect::<Vec<_>>()),
+        Series::new("fee", fills.iter().map(|f| f.fill.fee).collect::<Vec<_>>()),
+        Series::new("realized_pnl", fills.iter().map(|f| f.fill.realized_pnl).collect::<Vec<_>>()),
+    ])?)
+}
+
+pub fn equity_df(result: &BacktestResult) -> anyhow::Result<DataFrame> {
+    let equity = &result.equity;
+    Ok(DataFrame::new(vec![
+        datetime_series_ms("opentime", equity.iter().map(|e| e.opentime).collect()),
+        Series::new("collateral", equity.iter().map(|e| e.collateral).collect::<Vec<_>>()),
+        Series::new("unrealized_pnl", equity.iter().map(|e| e.unrealized_pnl).collect::<Vec<_>>()),
+        Series::new("equity", equity.iter().map(|e| e.equity).collect::<Vec<_>>()),
+        Series::new("long_pos", equity.iter().map(|e| e.long_pos).collect::<Vec<_>>()),
+        Series::new("short_pos", equity.iter().map(|e| e.short_pos).collect::<Vec<_>>()),
+    ])?)
+}
+
+pub fn summary_df(summaries: &[BacktestSummary]) -> anyhow::Result<DataFrame> {
+    Ok(DataFrame::new(vec![
+        Series::new("final_equity", summaries.iter().map(|s| s.final_equity).collect::<Vec<_>>()),
+        Series::new("total_pnl", summaries.iter().map(|s| s.total_pnl).collect::<Vec<_>>()),
+        Series::new("realized_pnl", summaries.iter().map(|s| s.realized_pnl).collect::<Vec<_>>()),
+        Series::new("fees", summaries.iter().map(|s| s.fees).collect::<Vec<_>>()),
+        Series::new("num_fills", summaries.iter().map(|s| s.num_fills as u64).collect::<Vec<_>>()),
+        Series::new("num_closes", summaries.iter().map(|s| s.num_closes as u64).collect::<Vec<_>>()),
+        Series::new("win_rate", summaries.iter().map(|s| s.win_rate).collect::<Vec<_>>()),
+        Series::new("max_drawdown", summaries.iter().map(|s| s.max_drawdown).collect::<Vec<_>>()),
+        Series::new("max_drawdown_rate", summaries.iter().map(|s| s.max_drawdown_rate).collect::<Vec<_>>()),
+        Series::new("sharpe", summaries.iter().map(|s| s.sharpe).collect::<Vec<_>>()),
+    ])?)
+}
+
+pub fn write_parquet(df: &mut DataFrame, path: &Path) -> anyhow::Result<()> {
+    let file = File::create(path)?;
+    ParquetWriter::new(file).finish(df)?;
+    Ok(())
+}
+
+/// pnl.parquet, trades.parquet, equity.parquetをdirに書き出す
+pub fn write_result(result: &BacktestResult, dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    write_parquet(&mut summary_df(std::slice::from_ref(&result.summary))?, &dir.join("pnl.parquet"))?;
+    write_parquet(&mut fills_df(result)?, &dir.join("trades.parquet"))?;
+    write_parquet(&mut equity_df(result)?, &dir.join("equity.parquet"))?;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, anyhow};
+use clap::Parser;
+use log::LevelFilter;
+
+use bot::{config::{self, Strategy}, logger, backtest::{replay::ReplayArgs, engine::run_backtest, report::write_result}};
+
+static LOGGER: logger::BotLogger = logger::BotLogger;
+
+/// config.bot.yamlのtracing_mmをmarket/の記録で再生する
+///
+/// ```shell
+/// ./backtest --name tracing_mm_coincheck --since 20230701 --until 20230731 --ref-klines btcusdt_150s.parquet
+/// ```
+#[derive(Parser)]
+struct Args {
+    #[clap(short, long)]
+    name: String,
+    #[clap(flatten)]
+    replay: ReplayArgs,
+    #[clap(long, default_value = "backtest")]
+    out: PathBuf,
+    /// 注文ごとのログを出す
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(if args.verbose { LevelFilter::Info } else { LevelFilter::Warn }))?;
+
+    let config = match config::load_config()?.remove(&args.name).context(anyhow!("{} is not found in config", args.name))? {
+        Strategy::TracingMm(config) => config,
+        _ => anyhow::bail!("{} is not tracing_mm", args.name),
+    };
+
+    let data = args.replay.source().load(&config)?;
+    let result = run_backtest(&config, &data, &args.replay.params(&config.symbol)?)?;
+    let out = args.out.join(args.replay.run_name(&args.name));
+    write_result(&result, &out)?;
+    println!("wrote {}", out.display());
+    println!("{:#?}", result.summary);
+    Ok(())
+}
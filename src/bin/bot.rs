@@ -1,11 +1,12 @@
-use std::{collections::HashMap, env, str::FromStr};
+use std::{collections::{HashMap, HashSet}, env, net::SocketAddr, str::FromStr};
 
 use anyhow::{Context, anyhow};
 use clap::Parser;
-use bot::{config::{Strategy, self}, logger, global_vars::{DEBUG, DebugFlag, get_debug}};
-use log::LevelFilter;
+use bot::{config::{Strategy, self}, logger, global_vars::{DEBUG, PAPER, DebugFlag, get_debug}, client::paper::PaperArgs, utils::{shutdown, metrics, control, alert}};
+use log::{info, error, LevelFilter};
+use maplit::hashset;
 use once_cell::sync::Lazy;
-use bot::symbol::Exchange;
+use bot::{symbol::Exchange, instrument::refresh_instruments};
 
 #[derive(Parser)]
 struct Args {
@@ -13,6 +14,17 @@ struct Args {
     name: String,
     #[clap(short, long, default_value = "none")]
     debug: String,
+    #[clap(flatten)]
+    paper: PaperArgs,
+    /// 起動時に取引所のAPIから銘柄情報を取り直す
+    #[clap(long)]
+    refresh_instruments: bool,
+    /// 指定するとPrometheus形式のメトリクスを`http://{addr}/metrics`で返す
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// 指定するとcontrol API（status, pause, resume, cancel_all, flatten, reload）を待ち受ける。ループバックのみ
+    #[clap(long)]
+    control_addr: Option<SocketAddr>,
 }
 
 static LOGGER: logger::BotLogger = logger::BotLogger;
@@ -26,24 +38,75 @@ async fn main() -> anyhow::Result<()> {
 
     env::set_var("NAME", &args.name);
     DEBUG.set(DebugFlag::from_str(&args.debug).unwrap()).unwrap();
+    PAPER.set(if args.paper.paper { Some(args.paper.clone()) } else { None }).unwrap();
 
     if get_debug()==DebugFlag::None {
         log::set_logger(&LOGGER)
             .map(|()| log::set_max_level(LevelFilter::Info))?;
     }
 
+    // 通知の設定が壊れていると最初の通知のときに落ちるので、起動時に確かめる
+    alert::init()?;
+
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(async move {
+            // 待ち受けられなくてもbotは動かす
+            if let Err(e) = metrics::serve_metrics(addr).await {
+                error!("failed to serve metrics: {:?}", e);
+            }
+        });
+    }
+    if let Some(addr) = args.control_addr {
+        control::init()?;
+        tokio::spawn(async move {
+            if let Err(e) = control::serve_control(addr).await {
+                error!("failed to serve control API: {:?}", e);
+            }
+        });
+    }
+
     let strategy = CONFIG.get(&args.name).context(anyhow!("{} is not found in config", args.name))?;
+    if args.refresh_instruments {
+        for exc in strategy_exchanges(strategy) {
+            // 取れなくてもinstruments.yamlの値で動かす
+            match refresh_instruments(exc).await {
+                Ok(n) => info!("refreshed {} instruments of {}", n, exc),
+                Err(e) => info!("failed to refresh instruments of {}: {:?}", exc, e),
+            }
+        }
+    }
+    strategy.check_instruments()?;
+    tokio::select! {
+        res = start_strategy(strategy) => res?,
+        sig = shutdown::wait_signal() => {
+            info!("{} received, shutting down", sig?);
+            let failed = shutdown::run_shutdown_hooks().await;
+            if failed > 0 {
+                anyhow::bail!("{} shutdown hooks failed", failed);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn strategy_exchanges(strategy: &Strategy) -> HashSet<Exchange> {
+    match strategy {
+        Strategy::Shannon(c) => hashset![c.symbol.exc],
+        Strategy::TracingMm(c) => hashset![c.symbol.exc, c.ref_symbol.exc],
+        Strategy::Crawler(c) => c.symbols.iter().map(|s| s.exc).collect(),
+    }
+}
+
+/// 戦略を起動する。通常は終了しない
+async fn start_strategy(strategy: &'static Strategy) -> anyhow::Result<()> {
     match strategy {
         Strategy::Shannon(strategy_config) => {
             bot::strategy::shannon_gmo::start_shannon_gmo(strategy_config).await;
         },
         Strategy::TracingMm(strategy_config) => {
             match strategy_config.symbol.exc {
-                Exchange::Bitflyer => {
-                    bot::strategy::tracingmm_bitflyer::start_tracingmm_bitflyer(strategy_config).await;
-                },
-                Exchange::Coincheck => {
-                    bot::strategy::tracingmm_coincheck::start_tracingmm_coincheck(strategy_config).await;
+                Exchange::Bitflyer | Exchange::Coincheck | Exchange::Gmo => {
+                    bot::strategy::tracingmm::start_tracingmm(strategy_config).await;
                 },
                 _ => {
                     anyhow::bail!("{} is not supported", strategy_config.symbol.exc);
@@ -0,0 +1,88 @@
+use std::{path::PathBuf, fs};
+
+use anyhow::{Context, anyhow};
+use clap::Parser;
+use log::LevelFilter;
+use serde_yaml::Value;
+
+use bot::{config::{self, Strategy, BOT_CONFIG_PATH}, logger, backtest::{replay::ReplayArgs, report::write_parquet, optimizer::{Optimizer, SearchSpace, SearchMethod, Objective, trials_df, config_fragment}}};
+
+static LOGGER: logger::BotLogger = logger::BotLogger;
+
+/// tracing_mmのパラメータをバックテストで探索する
+///
+/// ```shell
+/// ./optimize --name tracing_mm_coincheck --since 20230701 --until 20230731 --ref-klines btcusdt_150s.parquet --method tpe --trials 400
+/// ```
+#[derive(Parser)]
+struct Args {
+    #[clap(short, long)]
+    name: String,
+    #[clap(flatten)]
+    replay: ReplayArgs,
+    /// 探索範囲のyaml。省略時は全パラメータをデフォルトの範囲で探索する
+    #[clap(long)]
+    space: Option<PathBuf>,
+    #[clap(long, value_enum, default_value_t = SearchMethod::Tpe)]
+    method: SearchMethod,
+    /// random, tpeの試行回数
+    #[clap(long, default_value_t = 200)]
+    trials: usize,
+    #[clap(long, value_enum, default_value_t = Objective::TotalPnl)]
+    objective: Objective,
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+    /// 省略時はコア数
+    #[clap(long)]
+    threads: Option<usize>,
+    /// 表示する上位の件数
+    #[clap(long, default_value_t = 20)]
+    top: usize,
+    #[clap(long, default_value = "optimize")]
+    out: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Warn))?;
+
+    let config = match config::load_config()?.remove(&args.name).context(anyhow!("{} is not found in config", args.name))? {
+        Strategy::TracingMm(config) => config,
+        _ => anyhow::bail!("{} is not tracing_mm", args.name),
+    };
+    let space = match &args.space {
+        Some(path) => serde_yaml::from_str::<SearchSpace>(&fs::read_to_string(path)?)?,
+        None => SearchSpace::default(),
+    };
+    let threads = args.threads.unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+
+    let data = args.replay.source().load(&config)?;
+    let optimizer = Optimizer {
+        base: &config,
+        space: space.clone(),
+        data: &data,
+        params: args.replay.params(&config.symbol)?,
+        objective: args.objective,
+        threads,
+    };
+    let trials = optimizer.run(args.method, args.trials, args.seed);
+    let best = trials.first().context("all trials failed")?;
+
+    let out = args.out.join(args.replay.run_name(&args.name));
+    fs::create_dir_all(&out)?;
+    let mut df = trials_df(&space, &trials)?;
+    write_parquet(&mut df, &out.join("trials.parquet"))?;
+
+    let entry = serde_yaml::from_str::<Value>(&fs::read_to_string(BOT_CONFIG_PATH)?)?
+        .get(&args.name).cloned().context(anyhow!("{} is not found in {}", args.name, BOT_CONFIG_PATH))?;
+    let fragment = config_fragment(&args.name, &entry, &space.apply(&config, &best.values))?;
+    fs::write(out.join("config.bot.yaml"), &fragment)?;
+
+    std::env::set_var("POLARS_FMT_MAX_COLS", "30");
+    println!("{}", df.head(Some(args.top)));
+    println!("wrote {}", out.display());
+    println!("{}", fragment);
+    Ok(())
+}
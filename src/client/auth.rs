@@ -42,19 +42,49 @@ pub fn bitflyer_auth<T: serde::Serialize>(method: Method, path: &str, body: Opti
     Ok(headers)
 }
 
-const COINCHECK_BASE_URL: &str = "https://coincheck.com";
-
-pub fn coincheck_auth<T: serde::Serialize>(path: &str, body: Option<&T>, api_key_secret: &ApiCredentials, nonce: i64) -> anyhow::Result<HashMap<String, String>> {
+/// 署名には接続先のURLを含める
+pub fn coincheck_auth<T: serde::Serialize>(endpoint: &str, path: &str, body: Option<&T>, api_key_secret: &ApiCredentials, nonce: i64) -> anyhow::Result<HashMap<String, String>> {
     // get body form-data string from body
     let body = match body {
         Some(x) => serde_json::to_string(x)?,
         None => "".to_string(),
     };
-    let message = format!("{nonce}{COINCHECK_BASE_URL}{path}{body}");
+    let message = format!("{nonce}{endpoint}{path}{body}");
     let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, api_key_secret.api_secret.as_bytes()), message.as_bytes());
     Ok(hashmap! {
         "ACCESS-KEY".to_string() => api_key_secret.api_key.clone(),
         "ACCESS-NONCE".to_string() => nonce.to_string(),
         "ACCESS-SIGNATURE".to_string() => hex::encode(signature.as_ref()),
     })
-}
\ No newline at end of file
+}
+/// private websocketのloginメッセージ。RESTと同じく署名には接続先のURLを含める
+pub fn coincheck_ws_auth(url: &str, api_key_secret: &ApiCredentials, nonce: i64) -> serde_json::Value {
+    let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, api_key_secret.api_secret.as_bytes()), format!("{nonce}{url}").as_bytes());
+    serde_json::json!({
+        "type": "login",
+        "access_key": api_key_secret.api_key,
+        "access_nonce": nonce.to_string(),
+        "access_signature": hex::encode(signature.as_ref()),
+    })
+}
+
+/// queryはtimestamp, recvWindowを含めたクエリ文字列。返り値の署名をsignatureとしてクエリの末尾に付ける
+pub fn binance_auth(query: &str, api_key_secret: &ApiCredentials) -> anyhow::Result<(String, HashMap<String, String>)> {
+    let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, api_key_secret.api_secret.as_bytes()), query.as_bytes());
+    Ok((hex::encode(signature.as_ref()), hashmap! {
+        "X-MBX-APIKEY".to_string() => api_key_secret.api_key.clone(),
+    }))
+}
+
+/// Realtime APIのauthメソッドのparams。signatureはtimestamp + nonce
+pub fn bitflyer_ws_auth(api_key_secret: &ApiCredentials, nonce: &str) -> anyhow::Result<serde_json::Value> {
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, api_key_secret.api_secret.as_bytes());
+    let signature = hex::encode(hmac::sign(&key, format!("{}{}", timestamp, nonce).as_bytes()).as_ref());
+    Ok(serde_json::json!({
+        "api_key": api_key_secret.api_key,
+        "timestamp": timestamp,
+        "nonce": nonce,
+        "signature": signature,
+    }))
+}
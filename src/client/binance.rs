@@ -1,10 +1,799 @@
-use anyhow::Context;
-use serde::Deserialize;
+use std::{collections::HashMap, sync::atomic::{AtomicI64, Ordering}};
 
-use crate::{symbol::Symbol, order_types::Side};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use hyper::{Method, HeaderMap, StatusCode};
+use log::info;
+use maplit::hashmap;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
 
-use super::types::TradeRecord;
+use crate::{symbol::{Symbol, SymbolType, Exchange, Currency}, order_types::{Side, OrderType, PosSide}, error_types::BotError, data_structure::float_exp::FloatExp, utils::{kline_mmap::KLineRowData, time::{datetime_utc_from_timestamp, UnixTimeUnit}}};
 
+use super::{credentials::ApiCredentials, method::{make_header, get, request_with_query, GetRequest, HasPath, EmptyQueryRequest, acquire, EndpointClass, Priority, RequestFailure, with_retry, http_client}, auth::binance_auth, endpoints::endpoints, types::TradeRecord, exchange::{ExchangeClient, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker, OrderbookSnapshot}};
+
+/// サーバー時刻 - ローカル時刻 (ms)。recvWindowから外れたら取り直す
+static TIME_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// Timestamp for this request is outside of the recvWindow.
+const TIMESTAMP_ERROR_CODE: i64 = -1021;
+/// Unknown order sent.
+const UNKNOWN_ORDER_CODE: i64 = -2011;
+/// Too many requests / Too many new orders.
+const RATE_LIMIT_CODES: [i64; 2] = [-1003, -1015];
+/// Signature for this request is not valid. / Invalid API-key, IP, or permissions for action.
+const AUTH_ERROR_CODES: [i64; 3] = [-1022, -2014, -2015];
+/// Filter failure: ...
+const FILTER_FAILURE_CODE: i64 = -1013;
+/// New order rejected. 理由はmsgにしかない
+const NEW_ORDER_REJECTED_CODE: i64 = -2010;
+
+/// 現物のみ。先物はklinesの取得だけ対応している
+#[derive(Debug, Clone)]
+pub struct BinanceClient {
+    client: reqwest::Client,
+    endpoint: String,
+    futures_endpoint: String,
+    api_credentials: Option<ApiCredentials>,
+    recv_window: i64,
+}
+
+impl BinanceClient {
+    pub fn new(api_credentials: Option<ApiCredentials>) -> BinanceClient {
+        BinanceClient {
+            client: http_client(),
+            endpoint: endpoints(Exchange::Binance).rest,
+            futures_endpoint: endpoints(Exchange::Binance).futures,
+            api_credentials,
+            recv_window: 5000,
+        }
+    }
+
+    /// mock serverなど本番以外に向ける
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self.futures_endpoint = endpoint.to_string();
+        self
+    }
+
+    pub fn with_recv_window(mut self, recv_window: i64) -> Self {
+        self.recv_window = recv_window;
+        self
+    }
+
+    pub async fn get_public<S: GetRequest + HasPath>(
+        &self,
+        query: S,
+    ) -> anyhow::Result<S::Response> {
+        let req = query.to_json();
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Binance, EndpointClass::Public, Priority::Normal).await;
+            let res = get(&self.client, &self.endpoint, S::PATH, HeaderMap::new(), query.clone()).await;
+            catch_response(res, &req)
+        }).await
+    }
+
+    pub async fn get_private<S: GetRequest + HasPath>(&self, query: S) -> anyhow::Result<S::Response> {
+        with_retry(|| self.signed(Method::GET, &query)).await
+    }
+
+    pub async fn post<S: GetRequest + HasPath>(&self, query: S) -> anyhow::Result<S::Response> {
+        self.signed(Method::POST, &query).await
+    }
+
+    pub async fn delete<S: GetRequest + HasPath>(&self, query: S) -> anyhow::Result<S::Response> {
+        with_retry(|| self.signed(Method::DELETE, &query)).await
+    }
+
+    /// timestampがずれていたらサーバー時刻に合わせて1回だけやり直す
+    async fn signed<S: GetRequest + HasPath>(&self, method: Method, query: &S) -> anyhow::Result<S::Response> {
+        match self.signed_once(method.clone(), query).await {
+            Err(e) if matches!(e.downcast_ref::<BotError>(), Some(BotError::NonceError)) => {
+                self.sync_time().await?;
+                self.signed_once(method, query).await
+            },
+            res => res,
+        }
+    }
+
+    async fn signed_once<S: GetRequest + HasPath>(&self, method: Method, query: &S) -> anyhow::Result<S::Response> {
+        let api_credentials = match &self.api_credentials {
+            Some(x) => x,
+            None => bail!("api_credentials is None"),
+        };
+        // timestampがrecvWindowから外れないよう、待ってから署名する
+        let (class, priority) = match method {
+            Method::GET => (EndpointClass::Private, Priority::Normal),
+            Method::DELETE => (EndpointClass::Order, Priority::Cancel),
+            _ => (EndpointClass::Order, Priority::Normal),
+        };
+        acquire(Exchange::Binance, class, priority).await;
+        // serializerはSendでないので、awaitをまたがないようにここで作って捨てる
+        let query_string = {
+            let params = query.to_query();
+            let mut keys = params.keys().collect::<Vec<_>>();
+            keys.sort();
+            let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+            for key in keys {
+                serializer.append_pair(key, &params[key]);
+            }
+            serializer.append_pair("recvWindow", &self.recv_window.to_string());
+            serializer.append_pair("timestamp", &server_timestamp().to_string());
+            serializer.finish()
+        };
+        let (signature, auth) = binance_auth(&query_string, api_credentials)?;
+        let query_string = format!("{}&signature={}", query_string, signature);
+        let res = request_with_query(&self.client, method, &self.endpoint, S::PATH, make_header(auth), &query_string).await;
+        catch_response(res, &query.to_json())
+    }
+
+    /// サーバー時刻とのずれを測り直す
+    pub async fn sync_time(&self) -> anyhow::Result<()> {
+        let local = Utc::now().timestamp_millis();
+        let res = self.get_public(ServerTimeRequest).await?;
+        let offset = res.server_time - (local + Utc::now().timestamp_millis()) / 2;
+        info!("binance time offset: {}ms", offset);
+        TIME_OFFSET.store(offset, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn query_order(&self, symbol: Symbol, id: &OrderId) -> anyhow::Result<OrderItem> {
+        self.get_private(QueryOrderRequest {
+            symbol: symbol.to_native(),
+            order_id: Some(id.parse().context("invalid binance order id")?),
+            orig_client_order_id: None,
+        }).await
+    }
+
+    pub async fn query_order_by_client_id(&self, symbol: Symbol, client_order_id: &str) -> anyhow::Result<OrderItem> {
+        self.get_private(QueryOrderRequest {
+            symbol: symbol.to_native(),
+            order_id: None,
+            orig_client_order_id: Some(client_order_id.to_string()),
+        }).await
+    }
+
+    /// [start, end)のklineを古い順に返す。1000本ずつ取る
+    pub async fn klines(&self, symbol: Symbol, timeframe: Duration, start: DateTime<Utc>, end: DateTime<Utc>) -> anyhow::Result<Vec<KLineItem>> {
+        let interval = kline_interval(timeframe).with_context(|| format!("unsupported timeframe: {}s", timeframe.num_seconds()))?;
+        let (endpoint, path) = match symbol.r#type {
+            SymbolType::Spot => (&self.endpoint, KLinesRequest::PATH),
+            SymbolType::Perp => (&self.futures_endpoint, "/fapi/v1/klines"),
+        };
+        let mut ret = vec![];
+        let mut start_time = start.timestamp_millis();
+        while start_time < end.timestamp_millis() {
+            let query = KLinesRequest {
+                symbol: symbol.to_native(),
+                interval: interval.to_string(),
+                start_time,
+                end_time: end.timestamp_millis() - 1,
+                limit: 1000,
+            };
+            acquire(Exchange::Binance, EndpointClass::Public, Priority::Normal).await;
+            let req = query.to_json();
+            let res = get(&self.client, endpoint, path, HeaderMap::new(), query).await;
+            let klines: Vec<KLineItem> = catch_response(res, &req)?;
+            let Some(last) = klines.last() else {
+                break;
+            };
+            start_time = last.open_time + timeframe.num_milliseconds();
+            ret.extend(klines);
+        }
+        Ok(ret)
+    }
+}
+
+fn server_timestamp() -> i64 {
+    Utc::now().timestamp_millis() + TIME_OFFSET.load(Ordering::Relaxed)
+}
+
+/// エラーは{"code": -1121, "msg": "Invalid symbol."}の形で返ってくる
+fn catch_response<S: serde::Serialize, T: DeserializeOwned>(res: anyhow::Result<(StatusCode, Value)>, req: &S) -> anyhow::Result<T> {
+    let (status, value) = res?;
+    if let (Some(code), Some(message)) = (value["code"].as_i64(), value["msg"].as_str()) {
+        return Err(anyhow::Error::new(binance_error(status, code, message))
+            .context(format!("request: {}", serde_json::to_string(req).unwrap_or_default())));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// エラーコードをBotErrorにする。分類できないものはBinanceClientMessage
+fn binance_error(status: StatusCode, code: i64, message: &str) -> BotError {
+    match code {
+        TIMESTAMP_ERROR_CODE => BotError::NonceError,
+        code if RATE_LIMIT_CODES.contains(&code) => BotError::RateLimited,
+        code if AUTH_ERROR_CODES.contains(&code) => BotError::AuthFailed,
+        FILTER_FAILURE_CODE if message.contains("PERCENT_PRICE") => BotError::PriceOutOfRange,
+        NEW_ORDER_REJECTED_CODE if message.contains("insufficient balance") => BotError::InsufficientFunds,
+        NEW_ORDER_REJECTED_CODE => BotError::OrderRejected { reason: message.to_string() },
+        // メンテナンス中は決まったコードがなく、メッセージでしか分からない
+        _ if message.to_lowercase().contains("maintenance") => BotError::Maintenance,
+        _ => BotError::BinanceClientMessage { status, code, message: message.to_string() },
+    }
+}
+
+/// binanceのintervalの表記。対応していない足はNone
+pub fn kline_interval(timeframe: Duration) -> Option<&'static str> {
+    let interval = match timeframe.num_seconds() {
+        60 => "1m",
+        180 => "3m",
+        300 => "5m",
+        900 => "15m",
+        1800 => "30m",
+        3600 => "1h",
+        7200 => "2h",
+        14400 => "4h",
+        21600 => "6h",
+        28800 => "8h",
+        43200 => "12h",
+        86400 => "1d",
+        _ => return None,
+    };
+    Some(interval)
+}
+
+/// /api/v3/time
+pub struct ServerTimeRequest;
+
+impl EmptyQueryRequest for ServerTimeRequest {}
+
+impl HasPath for ServerTimeRequest {
+    const PATH: &'static str = "/api/v3/time";
+    type Response = ServerTimeResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerTimeResponse {
+    pub server_time: i64,
+}
+
+/// /api/v3/exchangeInfo
+pub struct ExchangeInfoRequest {
+    pub symbol: String,
+}
+
+impl GetRequest for ExchangeInfoRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+        }
+    }
+}
+
+impl HasPath for ExchangeInfoRequest {
+    const PATH: &'static str = "/api/v3/exchangeInfo";
+    type Response = ExchangeInfoResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExchangeInfoResponse {
+    pub symbols: Vec<ExchangeInfoSymbol>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeInfoSymbol {
+    pub symbol: String,
+    pub status: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub filters: Vec<SymbolFilter>,
+}
+
+/// 使うものだけ
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "filterType", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SymbolFilter {
+    #[serde(rename_all = "camelCase")]
+    PriceFilter { tick_size: String },
+    #[serde(rename_all = "camelCase")]
+    LotSize { min_qty: String, step_size: String },
+    #[serde(rename_all = "camelCase")]
+    Notional { min_notional: String },
+    #[serde(rename_all = "camelCase")]
+    MinNotional { min_notional: String },
+    #[serde(other)]
+    Other,
+}
+
+impl ExchangeInfoSymbol {
+    pub fn tick_size(&self) -> Option<&str> {
+        self.filters.iter().find_map(|f| match f {
+            SymbolFilter::PriceFilter { tick_size } => Some(tick_size.as_str()),
+            _ => None,
+        })
+    }
+
+    /// (step_size, min_qty)
+    pub fn lot_size(&self) -> Option<(&str, &str)> {
+        self.filters.iter().find_map(|f| match f {
+            SymbolFilter::LotSize { min_qty, step_size } => Some((step_size.as_str(), min_qty.as_str())),
+            _ => None,
+        })
+    }
+
+    /// quote建ての最小発注額
+    pub fn min_notional(&self) -> Option<&str> {
+        self.filters.iter().find_map(|f| match f {
+            SymbolFilter::Notional { min_notional } | SymbolFilter::MinNotional { min_notional } => Some(min_notional.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// POST /api/v3/order
+#[derive(Debug, Clone)]
+pub struct NewOrderRequest {
+    pub symbol: String,
+    pub side: Side,
+    /// LIMIT, MARKET, LIMIT_MAKER, STOP_LOSS, STOP_LOSS_LIMIT
+    pub order_type: String,
+    /// GTC, IOC, FOK。LIMIT, STOP_LOSS_LIMITでは必須
+    pub time_in_force: Option<String>,
+    pub quantity: FloatExp,
+    pub price: Option<FloatExp>,
+    pub stop_price: Option<FloatExp>,
+    pub new_client_order_id: Option<String>,
+}
+
+impl GetRequest for NewOrderRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        let mut query = hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+            "side".to_string() => if self.side == Side::Buy { "BUY".to_string() } else { "SELL".to_string() },
+            "type".to_string() => self.order_type.clone(),
+            "quantity".to_string() => self.quantity.to_string(),
+            "newOrderRespType".to_string() => "ACK".to_string(),
+        };
+        if let Some(time_in_force) = &self.time_in_force {
+            query.insert("timeInForce".to_string(), time_in_force.clone());
+        }
+        if let Some(price) = self.price {
+            query.insert("price".to_string(), price.to_string());
+        }
+        if let Some(stop_price) = self.stop_price {
+            query.insert("stopPrice".to_string(), stop_price.to_string());
+        }
+        if let Some(id) = &self.new_client_order_id {
+            query.insert("newClientOrderId".to_string(), id.clone());
+        }
+        query
+    }
+}
+
+impl HasPath for NewOrderRequest {
+    const PATH: &'static str = "/api/v3/order";
+    type Response = NewOrderResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NewOrderResponse {
+    pub symbol: String,
+    pub order_id: i64,
+    pub client_order_id: String,
+    pub transact_time: i64,
+}
+
+/// DELETE /api/v3/order
+pub struct CancelOrderRequest {
+    pub symbol: String,
+    pub order_id: i64,
+}
+
+impl GetRequest for CancelOrderRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+            "orderId".to_string() => self.order_id.to_string(),
+        }
+    }
+}
+
+impl HasPath for CancelOrderRequest {
+    const PATH: &'static str = "/api/v3/order";
+    type Response = Value;
+}
+
+/// DELETE /api/v3/openOrders
+pub struct CancelOpenOrdersRequest {
+    pub symbol: String,
+}
+
+impl GetRequest for CancelOpenOrdersRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+        }
+    }
+}
+
+impl HasPath for CancelOpenOrdersRequest {
+    const PATH: &'static str = "/api/v3/openOrders";
+    type Response = Value;
+}
+
+/// GET /api/v3/order。orderIdかorigClientOrderIdのどちらかを指定する
+pub struct QueryOrderRequest {
+    pub symbol: String,
+    pub order_id: Option<i64>,
+    pub orig_client_order_id: Option<String>,
+}
+
+impl GetRequest for QueryOrderRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        let mut query = hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+        };
+        if let Some(id) = self.order_id {
+            query.insert("orderId".to_string(), id.to_string());
+        }
+        if let Some(id) = &self.orig_client_order_id {
+            query.insert("origClientOrderId".to_string(), id.clone());
+        }
+        query
+    }
+}
+
+impl HasPath for QueryOrderRequest {
+    const PATH: &'static str = "/api/v3/order";
+    type Response = OrderItem;
+}
+
+/// GET /api/v3/openOrders
+pub struct OpenOrdersRequest {
+    pub symbol: String,
+}
+
+impl GetRequest for OpenOrdersRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+        }
+    }
+}
+
+impl HasPath for OpenOrdersRequest {
+    const PATH: &'static str = "/api/v3/openOrders";
+    type Response = Vec<OrderItem>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderItem {
+    pub symbol: String,
+    pub order_id: i64,
+    pub client_order_id: String,
+    pub price: String,
+    pub orig_qty: String,
+    pub executed_qty: String,
+    /// NEW, PARTIALLY_FILLED, FILLED, CANCELED, REJECTED, EXPIRED
+    pub status: String,
+    #[serde(rename = "type")]
+    pub order_type: String,
+    pub side: Side,
+    pub stop_price: String,
+    pub time: i64,
+}
+
+impl OrderItem {
+    pub fn to_order_type(&self) -> OrderType {
+        match self.order_type.as_str() {
+            "MARKET" => OrderType::Market,
+            "STOP_LOSS" => OrderType::Stop,
+            "STOP_LOSS_LIMIT" => OrderType::StopLimit,
+            _ => OrderType::Limit,
+        }
+    }
+
+    /// 未約定の数量
+    pub fn remaining(&self, amount_exp: i32) -> anyhow::Result<FloatExp> {
+        Ok(FloatExp::from_str(self.orig_qty.clone(), amount_exp)? - FloatExp::from_str(self.executed_qty.clone(), amount_exp)?)
+    }
+}
+
+/// GET /api/v3/account
+pub struct AccountRequest;
+
+impl GetRequest for AccountRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "omitZeroBalances".to_string() => "true".to_string(),
+        }
+    }
+}
+
+impl HasPath for AccountRequest {
+    const PATH: &'static str = "/api/v3/account";
+    type Response = AccountResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccountResponse {
+    pub balances: Vec<BalanceItem>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BalanceItem {
+    pub asset: String,
+    pub free: String,
+    pub locked: String,
+}
+
+/// GET /api/v3/ticker/24hr
+pub struct TickerRequest {
+    pub symbol: String,
+}
+
+impl GetRequest for TickerRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+        }
+    }
+}
+
+impl HasPath for TickerRequest {
+    const PATH: &'static str = "/api/v3/ticker/24hr";
+    type Response = TickerResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerResponse {
+    pub last_price: String,
+    pub bid_price: String,
+    pub ask_price: String,
+    pub volume: String,
+}
+
+/// GET /api/v3/depth
+pub struct DepthRequest {
+    pub symbol: String,
+    pub limit: i64,
+}
+
+impl GetRequest for DepthRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+            "limit".to_string() => self.limit.to_string(),
+        }
+    }
+}
+
+impl HasPath for DepthRequest {
+    const PATH: &'static str = "/api/v3/depth";
+    type Response = DepthResponse;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DepthResponse {
+    pub last_update_id: i64,
+    pub bids: Vec<(String, String)>,
+    pub asks: Vec<(String, String)>,
+}
+
+/// GET /api/v3/klines
+pub struct KLinesRequest {
+    pub symbol: String,
+    pub interval: String,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub limit: i64,
+}
+
+impl GetRequest for KLinesRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "symbol".to_string() => self.symbol.clone(),
+            "interval".to_string() => self.interval.clone(),
+            "startTime".to_string() => self.start_time.to_string(),
+            "endTime".to_string() => self.end_time.to_string(),
+            "limit".to_string() => self.limit.to_string(),
+        }
+    }
+}
+
+impl HasPath for KLinesRequest {
+    const PATH: &'static str = "/api/v3/klines";
+    type Response = Vec<KLineItem>;
+}
+
+/// [open_time, open, high, low, close, volume, close_time, quote_volume, trades, taker_buy_base, taker_buy_quote, ignore]
+#[derive(Debug, Clone)]
+pub struct KLineItem {
+    pub open_time: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub close_time: i64,
+}
+
+impl<'de> Deserialize<'de> for KLineItem {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let v = Vec::<Value>::deserialize(deserializer)?;
+        if v.len() < 7 {
+            return Err(serde::de::Error::custom(format!("invalid kline: {:?}", v)));
+        }
+        let int = |i: usize| -> Result<i64, D::Error> {
+            v[i].as_i64().ok_or_else(|| serde::de::Error::custom(format!("invalid kline: {:?}", v)))
+        };
+        let float = |i: usize| -> Result<f64, D::Error> {
+            v[i].as_str().and_then(|s| s.parse::<f64>().ok()).ok_or_else(|| serde::de::Error::custom(format!("invalid kline: {:?}", v)))
+        };
+        Ok(KLineItem {
+            open_time: int(0)?,
+            open: float(1)?,
+            high: float(2)?,
+            low: float(3)?,
+            close: float(4)?,
+            volume: float(5)?,
+            close_time: int(6)?,
+        })
+    }
+}
+
+impl KLineItem {
+    pub fn opentime(&self) -> DateTime<Utc> {
+        datetime_utc_from_timestamp(self.open_time, UnixTimeUnit::MilliSecond)
+    }
+
+    pub fn to_row_data(&self) -> KLineRowData {
+        KLineRowData {
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume: self.volume,
+        }
+    }
+}
+
+fn ensure_spot(symbol: Symbol) -> anyhow::Result<()> {
+    if symbol.r#type != SymbolType::Spot {
+        bail!("binance {} is not supported", symbol.r#type);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ExchangeClient for BinanceClient {
+    fn exchange(&self) -> Exchange {
+        Exchange::Binance
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        ensure_spot(order.symbol)?;
+        let (order_type, time_in_force, price, stop_price) = match order.order_type {
+            OrderType::Limit if order.post_only => ("LIMIT_MAKER", None, Some(order.limit_price()?), None),
+            OrderType::Limit => ("LIMIT", Some("GTC"), Some(order.limit_price()?), None),
+            OrderType::Market => ("MARKET", None, None, None),
+            OrderType::Stop => ("STOP_LOSS", None, None, Some(order.stop_price()?)),
+            OrderType::StopLimit => ("STOP_LOSS_LIMIT", Some("GTC"), Some(order.limit_price()?), Some(order.stop_price()?)),
+        };
+        let client_order_id = order.client_order_id_or_new();
+        let res = self.post(NewOrderRequest {
+            symbol: order.symbol.to_native(),
+            side: order.side,
+            order_type: order_type.to_string(),
+            time_in_force: time_in_force.map(|s| s.to_string()),
+            quantity: order.amount,
+            price,
+            stop_price,
+            new_client_order_id: Some(client_order_id.clone()),
+        }).await;
+        match res {
+            Ok(res) => Ok(res.order_id.to_string()),
+            // 発注はやり直さず、届いたかをnewClientOrderIdで確かめる
+            Err(e) if RequestFailure::of(&e).is_ambiguous() => match self.query_order_by_client_id(order.symbol, &client_order_id).await {
+                Ok(o) => {
+                    info!("order was placed despite {:?}: {}", e, o.order_id);
+                    Ok(o.order_id.to_string())
+                },
+                Err(query_err) => Err(e.context(format!("order result is unknown: {:?}", query_err))),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn cancel_order(&self, symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        self.delete(CancelOrderRequest {
+            symbol: symbol.to_native(),
+            order_id: id.parse().context("invalid binance order id")?,
+        }).await.map(|_| ())
+    }
+
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()> {
+        match self.delete(CancelOpenOrdersRequest { symbol: symbol.to_native() }).await {
+            // 注文がないときもエラーになる
+            Err(e) if matches!(e.downcast_ref::<BotError>(), Some(BotError::BinanceClientMessage { code: UNKNOWN_ORDER_CODE, .. })) => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        let res = self.get_private(OpenOrdersRequest { symbol: symbol.to_native() }).await?;
+        let mut ret = vec![];
+        for o in res {
+            let order_type = o.to_order_type();
+            ret.push(OpenOrder {
+                id: o.order_id.to_string(),
+                symbol,
+                side: o.side,
+                price: if matches!(order_type, OrderType::Limit | OrderType::StopLimit) { Some(FloatExp::from_str(o.price.clone(), symbol.price_precision())?) } else { None },
+                order_type,
+                amount: o.remaining(symbol.amount_precision())?,
+                created_at: datetime_utc_from_timestamp(o.time, UnixTimeUnit::MilliSecond),
+            });
+        }
+        Ok(ret)
+    }
+
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        ensure_spot(symbol)?;
+        let base = self.balances().await?.into_iter().find(|b| b.currency == symbol.base);
+        Ok(base.into_iter().map(|b| Position {
+            symbol,
+            pos_side: PosSide::Long,
+            amount: FloatExp::from_f64(b.total, symbol.amount_precision()),
+            price: FloatExp::new(0, symbol.price_precision()),
+        }).collect())
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        let res = self.get_private(AccountRequest).await?;
+        let mut ret = vec![];
+        for b in res.balances {
+            // 扱っていない通貨は無視する
+            let Ok(currency) = Currency::from_native(&b.asset, Exchange::Binance) else {
+                continue;
+            };
+            let free: f64 = b.free.parse()?;
+            let locked: f64 = b.locked.parse()?;
+            ret.push(AssetBalance { currency, total: free + locked, available: free });
+        }
+        Ok(ret)
+    }
+
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64> {
+        Ok(self.balances().await?.into_iter().find(|b| b.currency == symbol.settlement).map(|b| b.total).unwrap_or(0.))
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker> {
+        let res = self.get_public(TickerRequest { symbol: symbol.to_native() }).await?;
+        Ok(Ticker {
+            last: FloatExp::from_str(res.last_price, symbol.price_precision())?,
+            bid: FloatExp::from_str(res.bid_price, symbol.price_precision())?,
+            ask: FloatExp::from_str(res.ask_price, symbol.price_precision())?,
+            volume: res.volume.parse()?,
+        })
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        let res = self.get_public(DepthRequest { symbol: symbol.to_native(), limit: 100 }).await?;
+        let convert = |items: Vec<(String, String)>| -> anyhow::Result<Vec<(FloatExp, FloatExp)>> {
+            items.into_iter().map(|(price, size)| Ok((
+                FloatExp::from_str(price, symbol.price_precision())?,
+                FloatExp::from_str(size, symbol.amount_precision())?,
+            ))).collect()
+        };
+        Ok(OrderbookSnapshot {
+            bids: convert(res.bids)?,
+            asks: convert(res.asks)?,
+        })
+    }
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct WsAggTrade {
@@ -42,4 +831,37 @@ impl WsAggTrade {
             side: if self.is_buyer_maker { Side::Sell} else { Side::Buy },
         })
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_deserialize_exchange_info() {
+    let s = r#"{"timezone": "UTC", "serverTime": 1690000000000, "symbols": [{"symbol": "BTCUSDT", "status": "TRADING", "baseAsset": "BTC", "quoteAsset": "USDT", "filters": [
+        {"filterType": "PRICE_FILTER", "minPrice": "0.01000000", "maxPrice": "1000000.00000000", "tickSize": "0.01000000"},
+        {"filterType": "LOT_SIZE", "minQty": "0.00001000", "maxQty": "9000.00000000", "stepSize": "0.00001000"},
+        {"filterType": "ICEBERG_PARTS", "limit": 10},
+        {"filterType": "NOTIONAL", "minNotional": "5.00000000", "applyMinToMarket": true, "maxNotional": "9000000.00000000", "applyMaxToMarket": false, "avgPriceMins": 5}
+    ]}]}"#;
+    let res: ExchangeInfoResponse = serde_json::from_str(s).unwrap();
+    let symbol = &res.symbols[0];
+    assert_eq!(symbol.tick_size(), Some("0.01000000"));
+    assert_eq!(symbol.lot_size(), Some(("0.00001000", "0.00001000")));
+    assert_eq!(symbol.min_notional(), Some("5.00000000"));
+}
+
+#[test]
+fn test_deserialize_klines() {
+    let s = r#"[[1690000000000, "29000.01", "29100.00", "28900.00", "29050.00", "12.5", 1690000059999, "362500.0", 100, "6.0", "174000.0", "0"]]"#;
+    let res: Vec<KLineItem> = serde_json::from_str(s).unwrap();
+    assert_eq!(res[0].open_time, 1690000000000);
+    assert_eq!(res[0].close, 29050.0);
+    assert_eq!(kline_interval(Duration::minutes(15)), Some("15m"));
+    assert_eq!(kline_interval(Duration::seconds(10)), None);
+}
+
+#[test]
+fn test_binance_error() {
+    let status = StatusCode::BAD_REQUEST;
+    assert!(matches!(binance_error(status, TIMESTAMP_ERROR_CODE, "Timestamp for this request is outside of the recvWindow."), BotError::NonceError));
+    assert!(matches!(binance_error(status, -1000, "System is under maintenance."), BotError::Maintenance));
+    assert!(matches!(binance_error(status, -1000, "An unknown error occurred while processing the request."), BotError::BinanceClientMessage { .. }));
+}
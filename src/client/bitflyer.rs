@@ -1,31 +1,47 @@
-use anyhow::bail;
-use chrono::{DateTime, Utc};
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc, NaiveDateTime};
+use futures::channel::mpsc::UnboundedSender;
 use hyper::{Method, HeaderMap, StatusCode};
+use log::error;
 use maplit::hashmap;
+use parking_lot::Mutex;
 use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
-use serde_json::Value;
+use serde_json::{Value, json};
+use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
-use crate::{order_types::Side, symbol::Symbol, error_types::BotError, data_structure::float_exp::FloatExp, utils::time::{datetime_utc, deserialize_rfc3339}};
+use crate::{order_types::{Side, OrderType, PosSide}, symbol::{Symbol, Exchange, SymbolType, Currency}, error_types::BotError, data_structure::float_exp::FloatExp, utils::{time::{datetime_utc, deserialize_rfc3339}, strategy_utils::{ReconnectingWs, WsHandler}}};
 
-use super::{credentials::ApiCredentials, method::{make_header, GetRequest, get, post, HasPath, post_no_parse}, auth::bitflyer_auth, types::TradeRecord};
+use super::{credentials::ApiCredentials, method::{make_header, GetRequest, get, post, HasPath, post_no_parse, acquire, EndpointClass, Priority, with_retry, retry_if, http_client}, auth::{bitflyer_auth, bitflyer_ws_auth}, endpoints::endpoints, types::TradeRecord, exchange::{ExchangeClient, place_order_checked, find_unique_order, tracked_order_ids, OrderRecord, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker, OrderbookSnapshot, PrivateEvent, Execution, OrderEvent, OrderStatus}};
 
 #[derive(Debug, Clone)]
 pub struct BitflyerClient {
     client: reqwest::Client,
     endpoint: String,
     api_credentials: Option<ApiCredentials>,
+    /// sendparentorderで出した注文。child orderとidの形式が同じなので取り消し先をこれで分ける
+    parent_order_ids: Arc<Mutex<HashSet<OrderId>>>,
 }
 
 impl BitflyerClient {
     pub fn new(api_credentials: Option<ApiCredentials>) -> BitflyerClient {
         BitflyerClient {
-            client: reqwest::Client::new(),
-            endpoint: "https://api.bitflyer.com".to_string(),
+            client: http_client(),
+            endpoint: endpoints(Exchange::Bitflyer).rest,
             api_credentials,
+            parent_order_ids: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    /// mock serverなど本番以外に向ける
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
     fn make_header<T: serde::Serialize>(&self, method: Method, path: &str, body: Option<&T>) -> anyhow::Result<HeaderMap> {
         let api_credentials = match &self.api_credentials {
             Some(x) => x,
@@ -39,53 +55,104 @@ impl BitflyerClient {
         query: S,
     ) -> anyhow::Result<S::Response> {
         let req = query.to_json();
-        let res = get(&self.client, &self.endpoint, S::PATH, HeaderMap::new(), query).await;
-        catch_response(res, &req)
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Bitflyer, EndpointClass::Public, Priority::Normal).await;
+            let res = get(&self.client, &self.endpoint, S::PATH, HeaderMap::new(), query.clone()).await;
+            catch_response(res, &req)
+        }).await
     }
 
     pub async fn get_private<S: GetRequest + HasPath>(
         &self,
         query: S,
     ) -> anyhow::Result<S::Response> {
-        let url = Url::parse_with_params(format!("{}{}", &self.endpoint, S::PATH).as_str(), query.to_query()).unwrap();
-        let header_path = if query.to_query().len() > 0 {
+        let req = query.to_json();
+        let query = query.to_query();
+        let url = Url::parse_with_params(format!("{}{}", &self.endpoint, S::PATH).as_str(), &query).unwrap();
+        let header_path = if query.len() > 0 {
             url.path().to_string() + "?" + url.query().unwrap()
         } else {
             url.path().to_string()
         };
-        let req = query.to_json();
-        let res = get(&self.client, &self.endpoint, S::PATH, self.make_header::<Value>(Method::GET, &header_path, None)?, query).await;
-        catch_response(res, &req)
+        with_retry(|| async {
+            acquire(Exchange::Bitflyer, EndpointClass::Private, Priority::Normal).await;
+            let res = get(&self.client, &self.endpoint, S::PATH, self.make_header::<Value>(Method::GET, &header_path, None)?, query.clone()).await;
+            catch_response(res, &req)
+        }).await
     }
 
     pub async fn post<S: serde::Serialize + HasPath>(
         &self,
         body: &S,
     ) -> anyhow::Result<S::Response> {
-        let res = post(&self.client, &self.endpoint, S::PATH, self.make_header(Method::POST, S::PATH, Some(&body))?, body).await;
-        catch_response(res, &body)
+        let priority = Priority::from_path(S::PATH);
+        retry_if(priority == Priority::Cancel, || async {
+            acquire(Exchange::Bitflyer, EndpointClass::Order, priority).await;
+            let res = post(&self.client, &self.endpoint, S::PATH, self.make_header(Method::POST, S::PATH, Some(&body))?, body).await;
+            catch_response(res, &body)
+        }).await
     }
 
     pub async fn post_no_parse<S: serde::Serialize + HasPath>(
         &self,
         body: &S,
     ) -> anyhow::Result<()> {
-        post_no_parse(&self.client, &self.endpoint, S::PATH, self.make_header(Method::POST, S::PATH, Some(&body))?, body).await.map(|_| ())
+        let priority = Priority::from_path(S::PATH);
+        retry_if(priority == Priority::Cancel, || async {
+            acquire(Exchange::Bitflyer, EndpointClass::Order, priority).await;
+            post_no_parse(&self.client, &self.endpoint, S::PATH, self.make_header(Method::POST, S::PATH, Some(&body))?, body).await.map(|_| ())
+        }).await
+    }
+
+    /// 結果のわからなかった発注の受付idを、約定・取消済みを含むchild orderの履歴から探す
+    /// parent order（逆指値）は探さない
+    async fn find_child_order(&self, order: &NewOrder, sent_at: DateTime<Utc>) -> anyhow::Result<Option<OrderId>> {
+        if !matches!(order.order_type, OrderType::Limit | OrderType::Market) {
+            return Ok(None);
+        }
+        let res = self.get_private(GetChildOrdersRequest {
+            product_code: order.symbol.to_native(),
+            child_order_state: None,
+        }).await?;
+        let records = res.iter().map(|o| o.to_order_record(order.symbol)).collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(find_unique_order(&records, order, sent_at, &tracked_order_ids(order.symbol)))
+    }
+
+    async fn active_parent_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<ParentOrderItem>> {
+        self.get_private(GetParentOrdersRequest {
+            product_code: symbol.to_native(),
+            parent_order_state: Some("ACTIVE".to_string()),
+        }).await
+    }
+
+    /// child_order_eventsを購読してtxに流す。接続するたびに認証してから購読する
+    pub async fn subscribe_private(&self, tx: UnboundedSender<PrivateEvent>) -> anyhow::Result<()> {
+        let api_credentials = match &self.api_credentials {
+            Some(x) => x.clone(),
+            None => bail!("api_credentials is None"),
+        };
+        ReconnectingWs::new(&endpoints(Exchange::Bitflyer).private_ws)
+            .run(&mut PrivateWsHandler { api_credentials, tx }).await
     }
 }
 
+/// Margin amount is insufficient for this order.
+const MARGIN_INSUFFICIENT_CODE: i64 = -205;
+/// Market state is closed.
+const MARKET_CLOSED_CODE: i64 = -208;
+
+/// エラーは{"status": -205, "error_message": "...", "data": null}の形で返ってくる。
+/// レートリミット（429）と認証（401）はmethodでHTTPのステータスから分類している
 fn catch_response<S: serde::Serialize ,T: DeserializeOwned>(res: anyhow::Result<(StatusCode, Value)>, req: &S) -> anyhow::Result<T> {
     match res {
         Ok((status, value)) => {
             if let Some(message) = value["error_message"].as_str() {
-                // margin insufficiency
-                if message.contains("Margin amount is insufficient") {
-                    bail!(BotError::MarginInsufficiency)
-                }
-                if message.contains("Market state is closed") {
-                    bail!(BotError::Maintenance)
-                }
-                bail!(BotError::BitflyerClientMessage { status, message: message.to_string(), reqest: serde_json::to_string(req).unwrap_or_default() })
+                bail!(match value["status"].as_i64() {
+                    Some(MARGIN_INSUFFICIENT_CODE) => BotError::InsufficientFunds,
+                    Some(MARKET_CLOSED_CODE) => BotError::Maintenance,
+                    _ => BotError::BitflyerClientMessage { status, message: message.to_string(), reqest: serde_json::to_string(req).unwrap_or_default() },
+                })
             } else {
                 Ok(serde_json::from_value(value)?)
             }
@@ -132,6 +199,176 @@ impl ExecutionItem {
     }
 }
 
+/// /v1/executions
+pub struct ExecutionsRequest {
+    pub product_code: String,
+    pub count: i64,
+    /// このidより新しい約定だけ返す
+    pub after: Option<i64>,
+}
+
+impl HasPath for ExecutionsRequest {
+    const PATH: &'static str = "/v1/executions";
+    type Response = ExecutionsResponse;
+}
+
+impl GetRequest for ExecutionsRequest {
+    fn to_query(&self) -> std::collections::HashMap<String, String> {
+        let mut query = hashmap! {
+            "product_code".to_string() => self.product_code.clone(),
+            "count".to_string() => self.count.to_string(),
+        };
+        if let Some(after) = self.after {
+            query.insert("after".to_string(), after.to_string());
+        }
+        query
+    }
+}
+
+/// 新しい順
+pub type ExecutionsResponse = Vec<RestExecutionItem>;
+
+/// wsと違いexec_dateにタイムゾーンがなく、板寄せではsideが空文字になる
+#[derive(Deserialize, Debug, Clone)]
+pub struct RestExecutionItem {
+    pub id: i64,
+    pub side: String,
+    pub price: f64,
+    pub size: f64,
+    pub exec_date: String,
+}
+
+impl RestExecutionItem {
+    pub fn to_trade_record(&self, symbol: Symbol) -> anyhow::Result<TradeRecord> {
+        let exec_date = NaiveDateTime::parse_from_str(&self.exec_date, "%Y-%m-%dT%H:%M:%S%.f")?;
+        Ok(TradeRecord::new(
+            symbol,
+            exec_date.timestamp_millis(),
+            self.price,
+            self.size,
+            if self.side == "SELL" { Side::Sell } else { Side::Buy },
+        ))
+    }
+}
+
+/// authのレスポンスを見分けるid
+const WS_AUTH_ID: i64 = 1;
+
+/// FX_BTC_JPYのようなproduct_code
+pub fn parse_product_code(product_code: &str) -> anyhow::Result<Symbol> {
+    let (r#type, pair) = match product_code.strip_prefix("FX_") {
+        Some(pair) => (SymbolType::Perp, pair),
+        None => (SymbolType::Spot, product_code),
+    };
+    let Some((base, quote)) = pair.split_once('_') else {
+        bail!("invalid product_code: {}", product_code);
+    };
+    Ok(Symbol::new(
+        Currency::from_native(base, Exchange::Bitflyer)?,
+        Currency::from_native(quote, Exchange::Bitflyer)?,
+        r#type,
+        Exchange::Bitflyer,
+    ))
+}
+
+/// child_order_eventsの1件
+/// event_typeはORDER, ORDER_FAILED, CANCEL, CANCEL_FAILED, EXECUTION, EXPIRE
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChildOrderEvent {
+    pub product_code: String,
+    pub child_order_acceptance_id: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub event_date: DateTime<Utc>,
+    pub event_type: String,
+    pub side: Option<Side>,
+    pub price: Option<f64>,
+    pub size: Option<f64>,
+    /// EXECUTIONのみ
+    pub commission: Option<f64>,
+    /// EXECUTIONのみ。約定後の未約定数量
+    pub outstanding_size: Option<f64>,
+}
+
+impl ChildOrderEvent {
+    /// CANCEL_FAILEDなど状態が変わらないものと、instruments.yamlにない銘柄はNone
+    pub fn to_private_event(&self) -> anyhow::Result<Option<PrivateEvent>> {
+        let symbol = parse_product_code(&self.product_code)?;
+        let Ok(instrument) = symbol.instrument() else {
+            return Ok(None);
+        };
+        let price = self.price.map(|p| FloatExp::from_f64(p, instrument.price_precision));
+        let size = FloatExp::from_f64(self.size.unwrap_or(0.), instrument.amount_precision);
+        let status = match self.event_type.as_str() {
+            "EXECUTION" => {
+                let side = self.side.with_context(|| format!("execution without side: {:?}", self))?;
+                return Ok(Some(PrivateEvent::Execution(Execution {
+                    order_id: self.child_order_acceptance_id.clone(),
+                    symbol,
+                    side,
+                    price: price.unwrap_or(FloatExp::new(0, instrument.price_precision)),
+                    amount: size,
+                    fee: self.commission.unwrap_or(0.),
+                    timestamp: self.event_date,
+                })));
+            },
+            "ORDER" => OrderStatus::Ordered,
+            "ORDER_FAILED" => OrderStatus::Rejected,
+            "CANCEL" => OrderStatus::Canceled,
+            "EXPIRE" => OrderStatus::Expired,
+            _ => return Ok(None),
+        };
+        Ok(Some(PrivateEvent::Order(OrderEvent {
+            order_id: self.child_order_acceptance_id.clone(),
+            symbol,
+            side: self.side,
+            status,
+            price,
+            amount: size,
+            executed: FloatExp::new(0, instrument.amount_precision),
+            timestamp: self.event_date,
+        })))
+    }
+}
+
+struct PrivateWsHandler {
+    api_credentials: ApiCredentials,
+    tx: UnboundedSender<PrivateEvent>,
+}
+
+#[async_trait]
+impl WsHandler for PrivateWsHandler {
+    async fn on_connect(&mut self) -> anyhow::Result<Vec<Message>> {
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        let params = bitflyer_ws_auth(&self.api_credentials, &nonce)?;
+        Ok(vec![Message::Text(json!({"method": "auth", "params": params, "id": WS_AUTH_ID}).to_string())])
+    }
+
+    async fn on_message(&mut self, msg: Message, out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        let value: Value = serde_json::from_str(msg.to_text()?)?;
+        // 認証できたら購読する
+        if value["id"].as_i64() == Some(WS_AUTH_ID) {
+            if value["result"].as_bool() != Some(true) {
+                return Err(anyhow::Error::new(BotError::AuthFailed).context(format!("bitflyer ws auth failed: {}", value)));
+            }
+            out.unbounded_send(Message::Text(json!({"method": "subscribe", "params": {"channel": "child_order_events"}}).to_string()))?;
+            return Ok(());
+        }
+        let parsed: WsResponse = serde_json::from_value(value)?;
+        if parsed.params.channel != "child_order_events" {
+            return Ok(());
+        }
+        for item in serde_json::from_value::<Vec<ChildOrderEvent>>(parsed.params.message)? {
+            // 読めないものだけ飛ばし、同じメッセージの残りは流す
+            match item.to_private_event() {
+                Ok(Some(event)) => self.tx.unbounded_send(event)?,
+                Ok(None) => {},
+                Err(e) => error!("skip child_order_event: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct BoardResult {
     pub mid_price: f64,
@@ -257,6 +494,216 @@ impl HasPath for CancelChildOrderRequest {
     type Response = ();
 }
 
+#[derive(Serialize, Debug)]
+pub struct CancelParentOrderRequest {
+    pub product_code: String,
+    pub parent_order_acceptance_id: String,
+}
+
+impl HasPath for CancelParentOrderRequest {
+    const PATH: &'static str = "/v1/me/cancelparentorder";
+    type Response = ();
+}
+
+/// /v1/me/sendparentorder
+/// 逆指値はparent orderでしか出せない
+#[derive(Serialize, Debug)]
+pub struct ParentOrderRequest {
+    pub order_method: String,
+    pub minute_to_expire: Option<u32>,
+    pub parameters: Vec<ParentOrderParameter>,
+}
+
+impl ParentOrderRequest {
+    pub fn simple(parameter: ParentOrderParameter) -> Self {
+        Self {
+            order_method: "SIMPLE".to_string(),
+            minute_to_expire: None,
+            parameters: vec![parameter],
+        }
+    }
+}
+
+impl HasPath for ParentOrderRequest {
+    const PATH: &'static str = "/v1/me/sendparentorder";
+    type Response = ParentOrderResponse;
+}
+
+#[derive(Serialize, Debug)]
+pub struct ParentOrderParameter {
+    pub product_code: String,
+    /// LIMIT, MARKET, STOP, STOP_LIMIT
+    pub condition_type: String,
+    pub side: Side,
+    pub size: FloatExp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<FloatExp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_price: Option<FloatExp>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ParentOrderResponse {
+    pub parent_order_acceptance_id: String,
+}
+
+/// /v1/me/getchildorders
+pub struct GetChildOrdersRequest {
+    pub product_code: String,
+    /// ACTIVE, COMPLETED, CANCELED, EXPIRED, REJECTED。Noneならすべて
+    pub child_order_state: Option<String>,
+}
+
+impl GetRequest for GetChildOrdersRequest {
+    fn to_query(&self) -> std::collections::HashMap<String, String> {
+        let mut query = hashmap! {
+            "product_code".to_string() => self.product_code.clone(),
+        };
+        if let Some(state) = &self.child_order_state {
+            query.insert("child_order_state".to_string(), state.clone());
+        }
+        query
+    }
+}
+
+impl HasPath for GetChildOrdersRequest {
+    const PATH: &'static str = "/v1/me/getchildorders";
+    type Response = Vec<ChildOrderItem>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChildOrderItem {
+    pub child_order_id: String,
+    pub product_code: String,
+    pub side: Side,
+    pub child_order_type: String,
+    pub price: f64,
+    pub size: f64,
+    pub child_order_state: String,
+    /// "2015-07-07T08:45:53" (UTC, タイムゾーン表記なし)
+    pub child_order_date: String,
+    pub child_order_acceptance_id: String,
+    pub outstanding_size: f64,
+    pub executed_size: f64,
+}
+
+impl ChildOrderItem {
+    pub fn child_order_datetime(&self) -> anyhow::Result<DateTime<Utc>> {
+        let naive = NaiveDateTime::parse_from_str(&self.child_order_date, "%Y-%m-%dT%H:%M:%S%.f")?;
+        Ok(DateTime::<Utc>::from_utc(naive, Utc))
+    }
+
+    /// 受付idをidにする
+    pub fn to_order_record(&self, symbol: Symbol) -> anyhow::Result<OrderRecord> {
+        let order_type = if self.child_order_type == "MARKET" { OrderType::Market } else { OrderType::Limit };
+        Ok(OrderRecord {
+            id: self.child_order_acceptance_id.clone(),
+            side: self.side,
+            price: if order_type == OrderType::Limit { Some(FloatExp::from_f64(self.price, symbol.price_precision())) } else { None },
+            order_type,
+            amount: FloatExp::from_f64(self.size, symbol.amount_precision()),
+            created_at: self.child_order_datetime()?,
+        })
+    }
+}
+
+/// /v1/me/getparentorders
+pub struct GetParentOrdersRequest {
+    pub product_code: String,
+    /// ACTIVE, COMPLETED, CANCELED, EXPIRED, REJECTED。Noneならすべて
+    pub parent_order_state: Option<String>,
+}
+
+impl GetRequest for GetParentOrdersRequest {
+    fn to_query(&self) -> std::collections::HashMap<String, String> {
+        let mut query = hashmap! {
+            "product_code".to_string() => self.product_code.clone(),
+        };
+        if let Some(state) = &self.parent_order_state {
+            query.insert("parent_order_state".to_string(), state.clone());
+        }
+        query
+    }
+}
+
+impl HasPath for GetParentOrdersRequest {
+    const PATH: &'static str = "/v1/me/getparentorders";
+    type Response = Vec<ParentOrderItem>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ParentOrderItem {
+    pub parent_order_id: String,
+    pub product_code: String,
+    pub side: Side,
+    /// STOP, STOP_LIMIT, IFD, OCO, IFDOCOなど
+    pub parent_order_type: String,
+    pub price: f64,
+    pub size: f64,
+    pub parent_order_state: String,
+    /// "2015-07-07T08:45:53" (UTC, タイムゾーン表記なし)
+    pub parent_order_date: String,
+    pub parent_order_acceptance_id: String,
+    pub outstanding_size: f64,
+    pub executed_size: f64,
+}
+
+impl ParentOrderItem {
+    pub fn parent_order_datetime(&self) -> anyhow::Result<DateTime<Utc>> {
+        let naive = NaiveDateTime::parse_from_str(&self.parent_order_date, "%Y-%m-%dT%H:%M:%S%.f")?;
+        Ok(DateTime::<Utc>::from_utc(naive, Utc))
+    }
+
+    /// STOPとSTOP_LIMIT以外はこのbotからは出さないのでNone
+    pub fn order_type(&self) -> Option<OrderType> {
+        match self.parent_order_type.as_str() {
+            "STOP" => Some(OrderType::Stop),
+            "STOP_LIMIT" => Some(OrderType::StopLimit),
+            _ => None,
+        }
+    }
+}
+
+/// /v1/me/getbalance
+#[derive(Serialize, Debug)]
+pub struct GetBalanceRequest;
+
+impl GetRequest for GetBalanceRequest {
+    fn to_query(&self) -> std::collections::HashMap<String, String> {
+        hashmap! {}
+    }
+}
+
+impl HasPath for GetBalanceRequest {
+    const PATH: &'static str = "/v1/me/getbalance";
+    type Response = Vec<BalanceItem>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BalanceItem {
+    pub currency_code: String,
+    pub amount: f64,
+    pub available: f64,
+}
+
+/// /v1/board
+pub struct BoardRequest {
+    pub product_code: String,
+}
+
+impl GetRequest for BoardRequest {
+    fn to_query(&self) -> std::collections::HashMap<String, String> {
+        hashmap! {
+            "product_code".to_string() => self.product_code.clone(),
+        }
+    }
+}
+
+impl HasPath for BoardRequest {
+    const PATH: &'static str = "/v1/board";
+    type Response = BoardResult;
+}
+
 #[derive(Serialize, Debug)]
 pub struct GetCollateralRequest;
 
@@ -313,6 +760,177 @@ pub struct TickerResponse {
     pub volume_by_product: f64,
 }
 
+#[async_trait]
+impl ExchangeClient for BitflyerClient {
+    fn exchange(&self) -> Exchange {
+        Exchange::Bitflyer
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        place_order_checked(order, async {
+            let product_code = order.symbol.to_native();
+            match order.order_type {
+                OrderType::Limit | OrderType::Market => {
+                    let res = self.post(&ChildOrderRequest {
+                        product_code,
+                        child_order_type: if order.order_type == OrderType::Limit { ChildOrderType::Limit } else { ChildOrderType::Market },
+                        side: order.side,
+                        price: if order.order_type == OrderType::Limit { Some(order.limit_price()?) } else { None },
+                        size: order.amount,
+                        minute_to_expire: None,
+                    }).await?;
+                    Ok(res.child_order_acceptance_id)
+                },
+                OrderType::Stop | OrderType::StopLimit => {
+                    let res = self.post(&ParentOrderRequest::simple(ParentOrderParameter {
+                        product_code,
+                        condition_type: if order.order_type == OrderType::Stop { "STOP".to_string() } else { "STOP_LIMIT".to_string() },
+                        side: order.side,
+                        size: order.amount,
+                        price: if order.order_type == OrderType::StopLimit { Some(order.limit_price()?) } else { None },
+                        trigger_price: Some(order.stop_price()?),
+                    })).await?;
+                    self.parent_order_ids.lock().insert(res.parent_order_acceptance_id.clone());
+                    Ok(res.parent_order_acceptance_id)
+                },
+            }
+        }, |sent_at| self.find_child_order(order, sent_at)).await
+    }
+
+    /// 再起動前に出したparent orderはopen_ordersで読み直してから取り消す
+    async fn cancel_order(&self, symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        if self.parent_order_ids.lock().contains(id) {
+            self.post_no_parse(&CancelParentOrderRequest {
+                product_code: symbol.to_native(),
+                parent_order_acceptance_id: id.clone(),
+            }).await?;
+            self.parent_order_ids.lock().remove(id);
+            return Ok(());
+        }
+        self.post_no_parse(&CancelChildOrderRequest {
+            product_code: symbol.to_native(),
+            child_order_acceptance_id: id.clone(),
+        }).await
+    }
+
+    /// cancelallchildordersは逆指値（parent order）を取り消さないので1件ずつ取り消す
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()> {
+        self.post_no_parse(&CancelAllOrdersRequest {
+            product_code: symbol.to_native(),
+        }).await?;
+        for o in self.active_parent_orders(symbol).await? {
+            self.post_no_parse(&CancelParentOrderRequest {
+                product_code: symbol.to_native(),
+                parent_order_acceptance_id: o.parent_order_acceptance_id.clone(),
+            }).await?;
+            self.parent_order_ids.lock().remove(&o.parent_order_acceptance_id);
+        }
+        Ok(())
+    }
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        let res = self.get_private(GetChildOrdersRequest {
+            product_code: symbol.to_native(),
+            child_order_state: Some("ACTIVE".to_string()),
+        }).await?;
+        let mut ret = vec![];
+        // 再起動後もcancel_orderでparent orderとして取り消せるように覚えておく
+        for o in self.active_parent_orders(symbol).await? {
+            let Some(order_type) = o.order_type() else { continue };
+            self.parent_order_ids.lock().insert(o.parent_order_acceptance_id.clone());
+            ret.push(OpenOrder {
+                id: o.parent_order_acceptance_id.clone(),
+                symbol,
+                side: o.side,
+                price: if order_type == OrderType::StopLimit { Some(FloatExp::from_f64(o.price, symbol.price_precision())) } else { None },
+                order_type,
+                amount: FloatExp::from_f64(o.size - o.executed_size, symbol.amount_precision()),
+                created_at: o.parent_order_datetime()?,
+            });
+        }
+        for o in res {
+            let order_type = if o.child_order_type == "MARKET" { OrderType::Market } else { OrderType::Limit };
+            ret.push(OpenOrder {
+                id: o.child_order_acceptance_id.clone(),
+                symbol,
+                side: o.side,
+                price: if order_type == OrderType::Limit { Some(FloatExp::from_f64(o.price, symbol.price_precision())) } else { None },
+                order_type,
+                amount: FloatExp::from_f64(o.outstanding_size, symbol.amount_precision()),
+                created_at: o.child_order_datetime()?,
+            });
+        }
+        Ok(ret)
+    }
+
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        match symbol.r#type {
+            SymbolType::Perp => {
+                let res = self.get_private(GetPositionRequest {
+                    product_code: symbol.to_native(),
+                }).await?;
+                Ok(res.into_iter().map(|p| Position {
+                    symbol,
+                    pos_side: p.side.to_pos(),
+                    amount: FloatExp::from_f64(p.size, symbol.amount_precision()),
+                    price: FloatExp::from_f64(p.price, symbol.price_precision()),
+                }).collect())
+            },
+            SymbolType::Spot => {
+                let base = self.balances().await?.into_iter().find(|b| b.currency == symbol.base);
+                Ok(base.into_iter().map(|b| Position {
+                    symbol,
+                    pos_side: PosSide::Long,
+                    amount: FloatExp::from_f64(b.total, symbol.amount_precision()),
+                    price: FloatExp::new(0, symbol.price_precision()),
+                }).collect())
+            },
+        }
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        let res = self.get_private(GetBalanceRequest).await?;
+        Ok(res.into_iter().filter_map(|b| {
+            // 扱っていない通貨は無視する
+            Currency::from_native(&b.currency_code, Exchange::Bitflyer).ok().map(|currency| AssetBalance {
+                currency,
+                total: b.amount,
+                available: b.available,
+            })
+        }).collect())
+    }
+
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64> {
+        match symbol.r#type {
+            SymbolType::Perp => Ok(self.get_private(GetCollateralRequest {}).await?.collateral),
+            SymbolType::Spot => {
+                Ok(self.balances().await?.into_iter().find(|b| b.currency == symbol.settlement).map(|b| b.total).unwrap_or(0.))
+            },
+        }
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker> {
+        let res = self.get_public(TickerRequest { product_code: symbol.to_native() }).await?;
+        Ok(Ticker {
+            last: FloatExp::from_f64(res.ltp, symbol.price_precision()),
+            bid: FloatExp::from_f64(res.best_bid, symbol.price_precision()),
+            ask: FloatExp::from_f64(res.best_ask, symbol.price_precision()),
+            volume: res.volume_by_product,
+        })
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        let res = self.get_public(BoardRequest { product_code: symbol.to_native() }).await?;
+        let convert = |items: &Vec<PriceSizePair>| -> Vec<(FloatExp, FloatExp)> {
+            items.iter().map(|x| (FloatExp::from_f64(x.price, symbol.price_precision()), FloatExp::from_f64(x.size, symbol.amount_precision()))).collect()
+        };
+        Ok(OrderbookSnapshot {
+            bids: convert(&res.bids),
+            asks: convert(&res.asks),
+        })
+    }
+}
+
 #[test]
 fn test_ticker_result() {
     use chrono::Datelike;
@@ -336,4 +954,58 @@ fn test_ticker_result() {
     let res: TickerResult = serde_json::from_value(obj).unwrap();
     assert_eq!(res.product_code, "BTC_JPY");
     assert_eq!(res.timestamp.year(), 2019);
-}
\ No newline at end of file
+}
+#[test]
+fn test_child_order_events() {
+    let items: Vec<ChildOrderEvent> = serde_json::from_str(r#"[
+        {"product_code":"FX_BTC_JPY","child_order_id":"JFX20230801-000001-000001F","child_order_acceptance_id":"JRF20230801-000001-000001","event_date":"2023-08-01T00:00:00.123456Z","event_type":"ORDER","child_order_type":"LIMIT","side":"SELL","price":4000000,"size":0.03,"expire_date":"2023-09-01T00:00:00"},
+        {"product_code":"FX_BTC_JPY","child_order_id":"JFX20230801-000001-000001F","child_order_acceptance_id":"JRF20230801-000001-000001","event_date":"2023-08-01T00:00:01.5Z","event_type":"EXECUTION","exec_id":1,"side":"SELL","price":4000000,"size":0.01,"commission":0,"sfd":0,"outstanding_size":0.02},
+        {"product_code":"FX_BTC_JPY","child_order_id":"JFX20230801-000001-000001F","child_order_acceptance_id":"JRF20230801-000001-000001","event_date":"2023-08-01T00:00:02Z","event_type":"CANCEL_FAILED"},
+        {"product_code":"FX_BTC_JPY","child_order_id":"JFX20230801-000001-000001F","child_order_acceptance_id":"JRF20230801-000001-000001","event_date":"2023-08-01T00:00:03Z","event_type":"CANCEL","price":4000000,"size":0.02},
+        {"product_code":"FX_BTC_JPY","child_order_id":"JFX20230801-000001-000001F","child_order_acceptance_id":"JRF20230801-000001-000001","event_date":"2023-08-01T00:00:04Z","event_type":"EXECUTION","exec_id":2,"price":4000000,"size":0.01}
+    ]"#).unwrap();
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    match items[0].to_private_event().unwrap() {
+        Some(PrivateEvent::Order(order)) => {
+            assert_eq!(order.symbol, symbol);
+            assert_eq!(order.status, OrderStatus::Ordered);
+            assert_eq!(order.price, Some(FloatExp::new(4000000, 0)));
+        },
+        e => panic!("{:?}", e),
+    }
+    match items[1].to_private_event().unwrap() {
+        Some(PrivateEvent::Execution(execution)) => {
+            assert_eq!(execution.order_id, "JRF20230801-000001-000001");
+            assert_eq!(execution.side, Side::Sell);
+            assert_eq!(execution.amount, FloatExp::new(1, -2));
+        },
+        e => panic!("{:?}", e),
+    }
+    assert!(items[2].to_private_event().unwrap().is_none());
+    match items[3].to_private_event().unwrap() {
+        Some(PrivateEvent::Order(order)) => {
+            assert_eq!(order.status, OrderStatus::Canceled);
+            assert_eq!(order.side, None);
+        },
+        e => panic!("{:?}", e),
+    }
+    // sideのない約定は買いとみなさずエラーにする
+    assert!(items[4].to_private_event().is_err());
+    assert_eq!(parse_product_code("BTC_JPY").unwrap().r#type, SymbolType::Spot);
+    // instruments.yamlにない銘柄の約定は捨てる
+    let mut eth = items[1].clone();
+    eth.product_code = "ETH_JPY".to_string();
+    assert!(eth.to_private_event().unwrap().is_none());
+}
+#[test]
+fn test_parent_order_item() {
+    use chrono::Datelike;
+    let items: Vec<ParentOrderItem> = serde_json::from_str(r#"[
+        {"id":138398,"parent_order_id":"JCP20150825-046876-036161","product_code":"FX_BTC_JPY","side":"SELL","parent_order_type":"STOP","price":0,"average_price":0,"size":0.03,"parent_order_state":"ACTIVE","expire_date":"2015-09-24T04:35:02","parent_order_date":"2015-08-25T04:35:02","parent_order_acceptance_id":"JRF20150825-043502-015151","outstanding_size":0,"cancel_size":0,"executed_size":0,"total_commission":0},
+        {"id":138397,"parent_order_id":"JCP20150825-046876-036160","product_code":"FX_BTC_JPY","side":"BUY","parent_order_type":"IFD","price":30000,"average_price":0,"size":0.1,"parent_order_state":"ACTIVE","expire_date":"2015-09-24T04:35:02","parent_order_date":"2015-08-25T04:35:02","parent_order_acceptance_id":"JRF20150825-043502-015150","outstanding_size":0,"cancel_size":0,"executed_size":0,"total_commission":0}
+    ]"#).unwrap();
+    assert_eq!(items[0].order_type(), Some(OrderType::Stop));
+    assert_eq!(items[0].side, Side::Sell);
+    assert_eq!(items[0].parent_order_datetime().unwrap().year(), 2015);
+    assert_eq!(items[1].order_type(), None);
+}
@@ -1,25 +1,28 @@
-use std::{collections::HashMap, str::FromStr, sync::{atomic::AtomicI64, Arc}};
+use std::{collections::HashMap, sync::{atomic::AtomicI64, Arc}};
 use std::time::Duration as StdDuration;
 
+use async_trait::async_trait;
 use chrono::{Duration, DateTime, Utc};
+use futures::{future::join_all, channel::mpsc::UnboundedSender};
 use hyper::{HeaderMap, header::CONTENT_TYPE, http::HeaderName};
 use log::info;
 use maplit::hashmap;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
-use serde_json::Value;
+use serde_json::{Value, json};
 use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{symbol::{Symbol, SymbolType, Exchange, Currency}, utils::{time::{UnixTimeUnit, datetime_utc_from_timestamp, deserialize_rfc3339}, serde::deserialize_f64_from_str, useful_traits::{HashMapToHeaderMap, ResultFlatten}}, order_types::Side, data_structure::float_exp::FloatExp};
+use crate::{symbol::{Symbol, SymbolType, Exchange, Currency}, utils::{time::{UnixTimeUnit, datetime_utc_from_timestamp, deserialize_rfc3339}, serde::deserialize_f64_from_str, useful_traits::{HashMapToHeaderMap, ResultFlatten}, strategy_utils::{ReconnectingWs, WsHandler}}, order_types::{Side, OrderType, PosSide}, data_structure::float_exp::FloatExp, error_types::BotError};
 
-use super::{method::{get, GetRequest, HasPath, EmptyQueryRequest, post, delete}, types::{KLines, TradeRecord}, credentials::ApiCredentials, auth::coincheck_auth};
+use super::{method::{get, GetRequest, HasPath, EmptyQueryRequest, post, delete, acquire, EndpointClass, Priority, with_retry, http_client}, types::{KLines, TradeRecord}, credentials::ApiCredentials, auth::{coincheck_auth, coincheck_ws_auth}, endpoints::endpoints, exchange::{ExchangeClient, place_order_checked, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker, OrderbookSnapshot, PrivateEvent, Execution, OrderEvent, OrderStatus}};
 
 static PREV_NONCE: Lazy<Mutex<i64>> = Lazy::new(|| Mutex::new(0));
 
 const NONCE_INTERVAL_MS: i64 = 50;
 
 /// こちらでnonceを被らないように値を足してもサーバー側での到着が逆になればエラーになるので、
-/// sleepを入れて順序をつけている
+/// sleepを入れて順序をつけている。レートリミットで待った後に取る
 async fn get_nonce() -> i64 {
     let mut nonce = chrono::Utc::now().timestamp_millis();
     let mut prev_nonce = PREV_NONCE.lock().await;
@@ -33,6 +36,7 @@ async fn get_nonce() -> i64 {
     nonce
 }
 
+#[derive(Debug, Clone)]
 pub struct CoincheckClient {
     client: reqwest::Client,
     endpoint: String,
@@ -42,28 +46,43 @@ pub struct CoincheckClient {
 impl CoincheckClient {
     pub fn new(api_credentials: Option<ApiCredentials>) -> CoincheckClient {
         CoincheckClient {
-            client: reqwest::Client::new(),
-            endpoint: "https://coincheck.com".to_string(),
+            client: http_client(),
+            endpoint: endpoints(Exchange::Coincheck).rest,
             api_credentials,
         }
     }
 
+    /// mock serverなど本番以外に向ける
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_string();
+        self
+    }
+
     pub async fn get_public<S: GetRequest + HasPath>(
         &self,
         query: S,
     ) -> anyhow::Result<S::Response> {
-        get(&self.client, &self.endpoint, S::PATH, HeaderMap::new(), query).await
-            .map(|x: (_, RestResponse<S::Response>)| x.1.into_result()).flatten_()
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Coincheck, EndpointClass::Public, Priority::Normal).await;
+            get(&self.client, &self.endpoint, S::PATH, HeaderMap::new(), query.clone()).await
+                .map(|x: (_, RestResponse<S::Response>)| x.1.into_result()).flatten_()
+        }).await
     }
 
     pub async fn get_private<S: GetRequest + HasPath>(&self, query: S) -> anyhow::Result<S::Response> {
-        let header = coincheck_auth::<Value>(S::PATH, None, self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
-        let res: (_, RestResponse<S::Response>) = get(&self.client, &self.endpoint, S::PATH, header.to_header_map()?, query).await?;
-        res.1.into_result()
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Coincheck, EndpointClass::Private, Priority::Normal).await;
+            let header = coincheck_auth::<Value>(&self.endpoint, S::PATH, None, self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
+            let res: (_, RestResponse<S::Response>) = get(&self.client, &self.endpoint, S::PATH, header.to_header_map()?, query.clone()).await?;
+            res.1.into_result()
+        }).await
     }
 
     pub async fn post<S: Serialize + HasPath>(&self, body: &S) -> anyhow::Result<S::Response> {
-        let header = coincheck_auth(S::PATH, Some(body), self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
+        acquire(Exchange::Coincheck, EndpointClass::Order, Priority::Normal).await;
+        let header = coincheck_auth(&self.endpoint, S::PATH, Some(body), self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
         let res: (_, RestResponse<S::Response>) = post(&self.client, &self.endpoint, S::PATH, header.to_header_map()?, body).await?;
         res.1.into_result()
     }
@@ -71,9 +90,23 @@ impl CoincheckClient {
     /// pathに引数をもつ特殊APIなので直に実装
     pub async fn cancel_order(&self, id: i64) -> anyhow::Result<CancelOrderResponse> {
         let path = cancel_order_path(id);
-        let header = coincheck_auth::<Value>(&path, None, self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
-        let res: (_, RestResponse<CancelOrderResponse>) = delete(&self.client, &self.endpoint, &path, header.to_header_map()?).await?;
-        res.1.into_result()
+        with_retry(|| async {
+            acquire(Exchange::Coincheck, EndpointClass::Order, Priority::Cancel).await;
+            let header = coincheck_auth::<Value>(&self.endpoint, &path, None, self.api_credentials.as_ref().unwrap(), get_nonce().await)?;
+            let res: (_, RestResponse<CancelOrderResponse>) = delete(&self.client, &self.endpoint, &path, header.to_header_map()?).await?;
+            res.1.into_result()
+        }).await
+    }
+
+    /// order-events, execution-eventsを購読してtxに流す。接続するたびにloginしてから購読する
+    pub async fn subscribe_private(&self, tx: UnboundedSender<PrivateEvent>) -> anyhow::Result<()> {
+        let api_credentials = match &self.api_credentials {
+            Some(x) => x.clone(),
+            None => anyhow::bail!("api_credentials is None"),
+        };
+        let url = endpoints(Exchange::Coincheck).private_ws;
+        ReconnectingWs::new(&url)
+            .run(&mut PrivateWsHandler { url: url.clone(), api_credentials, tx }).await
     }
 }
 
@@ -88,7 +121,7 @@ impl<T> RestResponse<T> {
     pub fn into_result(self) -> anyhow::Result<T> {
         match self {
             RestResponse::Ok(x) => Ok(x),
-            RestResponse::Err(x) => Err(anyhow::anyhow!("Received error message: {}", x.error)),
+            RestResponse::Err(x) => Err(x.to_bot_error().into()),
         }
     }
 }
@@ -100,8 +133,20 @@ pub struct RestErrResponse {
 }
 
 impl RestErrResponse {
-    pub fn is_price_range_error(&self) -> bool {
-        self.error.contains("Rate deviates from actual price")
+    /// coincheckはエラーコードを返さないので、メッセージで分類する
+    pub fn to_bot_error(&self) -> BotError {
+        let error = self.error.as_str();
+        if error.contains("Rate deviates from actual price") {
+            BotError::PriceOutOfRange
+        } else if error.contains("Nonce must be incremented") {
+            BotError::NonceError
+        } else if error.contains("invalid authentication") {
+            BotError::AuthFailed
+        } else if error.to_lowercase().contains("insufficient") {
+            BotError::InsufficientFunds
+        } else {
+            BotError::CoincheckClientMessage { message: error.to_string() }
+        }
     }
 }
 
@@ -150,7 +195,9 @@ impl KLineResponse {
     }
 }
 
-pub struct OrderbookRequest;
+pub struct OrderbookRequest {
+    pub pair: Symbol,
+}
 
 impl HasPath for OrderbookRequest {
     const PATH: &'static str = "/api/order_books";
@@ -159,7 +206,9 @@ impl HasPath for OrderbookRequest {
 
 impl GetRequest for OrderbookRequest {
     fn to_query(&self) -> HashMap<String, String> {
-        hashmap! {}
+        hashmap! {
+            "pair".to_string() => self.pair.to_native(),
+        }
     }
 }
 
@@ -178,6 +227,56 @@ impl OrderbookResponse {
     }
 }
 
+/// 新しい順に最大100件
+pub struct TradesRequest {
+    pub pair: Symbol,
+    pub limit: i64,
+}
+
+impl HasPath for TradesRequest {
+    const PATH: &'static str = "/api/trades";
+    type Response = TradesResponse;
+}
+
+impl GetRequest for TradesRequest {
+    fn to_query(&self) -> HashMap<String, String> {
+        hashmap! {
+            "pair".to_string() => self.pair.to_native(),
+            "limit".to_string() => self.limit.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TradesResponse {
+    pub success: bool,
+    pub data: Vec<TradeItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TradeItem {
+    pub id: i64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub amount: f64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub rate: f64,
+    pub order_type: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl TradeItem {
+    pub fn to_trade_record(&self, symbol: Symbol) -> TradeRecord {
+        TradeRecord::new(
+            symbol,
+            self.created_at.timestamp_millis(),
+            self.rate,
+            self.amount,
+            if self.order_type == "sell" { Side::Sell } else { Side::Buy },
+        )
+    }
+}
+
 pub struct OpenOrderRequest;
 
 impl HasPath for OpenOrderRequest {
@@ -234,21 +333,58 @@ impl HasPath for BalanceRequest {
 
 impl EmptyQueryRequest for BalanceRequest {}
 
-#[derive(Debug, Clone, Deserialize)]
+/// {"success": true, "jpy": "0.8401", "btc": "7.75052654", "jpy_reserved": "3000.0", ...}
+/// 通貨ごとにfreeとused(`{currency}_reserved`)がある。ほかに`_lend_in_use`なども来るが使わない
+#[derive(Debug, Clone)]
 pub struct BalanceResponse {
     pub success: bool,
     /// free
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub jpy: f64,
-    /// free
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub btc: f64,
-    /// used
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub jpy_reserved: f64,
+    pub free: HashMap<Currency, f64>,
     /// used
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub btc_reserved: f64,
+    pub reserved: HashMap<Currency, f64>,
+}
+
+impl BalanceResponse {
+    pub fn free(&self, currency: Currency) -> f64 {
+        self.free.get(&currency).copied().unwrap_or(0.)
+    }
+
+    pub fn reserved(&self, currency: Currency) -> f64 {
+        self.reserved.get(&currency).copied().unwrap_or(0.)
+    }
+
+    pub fn total(&self, currency: Currency) -> f64 {
+        self.free(currency) + self.reserved(currency)
+    }
+}
+
+impl<'de> Deserialize<'de> for BalanceResponse {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, Value>::deserialize(deserializer)?;
+        let mut ret = BalanceResponse {
+            success: map.get("success").and_then(|v| v.as_bool()).unwrap_or(false),
+            free: HashMap::new(),
+            reserved: HashMap::new(),
+        };
+        for (key, value) in &map {
+            // 数値の文字列だけ拾う
+            let Some(amount) = value.as_str().and_then(|v| v.parse::<f64>().ok()) else {
+                continue;
+            };
+            let (name, target) = match key.strip_suffix("_reserved") {
+                Some(name) => (name, &mut ret.reserved),
+                None => (key.as_str(), &mut ret.free),
+            };
+            // btc_lend_in_useなどは通貨名にならないので捨てる
+            if let Ok(currency) = Currency::from_native(name, Exchange::Coincheck) {
+                target.insert(currency, amount);
+            }
+        }
+        Ok(ret)
+    }
 }
 
 fn deserialize_coincheck_pair<'de, D>(deserializer: D) -> Result<Symbol, D::Error>
@@ -256,14 +392,17 @@ where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    let s = s.to_uppercase();
+    parse_coincheck_pair(&s).map_err(serde::de::Error::custom)
+}
+
+/// btc_jpyのようなペア名
+pub fn parse_coincheck_pair(s: &str) -> anyhow::Result<Symbol> {
     let cs = s.split("_").collect::<Vec<_>>();
     if cs.len() != 2 {
-        return Err(serde::de::Error::custom(format!("invalid pair: {}", s)));
+        anyhow::bail!("invalid pair: {}", s);
     }
-    let (base, quote) = (cs[0], cs[1]);
-    let base = Currency::from_str(base).map_err(serde::de::Error::custom)?;
-    let quote = Currency::from_str(quote).map_err(serde::de::Error::custom)?;
+    let base = Currency::from_native(cs[0], Exchange::Coincheck)?;
+    let quote = Currency::from_native(cs[1], Exchange::Coincheck)?;
     Ok(Symbol::new(base, quote, SymbolType::Spot, Exchange::Coincheck))
 }
 
@@ -283,11 +422,12 @@ pub struct TransactionsResponse {
     pub transactions: Vec<TransactionItem>,
 }
 
+/// execution-eventsも同じ形で来る（created_atがevent_timeになる）
 #[derive(Debug, Clone, Deserialize)]
 pub struct TransactionItem {
     pub id: i64,
     pub order_id: i64,
-    #[serde(deserialize_with = "deserialize_rfc3339")]
+    #[serde(deserialize_with = "deserialize_rfc3339", alias = "event_time")]
     pub created_at: DateTime<Utc>,
     pub funds: TransactionFunds,
     #[serde(deserialize_with = "deserialize_coincheck_pair")]
@@ -301,13 +441,48 @@ pub struct TransactionItem {
     pub side: String,
 }
 
+impl TransactionItem {
+    pub fn to_execution(&self) -> anyhow::Result<Execution> {
+        let symbol = self.pair;
+        let instrument = symbol.instrument()?;
+        // 手数料はquoteで来るがbaseのときは価格で換算する
+        let fee = match self.fee_currency {
+            Some(currency) if currency == symbol.base => self.fee * self.rate,
+            _ => self.fee,
+        };
+        Ok(Execution {
+            order_id: self.order_id.to_string(),
+            symbol,
+            side: if self.side == "sell" { Side::Sell } else { Side::Buy },
+            price: FloatExp::from_f64(self.rate, instrument.price_precision),
+            amount: FloatExp::from_f64(self.funds.get(symbol.base).abs(), instrument.amount_precision),
+            fee,
+            timestamp: self.created_at,
+        })
+    }
+}
+
 /// 減るときは負になっている
-#[derive(Debug, Clone, Deserialize)]
-pub struct TransactionFunds {
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub btc: f64,
-    #[serde(deserialize_with = "deserialize_f64_from_str")]
-    pub jpy: f64,
+#[derive(Debug, Clone)]
+pub struct TransactionFunds(pub HashMap<Currency, f64>);
+
+impl TransactionFunds {
+    pub fn get(&self, currency: Currency) -> f64 {
+        self.0.get(&currency).copied().unwrap_or(0.)
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionFunds {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, String>::deserialize(deserializer)?;
+        map.into_iter().map(|(k, v)| Ok((
+            Currency::from_native(&k, Exchange::Coincheck).map_err(serde::de::Error::custom)?,
+            v.parse::<f64>().map_err(serde::de::Error::custom)?,
+        ))).collect::<Result<_, _>>().map(TransactionFunds)
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -327,12 +502,14 @@ impl OrderRequest {
                 rate,
                 amount,
                 time_in_force,
+                stop_loss_rate: None,
             }),
             Side::Sell => OrderRequest::Sell(LimitOrderRequest {
                 pair,
                 rate,
                 amount,
                 time_in_force,
+                stop_loss_rate: None,
             }),
         }
     }
@@ -348,9 +525,20 @@ impl OrderRequest {
                 pair,
                 amount,
                 time_in_force,
+                stop_loss_rate: None,
             }),
         }
     }
+
+    /// 逆指値を設定する。成行買いには設定しない
+    pub fn with_stop_loss_rate(mut self, stop_loss_rate: FloatExp) -> Self {
+        match &mut self {
+            OrderRequest::Buy(x) | OrderRequest::Sell(x) => x.stop_loss_rate = Some(stop_loss_rate),
+            OrderRequest::MarketSell(x) => x.stop_loss_rate = Some(stop_loss_rate),
+            OrderRequest::MarketBuy(_) => {},
+        }
+        self
+    }
 }
 
 impl HasPath for OrderRequest {
@@ -365,6 +553,9 @@ pub struct LimitOrderRequest {
     pub amount: FloatExp,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub time_in_force: Option<TimeInForce>,
+    /// 逆指値
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_loss_rate: Option<FloatExp>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -382,6 +573,9 @@ pub struct MarketSellOrderRequest {
     pub amount: FloatExp,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub time_in_force: Option<TimeInForce>,
+    /// 逆指値
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_loss_rate: Option<FloatExp>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -436,6 +630,227 @@ pub struct TickerResponse {
     pub timestamp: i64,
 }
 
+#[async_trait]
+impl ExchangeClient for CoincheckClient {
+    fn exchange(&self) -> Exchange {
+        Exchange::Coincheck
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        place_order_checked(order, async {
+            let time_in_force = if order.post_only { Some(TimeInForce::PostOnly) } else { None };
+            let req = match order.order_type {
+                OrderType::Limit => OrderRequest::limit_order(order.side, order.symbol, order.limit_price()?, order.amount, time_in_force),
+                OrderType::StopLimit => OrderRequest::limit_order(order.side, order.symbol, order.limit_price()?, order.amount, time_in_force)
+                    .with_stop_loss_rate(order.stop_price()?),
+                OrderType::Market | OrderType::Stop => {
+                    // 成行買いはquote建てでしか出せない
+                    if order.side == Side::Buy {
+                        anyhow::bail!("market buy order with base amount is not supported in coincheck: {:?}", order);
+                    }
+                    let req = OrderRequest::market_order(order.side, order.symbol, order.amount, time_in_force);
+                    if order.order_type == OrderType::Stop { req.with_stop_loss_rate(order.stop_price()?) } else { req }
+                },
+            };
+            let res = self.post(&req).await?.into_result()?;
+            Ok(res.id.to_string())
+        // client order idも発注履歴の照会もないので探さない
+        }, |_| async { Ok(None) }).await
+    }
+
+    async fn cancel_order(&self, _symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        CoincheckClient::cancel_order(self, id.parse::<i64>()?).await?;
+        Ok(())
+    }
+
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()> {
+        let res = self.get_private(OpenOrderRequest {}).await?;
+        join_all(
+            res.orders.iter().filter(|o| o.pair == symbol).map(|o| CoincheckClient::cancel_order(self, o.id))
+        ).await.into_iter().map(|r| r.map(|_| ())).collect::<anyhow::Result<()>>()
+    }
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        let res = self.get_private(OpenOrderRequest {}).await?;
+        Ok(res.orders.into_iter().filter(|o| o.pair == symbol).map(|o| OpenOrder {
+            id: o.id.to_string(),
+            symbol,
+            side: if o.order_type == "sell" { Side::Sell } else { Side::Buy },
+            order_type: OrderType::Limit,
+            price: Some(FloatExp::from_f64(o.rate, symbol.price_precision())),
+            amount: FloatExp::from_f64(o.pending_amount, symbol.amount_precision()),
+            created_at: o.created_at,
+        }).collect())
+    }
+
+    /// 残高と約定履歴から建値を復元する
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        // nonce must be incrementedエラーが頻繁に出るので、一度に複数のリクエストを送らないようにする
+        let balance = self.get_private(BalanceRequest).await?;
+        let trades = self.get_private(TransactionsRequest).await?;
+        let pos = FloatExp::from_f64(balance.total(symbol.base), symbol.amount_precision());
+        let mut init_notional = FloatExp::new(0, symbol.price_precision() + symbol.amount_precision());
+        // 約定履歴を逆順にたどる
+        // amount == 0になるところで終わり
+        let mut amount = pos;
+        for trade in trades.transactions.into_iter().filter(|t| t.pair == symbol) {
+            if amount.is_zero() {break;}
+            // trades.fundsは符号付きの値
+            let funds = FloatExp::from_f64(trade.funds.get(symbol.base), symbol.amount_precision());
+            init_notional += FloatExp::from_f64(trade.rate, symbol.price_precision()) * funds;
+            amount -= funds;
+        }
+        let price = if pos.is_zero() {
+            FloatExp::new(0, symbol.price_precision())
+        } else {
+            init_notional.div_round(pos, symbol.price_precision())
+        };
+        Ok(vec![Position {
+            symbol,
+            pos_side: PosSide::Long,
+            amount: pos,
+            price,
+        }])
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        let res = self.get_private(BalanceRequest).await?;
+        let mut currencies = res.free.keys().chain(res.reserved.keys()).copied().collect::<Vec<_>>();
+        currencies.sort();
+        currencies.dedup();
+        Ok(currencies.into_iter().map(|currency| AssetBalance {
+            currency,
+            total: res.total(currency),
+            available: res.free(currency),
+        }).collect())
+    }
+
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64> {
+        Ok(self.balances().await?.into_iter().find(|b| b.currency == symbol.settlement).map(|b| b.total).unwrap_or(0.))
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker> {
+        let res = self.get_public(TickerRequest { pair: symbol }).await?;
+        Ok(Ticker {
+            last: FloatExp::from_f64(res.last, symbol.price_precision()),
+            bid: FloatExp::from_f64(res.bid, symbol.price_precision()),
+            ask: FloatExp::from_f64(res.ask, symbol.price_precision()),
+            volume: res.volume,
+        })
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        let res = self.get_public(OrderbookRequest { pair: symbol }).await?;
+        let convert = |items: &Vec<PriceSizePair>| -> Vec<(FloatExp, FloatExp)> {
+            items.iter().map(|x| (FloatExp::from_f64(x.price, symbol.price_precision()), FloatExp::from_f64(x.size, symbol.amount_precision()))).collect()
+        };
+        Ok(OrderbookSnapshot {
+            bids: convert(&res.bids),
+            asks: convert(&res.asks),
+        })
+    }
+}
+
+/// private websocketのチャンネルごとのメッセージ
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "channel")]
+pub enum PrivateWsMessage {
+    #[serde(rename = "order-events")]
+    Order(OrderEventMsg),
+    #[serde(rename = "execution-events")]
+    Execution(TransactionItem),
+}
+
+/// order_eventはNEW, PARTIALLY_FILL, FILL, CANCEL, EXPIRYなど
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderEventMsg {
+    pub id: i64,
+    #[serde(deserialize_with = "deserialize_coincheck_pair")]
+    pub pair: Symbol,
+    pub order_event: String,
+    /// buy, sell, market_buy, market_sell
+    pub order_type: String,
+    pub rate: Option<String>,
+    pub pending_amount: Option<String>,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub event_time: DateTime<Utc>,
+}
+
+impl OrderEventMsg {
+    /// 注文数量は来ないのでamountは未約定の数量になる。知らないorder_eventはNone
+    pub fn to_order_event(&self) -> anyhow::Result<Option<OrderEvent>> {
+        let symbol = self.pair;
+        let instrument = symbol.instrument()?;
+        let status = match self.order_event.as_str() {
+            "NEW" => OrderStatus::Ordered,
+            "PARTIALLY_FILL" => OrderStatus::PartiallyFilled,
+            "FILL" => OrderStatus::Filled,
+            "CANCEL" => OrderStatus::Canceled,
+            "EXPIRY" => OrderStatus::Expired,
+            _ => return Ok(None),
+        };
+        let parse = |x: &Option<String>, exp: i32| x.as_ref().map(|x| FloatExp::from_str(x.clone(), exp)).transpose();
+        Ok(Some(OrderEvent {
+            order_id: self.id.to_string(),
+            symbol,
+            side: Some(if self.order_type.ends_with("sell") { Side::Sell } else { Side::Buy }),
+            status,
+            price: parse(&self.rate, instrument.price_precision)?,
+            amount: parse(&self.pending_amount, instrument.amount_precision)?.unwrap_or(FloatExp::new(0, instrument.amount_precision)),
+            executed: FloatExp::new(0, instrument.amount_precision),
+            timestamp: self.event_time,
+        }))
+    }
+}
+
+struct PrivateWsHandler {
+    url: String,
+    api_credentials: ApiCredentials,
+    tx: UnboundedSender<PrivateEvent>,
+}
+
+#[async_trait]
+impl WsHandler for PrivateWsHandler {
+    /// RESTと同じnonceの列から取る
+    async fn on_connect(&mut self) -> anyhow::Result<Vec<Message>> {
+        let nonce = get_nonce().await;
+        Ok(vec![Message::Text(coincheck_ws_auth(&self.url, &self.api_credentials, nonce).to_string())])
+    }
+
+    async fn on_message(&mut self, msg: Message, out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        let value: Value = serde_json::from_str(msg.to_text()?)?;
+        // {"type": "login", "success": true}, {"type": "subscribe", "success": true}
+        match value["type"].as_str() {
+            Some("login") => {
+                if value["success"].as_bool() != Some(true) {
+                    return Err(anyhow::Error::new(BotError::AuthFailed).context(format!("coincheck ws login failed: {}", value)));
+                }
+                out.unbounded_send(Message::Text(json!({"type": "subscribe", "channels": ["order-events", "execution-events"]}).to_string()))?;
+                return Ok(());
+            },
+            Some(_) => {
+                if value["success"].as_bool() == Some(false) {
+                    anyhow::bail!("coincheck ws error: {}", value);
+                }
+                return Ok(());
+            },
+            None => {},
+        }
+        let event = match serde_json::from_value::<PrivateWsMessage>(value)? {
+            // instruments.yamlにない銘柄は戦略で扱わないので捨てる
+            PrivateWsMessage::Order(x) if x.pair.instrument().is_err() => return Ok(()),
+            PrivateWsMessage::Execution(x) if x.pair.instrument().is_err() => return Ok(()),
+            PrivateWsMessage::Order(x) => match x.to_order_event()? {
+                Some(x) => PrivateEvent::Order(x),
+                None => return Ok(()),
+            },
+            PrivateWsMessage::Execution(x) => PrivateEvent::Execution(x.to_execution()?),
+        };
+        self.tx.unbounded_send(event)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum WsResponse {
@@ -465,7 +880,7 @@ impl WsTradesResponse {
         let mut ret = vec![];
         for item in &self.0 {
             ret.push(TradeRecord::new(
-                Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Spot, Exchange::Coincheck),
+                parse_coincheck_pair(&item[2])?,
                 item[0].parse::<i64>()? * 1000,
                 item[3].parse::<f64>()?,
                 item[4].parse::<f64>()?,
@@ -474,6 +889,11 @@ impl WsTradesResponse {
         }
         Ok(ret)
     }
+
+    /// 1メッセージの中は新しい順に並んでいる
+    pub fn max_id(&self) -> Option<i64> {
+        self.0.iter().filter_map(|item| item.get(1)?.parse::<i64>().ok()).max()
+    }
 }
 
 // ['btc_jpy', {'bids': [['4246651.0', '0'], ['4246654.0', '0.05'], ['4245433.0', '0.0114406']], 'asks': [['4255238.0', '0'], ['4255236.0', '0.1']], 'last_update_at': '1690096140'}]
@@ -562,6 +982,21 @@ fn test_deserialize_open_orders() {
     assert_eq!(obj.orders[0].id, 5710599665);
 }
 
+#[test]
+fn test_deserialize_balance() {
+    let s = r#"{"success": true, "jpy": "0.8401", "btc": "7.75052654", "eth": "0.5", "jpy_reserved": "3000.0", "btc_reserved": "3.5002", "jpy_lend_in_use": "0", "btc_lent": "0", "eth_reserved": "0"}"#;
+    let obj: BalanceResponse = serde_json::from_str(s).unwrap();
+    assert_eq!(obj.success, true);
+    assert_eq!(obj.free(Currency::JPY), 0.8401);
+    assert_eq!(obj.reserved(Currency::JPY), 3000.0);
+    assert_eq!(obj.total(Currency::BTC), 7.75052654 + 3.5002);
+    assert_eq!(obj.free(Currency::ETH), 0.5);
+    assert_eq!(obj.total(Currency::XRP), 0.);
+    let s = r#"{"btc": "-0.005", "jpy": "21350.0"}"#;
+    let funds: TransactionFunds = serde_json::from_str(s).unwrap();
+    assert_eq!(funds.get(Currency::BTC), -0.005);
+}
+
 #[tokio::test]
 async fn test_open_orders() {
     let client = CoincheckClient::new(Some(crate::client::credentials::CREDENTIALS.coincheck.clone()));
@@ -590,6 +1025,40 @@ async fn test_post_order() {
         rate: FloatExp::from_f64(1000000.0, 0),
         amount: FloatExp::from_f64(0.005, -3),
         time_in_force: None,
+        stop_loss_rate: None,
     })).await.unwrap();
     println!("{:?}", res);
-}
\ No newline at end of file
+}
+#[test]
+fn test_private_ws_message() {
+    let msg: PrivateWsMessage = serde_json::from_str(r#"{"channel":"order-events","id":5710599665,"pair":"btc_jpy","order_event":"PARTIALLY_FILL","order_type":"sell","rate":"4200000.0","stop_loss_rate":null,"pending_amount":"0.003","pending_market_buy_amount":null,"event_time":"2023-07-29T14:23:31.000Z"}"#).unwrap();
+    let order = match msg {
+        PrivateWsMessage::Order(x) => x.to_order_event().unwrap().unwrap(),
+        x => panic!("{:?}", x),
+    };
+    assert_eq!(order.status, OrderStatus::PartiallyFilled);
+    assert_eq!(order.side, Some(Side::Sell));
+    assert_eq!(order.amount, FloatExp::new(3, -3));
+
+    let msg: PrivateWsMessage = serde_json::from_str(r#"{"channel":"execution-events","id":402,"order_id":5710599665,"event_time":"2023-07-29T14:23:32.000Z","funds":{"btc":"-0.002","jpy":"8400.0"},"pair":"btc_jpy","rate":"4200000.0","fee_currency":"jpy","fee":"0.0","liquidity":"M","side":"sell"}"#).unwrap();
+    let execution = match msg {
+        PrivateWsMessage::Execution(x) => x.to_execution().unwrap(),
+        x => panic!("{:?}", x),
+    };
+    assert_eq!(execution.order_id, "5710599665");
+    assert_eq!(execution.side, Side::Sell);
+    assert_eq!(execution.amount, FloatExp::new(2, -3));
+    assert_eq!(execution.price, FloatExp::new(4200000, 0));
+}
+
+#[test]
+fn test_rest_error() {
+    use crate::error_types::ErrorAction;
+    let error = |message: &str| RestErrResponse { error: message.to_string(), success: false }.to_bot_error();
+    assert!(matches!(error("Rate deviates from actual price"), BotError::PriceOutOfRange));
+    assert!(matches!(error("Nonce must be incremented"), BotError::NonceError));
+    let rejected = error("Amount Amount can't be blank");
+    assert!(matches!(rejected, BotError::CoincheckClientMessage { .. }));
+    assert_eq!(rejected.action(), ErrorAction::Fatal);
+    assert_eq!(error("Rate deviates from actual price").action(), ErrorAction::Ignore);
+}
@@ -7,6 +7,9 @@ pub struct Credentials {
     pub gmo: ApiCredentials,
     pub bitflyer: ApiCredentials,
     pub coincheck: ApiCredentials,
+    /// binanceで発注しなければ不要
+    #[serde(default)]
+    pub binance: Option<ApiCredentials>,
     pub mail: MailCredentials,
 }
 
@@ -21,6 +24,13 @@ pub struct MailCredentials {
     pub user: String,
     pub password: String,
     pub sendto: String,
+    /// SMTPサーバー。省略するとgmail
+    #[serde(default = "default_smtp_host")]
+    pub host: String,
+}
+
+fn default_smtp_host() -> String {
+    "smtp.gmail.com".to_string()
 }
 
 pub static CREDENTIALS: Lazy<Credentials> = Lazy::new(|| {
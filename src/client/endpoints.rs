@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::{symbol::Exchange, config::read_config_section};
+
+/// 取引所の接続先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    pub rest: String,
+    /// gmoのprivate API。ほかの取引所ではrestを使う
+    pub private: String,
+    pub ws: String,
+    /// 約定・注文イベントのwebsocket。gmoはこの後ろにトークンを付ける
+    pub private_ws: String,
+    /// binanceのUSDⓈ-M先物のREST。ほかの取引所ではrestを使う
+    pub futures: String,
+}
+
+/// config.yamlの`endpoints`。指定したものだけ上書きする
+///
+/// ```yaml
+/// endpoints:
+///   gmo:
+///     rest: http://127.0.0.1:18080/public
+///     private: http://127.0.0.1:18080/private
+///     ws: ws://127.0.0.1:18081/ws/public/v1
+///     private_ws: ws://127.0.0.1:18081/ws/private/v1
+///   binance:
+///     futures: http://127.0.0.1:18080
+/// ```
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct EndpointsOverride {
+    pub rest: Option<String>,
+    pub private: Option<String>,
+    pub ws: Option<String>,
+    pub private_ws: Option<String>,
+    pub futures: Option<String>,
+}
+
+/// config.yamlになければ本番の接続先を使う
+static OVERRIDES: Lazy<HashMap<Exchange, EndpointsOverride>> = Lazy::new(|| read_config_section("endpoints"));
+
+pub fn default_endpoints(exc: Exchange) -> Endpoints {
+    let (rest, private, ws, private_ws, futures) = match exc {
+        Exchange::Gmo => ("https://api.coin.z.com/public", "https://api.coin.z.com/private", "wss://api.coin.z.com/ws/public/v1", "wss://api.coin.z.com/ws/private/v1", "https://api.coin.z.com/public"),
+        Exchange::Bitflyer => ("https://api.bitflyer.com", "https://api.bitflyer.com", "wss://ws.lightstream.bitflyer.com/json-rpc", "wss://ws.lightstream.bitflyer.com/json-rpc", "https://api.bitflyer.com"),
+        Exchange::Coincheck => ("https://coincheck.com", "https://coincheck.com", "wss://ws-api.coincheck.com/", "wss://stream.coincheck.com", "https://coincheck.com"),
+        Exchange::Binance => ("https://api.binance.com", "https://api.binance.com", "wss://stream.binance.com:9443/ws", "wss://stream.binance.com:9443/ws", "https://fapi.binance.com"),
+    };
+    Endpoints { rest: rest.to_string(), private: private.to_string(), ws: ws.to_string(), private_ws: private_ws.to_string(), futures: futures.to_string() }
+}
+
+pub fn endpoints(exc: Exchange) -> Endpoints {
+    let mut ret = default_endpoints(exc);
+    if let Some(o) = OVERRIDES.get(&exc) {
+        if let Some(rest) = &o.rest {
+            ret.rest = rest.clone();
+        }
+        if let Some(private) = &o.private {
+            ret.private = private.clone();
+        }
+        if let Some(ws) = &o.ws {
+            ret.ws = ws.clone();
+        }
+        if let Some(private_ws) = &o.private_ws {
+            ret.private_ws = private_ws.clone();
+        }
+        if let Some(futures) = &o.futures {
+            ret.futures = futures.clone();
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_endpoints_override() {
+    let config: HashMap<Exchange, EndpointsOverride> = crate::config::parse_config_section("endpoints:\n  coincheck:\n    rest: http://127.0.0.1:1\n", "endpoints").unwrap();
+    let o = &config[&Exchange::Coincheck];
+    assert_eq!(o.rest.as_deref(), Some("http://127.0.0.1:1"));
+    assert!(o.ws.is_none());
+    assert_eq!(default_endpoints(Exchange::Gmo).private, "https://api.coin.z.com/private");
+    assert_eq!(default_endpoints(Exchange::Binance).futures, "https://fapi.binance.com");
+}
@@ -0,0 +1,430 @@
+use std::{collections::HashSet, future::Future, sync::Arc, time::Duration as StdDuration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures::channel::mpsc::UnboundedSender;
+use log::info;
+use once_cell::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::{symbol::{Symbol, Exchange, Currency}, order_types::{Side, PosSide, OrderType}, data_structure::float_exp::{FloatExp, RoundingMode}};
+
+use super::{gmo::GmoClient, bitflyer::BitflyerClient, coincheck::CoincheckClient, binance::BinanceClient, credentials::CREDENTIALS, method::{RequestFailure, retry_policy}};
+
+pub type OrderId = String;
+
+/// 取引所共通の新規注文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewOrder {
+    pub symbol: Symbol,
+    pub side: Side,
+    pub order_type: OrderType,
+    /// Limit, StopLimitの指値
+    pub price: Option<FloatExp>,
+    /// Stop, StopLimitの逆指値
+    pub trigger_price: Option<FloatExp>,
+    pub amount: FloatExp,
+    pub post_only: bool,
+    /// 取引所に渡せるとき（gmo, binance）は結果のわからなかった発注をこれで問い合わせる。なければclient側で作る
+    pub client_order_id: Option<String>,
+}
+
+impl NewOrder {
+    pub fn limit(symbol: Symbol, side: Side, price: FloatExp, amount: FloatExp) -> Self {
+        Self {
+            symbol,
+            side,
+            order_type: OrderType::Limit,
+            price: Some(price),
+            trigger_price: None,
+            amount,
+            post_only: false,
+            client_order_id: None,
+        }
+    }
+
+    pub fn market(symbol: Symbol, side: Side, amount: FloatExp) -> Self {
+        Self {
+            symbol,
+            side,
+            order_type: OrderType::Market,
+            price: None,
+            trigger_price: None,
+            amount,
+            post_only: false,
+            client_order_id: None,
+        }
+    }
+
+    pub fn stop(symbol: Symbol, side: Side, trigger_price: FloatExp, amount: FloatExp) -> Self {
+        Self {
+            symbol,
+            side,
+            order_type: OrderType::Stop,
+            price: None,
+            trigger_price: Some(trigger_price),
+            amount,
+            post_only: false,
+            client_order_id: None,
+        }
+    }
+
+    pub fn post_only(mut self) -> Self {
+        self.post_only = true;
+        self
+    }
+
+    pub fn with_client_order_id(mut self, client_order_id: &str) -> Self {
+        self.client_order_id = Some(client_order_id.to_string());
+        self
+    }
+
+    /// 指定がなければ発注ごとに作る
+    pub fn client_order_id_or_new(&self) -> String {
+        self.client_order_id.clone().unwrap_or_else(|| Uuid::new_v4().simple().to_string())
+    }
+
+    /// Limit, StopLimitで指値がなければエラー
+    pub fn limit_price(&self) -> anyhow::Result<FloatExp> {
+        self.price.ok_or_else(|| anyhow::anyhow!("price is required for {:?}", self.order_type))
+    }
+
+    /// Stop, StopLimitで逆指値がなければエラー
+    pub fn stop_price(&self) -> anyhow::Result<FloatExp> {
+        self.trigger_price.ok_or_else(|| anyhow::anyhow!("trigger_price is required for {:?}", self.order_type))
+    }
+
+    /// instruments.yamlの呼値・数量単位に丸める。指値は不利にならない側、数量は切り下げ
+    /// 数量が0になればエラー
+    pub fn round_to_instrument(&self) -> anyhow::Result<Self> {
+        let instrument = self.symbol.instrument()?;
+        let mode = match self.side {
+            Side::Buy => RoundingMode::Floor,
+            Side::Sell => RoundingMode::Ceil,
+        };
+        let amount = instrument.round_amount(self.amount);
+        if amount.is_zero() {
+            anyhow::bail!("amount {} is less than lot_size {}", self.amount, instrument.lot_size);
+        }
+        Ok(Self {
+            price: self.price.map(|p| instrument.round_price(p, mode)),
+            trigger_price: self.trigger_price.map(|p| instrument.round_price(p, RoundingMode::HalfUp)),
+            amount,
+            ..self.clone()
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenOrder {
+    pub id: OrderId,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub price: Option<FloatExp>,
+    /// 未約定の数量
+    pub amount: FloatExp,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub symbol: Symbol,
+    pub pos_side: PosSide,
+    pub amount: FloatExp,
+    /// 建値。不明なら0
+    pub price: FloatExp,
+}
+
+#[derive(Debug, Clone)]
+pub struct AssetBalance {
+    pub currency: Currency,
+    pub total: f64,
+    pub available: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ticker {
+    pub last: FloatExp,
+    pub bid: FloatExp,
+    pub ask: FloatExp,
+    /// 直近24時間のbase建て出来高
+    pub volume: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderbookSnapshot {
+    /// priceの降順
+    pub bids: Vec<(FloatExp, FloatExp)>,
+    /// priceの昇順
+    pub asks: Vec<(FloatExp, FloatExp)>,
+}
+
+impl OrderbookSnapshot {
+    pub fn by_side(&self, side: Side) -> &Vec<(FloatExp, FloatExp)> {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+}
+
+/// private websocketで受け取る取引所共通のイベント
+#[derive(Debug, Clone)]
+pub enum PrivateEvent {
+    Execution(Execution),
+    Order(OrderEvent),
+    /// 建玉が変わったときの数量。決済されたら0
+    Position(Position),
+}
+
+/// 約定
+#[derive(Debug, Clone)]
+pub struct Execution {
+    pub order_id: OrderId,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub price: FloatExp,
+    pub amount: FloatExp,
+    /// 決済通貨建て。わからなければ0
+    pub fee: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    /// 板に載った
+    Ordered,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    Expired,
+    Rejected,
+}
+
+impl OrderStatus {
+    /// これ以上イベントが来ない状態
+    pub fn is_closed(&self) -> bool {
+        matches!(self, OrderStatus::Filled | OrderStatus::Canceled | OrderStatus::Expired | OrderStatus::Rejected)
+    }
+}
+
+/// 注文の状態変化
+#[derive(Debug, Clone)]
+pub struct OrderEvent {
+    pub order_id: OrderId,
+    pub symbol: Symbol,
+    /// bitflyerのCANCEL・EXPIREなどには付かない
+    pub side: Option<Side>,
+    pub status: OrderStatus,
+    pub price: Option<FloatExp>,
+    /// 注文数量
+    pub amount: FloatExp,
+    /// 約定済みの数量
+    pub executed: FloatExp,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// 取引所に依存しない注文・残高操作
+#[async_trait]
+pub trait ExchangeClient: Send + Sync {
+    fn exchange(&self) -> Exchange;
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId>;
+
+    async fn cancel_order(&self, symbol: Symbol, id: &OrderId) -> anyhow::Result<()>;
+
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()>;
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>>;
+
+    /// 現物では保有しているbaseをLongとして返す
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>>;
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>>;
+
+    /// 証拠金として使える決済通貨建ての資産
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64>;
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker>;
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot>;
+
+    async fn limit_order(&self, symbol: Symbol, side: Side, price: FloatExp, amount: FloatExp) -> anyhow::Result<OrderId> {
+        self.place_order(&NewOrder::limit(symbol, side, price, amount)).await
+    }
+
+    async fn market_order(&self, symbol: Symbol, side: Side, amount: FloatExp) -> anyhow::Result<OrderId> {
+        self.place_order(&NewOrder::market(symbol, side, amount)).await
+    }
+
+    async fn stop_order(&self, symbol: Symbol, side: Side, trigger_price: FloatExp, amount: FloatExp) -> anyhow::Result<OrderId> {
+        self.place_order(&NewOrder::stop(symbol, side, trigger_price, amount)).await
+    }
+}
+
+/// config.yamlの認証情報でprivate clientを作る
+pub fn private_client(exc: Exchange) -> anyhow::Result<Arc<dyn ExchangeClient>> {
+    match exc {
+        Exchange::Gmo => Ok(Arc::new(GmoClient::new(Some(CREDENTIALS.gmo.clone())))),
+        Exchange::Bitflyer => Ok(Arc::new(BitflyerClient::new(Some(CREDENTIALS.bitflyer.clone())))),
+        Exchange::Coincheck => Ok(Arc::new(CoincheckClient::new(Some(CREDENTIALS.coincheck.clone())))),
+        Exchange::Binance => {
+            let credentials = CREDENTIALS.binance.clone().ok_or_else(|| anyhow::anyhow!("binance credentials are not found in config.yaml"))?;
+            Ok(Arc::new(BinanceClient::new(Some(credentials))))
+        },
+    }
+}
+
+/// subscribe_private_eventsで約定・注文のイベントを受け取れる取引所
+pub fn has_private_events(exc: Exchange) -> bool {
+    matches!(exc, Exchange::Gmo | Exchange::Bitflyer | Exchange::Coincheck)
+}
+
+/// config.yamlの認証情報でprivate websocketにつなぎ、イベントをtxに流す。切断されてもつなぎ直し続ける
+pub async fn subscribe_private_events(exc: Exchange, tx: UnboundedSender<PrivateEvent>) -> anyhow::Result<()> {
+    match exc {
+        Exchange::Gmo => GmoClient::new(Some(CREDENTIALS.gmo.clone())).subscribe_private(tx).await,
+        Exchange::Bitflyer => BitflyerClient::new(Some(CREDENTIALS.bitflyer.clone())).subscribe_private(tx).await,
+        Exchange::Coincheck => CoincheckClient::new(Some(CREDENTIALS.coincheck.clone())).subscribe_private(tx).await,
+        _ => anyhow::bail!("private websocket of {} is not supported", exc),
+    }
+}
+
+/// 取引所と手元の時計のずれを見込む
+const AMBIGUOUS_ORDER_CLOCK_SKEW_MS: i64 = 1000;
+
+type TrackedOrderIds = Box<dyn Fn(Symbol) -> HashSet<OrderId> + Send + Sync>;
+
+/// 戦略が把握している注文id。結果のわからなかった発注を履歴から探すとき、これらは別の注文なので除く
+static TRACKED_ORDER_IDS: OnceCell<TrackedOrderIds> = OnceCell::new();
+
+/// 注文を管理している戦略の起動時に1回だけ呼ぶ
+pub fn register_tracked_order_ids(f: impl Fn(Symbol) -> HashSet<OrderId> + Send + Sync + 'static) {
+    if TRACKED_ORDER_IDS.set(Box::new(f)).is_err() {
+        panic!("tracked order ids are already registered");
+    }
+}
+
+pub fn tracked_order_ids(symbol: Symbol) -> HashSet<OrderId> {
+    TRACKED_ORDER_IDS.get().map(|f| f(symbol)).unwrap_or_default()
+}
+
+/// 発注履歴の1件。約定し切ったもの・取り消されたものも含む
+#[derive(Debug, Clone)]
+pub struct OrderRecord {
+    pub id: OrderId,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub price: Option<FloatExp>,
+    /// 注文数量
+    pub amount: FloatExp,
+    pub created_at: DateTime<Utc>,
+}
+
+/// client order idを渡せない取引所（bitflyer）で、結果のわからなかった発注を発注履歴から探す
+/// 送った時刻以降にできた同じ内容の注文がちょうど1件のときだけそのidを返す。trackedの注文は別の注文なので除く
+/// 候補が複数あればどれか決められないのでNone
+pub fn find_unique_order(records: &[OrderRecord], order: &NewOrder, sent_at: DateTime<Utc>, tracked: &HashSet<OrderId>) -> Option<OrderId> {
+    let mut candidates = records.iter()
+        .filter(|o| o.side == order.side && o.order_type == order.order_type)
+        .filter(|o| o.price == order.price && o.amount == order.amount)
+        .filter(|o| o.created_at >= sent_at - Duration::milliseconds(AMBIGUOUS_ORDER_CLOCK_SKEW_MS))
+        .filter(|o| !tracked.contains(&o.id));
+    match (candidates.next(), candidates.next()) {
+        (Some(o), None) => Some(o.id.clone()),
+        _ => None,
+    }
+}
+
+/// 発注はやり直さない。タイムアウト・5xxで結果がわからなければlookupで取引所に問い合わせ、見つかればその注文idを返す
+/// lookupには送る直前の時刻を渡す。見つからなければ届いていないとも言い切れないのでエラーにする
+/// - gmo: `clientOrderId`を付けて送り、未約定注文と直近の約定の注文から探す
+/// - bitflyer: 受付idはこちらから指定できないので、発注履歴から`find_unique_order`で受付idを探す
+/// - coincheck: client order idも発注履歴の照会もないので探さない
+pub async fn place_order_checked<S, L, LF>(order: &NewOrder, send: S, lookup: L) -> anyhow::Result<OrderId>
+where
+    S: Future<Output = anyhow::Result<OrderId>>,
+    L: FnOnce(DateTime<Utc>) -> LF,
+    LF: Future<Output = anyhow::Result<Option<OrderId>>>,
+{
+    let sent_at = Utc::now();
+    let err = match send.await {
+        Err(e) if RequestFailure::of(&e).is_ambiguous() => e,
+        res => return res,
+    };
+    // 遅れて届いた注文が反映されるのを待つ
+    tokio::time::sleep(StdDuration::from_millis(retry_policy().base_delay_ms)).await;
+    match lookup(sent_at).await {
+        Ok(Some(id)) => {
+            info!("order was placed despite {:?}: {} {:?}", err, id, order);
+            Ok(id)
+        },
+        Ok(None) => Err(err.context("order result is unknown and not found on the exchange")),
+        Err(lookup_err) => Err(err.context(format!("order result is unknown: {:?}", lookup_err))),
+    }
+}
+
+/// PosSideごとに数量と建値を集計する
+pub fn aggregate_positions(positions: &Vec<Position>, price_exp: i32, amount_exp: i32) -> [(FloatExp, FloatExp); 2] {
+    let mut ret = [(FloatExp::new(0, amount_exp), FloatExp::new(0, price_exp + amount_exp)); 2];
+    for pos in positions {
+        let idx = pos.pos_side as usize;
+        ret[idx].0 += pos.amount.round(amount_exp);
+        ret[idx].1 += (pos.price.round(price_exp) * pos.amount.round(amount_exp)).round(price_exp + amount_exp);
+    }
+    ret
+}
+
+#[test]
+fn test_aggregate_positions() {
+    use crate::symbol::{Currency, SymbolType};
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let positions = vec![
+        Position { symbol, pos_side: PosSide::Long, amount: FloatExp::new(1, -2), price: FloatExp::new(4000000, 0) },
+        Position { symbol, pos_side: PosSide::Long, amount: FloatExp::new(2, -2), price: FloatExp::new(4300000, 0) },
+        Position { symbol, pos_side: PosSide::Short, amount: FloatExp::new(1, -2), price: FloatExp::new(4100000, 0) },
+    ];
+    let agg = aggregate_positions(&positions, 0, -2);
+    assert_eq!(agg[0].0, FloatExp::new(3, -2));
+    assert_eq!(agg[0].1, FloatExp::new(12600000, -2));
+    assert_eq!(agg[1].0, FloatExp::new(1, -2));
+}
+
+#[test]
+fn test_find_unique_order() {
+    use crate::symbol::{Currency, SymbolType};
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let sent_at = Utc::now();
+    let req = NewOrder::market(symbol, Side::Buy, FloatExp::new(2, -2));
+    let record = |id: &str, side, amount, created_at| OrderRecord {
+        id: id.to_string(), side, order_type: OrderType::Market, price: None, amount: FloatExp::new(amount, -2), created_at,
+    };
+    let records = vec![
+        record("old", Side::Buy, 2, sent_at - Duration::seconds(5)),
+        record("sell", Side::Sell, 2, sent_at),
+        record("other_amount", Side::Buy, 1, sent_at),
+        // 約定し切った成行も履歴にある
+        record("filled", Side::Buy, 2, sent_at + Duration::seconds(1)),
+        record("tracked", Side::Buy, 2, sent_at + Duration::seconds(2)),
+    ];
+    let tracked = HashSet::from(["tracked".to_string()]);
+    assert_eq!(find_unique_order(&records, &req, sent_at, &tracked), Some("filled".to_string()));
+    // 同じ内容の注文が2件あればどちらか決めない
+    assert_eq!(find_unique_order(&records, &req, sent_at, &HashSet::new()), None);
+    assert_eq!(find_unique_order(&records[..3], &req, sent_at, &tracked), None);
+}
+
+#[test]
+fn test_round_to_instrument() {
+    use crate::symbol::{Currency, SymbolType};
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+    let buy = NewOrder::limit(symbol, Side::Buy, FloatExp::new(40000005, -1), FloatExp::new(15, -3)).round_to_instrument().unwrap();
+    assert_eq!(buy.price, Some(FloatExp::new(4000000, 0)));
+    assert_eq!(buy.amount, FloatExp::new(1, -2));
+    let sell = NewOrder::limit(symbol, Side::Sell, FloatExp::new(40000005, -1), FloatExp::new(15, -3)).round_to_instrument().unwrap();
+    assert_eq!(sell.price, Some(FloatExp::new(4000001, 0)));
+    let stop = NewOrder::stop(symbol, Side::Sell, FloatExp::new(40000005, -1), FloatExp::new(1, -2)).round_to_instrument().unwrap();
+    assert_eq!(stop.trigger_price, Some(FloatExp::new(4000001, 0)));
+    assert!(NewOrder::market(symbol, Side::Buy, FloatExp::new(5, -3)).round_to_instrument().is_err());
+}
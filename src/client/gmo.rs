@@ -1,14 +1,18 @@
-use std::str::FromStr;
-
 use anyhow::{bail};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::channel::mpsc::UnboundedSender;
 use hyper::{Method, HeaderMap};
+use log::info;
+use maplit::hashmap;
 use serde::{Deserialize, Serialize, Deserializer};
-use serde_json::{Value};
+use serde_json::{Value, json};
+use tokio::select;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::{Side, OrderType}, error_types::BotError, utils::{time::deserialize_rfc3339, serde::deserialize_f64_from_str}};
+use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::{Side, OrderType, PosSide}, error_types::BotError, utils::{time::deserialize_rfc3339, serde::deserialize_f64_from_str, strategy_utils::{ReconnectingWs, WsHandler}}, data_structure::float_exp::FloatExp};
 
-use super::{method::{make_header, get, post, GetRequest, EmptyQueryRequest, HasPath}, credentials::ApiCredentials, auth::gmo_coin_auth};
+use super::{method::{make_header, get, post, put, GetRequest, EmptyQueryRequest, HasPath, acquire, EndpointClass, Priority, with_retry, retry_if, http_client}, credentials::ApiCredentials, auth::gmo_coin_auth, endpoints::endpoints, types::TradeRecord, exchange::{ExchangeClient, place_order_checked, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker as CommonTicker, OrderbookSnapshot, PrivateEvent, Execution, OrderEvent, OrderStatus}};
 
 #[derive(Debug, Clone)]
 pub struct GmoClient {
@@ -21,13 +25,20 @@ pub struct GmoClient {
 impl GmoClient {
     pub fn new(api_credentials: Option<ApiCredentials>) -> GmoClient {
         GmoClient {
-            client: reqwest::Client::new(),
-            public_endpoint: "https://api.coin.z.com/public".to_string(),
-            private_endpoint: "https://api.coin.z.com/private".to_string(),
+            client: http_client(),
+            public_endpoint: endpoints(Exchange::Gmo).rest,
+            private_endpoint: endpoints(Exchange::Gmo).private,
             api_credentials,
         }
     }
 
+    /// mock serverなど本番以外に向ける
+    pub fn with_endpoints(mut self, public_endpoint: &str, private_endpoint: &str) -> Self {
+        self.public_endpoint = public_endpoint.to_string();
+        self.private_endpoint = private_endpoint.to_string();
+        self
+    }
+
     fn make_header<T: serde::Serialize>(&self, method: Method, path: &str, body: Option<&T>) -> anyhow::Result<HeaderMap> {
         let api_credentials = match &self.api_credentials {
             Some(x) => x,
@@ -41,8 +52,12 @@ impl GmoClient {
         path: &str,
         query: S,
     ) -> anyhow::Result<T> {
-        get(&self.client, &self.public_endpoint, path, HeaderMap::new(), query).await
-            .map(|x| x.1)
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Gmo, EndpointClass::Public, Priority::Normal).await;
+            get(&self.client, &self.public_endpoint, path, HeaderMap::new(), query.clone()).await
+                .map(|x| x.1)
+        }).await
     }
 
     pub async fn get_private<S: GetRequest, T: serde::de::DeserializeOwned>(
@@ -50,8 +65,12 @@ impl GmoClient {
         path: &str,
         query: S,
     ) -> anyhow::Result<T> {
-        get(&self.client, &self.private_endpoint, path, self.make_header::<Value>(Method::GET, path, None)?, query).await
-            .map(|x| x.1)
+        let query = query.to_query();
+        with_retry(|| async {
+            acquire(Exchange::Gmo, EndpointClass::Private, Priority::Normal).await;
+            get(&self.client, &self.private_endpoint, path, self.make_header::<Value>(Method::GET, path, None)?, query.clone()).await
+                .map(|x| x.1)
+        }).await
     }
 
     pub async fn post<S: serde::Serialize, T: serde::de::DeserializeOwned>(
@@ -59,8 +78,92 @@ impl GmoClient {
         path: &str,
         body: &S,
     ) -> anyhow::Result<T> {
-        post(&self.client, &self.private_endpoint, path, self.make_header(Method::POST, path, Some(&body))?, body).await
-            .map(|x| x.1)
+        let priority = Priority::from_path(path);
+        retry_if(priority == Priority::Cancel, || async {
+            acquire(Exchange::Gmo, EndpointClass::Order, priority).await;
+            post(&self.client, &self.private_endpoint, path, self.make_header(Method::POST, path, Some(&body))?, body).await
+                .map(|x| x.1)
+        }).await
+    }
+
+    pub async fn put<S: serde::Serialize, T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &S,
+    ) -> anyhow::Result<T> {
+        let priority = Priority::from_path(path);
+        retry_if(priority == Priority::Cancel, || async {
+            acquire(Exchange::Gmo, EndpointClass::Order, priority).await;
+            put(&self.client, &self.private_endpoint, path, self.make_header(Method::PUT, path, Some(&body))?, body).await
+                .map(|x| x.1)
+        }).await
+    }
+
+    /// private websocketのアクセストークンを発行する。有効期限は60分
+    pub async fn create_ws_token(&self) -> anyhow::Result<String> {
+        let res: GmoClientResponse<String> = self.post("/v1/ws-auth", &json!({})).await?;
+        res.into_result()
+    }
+
+    /// 有効期限を60分に延長する
+    pub async fn extend_ws_token(&self, token: &str) -> anyhow::Result<()> {
+        let res: GmoClientResponse<Value> = self.put("/v1/ws-auth", &json!({"token": token})).await?;
+        res.into_status_result()
+    }
+
+    /// clientOrderIdで注文を探す。未約定なら/v1/activeOrders、約定し切っていれば直近の約定の注文から
+    pub async fn find_order_by_client_id(&self, symbol: Symbol, client_order_id: &str) -> anyhow::Result<Option<OrderId>> {
+        let query = hashmap! {"symbol".to_owned() => symbol.to_native()};
+        let res: GmoClientResponse<GmoList<ActiveOrder>> = self.get_private("/v1/activeOrders", query.clone()).await?;
+        let mut orders = res.into_result()?.list;
+        let res: GmoClientResponse<GmoList<LatestExecution>> = self.get_private("/v1/latestExecutions", query).await?;
+        let mut order_ids: Vec<String> = vec![];
+        for e in res.into_result()?.list {
+            let id = e.order_id.to_string();
+            if !order_ids.contains(&id) {
+                order_ids.push(id);
+            }
+        }
+        // /v1/ordersは10件まで
+        order_ids.truncate(10);
+        if !order_ids.is_empty() {
+            let res: GmoClientResponse<GmoList<ActiveOrder>> = self.get_private("/v1/orders", hashmap! {"orderId".to_owned() => order_ids.join(",")}).await?;
+            orders.extend(res.into_result()?.list);
+        }
+        Ok(orders.into_iter().find(|o| o.client_order_id.as_deref() == Some(client_order_id)).map(|o| o.order_id.to_string()))
+    }
+
+    /// executionEvents, orderEvents, positionEventsを購読してtxに流す
+    /// トークンは30分ごとに延長し、延長や再接続に失敗したら発行し直す
+    pub async fn subscribe_private(&self, tx: UnboundedSender<PrivateEvent>) -> anyhow::Result<()> {
+        loop {
+            let token = self.create_ws_token().await?;
+            let ws = ["executionEvents", "orderEvents", "positionEvents"].iter().fold(
+                ReconnectingWs::new(&format!("{}/{}", endpoints(Exchange::Gmo).private_ws, token)),
+                |ws, channel| ws.subscribe(json!({"command": "subscribe", "channel": channel})),
+            )
+                // 連続でsubscribeすると無視されるので少し待つ
+                .subscribe_interval(std::time::Duration::from_millis(1500))
+                // gmoはサーバーからpingが来る
+                .ping_interval(None)
+                .liveness_timeout(std::time::Duration::from_secs(120))
+                .metrics_label(&endpoints(Exchange::Gmo).private_ws);
+            let mut handler = PrivateWsHandler { tx: tx.clone() };
+            select! {
+                res = ws.run(&mut handler) => info!("gmo private websocket stopped: {:?}", res),
+                e = async {
+                    loop {
+                        tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
+                        if let Err(e) = self.extend_ws_token(&token).await {
+                            return e;
+                        }
+                    }
+                } => info!("failed to extend gmo ws token: {:?}", e),
+            }
+            if tx.is_closed() {
+                return Ok(());
+            }
+        }
     }
 }
 
@@ -76,15 +179,46 @@ impl <T> GmoClientResponse<T> {
     pub fn into_result(self) -> anyhow::Result<T> {
         match self.data {
             Some(data) => Ok(data),
-            _ => {
-                match self.messages {
-                    Some(messages) if messages[0].message_string.to_lowercase().contains("maintenance") => Err(BotError::Maintenance.into()),
-                    Some(messages) => Err(BotError::GmoClientMessage { code: messages[0].message_code.clone(), message: messages[0].message_string.clone() }.into()),
-                    _ => Err(BotError::GmoClientMessage { code: "unknown".to_string(), message: "unknown".to_string() }.into()),
-                }
-            }
+            _ => Err(self.messages_error()),
         }
     }
+
+    /// dataを返さないAPI(cancelOrderなど)用。statusが0なら成功
+    pub fn into_status_result(self) -> anyhow::Result<()> {
+        if self.status == 0 {
+            Ok(())
+        } else {
+            Err(self.messages_error())
+        }
+    }
+
+    fn messages_error(self) -> anyhow::Error {
+        match self.messages {
+            Some(messages) => gmo_error(&messages[0].message_code, &messages[0].message_string).into(),
+            _ => BotError::GmoClientMessage { code: "unknown".to_string(), message: "unknown".to_string() }.into(),
+        }
+    }
+}
+
+/// Requests are too many.
+const RATE_LIMIT_CODE: &str = "ERR-5003";
+
+/// エラーコードをBotErrorにする。分類できないものはGmoClientMessage
+pub fn gmo_error(code: &str, message: &str) -> BotError {
+    match code {
+        RATE_LIMIT_CODE => BotError::RateLimited,
+        // API-TIMESTAMPが遅すぎる・早すぎる
+        "ERR-5008" | "ERR-5009" => BotError::NonceError,
+        // 署名・APIキー・権限
+        "ERR-5010" | "ERR-5011" | "ERR-5012" | "ERR-5014" => BotError::AuthFailed,
+        // 定期・臨時メンテナンス
+        "ERR-5201" | "ERR-5202" => BotError::Maintenance,
+        // 余力・証拠金不足
+        "ERR-201" | "ERR-208" => BotError::InsufficientFunds,
+        // 注文の状態が変更できない・注文が存在しない
+        "ERR-5122" | "ERR-5123" => BotError::OrderRejected { reason: format!("{} {}", code, message) },
+        _ => BotError::GmoClientMessage { code: code.to_string(), message: message.to_string() },
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -116,7 +250,85 @@ pub type Tickers = Vec<Ticker>;
 
 #[derive(Debug, Deserialize)]
 pub struct Ticker {
-    pub last: String
+    pub last: String,
+    pub ask: String,
+    pub bid: String,
+    pub volume: String,
+}
+
+/// /v1/symbols
+pub type SymbolRules = Vec<SymbolRule>;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolRule {
+    pub symbol: String,
+    pub min_order_size: String,
+    pub max_order_size: String,
+    pub size_step: String,
+    pub tick_size: String,
+    pub taker_fee: String,
+    pub maker_fee: String,
+}
+
+/// /v1/account/margin
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountMargin {
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub actual_profit_loss: f64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub available_amount: f64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub margin: f64,
+}
+
+/// ページングされる一覧のレスポンス。空のときはlistが無い
+#[derive(Debug, Deserialize)]
+pub struct GmoList<T> {
+    #[serde(default = "Vec::new")]
+    pub list: Vec<T>,
+}
+
+/// /v1/activeOrders, /v1/orders
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveOrder {
+    pub order_id: i64,
+    /// 発注時に付けたもの
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    pub symbol: String,
+    pub side: Side,
+    pub execution_type: OrderType,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub size: f64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub executed_size: f64,
+    pub price: Option<String>,
+    pub status: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// /v1/openPositions
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenPosition {
+    pub position_id: i64,
+    pub symbol: String,
+    pub side: Side,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub size: f64,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub price: f64,
+}
+
+/// /v1/orderbooks (REST)
+#[derive(Debug, Deserialize)]
+pub struct OrderbooksSnapshot {
+    pub asks: Vec<PriceSizePair>,
+    pub bids: Vec<PriceSizePair>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -134,9 +346,145 @@ pub struct CreateOrderRequest {
     pub side: Side,
     pub execution_type: OrderType,
     pub size: String,
-    pub price: String,
+    /// MARKETでは不要、STOPでは逆指値
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub time_in_force: Option<GmoTimeInForce>,
+    /// 結果のわからなかった発注を問い合わせるときに使う
+    pub client_order_id: String,
+}
+
+/// /v1/latestExecutions
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatestExecution {
+    pub order_id: i64,
+}
+
+#[async_trait]
+impl ExchangeClient for GmoClient {
+    fn exchange(&self) -> Exchange {
+        Exchange::Gmo
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        let client_order_id = order.client_order_id_or_new();
+        place_order_checked(order, async {
+            let price = match order.order_type {
+                OrderType::Limit => Some(order.limit_price()?),
+                OrderType::Market => None,
+                OrderType::Stop => Some(order.stop_price()?),
+                OrderType::StopLimit => bail!("StopLimit is not supported in gmo"),
+            };
+            let req = CreateOrderRequest {
+                symbol: order.symbol,
+                side: order.side,
+                execution_type: order.order_type.clone(),
+                size: format!("{}", order.amount),
+                price: price.map(|p| format!("{}", p)),
+                time_in_force: if order.post_only { Some(GmoTimeInForce::SOK) } else { None },
+                client_order_id: client_order_id.clone(),
+            };
+            let res: GmoClientResponse<String> = self.post("/v1/order", &req).await?;
+            res.into_result()
+        }, |_| self.find_order_by_client_id(order.symbol, &client_order_id)).await
+    }
+
+    async fn cancel_order(&self, _symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        let res: GmoClientResponse<Value> = self.post("/v1/cancelOrder", &json!({"orderId": id.parse::<i64>()?})).await?;
+        res.into_status_result()
+    }
+
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()> {
+        let res: GmoClientResponse<Value> = self.post("/v1/cancelBulkOrder", &json!({"symbols": [symbol.to_native()]})).await?;
+        res.into_status_result()
+    }
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        let res: GmoClientResponse<GmoList<ActiveOrder>> = self.get_private("/v1/activeOrders", hashmap! {"symbol".to_owned() => symbol.to_native()}).await?;
+        Ok(res.into_result()?.list.into_iter().map(|o| OpenOrder {
+            id: o.order_id.to_string(),
+            symbol,
+            side: o.side,
+            order_type: o.execution_type,
+            price: o.price.and_then(|p| p.parse::<f64>().ok()).map(|p| FloatExp::from_f64(p, symbol.price_precision())),
+            amount: FloatExp::from_f64(o.size - o.executed_size, symbol.amount_precision()),
+            created_at: o.timestamp,
+        }).collect())
+    }
+
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        match symbol.r#type {
+            SymbolType::Perp => {
+                let res: GmoClientResponse<GmoList<OpenPosition>> = self.get_private("/v1/openPositions", hashmap! {"symbol".to_owned() => symbol.to_native()}).await?;
+                Ok(res.into_result()?.list.into_iter().map(|p| Position {
+                    symbol,
+                    pos_side: p.side.to_pos(),
+                    amount: FloatExp::from_f64(p.size, symbol.amount_precision()),
+                    price: FloatExp::from_f64(p.price, symbol.price_precision()),
+                }).collect())
+            },
+            SymbolType::Spot => {
+                let base = self.balances().await?.into_iter().find(|b| b.currency == symbol.base);
+                Ok(base.into_iter().map(|b| Position {
+                    symbol,
+                    pos_side: PosSide::Long,
+                    amount: FloatExp::from_f64(b.total, symbol.amount_precision()),
+                    price: FloatExp::new(0, symbol.price_precision()),
+                }).collect())
+            },
+        }
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        let res: GmoClientResponse<AccountAssets> = self.get_private(AccountAssetsRequest::PATH, AccountAssetsRequest {}).await?;
+        let mut ret = vec![];
+        for asset in res.into_result()? {
+            // 扱っていない通貨は無視する
+            if let Ok(currency) = Currency::from_native(&asset.symbol, Exchange::Gmo) {
+                ret.push(AssetBalance {
+                    currency,
+                    total: asset.amount.parse()?,
+                    available: asset.available.parse()?,
+                });
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64> {
+        match symbol.r#type {
+            SymbolType::Perp => {
+                let res: GmoClientResponse<AccountMargin> = self.get_private("/v1/account/margin", AccountAssetsRequest {}).await?;
+                Ok(res.into_result()?.actual_profit_loss)
+            },
+            SymbolType::Spot => {
+                Ok(self.balances().await?.into_iter().find(|b| b.currency == symbol.settlement).map(|b| b.total).unwrap_or(0.))
+            },
+        }
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<CommonTicker> {
+        let res: GmoClientResponse<Tickers> = self.get_public("/v1/ticker", hashmap! {"symbol".to_owned() => symbol.to_native()}).await?;
+        let ticker = res.into_result()?.into_iter().next().ok_or_else(|| anyhow::anyhow!("ticker is empty"))?;
+        Ok(CommonTicker {
+            last: FloatExp::from_str(ticker.last, symbol.price_precision())?,
+            bid: FloatExp::from_str(ticker.bid, symbol.price_precision())?,
+            ask: FloatExp::from_str(ticker.ask, symbol.price_precision())?,
+            volume: ticker.volume.parse()?,
+        })
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        let res: GmoClientResponse<OrderbooksSnapshot> = self.get_public("/v1/orderbooks", hashmap! {"symbol".to_owned() => symbol.to_native()}).await?;
+        let res = res.into_result()?;
+        let convert = |items: Vec<PriceSizePair>| -> Vec<(FloatExp, FloatExp)> { items.into_iter().map(|x| (FloatExp::from_f64(x.price, symbol.price_precision()), FloatExp::from_f64(x.size, symbol.amount_precision()))).collect() };
+        Ok(OrderbookSnapshot {
+            bids: convert(res.bids),
+            asks: convert(res.asks),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -154,7 +502,7 @@ pub struct WsErrResponse {
 
 impl WsErrResponse {
     pub fn is_too_many_request(&self) -> bool {
-        self.error.contains("Request too many")
+        self.error.starts_with(RATE_LIMIT_CODE)
     }
 }
 
@@ -162,6 +510,26 @@ impl WsErrResponse {
 #[serde(tag = "channel", rename_all = "camelCase")]
 pub enum WsOkResponse {
     Orderbooks(OrderbooksResult),
+    Trades(TradesResult),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TradesResult {
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub price: f64,
+    pub side: Side,
+    #[serde(deserialize_with = "deserialize_f64_from_str")]
+    pub size: f64,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(deserialize_with = "deserialize_gmo_symbol")]
+    pub symbol: Symbol,
+}
+
+impl TradesResult {
+    pub fn to_trade_record(&self) -> TradeRecord {
+        TradeRecord::new(self.symbol, self.timestamp.timestamp_millis(), self.price, self.size, self.side)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -182,6 +550,166 @@ pub struct PriceSizePair {
     pub size: f64,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PrivateWsResponse {
+    Ok(PrivateWsMessage),
+    Err(WsErrResponse),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "channel", rename_all = "camelCase")]
+pub enum PrivateWsMessage {
+    ExecutionEvents(ExecutionEventMsg),
+    OrderEvents(OrderEventMsg),
+    PositionEvents(PositionEventMsg),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionEventMsg {
+    pub order_id: i64,
+    pub execution_id: i64,
+    #[serde(deserialize_with = "deserialize_gmo_symbol")]
+    pub symbol: Symbol,
+    /// OPEN, CLOSE
+    pub settle_type: String,
+    pub side: Side,
+    pub execution_price: String,
+    pub execution_size: String,
+    pub fee: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub execution_timestamp: DateTime<Utc>,
+}
+
+impl ExecutionEventMsg {
+    pub fn to_execution(&self) -> anyhow::Result<Execution> {
+        let instrument = self.symbol.instrument()?;
+        Ok(Execution {
+            order_id: self.order_id.to_string(),
+            symbol: self.symbol,
+            side: self.side,
+            price: FloatExp::from_str(self.execution_price.clone(), instrument.price_precision)?,
+            amount: FloatExp::from_str(self.execution_size.clone(), instrument.amount_precision)?,
+            fee: self.fee.parse()?,
+            timestamp: self.execution_timestamp,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderEventMsg {
+    pub order_id: i64,
+    #[serde(deserialize_with = "deserialize_gmo_symbol")]
+    pub symbol: Symbol,
+    pub side: Side,
+    /// ORDERED, MODIFYING, CANCELLING, CANCELED, EXECUTED, EXPIRED
+    pub order_status: String,
+    /// MARKETでは無い
+    pub order_price: Option<String>,
+    pub order_size: String,
+    pub order_executed_size: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub order_timestamp: DateTime<Utc>,
+}
+
+impl OrderEventMsg {
+    pub fn to_order_event(&self) -> anyhow::Result<OrderEvent> {
+        let instrument = self.symbol.instrument()?;
+        let amount = FloatExp::from_str(self.order_size.clone(), instrument.amount_precision)?;
+        let executed = FloatExp::from_str(self.order_executed_size.clone(), instrument.amount_precision)?;
+        let status = match self.order_status.as_str() {
+            "CANCELED" => OrderStatus::Canceled,
+            "EXECUTED" => OrderStatus::Filled,
+            "EXPIRED" => OrderStatus::Expired,
+            _ if executed.is_zero() => OrderStatus::Ordered,
+            _ => OrderStatus::PartiallyFilled,
+        };
+        Ok(OrderEvent {
+            order_id: self.order_id.to_string(),
+            symbol: self.symbol,
+            side: Some(self.side),
+            status,
+            price: match &self.order_price {
+                Some(p) if !p.is_empty() => Some(FloatExp::from_str(p.clone(), instrument.price_precision)?),
+                _ => None,
+            },
+            amount,
+            executed,
+            timestamp: self.order_timestamp,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionEventMsg {
+    pub position_id: i64,
+    #[serde(deserialize_with = "deserialize_gmo_symbol")]
+    pub symbol: Symbol,
+    pub side: Side,
+    pub size: String,
+    pub price: String,
+    /// OPR: 新規, UPR: 更新, ULR: ロスカットレート更新, CPR: 決済
+    pub msg_type: String,
+}
+
+impl PositionEventMsg {
+    pub fn to_position(&self) -> anyhow::Result<Position> {
+        let instrument = self.symbol.instrument()?;
+        Ok(Position {
+            symbol: self.symbol,
+            pos_side: self.side.to_pos(),
+            amount: if self.msg_type == "CPR" {
+                FloatExp::new(0, instrument.amount_precision)
+            } else {
+                FloatExp::from_str(self.size.clone(), instrument.amount_precision)?
+            },
+            price: FloatExp::from_str(self.price.clone(), instrument.price_precision)?,
+        })
+    }
+}
+
+impl PrivateWsMessage {
+    pub fn symbol(&self) -> Symbol {
+        match self {
+            PrivateWsMessage::ExecutionEvents(x) => x.symbol,
+            PrivateWsMessage::OrderEvents(x) => x.symbol,
+            PrivateWsMessage::PositionEvents(x) => x.symbol,
+        }
+    }
+
+    pub fn to_private_event(&self) -> anyhow::Result<PrivateEvent> {
+        Ok(match self {
+            PrivateWsMessage::ExecutionEvents(x) => PrivateEvent::Execution(x.to_execution()?),
+            PrivateWsMessage::OrderEvents(x) => PrivateEvent::Order(x.to_order_event()?),
+            PrivateWsMessage::PositionEvents(x) => PrivateEvent::Position(x.to_position()?),
+        })
+    }
+}
+
+struct PrivateWsHandler {
+    tx: UnboundedSender<PrivateEvent>,
+}
+
+#[async_trait]
+impl WsHandler for PrivateWsHandler {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        let msg = msg.to_text()?;
+        match serde_json::from_str::<PrivateWsResponse>(msg)? {
+            // instruments.yamlにない銘柄は戦略で扱わないので捨てる
+            PrivateWsResponse::Ok(x) if x.symbol().instrument().is_err() => {},
+            PrivateWsResponse::Ok(x) => {
+                self.tx.unbounded_send(x.to_private_event()?)?;
+            },
+            PrivateWsResponse::Err(x) if x.is_too_many_request() => anyhow::bail!(BotError::WsTooManyRequest),
+            PrivateWsResponse::Err(x) => anyhow::bail!("Websocket error response: {}", x.error),
+        }
+        Ok(())
+    }
+}
+
 fn deserialize_gmo_symbol<'de, D>(deserializer: D) -> Result<Symbol, D::Error>
     where
         D: Deserializer<'de>,
@@ -192,12 +720,12 @@ fn deserialize_gmo_symbol<'de, D>(deserializer: D) -> Result<Symbol, D::Error>
             return Err(serde::de::Error::custom("invalid symbol"));
         }
         let (base, quote, r#type) = if cs.len() == 1 {
-            (Currency::from_str(cs[0]).map_err(serde::de::Error::custom)?,
+            (Currency::from_native(cs[0], Exchange::Gmo).map_err(serde::de::Error::custom)?,
                 Currency::JPY,
                 SymbolType::Spot)
         } else {
-            (Currency::from_str(cs[0]).map_err(serde::de::Error::custom)?,
-                Currency::from_str(cs[1]).map_err(serde::de::Error::custom)?,
+            (Currency::from_native(cs[0], Exchange::Gmo).map_err(serde::de::Error::custom)?,
+                Currency::from_native(cs[1], Exchange::Gmo).map_err(serde::de::Error::custom)?,
                 SymbolType::Perp)
         };
         Ok(Symbol::new(base, quote, r#type, Exchange::Gmo))
@@ -225,8 +753,9 @@ async fn test_gmo_client_order() {
         side: Side::Buy,
         execution_type: OrderType::Limit,
         size: "0.001".to_string(),
-        price: "2000000".to_string(),
+        price: Some("2000000".to_string()),
         time_in_force: None,
+        client_order_id: uuid::Uuid::new_v4().simple().to_string(),
     }).await.unwrap();
     println!("{:?}", res);
 }
@@ -239,4 +768,28 @@ fn test_ws_response() {
     let s = r#"{"error":"ERR-5003 Request too many."}"#;
     let parsed: WsResponse = serde_json::from_str(s).unwrap();
     assert!(matches!(parsed, WsResponse::Err(_)));
-}
\ No newline at end of file
+    let s = r#"{"channel":"trades","price":"750760","side":"BUY","size":"0.1","timestamp":"2018-03-30T12:34:56.789Z","symbol":"BTC_JPY"}"#;
+    let parsed: WsResponse = serde_json::from_str(s).unwrap();
+    assert!(matches!(parsed, WsResponse::Ok(WsOkResponse::Trades(_))));
+}
+#[test]
+fn test_private_ws_message() {
+    let s = r#"{"channel":"executionEvents","orderId":123456789,"executionId":72123911,"symbol":"BTC","settleType":"OPEN","executionType":"LIMIT","side":"BUY","executionPrice":"877404","executionSize":"0.5","positionId":123456789,"orderTimestamp":"2019-03-19T02:15:06.081Z","executionTimestamp":"2019-03-19T02:15:06.081Z","lossGain":"0","fee":"323","orderPrice":"877200","orderSize":"0.8","orderExecutedSize":"0.7","timeInForce":"FAS","msgType":"ER"}"#;
+    let PrivateWsResponse::Ok(msg) = serde_json::from_str::<PrivateWsResponse>(s).unwrap() else { panic!() };
+    let PrivateEvent::Execution(execution) = msg.to_private_event().unwrap() else { panic!() };
+    assert_eq!(execution.order_id, "123456789");
+    assert_eq!(execution.symbol.r#type, SymbolType::Spot);
+    assert_eq!(execution.price, FloatExp::new(877404, 0));
+    assert_eq!(execution.amount, FloatExp::new(50, -2));
+
+    let s = r#"{"channel":"orderEvents","orderId":123456789,"symbol":"BTC_JPY","settleType":"OPEN","executionType":"LIMIT","side":"BUY","orderStatus":"CANCELED","cancelType":"USER","orderTimestamp":"2019-03-19T02:15:06.059Z","orderPrice":"876045","orderSize":"0.8","orderExecutedSize":"0.3","losscutPrice":"0","timeInForce":"FAS","msgType":"COR"}"#;
+    let PrivateWsResponse::Ok(msg) = serde_json::from_str::<PrivateWsResponse>(s).unwrap() else { panic!() };
+    let PrivateEvent::Order(order) = msg.to_private_event().unwrap() else { panic!() };
+    assert_eq!(order.status, OrderStatus::Canceled);
+    assert_eq!(order.executed, FloatExp::new(30, -2));
+
+    let s = r#"{"channel":"positionEvents","positionId":1234567,"symbol":"BTC_JPY","side":"BUY","size":"0.22","orderdSize":"0","price":"876045","lossGain":"14","leverage":"4","losscutPrice":"766556","timestamp":"2019-03-19T02:15:06.094Z","msgType":"CPR"}"#;
+    let PrivateWsResponse::Ok(msg) = serde_json::from_str::<PrivateWsResponse>(s).unwrap() else { panic!() };
+    let PrivateEvent::Position(position) = msg.to_private_event().unwrap() else { panic!() };
+    assert!(position.amount.is_zero());
+}
@@ -7,13 +7,14 @@ use super::credentials::{CREDENTIALS};
 
 
 pub static MAILER: Lazy<Mutex<SmtpTransport>> = Lazy::new(|| {
-    let mailer = SmtpTransport::relay("smtp.gmail.com")
+    let mailer = SmtpTransport::relay(&CREDENTIALS.mail.host)
         .unwrap()
         .credentials(Credentials::new(CREDENTIALS.mail.user.clone(), CREDENTIALS.mail.password.clone()))
         .build();
     Mutex::new(mailer)
 });
 
+/// 同期で送る。botからは`utils::alert`を使う
 pub fn send_mail(subject: String, body: String) -> Result<()> {
     MAILER.lock().send(
         &Message::builder()
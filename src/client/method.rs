@@ -1,9 +1,16 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, time::Duration};
 
 use hyper::{header::CONTENT_TYPE, http::HeaderName, HeaderMap, StatusCode};
 use log::info;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use reqwest::{self, Url, Response};
+use serde::Deserialize;
 use serde_json::Value;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{symbol::Exchange, utils::{strategy_utils::Backoff, metrics}, error_types::BotError, config::read_config_section};
 
 
 pub async fn get<S: GetRequest, T: serde::de::DeserializeOwned>(
@@ -15,13 +22,9 @@ pub async fn get<S: GetRequest, T: serde::de::DeserializeOwned>(
 ) -> anyhow::Result<(StatusCode, T)> {
     let url_str = format!("{}{}", endpoint, path);
     let url = Url::parse_with_params(&url_str, query.to_query())?;
-    let res = client.get(url)
-        .headers(header)
-        .send()
-        .await?;
-    let res = error_for_server(res)?;
+    let res = send(client, client.get(url).headers(header)).await?;
     let status = res.status();
-    let body = res.json().await?;
+    let body = res.json().await.map_err(classify_reqwest_error)?;
     Ok((status, body))
 }
 
@@ -34,14 +37,24 @@ pub async fn post<S: serde::Serialize, T: serde::de::DeserializeOwned>(
 ) -> anyhow::Result<(StatusCode, T)> {
     let url_str = format!("{}{}", endpoint, path);
     let url = Url::parse(&url_str)?;
-    let res = client.post(url)
-        .headers(header)
-        .json(body)
-        .send()
-        .await?;
-    let res = error_for_server(res)?;
+    let res = send(client, client.post(url).headers(header).json(body)).await?;
     let status = res.status();
-    let body = res.json().await?;
+    let body = res.json().await.map_err(classify_reqwest_error)?;
+    Ok((status, body))
+}
+
+pub async fn put<S: serde::Serialize, T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    endpoint: &str,
+    path: &str,
+    header: HeaderMap,
+    body: &S,
+) -> anyhow::Result<(StatusCode, T)> {
+    let url_str = format!("{}{}", endpoint, path);
+    let url = Url::parse(&url_str)?;
+    let res = send(client, client.put(url).headers(header).json(body)).await?;
+    let status = res.status();
+    let body = res.json().await.map_err(classify_reqwest_error)?;
     Ok((status, body))
 }
 
@@ -54,12 +67,7 @@ pub async fn post_no_parse<S: serde::Serialize>(
 ) -> anyhow::Result<StatusCode> {
     let url_str = format!("{}{}", endpoint, path);
     let url = Url::parse(&url_str)?;
-    let res = client.post(url)
-        .headers(header)
-        .json(body)
-        .send()
-        .await?;
-    let res = error_for_server(res)?;
+    let res = send(client, client.post(url).headers(header).json(body)).await?;
     let status = res.status();
     Ok(status)
 }
@@ -67,16 +75,51 @@ pub async fn post_no_parse<S: serde::Serialize>(
 pub async fn delete<T: serde::de::DeserializeOwned>(client: &reqwest::Client, endpoint: &str, path: &str, header: HeaderMap) -> anyhow::Result<(StatusCode, T)> {
     let url_str = format!("{}{}", endpoint, path);
     let url = Url::parse(&url_str)?;
-    let res = client.delete(url)
-        .headers(header)
-        .send()
-        .await?;
-    let res = error_for_server(res)?;
+    let res = send(client, client.delete(url).headers(header)).await?;
     let status = res.status();
-    let body = res.json().await?;
+    let body = res.json().await.map_err(classify_reqwest_error)?;
     Ok((status, body))
 }
 
+/// パラメータをすべてクエリ文字列で送る（binance）。queryはエンコード済み
+pub async fn request_with_query<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    endpoint: &str,
+    path: &str,
+    header: HeaderMap,
+    query: &str,
+) -> anyhow::Result<(StatusCode, T)> {
+    let mut url = Url::parse(&format!("{}{}", endpoint, path))?;
+    if !query.is_empty() {
+        url.set_query(Some(query));
+    }
+    let res = send(client, client.request(method, url).headers(header)).await?;
+    let status = res.status();
+    let body = res.json().await.map_err(classify_reqwest_error)?;
+    Ok((status, body))
+}
+
+/// 送ってエンドポイントごとのレイテンシとステータスをmetricsに記録する
+async fn send(client: &reqwest::Client, builder: reqwest::RequestBuilder) -> anyhow::Result<Response> {
+    let request = builder.build().map_err(classify_reqwest_error)?;
+    let host = request.url().host_str().unwrap_or_default().to_string();
+    let method = request.method().to_string();
+    let path = metrics::normalize_path(request.url().path());
+    let started = Instant::now();
+    let res = client.execute(request).await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    let labels = [("host", host.as_str()), ("method", method.as_str()), ("path", path.as_str())];
+    metrics::inc_counter("bot_rest_requests_total", &[labels[0], labels[1], labels[2], ("status", status.as_str())], 1.);
+    if res.is_ok() {
+        metrics::observe("bot_rest_request_duration_seconds", &labels, started.elapsed().as_secs_f64());
+    }
+    error_for_server(res.map_err(classify_reqwest_error)?)
+}
+
 pub fn make_header(auth: HashMap<String, String>) -> reqwest::header::HeaderMap {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
@@ -86,15 +129,33 @@ pub fn make_header(auth: HashMap<String, String>) -> reqwest::header::HeaderMap
     headers
 }
 
-/// サーバーエラーのときだけErrを投げる
-fn error_for_server(response: Response) -> reqwest::Result<Response> {
-    if response.status().is_server_error() {
-        response.error_for_status()
+/// 5xx, 429, 401, 403のときだけErrを投げる。ほかのエラーは取引所ごとにbodyで判断する
+fn error_for_server(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_server_error() || [StatusCode::TOO_MANY_REQUESTS, StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN].contains(&status) {
+        response.error_for_status().map_err(classify_reqwest_error)
     } else {
         Ok(response)
     }
 }
 
+/// reqwestのエラーをBotErrorで包む。元のreqwest::Errorもdowncastできる
+pub fn classify_reqwest_error(e: reqwest::Error) -> anyhow::Error {
+    let bot_error = match e.status() {
+        Some(status) if status.is_server_error() => Some(BotError::ServerError { status, message: e.to_string() }),
+        Some(status) if status == StatusCode::TOO_MANY_REQUESTS => Some(BotError::RateLimited),
+        Some(status) if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN => Some(BotError::AuthFailed),
+        Some(_) => None,
+        None if e.is_connect() => Some(BotError::Network { connected: false, message: e.to_string() }),
+        None if e.is_timeout() || e.is_request() || e.is_body() => Some(BotError::Network { connected: true, message: e.to_string() }),
+        None => None,
+    };
+    match bot_error {
+        Some(bot_error) => anyhow::Error::new(e).context(bot_error),
+        None => e.into(),
+    }
+}
+
 pub trait GetRequest {
     fn to_query(&self) -> HashMap<String, String>;
     fn to_json(&self) -> Value {
@@ -120,3 +181,377 @@ pub trait HasPath {
     const PATH: &'static str;
     type Response: serde::de::DeserializeOwned;
 }
+
+/// config.yamlの`retry`。取得とキャンセルだけやり直し、発注はやり直さない
+///
+/// ```yaml
+/// retry:
+///   max_retries: 3
+///   base_delay_ms: 200
+///   max_delay_ms: 5000
+///   timeout_ms: 10000
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// 応答がこれより遅ければタイムアウトにする
+    pub timeout_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: 3, base_delay_ms: 200, max_delay_ms: 5000, timeout_ms: 10000 }
+    }
+}
+
+impl RetryPolicy {
+    /// attempt回目(0始まり)のやり直しまでの待ち。Backoffの半分から全部までのランダムにする
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let backoff = Backoff { initial: Duration::from_millis(self.base_delay_ms), max: Duration::from_millis(self.max_delay_ms) };
+        let cap = backoff.delay(attempt as u32).as_millis() as u64;
+        let jitter = (Uuid::new_v4().as_u128() % (cap / 2 + 1) as u128) as u64;
+        Duration::from_millis(cap - cap / 2 + jitter)
+    }
+}
+
+static RETRY_POLICY: Lazy<RetryPolicy> = Lazy::new(|| read_config_section("retry"));
+
+pub fn retry_policy() -> RetryPolicy {
+    *RETRY_POLICY
+}
+
+/// 各取引所のclientで使う。retryのtimeout_msでタイムアウトする
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_millis(retry_policy().timeout_ms))
+        .build()
+        .unwrap()
+}
+
+/// リクエストの失敗の種類。retryと発注の確認に使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFailure {
+    /// 接続できなかった。サーバーには届いていない
+    Connect,
+    /// 接続後にタイムアウト・切断した。サーバーで処理されたかわからない
+    Timeout,
+    /// 5xx。処理されたかわからない
+    Server,
+    /// レートリミット・nonceエラー。処理されずに断られた
+    Refused,
+    /// 4xxや取引所のエラー。やり直しても同じ
+    Rejected,
+}
+
+impl RequestFailure {
+    pub fn of(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<BotError>() {
+            Some(BotError::Network { connected: false, .. }) => RequestFailure::Connect,
+            Some(BotError::Network { connected: true, .. }) => RequestFailure::Timeout,
+            Some(BotError::ServerError { .. }) => RequestFailure::Server,
+            Some(BotError::RateLimited | BotError::NonceError) => RequestFailure::Refused,
+            _ => RequestFailure::Rejected,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        *self != RequestFailure::Rejected
+    }
+
+    /// 発注が通ったかどうか、取引所に確かめる必要がある
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, RequestFailure::Timeout | RequestFailure::Server)
+    }
+}
+
+/// 冪等なリクエスト（取得・キャンセル）をretry_policyでやり直す。Rejectedはそのまま返す
+pub async fn with_retry<T, F, Fut>(f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    retry_if(true, f).await
+}
+
+/// retryがfalseなら1回だけ送る
+pub async fn retry_if<T, F, Fut>(retry: bool, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let policy = retry_policy();
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(e) if retry && attempt < policy.max_retries && RequestFailure::of(&e).is_retryable() => {
+                let wait = policy.backoff(attempt);
+                info!("retry {}/{} after {:?}: {:?}", attempt + 1, policy.max_retries, wait, e);
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            },
+            res => return res,
+        }
+    }
+}
+
+/// レートリミットの単位。取引所ごとにpublic・private（取得）・order（発注・キャンセル）で別のバケットを持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointClass {
+    Public,
+    Private,
+    Order,
+}
+
+/// キャンセルは待っている間、同じバケットの新規注文より先に通す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    Cancel,
+}
+
+impl Priority {
+    /// pathにcancelを含むものはキャンセル扱い
+    pub fn from_path(path: &str) -> Self {
+        if path.to_lowercase().contains("cancel") {
+            Priority::Cancel
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+/// capacityまで貯められ、1秒にper_sec個ずつ補充されるtoken bucket
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimit {
+    pub capacity: f64,
+    pub per_sec: f64,
+}
+
+impl RateLimit {
+    const fn new(capacity: f64, per_sec: f64) -> Self {
+        Self { capacity, per_sec }
+    }
+}
+
+/// 各取引所の公表値より少し控えめにしている
+pub fn default_rate_limit(exc: Exchange, class: EndpointClass) -> RateLimit {
+    use EndpointClass::*;
+    match (exc, class) {
+        // 5分で500回（IP・APIキーごと）、発注系は5分で300回
+        // 3つのバケットは同じ500回に数えられるので、合計で5分に450回+capacity分に収める
+        (Exchange::Bitflyer, Public) => RateLimit::new(5., 0.4),
+        (Exchange::Bitflyer, Private) => RateLimit::new(5., 0.6),
+        (Exchange::Bitflyer, Order) => RateLimit::new(3., 0.5),
+        // 1秒6回。バーストしても1秒で6回を超えないようにする
+        (Exchange::Gmo, _) => RateLimit::new(2., 4.),
+        (Exchange::Coincheck, Public) => RateLimit::new(5., 5.),
+        (Exchange::Coincheck, _) => RateLimit::new(2., 4.),
+        // 1分6000weight
+        (Exchange::Binance, Public) => RateLimit::new(20., 20.),
+        (Exchange::Binance, Private) => RateLimit::new(10., 10.),
+        (Exchange::Binance, Order) => RateLimit::new(5., 10.),
+    }
+}
+
+/// config.yamlの`rate_limits`。指定したものだけ上書きする
+///
+/// ```yaml
+/// rate_limits:
+///   bitflyer:
+///     order: {capacity: 3, per_sec: 0.5}
+/// ```
+static RATE_LIMIT_OVERRIDES: Lazy<HashMap<Exchange, HashMap<EndpointClass, RateLimit>>> = Lazy::new(|| read_config_section("rate_limits"));
+
+pub fn rate_limit(exc: Exchange, class: EndpointClass) -> RateLimit {
+    RATE_LIMIT_OVERRIDES.get(&exc)
+        .and_then(|o| o.get(&class))
+        .copied()
+        .unwrap_or_else(|| default_rate_limit(exc, class))
+}
+
+const EXCHANGES: [Exchange; 4] = [Exchange::Gmo, Exchange::Bitflyer, Exchange::Coincheck, Exchange::Binance];
+const CLASSES: [EndpointClass; 3] = [EndpointClass::Public, EndpointClass::Private, EndpointClass::Order];
+
+static LIMITERS: Lazy<HashMap<(Exchange, EndpointClass), TokenBucket>> = Lazy::new(|| {
+    EXCHANGES.iter()
+        .flat_map(|&exc| CLASSES.iter().map(move |&class| ((exc, class), TokenBucket::new(rate_limit(exc, class)))))
+        .collect()
+});
+
+/// これ以上待たされたらログに出す
+const SLOW_QUEUE_DELAY: Duration = Duration::from_secs(1);
+/// キャンセル待ちで止めているときに見直す間隔
+const MIN_WAIT: Duration = Duration::from_millis(10);
+
+/// リクエストの前に呼ぶ。トークンが取れるまで待ち、待った時間を返す
+pub async fn acquire(exc: Exchange, class: EndpointClass, priority: Priority) -> Duration {
+    let delay = LIMITERS[&(exc, class)].acquire(priority).await;
+    if delay > SLOW_QUEUE_DELAY {
+        info!("rate limit queue delay {:?} {:?} {:?}: {:?}", exc, class, priority, delay);
+    }
+    delay
+}
+
+/// 起動からの待ち時間の集計
+pub fn queue_delay_stats() -> Vec<(Exchange, EndpointClass, QueueDelayStats)> {
+    EXCHANGES.iter()
+        .flat_map(|&exc| CLASSES.iter().map(move |&class| (exc, class, LIMITERS[&(exc, class)].stats())))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueDelayStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl QueueDelayStats {
+    fn record(&mut self, delay: Duration) {
+        self.count += 1;
+        self.total += delay;
+        self.max = self.max.max(delay);
+    }
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+    waiting_cancels: usize,
+    stats: QueueDelayStats,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+}
+
+/// 待っているキャンセルの数を、acquireがキャンセルされても戻すため
+struct CancelWaiting<'a>(&'a Mutex<BucketState>);
+
+impl Drop for CancelWaiting<'_> {
+    fn drop(&mut self) {
+        self.0.lock().waiting_cancels -= 1;
+    }
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        let state = BucketState { tokens: limit.capacity, last: Instant::now(), waiting_cancels: 0, stats: QueueDelayStats::default() };
+        Self { limit, state: Mutex::new(state) }
+    }
+
+    pub async fn acquire(&self, priority: Priority) -> Duration {
+        let start = Instant::now();
+        let _waiting = (priority == Priority::Cancel).then(|| {
+            self.state.lock().waiting_cancels += 1;
+            CancelWaiting(&self.state)
+        });
+        while let Err(wait) = self.try_take(priority, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+        let delay = start.elapsed();
+        self.state.lock().stats.record(delay);
+        delay
+    }
+
+    /// 取れなければ次に試すまでの時間を返す
+    fn try_take(&self, priority: Priority, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.last = now;
+        state.tokens = (state.tokens + elapsed * self.limit.per_sec).min(self.limit.capacity);
+        if priority == Priority::Normal && state.waiting_cancels > 0 {
+            return Err(MIN_WAIT);
+        }
+        if state.tokens >= 1. {
+            state.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1. - state.tokens) / self.limit.per_sec).max(MIN_WAIT))
+        }
+    }
+
+    pub fn stats(&self) -> QueueDelayStats {
+        self.state.lock().stats
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let bucket = TokenBucket::new(RateLimit::new(2., 4.));
+    let now = bucket.state.lock().last;
+    assert!(bucket.try_take(Priority::Normal, now).is_ok());
+    assert!(bucket.try_take(Priority::Normal, now).is_ok());
+    // 空になったら1/4秒待つ
+    assert_eq!(bucket.try_take(Priority::Normal, now), Err(Duration::from_millis(250)));
+    assert!(bucket.try_take(Priority::Normal, now + Duration::from_millis(250)).is_ok());
+    // capacityを超えては貯まらない
+    let later = now + Duration::from_secs(10);
+    assert!(bucket.try_take(Priority::Normal, later).is_ok());
+    assert!(bucket.try_take(Priority::Normal, later).is_ok());
+    assert!(bucket.try_take(Priority::Normal, later).is_err());
+
+    assert_eq!(Priority::from_path("/v1/me/cancelchildorder"), Priority::Cancel);
+    assert_eq!(Priority::from_path("/v1/cancelOrder"), Priority::Cancel);
+    assert_eq!(Priority::from_path("/v1/order"), Priority::Normal);
+}
+
+#[test]
+fn test_retry_backoff() {
+    let policy = RetryPolicy { max_retries: 3, base_delay_ms: 100, max_delay_ms: 1000, timeout_ms: 1000 };
+    for _ in 0..100 {
+        let wait = policy.backoff(0);
+        assert!(wait >= Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        let wait = policy.backoff(2);
+        assert!(wait >= Duration::from_millis(200) && wait <= Duration::from_millis(400));
+        let wait = policy.backoff(10);
+        assert!(wait >= Duration::from_millis(500) && wait <= Duration::from_millis(1000));
+    }
+}
+
+#[tokio::test]
+async fn test_retry_if() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let count = AtomicUsize::new(0);
+    // 取引所のエラーはやり直さない
+    let res: anyhow::Result<()> = with_retry(|| async { count.fetch_add(1, Ordering::Relaxed); anyhow::bail!("rejected") }).await;
+    assert!(res.is_err());
+    assert_eq!(count.load(Ordering::Relaxed), 1);
+    // つながらなければやり直す
+    let client = http_client();
+    let count = AtomicUsize::new(0);
+    let res = with_retry(|| async {
+        count.fetch_add(1, Ordering::Relaxed);
+        client.get("http://127.0.0.1:1/").send().await.map_err(classify_reqwest_error)
+    }).await;
+    assert_eq!(RequestFailure::of(&res.unwrap_err()), RequestFailure::Connect);
+    assert_eq!(count.load(Ordering::Relaxed), retry_policy().max_retries + 1);
+}
+
+#[tokio::test]
+async fn test_token_bucket_cancel_priority() {
+    use std::sync::Arc;
+    let bucket = Arc::new(TokenBucket::new(RateLimit::new(1., 10.)));
+    let done = Arc::new(Mutex::new(vec![]));
+    bucket.acquire(Priority::Normal).await;
+    // 先に待ち始めた新規注文より、後から来たキャンセルを先に通す
+    let (b, d) = (bucket.clone(), done.clone());
+    let order = tokio::spawn(async move { b.acquire(Priority::Normal).await; d.lock().push(Priority::Normal) });
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let (b, d) = (bucket.clone(), done.clone());
+    let cancel = tokio::spawn(async move { b.acquire(Priority::Cancel).await; d.lock().push(Priority::Cancel) });
+    order.await.unwrap();
+    cancel.await.unwrap();
+    assert_eq!(*done.lock(), vec![Priority::Cancel, Priority::Normal]);
+    assert_eq!(bucket.state.lock().waiting_cancels, 0);
+    let stats = bucket.stats();
+    assert_eq!(stats.count, 3);
+    assert!(stats.max >= Duration::from_millis(150));
+}
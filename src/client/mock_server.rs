@@ -0,0 +1,405 @@
+//! clientのテスト用のmock server
+//! GMO, bitFlyer, Coincheck, Binanceのレスポンス形式（エラー、メンテナンスを含む）を返し、受け取ったリクエストを記録する
+
+use std::{collections::{HashMap, VecDeque}, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration as StdDuration};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode, header::CONTENT_TYPE, service::{make_service_fn, service_fn}};
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use tokio::{net::TcpListener, select, spawn, sync::broadcast};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn gmo_ok(data: Value) -> Self {
+        Self::json(200, json!({"status": 0, "data": data, "responsetime": "2023-07-01T00:00:00.000Z"}))
+    }
+
+    /// cancelOrderなどdataのないレスポンス
+    pub fn gmo_status_ok() -> Self {
+        Self::json(200, json!({"status": 0, "responsetime": "2023-07-01T00:00:00.000Z"}))
+    }
+
+    pub fn gmo_error(code: &str, message: &str) -> Self {
+        Self::json(200, json!({
+            "status": 1,
+            "messages": [{"message_code": code, "message_string": message}],
+            "responsetime": "2023-07-01T00:00:00.000Z",
+        }))
+    }
+
+    pub fn gmo_maintenance() -> Self {
+        Self::json(200, json!({
+            "status": 5,
+            "messages": [{"message_code": "ERR-5201", "message_string": "MAINTENANCE. Please wait for a while"}],
+            "responsetime": "2023-07-01T00:00:00.000Z",
+        }))
+    }
+
+    pub fn bitflyer_error(status: u16, code: i64, message: &str) -> Self {
+        Self::json(status, json!({"status": code, "error_message": message, "data": null}))
+    }
+
+    pub fn bitflyer_maintenance() -> Self {
+        Self::bitflyer_error(400, -208, "Market state is closed.")
+    }
+
+    pub fn binance_error(status: u16, code: i64, message: &str) -> Self {
+        Self::json(status, json!({"code": code, "msg": message}))
+    }
+
+    /// RestErrResponseの形式
+    pub fn coincheck_error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({"success": false, "error": message}))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    /// 最後の1件は繰り返し返す
+    routes: HashMap<(Method, String), VecDeque<MockResponse>>,
+    requests: Vec<RecordedRequest>,
+    ws_received: Vec<String>,
+}
+
+/// 127.0.0.1の空きポートでHTTPとwebsocketを待ち受ける。dropしてもtokioのruntimeが終わるまで動き続ける
+pub struct MockServer {
+    addr: SocketAddr,
+    ws_addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    ws_tx: broadcast::Sender<Message>,
+}
+
+impl MockServer {
+    pub async fn start() -> anyhow::Result<Self> {
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let http_state = state.clone();
+        let make_svc = make_service_fn(move |_| {
+            let state = http_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle_http(state.clone(), req))) }
+        });
+        spawn(Server::from_tcp(listener)?.serve(make_svc));
+
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let ws_addr = ws_listener.local_addr()?;
+        let (ws_tx, _) = broadcast::channel(1024);
+        let tx = ws_tx.clone();
+        let ws_state = state.clone();
+        spawn(async move {
+            while let Ok((stream, _)) = ws_listener.accept().await {
+                let rx = tx.subscribe();
+                let state = ws_state.clone();
+                spawn(async move {
+                    if let Ok(ws) = accept_async(stream).await {
+                        handle_ws(state, ws, rx).await;
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, ws_addr, state, ws_tx })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}/", self.ws_addr)
+    }
+
+    /// 同じmethod, pathに複数登録すると順に返す
+    pub fn mock(&self, method: Method, path: &str, res: MockResponse) {
+        self.state.lock().routes.entry((method, path.to_string())).or_default().push_back(res);
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().requests.clone()
+    }
+
+    /// 接続中のすべてのwebsocketに送る
+    pub fn ws_send(&self, msg: Value) {
+        let _ = self.ws_tx.send(Message::Text(msg.to_string()));
+    }
+
+    /// 接続中のすべてのwebsocketをサーバー側から閉じる
+    pub fn ws_disconnect(&self) {
+        let _ = self.ws_tx.send(Message::Close(None));
+    }
+
+    pub fn ws_received(&self) -> Vec<String> {
+        self.state.lock().ws_received.clone()
+    }
+
+    /// websocketでn件受け取るまで待つ
+    pub async fn wait_ws_received(&self, n: usize) -> anyhow::Result<Vec<String>> {
+        for _ in 0..100 {
+            let received = self.ws_received();
+            if received.len() >= n {
+                return Ok(received);
+            }
+            tokio::time::sleep(StdDuration::from_millis(20)).await;
+        }
+        anyhow::bail!("mock server did not receive {} ws messages", n);
+    }
+}
+
+async fn handle_http(state: Arc<Mutex<MockState>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await.map(|b| String::from_utf8_lossy(&b).to_string()).unwrap_or_default();
+    let path = parts.uri.path().to_string();
+    let res = {
+        let mut state = state.lock();
+        state.requests.push(RecordedRequest {
+            method: parts.method.clone(),
+            path: path.clone(),
+            query: parts.uri.query().map(|q| q.to_string()),
+            headers: parts.headers,
+            body,
+        });
+        match state.routes.get_mut(&(parts.method, path)) {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some(queue) => queue[0].clone(),
+            None => MockResponse::json(404, json!({"error": "not mocked"})),
+        }
+    };
+    Ok(Response::builder()
+        .status(res.status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(res.body))
+        .unwrap())
+}
+
+async fn handle_ws(state: Arc<Mutex<MockState>>, ws: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, mut rx: broadcast::Receiver<Message>) {
+    let (mut write, mut read) = ws.split();
+    loop {
+        select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    let close = msg.is_close();
+                    if write.send(msg).await.is_err() || close { break }
+                },
+                Err(_) => break,
+            },
+            msg = read.next() => match msg {
+                Some(Ok(Message::Text(text))) => state.lock().ws_received.push(text),
+                Some(Ok(Message::Ping(payload))) => if write.send(Message::Pong(payload)).await.is_err() { break },
+                Some(Ok(_)) => {},
+                _ => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_credentials() -> super::credentials::ApiCredentials {
+    super::credentials::ApiCredentials { api_key: "key".to_string(), api_secret: "secret".to_string() }
+}
+
+#[cfg(test)]
+fn hmac_hex(message: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"secret");
+    hex::encode(ring::hmac::sign(&key, message.as_bytes()).as_ref())
+}
+
+#[tokio::test]
+async fn test_mock_gmo() {
+    use super::{gmo::GmoClient, exchange::{ExchangeClient, NewOrder}};
+    use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::Side, data_structure::float_exp::FloatExp, error_types::BotError};
+
+    let server = MockServer::start().await.unwrap();
+    let client = GmoClient::new(Some(test_credentials()))
+        .with_endpoints(&format!("{}/public", server.url()), &format!("{}/private", server.url()));
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+
+    server.mock(Method::GET, "/public/v1/ticker", MockResponse::gmo_ok(json!([{"ask": "4000001", "bid": "3999999", "high": "4100000", "last": "4000000", "low": "3900000", "symbol": "BTC_JPY", "timestamp": "2023-07-01T00:00:00.000Z", "volume": "123.45"}])));
+    let ticker = client.ticker(symbol).await.unwrap();
+    assert_eq!(ticker.last, FloatExp::new(4000000, 0));
+    assert!((ticker.volume - 123.45).abs() < 1e-9);
+
+    server.mock(Method::POST, "/private/v1/order", MockResponse::gmo_ok(json!("12345")));
+    server.mock(Method::POST, "/private/v1/order", MockResponse::gmo_error("ERR-201", "Insufficient funds."));
+    server.mock(Method::POST, "/private/v1/order", MockResponse::gmo_maintenance());
+    let order = NewOrder::limit(symbol, Side::Buy, FloatExp::new(4000000, 0), FloatExp::new(1, -2)).post_only();
+    assert_eq!(client.place_order(&order).await.unwrap(), "12345");
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<BotError>(), Some(BotError::InsufficientFunds)));
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<BotError>(), Some(BotError::Maintenance)));
+
+    // 署名はtimestamp + method + path(/v1以降) + body
+    let req = server.requests().into_iter().find(|r| r.path == "/private/v1/order").unwrap();
+    let timestamp = req.headers["API-TIMESTAMP"].to_str().unwrap();
+    assert_eq!(req.headers["API-KEY"], "key");
+    assert_eq!(req.headers["API-SIGN"].to_str().unwrap(), hmac_hex(&format!("{}POST/v1/order{}", timestamp, req.body)));
+    assert_eq!(serde_json::from_str::<Value>(&req.body).unwrap()["timeInForce"], "SOK");
+    assert!(serde_json::from_str::<Value>(&req.body).unwrap()["clientOrderId"].is_string());
+}
+
+/// 結果がわからないときはclientOrderIdで照会して、一致した注文だけを採用する
+#[tokio::test]
+async fn test_mock_gmo_unknown_order() {
+    use super::{gmo::GmoClient, exchange::{ExchangeClient, NewOrder}};
+    use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::Side, data_structure::float_exp::FloatExp};
+
+    let server = MockServer::start().await.unwrap();
+    let client = GmoClient::new(Some(test_credentials()))
+        .with_endpoints(&format!("{}/public", server.url()), &format!("{}/private", server.url()));
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+    let active = |client_order_id: &str| json!({"list": [{"orderId": 777, "rootOrderId": 777, "symbol": "BTC_JPY", "side": "BUY", "orderType": "NORMAL", "executionType": "LIMIT", "settleType": "OPEN", "size": "0.01", "executedSize": "0", "price": "4000000", "losscutPrice": "0", "status": "ORDERED", "timeInForce": "SOK", "timestamp": "2023-07-01T00:00:00.000Z", "clientOrderId": client_order_id}]});
+    let order = NewOrder::limit(symbol, Side::Buy, FloatExp::new(4000000, 0), FloatExp::new(1, -2)).with_client_order_id("cid1");
+    server.mock(Method::POST, "/private/v1/order", MockResponse::json(503, json!({})));
+    server.mock(Method::GET, "/private/v1/activeOrders", MockResponse::gmo_ok(active("cid1")));
+    server.mock(Method::GET, "/private/v1/activeOrders", MockResponse::gmo_ok(active("other")));
+    server.mock(Method::GET, "/private/v1/latestExecutions", MockResponse::gmo_ok(json!({"list": []})));
+    assert_eq!(client.place_order(&order).await.unwrap(), "777");
+    assert!(client.place_order(&order).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mock_bitflyer() {
+    use super::{bitflyer::BitflyerClient, exchange::{ExchangeClient, NewOrder}};
+    use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::Side, data_structure::float_exp::FloatExp, error_types::BotError};
+
+    let server = MockServer::start().await.unwrap();
+    let client = BitflyerClient::new(Some(test_credentials())).with_endpoint(&server.url());
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let order = NewOrder::limit(symbol, Side::Sell, FloatExp::new(4000000, 0), FloatExp::new(1, -2));
+
+    server.mock(Method::POST, "/v1/me/sendchildorder", MockResponse::json(200, json!({"child_order_acceptance_id": "JRF20230701-000000-000001"})));
+    server.mock(Method::POST, "/v1/me/sendchildorder", MockResponse::bitflyer_error(400, -205, "Margin amount is insufficient for this order."));
+    server.mock(Method::POST, "/v1/me/sendchildorder", MockResponse::bitflyer_maintenance());
+    assert_eq!(client.place_order(&order).await.unwrap(), "JRF20230701-000000-000001");
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<BotError>(), Some(BotError::InsufficientFunds)));
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<BotError>(), Some(BotError::Maintenance)));
+
+    let req = &server.requests()[0];
+    let timestamp = req.headers["ACCESS-TIMESTAMP"].to_str().unwrap();
+    assert_eq!(req.headers["ACCESS-SIGN"].to_str().unwrap(), hmac_hex(&format!("{}POST/v1/me/sendchildorder{}", timestamp, req.body)));
+
+    // 逆指値はparent orderなのでcancelparentorderで取り消す
+    server.mock(Method::POST, "/v1/me/sendparentorder", MockResponse::json(200, json!({"parent_order_acceptance_id": "JRF20230701-000000-000002"})));
+    server.mock(Method::POST, "/v1/me/cancelparentorder", MockResponse::json(200, json!(null)));
+    server.mock(Method::POST, "/v1/me/cancelchildorder", MockResponse::json(200, json!(null)));
+    let stop = NewOrder::stop(symbol, Side::Sell, FloatExp::new(3800000, 0), FloatExp::new(1, -2));
+    let parent_id = client.place_order(&stop).await.unwrap();
+    client.cancel_order(symbol, &parent_id).await.unwrap();
+    client.cancel_order(symbol, &"JRF20230701-000000-000001".to_string()).await.unwrap();
+    let paths = server.requests().iter().map(|r| r.path.clone()).collect::<Vec<_>>();
+    assert_eq!(paths[paths.len() - 2..], ["/v1/me/cancelparentorder", "/v1/me/cancelchildorder"]);
+}
+
+#[tokio::test]
+async fn test_mock_coincheck() {
+    use super::{coincheck::CoincheckClient, exchange::{ExchangeClient, NewOrder}};
+    use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::Side, data_structure::float_exp::FloatExp};
+
+    let server = MockServer::start().await.unwrap();
+    let client = CoincheckClient::new(Some(test_credentials())).with_endpoint(&server.url());
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Spot, Exchange::Coincheck);
+    let order = NewOrder::limit(symbol, Side::Buy, FloatExp::new(4000000, 0), FloatExp::new(1, -2));
+
+    let ok = json!({"success": true, "id": 12345, "rate": "4000000.0", "amount": "0.01", "order_type": "buy", "time_in_force": "good_til_cancelled", "stop_loss_rate": null, "pair": "btc_jpy", "created_at": "2023-07-01T00:00:00.000Z"});
+    server.mock(Method::POST, "/api/exchange/orders", MockResponse::json(200, ok.clone()));
+    server.mock(Method::POST, "/api/exchange/orders", MockResponse::coincheck_error(400, "Amount Amount can't be blank"));
+    server.mock(Method::POST, "/api/exchange/orders", MockResponse::json(200, ok));
+    assert_eq!(client.place_order(&order).await.unwrap(), "12345");
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(err.to_string().contains("can't be blank"));
+    client.place_order(&order).await.unwrap();
+
+    // nonceは単調増加し、署名には接続先のURLを含む
+    let reqs = server.requests();
+    let nonces = reqs.iter().map(|r| r.headers["ACCESS-NONCE"].to_str().unwrap().parse::<i64>().unwrap()).collect::<Vec<_>>();
+    assert!(nonces.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(
+        reqs[0].headers["ACCESS-SIGNATURE"].to_str().unwrap(),
+        hmac_hex(&format!("{}{}/api/exchange/orders{}", nonces[0], server.url(), reqs[0].body))
+    );
+}
+
+#[tokio::test]
+async fn test_mock_binance() {
+    use super::{binance::BinanceClient, exchange::{ExchangeClient, NewOrder}};
+    use crate::{symbol::{Symbol, Currency, SymbolType, Exchange}, order_types::Side, data_structure::float_exp::FloatExp, error_types::BotError};
+
+    let server = MockServer::start().await.unwrap();
+    let client = BinanceClient::new(Some(test_credentials())).with_endpoint(&server.url());
+    let symbol = Symbol::new(Currency::BTC, Currency::USDT, SymbolType::Spot, Exchange::Binance);
+    let order = NewOrder::limit(symbol, Side::Buy, FloatExp::new(2900001, -2), FloatExp::new(150, -5)).post_only();
+
+    // timestampのずれはサーバー時刻に合わせて1回だけやり直す
+    server.mock(Method::POST, "/api/v3/order", MockResponse::binance_error(400, -1021, "Timestamp for this request is outside of the recvWindow."));
+    server.mock(Method::POST, "/api/v3/order", MockResponse::json(200, json!({"symbol": "BTCUSDT", "orderId": 28, "orderListId": -1, "clientOrderId": "6gCrw2kRUAF9CvJDGP16IP", "transactTime": 1507725176595i64})));
+    server.mock(Method::POST, "/api/v3/order", MockResponse::binance_error(400, -2010, "Account has insufficient balance for requested action."));
+    server.mock(Method::GET, "/api/v3/time", MockResponse::json(200, json!({"serverTime": chrono::Utc::now().timestamp_millis()})));
+    assert_eq!(client.place_order(&order).await.unwrap(), "28");
+    let err = client.place_order(&order).await.unwrap_err();
+    assert!(matches!(err.downcast_ref::<BotError>(), Some(BotError::InsufficientFunds)));
+
+    // 注文がなくてもcancel_all_ordersは成功する
+    server.mock(Method::DELETE, "/api/v3/openOrders", MockResponse::binance_error(400, -2011, "Unknown order sent."));
+    client.cancel_all_orders(symbol).await.unwrap();
+
+    server.mock(Method::GET, "/api/v3/account", MockResponse::json(200, json!({"balances": [{"asset": "BTC", "free": "0.1", "locked": "0.05"}, {"asset": "USDT", "free": "1000", "locked": "0"}]})));
+    let balances = client.balances().await.unwrap();
+    assert_eq!(balances.iter().find(|b| b.currency == Currency::BTC).unwrap().total, 0.1 + 0.05);
+    assert_eq!(client.collateral(symbol).await.unwrap(), 1000.);
+
+    // 署名はsignature以前のクエリ文字列。bodyは送らない
+    let reqs = server.requests();
+    let req = reqs.iter().filter(|r| r.path == "/api/v3/order").last().unwrap();
+    let query = req.query.clone().unwrap();
+    let (signed, signature) = query.split_once("&signature=").unwrap();
+    assert_eq!(signature, hmac_hex(signed));
+    assert_eq!(req.headers["X-MBX-APIKEY"], "key");
+    assert!(signed.contains("type=LIMIT_MAKER") && signed.contains("price=29000.01") && signed.contains("quantity=0.00150"));
+    assert!(req.body.is_empty());
+    assert!(reqs.iter().any(|r| r.path == "/api/v3/time"));
+}
+
+#[tokio::test]
+async fn test_mock_ws() {
+    use tokio_tungstenite::connect_async;
+    use super::gmo;
+
+    let server = MockServer::start().await.unwrap();
+    let (socket, _) = connect_async(url::Url::parse(&server.ws_url()).unwrap()).await.unwrap();
+    let (mut write, mut read) = socket.split();
+    write.send(Message::Text(json!({"command": "subscribe", "channel": "trades", "symbol": "BTC_JPY"}).to_string())).await.unwrap();
+    let received = server.wait_ws_received(1).await.unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&received[0]).unwrap()["channel"], "trades");
+
+    server.ws_send(json!({"channel": "trades", "price": "4000000", "side": "BUY", "size": "0.01", "timestamp": "2023-07-01T00:00:00.000Z", "symbol": "BTC_JPY"}));
+    let msg = read.next().await.unwrap().unwrap();
+    let parsed = serde_json::from_str::<gmo::WsResponse>(msg.to_text().unwrap()).unwrap();
+    assert!(matches!(parsed, gmo::WsResponse::Ok(gmo::WsOkResponse::Trades(_))));
+}
@@ -7,3 +7,8 @@ pub mod mail;
 pub mod types;
 pub mod bitflyer;
 pub mod binance;
+pub mod exchange;
+pub mod paper;
+pub mod endpoints;
+#[cfg(test)]
+pub mod mock_server;
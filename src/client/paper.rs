@@ -0,0 +1,434 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::bail;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::channel::mpsc::UnboundedSender;
+use log::{info, error};
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::{symbol::{Symbol, Exchange, SymbolType}, order_types::{Side, OrderType}, data_structure::float_exp::FloatExp, backtest::matching::{SimExchange, FeeModel, SimFill}, utils::{status_repository::StatusRepository, tracingmm_utils::TracingMMPosition, time::{UnixTimeMs, UnixTimeUnit, datetime_utc_from_timestamp}}};
+
+use super::{exchange::{ExchangeClient, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker, OrderbookSnapshot, PrivateEvent, Execution}, types::TradeRecord, gmo::GmoClient, bitflyer::BitflyerClient, coincheck::CoincheckClient};
+
+/// --paperで使う初期資産と手数料
+#[derive(Debug, Clone, clap::Args)]
+pub struct PaperArgs {
+    /// 実際には発注せず、live feedで約定をシミュレートする
+    #[clap(long)]
+    pub paper: bool,
+    /// 決済通貨建ての初期資産
+    #[clap(long, default_value_t = 1_000_000.)]
+    pub paper_collateral: f64,
+    /// 省略するとinstruments.yamlの手数料
+    #[clap(long)]
+    pub paper_maker_fee: Option<f64>,
+    #[clap(long)]
+    pub paper_taker_fee: Option<f64>,
+    /// 板がないときの成行のスリッページ
+    #[clap(long, default_value_t = 0.)]
+    pub paper_slippage: f64,
+}
+
+impl PaperArgs {
+    pub fn fee(&self, symbol: &Symbol) -> anyhow::Result<FeeModel> {
+        let instrument = symbol.instrument()?;
+        Ok(FeeModel {
+            maker: self.paper_maker_fee.unwrap_or(instrument.maker_fee),
+            taker: self.paper_taker_fee.unwrap_or(instrument.taker_fee),
+            slippage: self.paper_slippage,
+        })
+    }
+}
+
+/// 認証不要のpublic APIだけを使うclient
+pub fn public_client(exc: Exchange) -> anyhow::Result<Arc<dyn ExchangeClient>> {
+    match exc {
+        Exchange::Gmo => Ok(Arc::new(GmoClient::new(None))),
+        Exchange::Bitflyer => Ok(Arc::new(BitflyerClient::new(None))),
+        Exchange::Coincheck => Ok(Arc::new(CoincheckClient::new(None))),
+        _ => bail!("{} is not supported", exc),
+    }
+}
+
+#[derive(Debug)]
+struct PaperState {
+    sim: SimExchange,
+    /// 逆指値。約定履歴がtrigger_priceに達したら成行で約定させる
+    stops: BTreeMap<OrderId, NewOrder>,
+    /// 板に置かない注文（即時約定・成行・逆指値）のid。同じ時刻の注文でも重ならないように数える
+    next_order_id: u64,
+    /// 直近の板。[bids(降順), asks(昇順)]
+    book: [Vec<(f64, f64)>; 2],
+}
+
+/// 発注をSimExchangeに流すExchangeClient
+/// 指値はon_tradesで渡された約定履歴、成行はon_orderbookで渡された板に対して約定させる
+/// ticker, orderbookは本番のpublic APIから取得する
+pub struct PaperClient {
+    symbol: Symbol,
+    public: Arc<dyn ExchangeClient>,
+    state: Mutex<PaperState>,
+    status: Mutex<StatusRepository>,
+    /// subscribe_privateで渡されたら約定を流す
+    events: Mutex<Option<UnboundedSender<PrivateEvent>>>,
+}
+
+impl PaperClient {
+    /// `.status_paper_account_{name}_{symbol}.json`があればその資産と建玉から再開する
+    pub fn new(name: &str, symbol: Symbol, args: &PaperArgs) -> anyhow::Result<Self> {
+        let long_only = symbol.r#type == SymbolType::Spot;
+        let mut sim = SimExchange::new(symbol, args.paper_collateral, args.fee(&symbol)?, long_only);
+        let status = StatusRepository::new_init(&format!("paper_account_{}", name), &symbol, None)?;
+        if let Some(collateral) = status[&symbol]["collateral"].as_f64() {
+            let restore_pos = |key: &str| -> TracingMMPosition {
+                let mut pos = TracingMMPosition::new(symbol.price_precision(), symbol.amount_precision());
+                pos.pos = FloatExp::from_f64(status[&symbol][key]["pos"].as_f64().unwrap_or(0.), symbol.amount_precision());
+                pos.entry_price = FloatExp::from_f64(status[&symbol][key]["entry_price"].as_f64().unwrap_or(0.), symbol.price_precision());
+                pos.init_notional = (pos.entry_price * pos.pos).round(symbol.price_precision() + symbol.amount_precision());
+                pos
+            };
+            sim.restore(collateral, [restore_pos("long"), restore_pos("short")]);
+            info!("paper: restored. collateral: {}, pos: {:?}", collateral, sim.position());
+        }
+        Ok(Self {
+            symbol,
+            public: public_client(symbol.exc)?,
+            state: Mutex::new(PaperState {
+                sim,
+                stops: BTreeMap::new(),
+                next_order_id: 0,
+                book: [vec![], vec![]],
+            }),
+            status: Mutex::new(status),
+            events: Mutex::new(None),
+        })
+    }
+
+    /// 約定をtxに流す。本番のprivate websocketと同じ経路で戦略に渡す
+    pub fn subscribe_private(&self, tx: UnboundedSender<PrivateEvent>) {
+        *self.events.lock() = Some(tx);
+    }
+
+    /// 約定履歴で指値と逆指値を約定させる
+    pub fn on_trades(&self, trades: &[TradeRecord]) {
+        let mut fills = vec![];
+        {
+            let mut state = self.state.lock();
+            for trade in trades.iter().filter(|t| t.symbol == self.symbol) {
+                fills.extend(state.sim.on_trade(trade));
+                let triggered = state.stops.iter().filter(|(_, o)| {
+                    let trigger = o.trigger_price.map(|p| p.to_f64()).unwrap_or(0.);
+                    match o.side {
+                        Side::Buy => trade.price >= trigger,
+                        Side::Sell => trade.price <= trigger,
+                    }
+                }).map(|(id, _)| id.clone()).collect::<Vec<_>>();
+                for id in triggered {
+                    let order = state.stops.remove(&id).unwrap();
+                    info!("paper: stop triggered. id: {}, side: {:?}, trigger_price: {:?}", id, order.side, order.trigger_price);
+                    fills.extend(with_order_id(state.take(order.side, order.amount, None, trade.timestamp), &id));
+                }
+            }
+        }
+        self.on_fills(&fills);
+    }
+
+    /// 板のベストN件を更新する。get_bestの0埋めはそのまま渡してよい
+    pub fn on_orderbook<const N: usize>(&self, best: &[[(f64, f64); N]; 2]) {
+        let mut state = self.state.lock();
+        for (book, best) in state.book.iter_mut().zip(best) {
+            *book = best.iter().filter(|(_, size)| *size > 0.).cloned().collect();
+        }
+    }
+
+    fn on_fills(&self, fills: &[SimFill]) {
+        if fills.is_empty() {
+            return;
+        }
+        let events = self.events.lock().clone();
+        for fill in fills {
+            info!("paper fill. side: {:?}, price: {}, amount: {}, liquidity: {:?}, fee: {}, realized_pnl: {}, id: {:?}",
+                fill.side, fill.price, fill.amount, fill.liquidity, fill.fee, fill.realized_pnl, fill.order_id);
+            if let (Some(tx), Some(order_id)) = (&events, &fill.order_id) {
+                let _ = tx.unbounded_send(PrivateEvent::Execution(Execution {
+                    order_id: order_id.clone(),
+                    symbol: self.symbol,
+                    side: fill.side,
+                    price: fill.price,
+                    amount: fill.amount,
+                    fee: fill.fee,
+                    timestamp: datetime_utc_from_timestamp(fill.timestamp, UnixTimeUnit::MilliSecond),
+                }));
+            }
+        }
+        let (collateral, pos) = {
+            let state = self.state.lock();
+            (state.sim.collateral(), state.sim.position())
+        };
+        let pos_json = |p: &TracingMMPosition| json!({"pos": p.pos.to_f64(), "entry_price": p.entry_price.to_f64()});
+        let res = self.status.lock().update(self.symbol, json!({
+            "collateral": collateral,
+            "long": pos_json(&pos[0]),
+            "short": pos_json(&pos[1]),
+        }));
+        if let Err(e) = res {
+            error!("paper: failed to write status: {:?}", e);
+        }
+    }
+}
+
+impl PaperState {
+    fn issue_order_id(&mut self, kind: &str) -> OrderId {
+        self.next_order_id += 1;
+        format!("paper-{}-{}", kind, self.next_order_id)
+    }
+
+    /// 板を指値まで食ってtakerで約定させる。板がなければ直近の約定価格で約定させる
+    fn take(&mut self, side: Side, amount: FloatExp, limit: Option<FloatExp>, timestamp: UnixTimeMs) -> Vec<SimFill> {
+        let book = &self.book[side.inv() as usize];
+        if book.is_empty() {
+            if let Some(limit) = limit {
+                let last = match self.sim.last_price() {
+                    Some(x) => x,
+                    None => return vec![],
+                };
+                let crossed = match side {
+                    Side::Buy => last <= limit,
+                    Side::Sell => last >= limit,
+                };
+                if !crossed {
+                    return vec![];
+                }
+            }
+            return self.sim.market(side, amount, timestamp).into_iter().collect();
+        }
+        let worst = book.last().map(|x| x.0);
+        let levels = sweep(book, side, amount.to_f64(), limit.map(|p| p.to_f64()));
+        let mut fills = vec![];
+        for (price, size) in levels {
+            let price = FloatExp::from_f64(price, self.symbol().price_precision());
+            let size = FloatExp::from_f64(size, self.symbol().amount_precision());
+            fills.extend(self.sim.take(side, price, size, timestamp));
+        }
+        // 指値の残りは板に置く。成行・逆指値で持っている板を食い尽くした分は、一番悪い価格にスリッページを乗せて約定させる
+        let rest = amount.round(self.symbol().amount_precision()) - self.filled_amount(&fills);
+        if limit.is_none() && rest.value > 0 {
+            if let Some(worst) = worst {
+                let slip = match side {
+                    Side::Buy => 1. + self.sim.fee.slippage,
+                    Side::Sell => 1. - self.sim.fee.slippage,
+                };
+                let price = FloatExp::from_f64(worst * slip, self.symbol().price_precision());
+                info!("paper: orderbook is exhausted, fill the rest at {}. side: {:?}, rest: {}", price, side, rest);
+                fills.extend(self.sim.take(side, price, rest, timestamp));
+            }
+            let unfilled = amount.round(self.symbol().amount_precision()) - self.filled_amount(&fills);
+            if unfilled.value > 0 {
+                error!("paper: {} of {:?} {} is not filled", unfilled, side, amount);
+            }
+        }
+        fills
+    }
+
+    fn symbol(&self) -> Symbol {
+        self.sim.symbol
+    }
+
+    fn filled_amount(&self, fills: &[SimFill]) -> FloatExp {
+        fills.iter().fold(FloatExp::new(0, self.symbol().amount_precision()), |acc, f| acc + f.amount)
+    }
+}
+
+/// 即時約定した分を発注したidの約定にする
+fn with_order_id(fills: Vec<SimFill>, id: &OrderId) -> Vec<SimFill> {
+    fills.into_iter().map(|f| SimFill { order_id: Some(id.clone()), ..f }).collect()
+}
+
+/// 反対側の板を良い順に食ったときの(price, size)
+/// limitを超える価格の板は食わない
+pub fn sweep(book: &[(f64, f64)], side: Side, amount: f64, limit: Option<f64>) -> Vec<(f64, f64)> {
+    let mut remaining = amount;
+    let mut ret = vec![];
+    for &(price, size) in book {
+        if remaining <= 0. {
+            break;
+        }
+        let within = match (side, limit) {
+            (_, None) => true,
+            (Side::Buy, Some(limit)) => price <= limit,
+            (Side::Sell, Some(limit)) => price >= limit,
+        };
+        if !within {
+            break;
+        }
+        let taken = size.min(remaining);
+        ret.push((price, taken));
+        remaining -= taken;
+    }
+    ret
+}
+
+#[async_trait]
+impl ExchangeClient for PaperClient {
+    fn exchange(&self) -> Exchange {
+        self.symbol.exc
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        if order.symbol != self.symbol {
+            bail!("paper client only supports {:?}", self.symbol);
+        }
+        let now = Utc::now().timestamp_millis();
+        let (id, fills) = {
+            let mut state = self.state.lock();
+            match order.order_type {
+                OrderType::Limit => {
+                    let price = order.limit_price()?;
+                    let best = state.book[order.side.inv() as usize].first().map(|x| x.0);
+                    let crossed = match (order.side, best) {
+                        (Side::Buy, Some(best)) => best <= price.to_f64(),
+                        (Side::Sell, Some(best)) => best >= price.to_f64(),
+                        (_, None) => false,
+                    };
+                    if crossed && order.post_only {
+                        bail!("paper: post_only order would take. side: {:?}, price: {}", order.side, price);
+                    }
+                    // 板を跨いだ分はtaker、残りを指値として置く
+                    let fills = if crossed { state.take(order.side, order.amount, Some(price), now) } else { vec![] };
+                    let rest = order.amount.round(self.symbol.amount_precision()) - state.filled_amount(&fills);
+                    let id = if rest.value > 0 {
+                        state.sim.place_limit(order.side, price, rest, now)
+                    } else {
+                        state.issue_order_id("taken")
+                    };
+                    let fills = with_order_id(fills, &id);
+                    (id, fills)
+                },
+                OrderType::Market => {
+                    let fills = state.take(order.side, order.amount, None, now);
+                    if fills.is_empty() {
+                        bail!("paper: no price to fill market order");
+                    }
+                    let id = state.issue_order_id("market");
+                    let fills = with_order_id(fills, &id);
+                    (id, fills)
+                },
+                OrderType::Stop => {
+                    order.stop_price()?;
+                    let id = state.issue_order_id("stop");
+                    state.stops.insert(id.clone(), order.clone());
+                    (id, vec![])
+                },
+                OrderType::StopLimit => bail!("StopLimit is not supported in paper"),
+            }
+        };
+        info!("paper order. type: {:?}, side: {:?}, price: {:?}, trigger_price: {:?}, amount: {}, id: {}", order.order_type, order.side, order.price, order.trigger_price, order.amount, id);
+        self.on_fills(&fills);
+        Ok(id)
+    }
+
+    async fn cancel_order(&self, _symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        let mut state = self.state.lock();
+        if !state.sim.cancel(id) && state.stops.remove(id).is_none() {
+            bail!("paper: order {} is not found", id);
+        }
+        Ok(())
+    }
+
+    async fn cancel_all_orders(&self, _symbol: Symbol) -> anyhow::Result<()> {
+        let mut state = self.state.lock();
+        state.sim.cancel_all();
+        state.stops.clear();
+        Ok(())
+    }
+
+    async fn open_orders(&self, _symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        let state = self.state.lock();
+        let mut ret = state.sim.open_orders();
+        ret.extend(state.stops.iter().map(|(id, o)| OpenOrder {
+            id: id.clone(),
+            symbol: o.symbol,
+            side: o.side,
+            order_type: o.order_type.clone(),
+            price: o.trigger_price,
+            amount: o.amount,
+            created_at: Utc::now(),
+        }));
+        Ok(ret)
+    }
+
+    async fn positions(&self, _symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        Ok(self.state.lock().sim.positions())
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        let state = self.state.lock();
+        let quote = state.sim.collateral_for_sizing();
+        let mut ret = vec![AssetBalance { currency: self.symbol.settlement, total: quote, available: quote }];
+        if self.symbol.r#type == SymbolType::Spot {
+            let base = state.sim.position()[0].pos.to_f64();
+            ret.push(AssetBalance { currency: self.symbol.base, total: base, available: base });
+        }
+        Ok(ret)
+    }
+
+    async fn collateral(&self, _symbol: Symbol) -> anyhow::Result<f64> {
+        let state = self.state.lock();
+        Ok(match (self.symbol.r#type, state.sim.last_price()) {
+            (SymbolType::Perp, Some(last)) => state.sim.equity(last),
+            _ => state.sim.collateral_for_sizing(),
+        })
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker> {
+        self.public.ticker(symbol).await
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        self.public.orderbook(symbol).await
+    }
+}
+
+#[test]
+fn test_sweep() {
+    let asks = vec![(100., 1.), (101., 2.), (102., 3.)];
+    assert_eq!(sweep(&asks, Side::Buy, 2.5, None), vec![(100., 1.), (101., 1.5)]);
+    assert_eq!(sweep(&asks, Side::Buy, 10., Some(101.)), vec![(100., 1.), (101., 2.)]);
+    let bids = vec![(99., 1.), (98., 1.)];
+    assert_eq!(sweep(&bids, Side::Sell, 3., Some(98.5)), vec![(99., 1.)]);
+}
+
+#[test]
+fn test_issue_order_id() {
+    use crate::symbol::Currency;
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+    let mut state = PaperState {
+        sim: SimExchange::new(symbol, 1_000_000., FeeModel { maker: 0., taker: 0., slippage: 0. }, false),
+        stops: BTreeMap::new(),
+        next_order_id: 0,
+        book: [vec![], vec![]],
+    };
+    // 同じ時刻の注文でもidが重ならない
+    let ids = [state.issue_order_id("market"), state.issue_order_id("market"), state.issue_order_id("taken")];
+    assert_eq!(ids, ["paper-market-1", "paper-market-2", "paper-taken-3"]);
+}
+
+#[test]
+fn test_take_exhausted_orderbook() {
+    use crate::symbol::Currency;
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+    let mut state = PaperState {
+        sim: SimExchange::new(symbol, 1_000_000., FeeModel { maker: 0., taker: 0., slippage: 0.01 }, false),
+        stops: BTreeMap::new(),
+        next_order_id: 0,
+        book: [vec![(99., 1.)], vec![(100., 1.), (101., 1.)]],
+    };
+    // 板の2件で足りない1は101にスリッページを乗せて約定させる
+    let fills = state.take(Side::Buy, FloatExp::new(3, 0), None, 0);
+    assert_eq!(fills.iter().map(|f| (f.price.to_f64(), f.amount.to_f64())).collect::<Vec<_>>(), vec![(100., 1.), (101., 1.), (102., 1.)]);
+    // 指値の残りは約定させない
+    let fills = state.take(Side::Sell, FloatExp::new(3, 0), Some(FloatExp::new(99, 0)), 0);
+    assert_eq!(state.filled_amount(&fills), FloatExp::new(1, 0));
+}
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use chrono::Duration;
-use serde::{Deserialize, Deserializer};
+use log::error;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
 
-use crate::{symbol::{Symbol}, utils::tracingmm_utils::PriceInOut};
+use crate::{symbol::{Symbol, Exchange, SymbolType}, utils::tracingmm_utils::PriceInOut, data_structure::float_exp::FloatExp};
 
 pub type Config = HashMap<String, Strategy>;
 
@@ -20,6 +21,23 @@ pub enum Strategy {
 pub struct ShannonConfig {
     pub symbol: Symbol,
     pub virtual_amount: VirtualAmount,
+    #[serde(default)]
+    pub risk: RiskLimits,
+}
+
+/// 発注前のリスク制限。省略した項目は制限しない
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct RiskLimits {
+    /// 1注文の決済通貨建ての最大金額
+    pub max_order_notional: Option<f64>,
+    /// base建ての最大建玉（LongとShortの差し引き）。建玉を減らす注文は通す
+    pub max_position: Option<f64>,
+    /// 直近1分間の最大発注回数
+    pub max_orders_per_minute: Option<usize>,
+    /// 指値・逆指値の基準価格（直近の約定かmid）からの最大乖離率
+    pub price_band_rate: Option<f64>,
+    /// UTCの1日の最大損失（決済通貨建て、実現損益+評価損益）。超えたら建玉を増やす注文を止める
+    pub daily_loss_limit: Option<f64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,12 +46,70 @@ pub struct VirtualAmount {
     pub quote: f64,
 }
 
+pub const BOT_CONFIG_PATH: &str = "config.bot.yaml";
+
+/// 認証情報と取引所まわりの設定
+pub const CONFIG_PATH: &str = "config.yaml";
+
+/// config.yamlのkeyの節を読む。ファイルか節がなければdefault、壊れていればログに出して落とす
+pub fn read_config_section<T: DeserializeOwned + Default>(key: &str) -> T {
+    try_read_config_section(key).unwrap_or_else(|e| {
+        error!("{:?}", e);
+        panic!("{:?}", e);
+    })
+}
+
+/// read_config_sectionの落とさない版。起動時に設定を確かめるのに使う
+pub fn try_read_config_section<T: DeserializeOwned + Default>(key: &str) -> Result<T> {
+    let config = match std::fs::read_to_string(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", CONFIG_PATH)),
+    };
+    parse_config_section(&config, key).with_context(|| format!("failed to parse `{}` in {}", key, CONFIG_PATH))
+}
+
+pub(crate) fn parse_config_section<T: DeserializeOwned + Default>(config: &str, key: &str) -> Result<T> {
+    let config: serde_yaml::Value = serde_yaml::from_str(config)?;
+    match config.get(key) {
+        None | Some(serde_yaml::Value::Null) => Ok(T::default()),
+        Some(section) => Ok(serde_yaml::from_value(section.clone())?),
+    }
+}
+
 pub fn load_config() -> Result<Config> {
-    let config = std::fs::read_to_string("config.bot.yaml").unwrap();
+    let config = std::fs::read_to_string(BOT_CONFIG_PATH).unwrap();
     let config: Config = serde_yaml::from_str(&config).unwrap();
     Ok(config)
 }
 
+impl Strategy {
+    /// 注文を出す銘柄。精度・手数料をinstruments.yamlから引くので起動時に登録を確かめる
+    /// ref_symbol・spot_symbolは足を読むだけ、crawlerは約定・板を保存するだけなので含めない
+    pub fn trading_symbols(&self) -> Vec<Symbol> {
+        match self {
+            Strategy::Shannon(c) => vec![c.symbol],
+            Strategy::TracingMm(c) => vec![c.symbol],
+            Strategy::Crawler(_) => vec![],
+        }
+    }
+
+    /// instruments.yamlにない銘柄があればエラー
+    pub fn check_instruments(&self) -> Result<()> {
+        for symbol in self.trading_symbols() {
+            symbol.instrument()?;
+        }
+        Ok(())
+    }
+}
+
+/// 起動後にconfig.bot.yamlを読み直す。壊れていてもpanicしない
+pub fn read_strategy(name: &str) -> Result<Strategy> {
+    let config = std::fs::read_to_string(BOT_CONFIG_PATH)?;
+    let mut config: Config = serde_yaml::from_str(&config)?;
+    config.remove(name).with_context(|| format!("{} is not found in {}", name, BOT_CONFIG_PATH))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CrawlerConfig {
     pub symbols: Vec<Symbol>,
@@ -72,7 +148,7 @@ impl Into<Duration> for Timeframe {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct TracingMMConfig {
     pub symbol: Symbol,
     pub timeframe: Timeframe,
@@ -88,8 +164,283 @@ pub struct TracingMMConfig {
     pub losscut_rate: Option<f64>,
     /// timeframeで何フレームか
     pub exit_mean_frame: i32,
+
+    /// 取引所ごとの挙動。省略時はsymbolから決める
+    #[serde(default)]
+    pub hooks: Option<TracingMMHooks>,
+
+    #[serde(default)]
+    pub risk: RiskLimits,
+}
+
+impl TracingMMConfig {
+    pub fn hooks(&self) -> TracingMMHooks {
+        self.hooks.clone().unwrap_or_else(|| TracingMMHooks::default_for(&self.symbol))
+    }
+
+    pub fn tunables(&self) -> TracingMMTunables {
+        TracingMMTunables {
+            leverage: self.leverage,
+            max_side_positions: self.max_side_positions,
+            beta: self.beta.clone(),
+            gamma: self.gamma.clone(),
+            losscut_rate: self.losscut_rate,
+        }
+    }
+
+    /// 読み直した設定の調整用の値を検証して返す
+    /// 調整用の値以外（symbol, ref_symbol, timeframe, atr_period, exit_mean_frame, hooks, risk）が変わっていればエラー
+    pub fn reload_tunables(&self, next: &TracingMMConfig) -> Result<TracingMMTunables> {
+        if next.symbol != self.symbol {
+            bail!("symbol cannot be changed without restart: {:?} -> {:?}", self.symbol, next.symbol);
+        }
+        if next.ref_symbol != self.ref_symbol {
+            bail!("ref_symbol cannot be changed without restart: {:?} -> {:?}", self.ref_symbol, next.ref_symbol);
+        }
+        if next.timeframe.0 != self.timeframe.0 {
+            bail!("timeframe cannot be changed without restart: {:?} -> {:?}", self.timeframe.0, next.timeframe.0);
+        }
+        if next.atr_period != self.atr_period {
+            bail!("atr_period cannot be changed without restart: {} -> {}", self.atr_period, next.atr_period);
+        }
+        if next.exit_mean_frame != self.exit_mean_frame {
+            bail!("exit_mean_frame cannot be changed without restart: {} -> {}", self.exit_mean_frame, next.exit_mean_frame);
+        }
+        if next.hooks() != self.hooks() {
+            bail!("hooks cannot be changed without restart: {:?} -> {:?}", self.hooks(), next.hooks());
+        }
+        if next.risk != self.risk {
+            bail!("risk cannot be changed without restart: {:?} -> {:?}", self.risk, next.risk);
+        }
+        let tunables = next.tunables();
+        tunables.validate()?;
+        Ok(tunables)
+    }
+
+    pub fn with_tunables(&self, tunables: TracingMMTunables) -> Self {
+        Self {
+            leverage: tunables.leverage,
+            max_side_positions: tunables.max_side_positions,
+            beta: tunables.beta,
+            gamma: tunables.gamma,
+            losscut_rate: tunables.losscut_rate,
+            ..self.clone()
+        }
+    }
+}
+
+/// tracing_mmの再起動せずに変えられる項目
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracingMMTunables {
+    pub leverage: f64,
+    pub max_side_positions: i64,
+    pub beta: PriceInOut,
+    pub gamma: PriceInOut,
+    pub losscut_rate: Option<f64>,
+}
+
+impl TracingMMTunables {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.leverage.is_finite() && self.leverage > 0.) {
+            bail!("leverage must be positive: {}", self.leverage);
+        }
+        if self.max_side_positions < 1 {
+            bail!("max_side_positions must be at least 1: {}", self.max_side_positions);
+        }
+        if let Some(rate) = self.losscut_rate {
+            if !(rate > 0. && rate < 1.) {
+                bail!("losscut_rate must be in (0, 1): {}", rate);
+            }
+        }
+        for (name, x) in [("beta", &self.beta), ("gamma", &self.gamma)] {
+            if !(x.r#in.is_finite() && x.out.is_finite()) {
+                bail!("{} must be finite: {:?}", name, x);
+            }
+        }
+        Ok(())
+    }
+
+    /// available_quoteとliquidity_limited_baseの計算に使う項目が変わるか
+    pub fn changes_sizing(&self, next: &Self) -> bool {
+        self.leverage != next.leverage
+            || self.max_side_positions != next.max_side_positions
+            || self.beta != next.beta
+            || self.gamma != next.gamma
+    }
+
+    /// 変わった項目を`name: prev -> next`で返す
+    pub fn diff(&self, next: &Self) -> Vec<String> {
+        let mut ret = vec![];
+        if self.leverage != next.leverage {
+            ret.push(format!("leverage: {} -> {}", self.leverage, next.leverage));
+        }
+        if self.max_side_positions != next.max_side_positions {
+            ret.push(format!("max_side_positions: {} -> {}", self.max_side_positions, next.max_side_positions));
+        }
+        if self.beta != next.beta {
+            ret.push(format!("beta: {:?} -> {:?}", self.beta, next.beta));
+        }
+        if self.gamma != next.gamma {
+            ret.push(format!("gamma: {:?} -> {:?}", self.gamma, next.gamma));
+        }
+        if self.losscut_rate != next.losscut_rate {
+            ret.push(format!("losscut_rate: {:?} -> {:?}", self.losscut_rate, next.losscut_rate));
+        }
+        ret
+    }
 }
 
 fn max_side_positions_default() -> i64 {
     3
-}
\ No newline at end of file
+}
+
+/// tracing mmの取引所ごとの差分
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TracingMMHooks {
+    /// 現物などLongのみ持つ
+    #[serde(default)]
+    pub long_only: bool,
+    /// reserved orderを発火させる価格の取得元
+    #[serde(default)]
+    pub fire_source: FireSource,
+    /// fire_sourceがorderbookのとき、何番目の価格を交差したら発火するか
+    #[serde(default = "orderbook_nth_default")]
+    pub orderbook_nth: usize,
+    /// open/close注文を取引所に出さず、reserved orderとして持つ
+    #[serde(default)]
+    pub reserve_limit_orders: bool,
+    /// SFDによる新規注文の抑制
+    #[serde(default)]
+    pub sfd: Option<SfdConfig>,
+    /// 省略するとinstruments.yamlの最小発注数量
+    #[serde(default)]
+    pub order_min_amount: Option<f64>,
+}
+
+impl TracingMMHooks {
+    pub fn order_min_amount(&self, symbol: &Symbol) -> anyhow::Result<FloatExp> {
+        Ok(match self.order_min_amount {
+            Some(amount) => FloatExp::from_f64(amount, symbol.amount_precision()),
+            None => symbol.instrument()?.min_order_amount(),
+        })
+    }
+
+    pub fn default_for(symbol: &Symbol) -> Self {
+        match symbol.exc {
+            Exchange::Bitflyer => Self {
+                long_only: symbol.r#type == SymbolType::Spot,
+                fire_source: FireSource::Trades,
+                orderbook_nth: orderbook_nth_default(),
+                reserve_limit_orders: false,
+                sfd: if symbol.r#type == SymbolType::Perp {
+                    Some(SfdConfig {
+                        spot_symbol: Symbol::new(symbol.base, symbol.quote, SymbolType::Spot, Exchange::Bitflyer),
+                        limit_rate: 0.04,
+                    })
+                } else {
+                    None
+                },
+                order_min_amount: None,
+            },
+            // 板の更新が速いので板から発火させ、指値も直前まで出さない
+            Exchange::Coincheck => Self {
+                long_only: true,
+                fire_source: FireSource::Orderbook,
+                orderbook_nth: orderbook_nth_default(),
+                reserve_limit_orders: true,
+                sfd: None,
+                order_min_amount: None,
+            },
+            _ => Self {
+                long_only: symbol.r#type == SymbolType::Spot,
+                fire_source: FireSource::Trades,
+                orderbook_nth: orderbook_nth_default(),
+                reserve_limit_orders: false,
+                sfd: None,
+                order_min_amount: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FireSource {
+    #[default]
+    Trades,
+    Orderbook,
+}
+
+fn orderbook_nth_default() -> usize {
+    2
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SfdConfig {
+    pub spot_symbol: Symbol,
+    /// 現物との乖離率がこれを超える方向には新規注文を出さない
+    pub limit_rate: f64,
+}
+
+#[test]
+fn test_reload_tunables() {
+    let yaml = r#"
+symbol: {base: BTC, quote: JPY, settlement: JPY, type: spot, exc: coincheck}
+timeframe: 150s
+leverage: 1
+ref_symbol: {base: BTC, quote: USDT, settlement: USDT, type: spot, exc: binance}
+atr_period: 25
+beta: {in: 1.0, out: 1.0}
+gamma: {in: 1.0, out: 1.0}
+losscut_rate: 0.05
+exit_mean_frame: 80
+"#;
+    let current: TracingMMConfig = serde_yaml::from_str(yaml).unwrap();
+
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("leverage: 1", "leverage: 2").replace("gamma: {in: 1.0", "gamma: {in: 1.5")).unwrap();
+    let tunables = current.reload_tunables(&next).unwrap();
+    let diff = current.tunables().diff(&tunables);
+    assert_eq!(diff.len(), 2);
+    assert_eq!(diff[0], "leverage: 1 -> 2");
+    assert!(diff[1].starts_with("gamma: "));
+    assert!(current.tunables().changes_sizing(&tunables));
+    assert_eq!(current.with_tunables(tunables).leverage, 2.);
+
+    // losscut_rateだけなら発注量は変わらない
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("losscut_rate: 0.05", "losscut_rate: 0.1")).unwrap();
+    assert!(!current.tunables().changes_sizing(&current.reload_tunables(&next).unwrap()));
+
+    // 起動時に決まる項目は変えられない
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("timeframe: 150s", "timeframe: 300s")).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("base: BTC, quote: JPY", "base: ETH, quote: JPY")).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("losscut_rate: 0.05", "losscut_rate: 1.5")).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    // 調整用の値以外の変更も黙って捨てずに断る
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("atr_period: 25", "atr_period: 30")).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    let next: TracingMMConfig = serde_yaml::from_str(&yaml.replace("exit_mean_frame: 80", "exit_mean_frame: 60")).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    let next: TracingMMConfig = serde_yaml::from_str(&format!("{}hooks: {{fire_source: trades}}\n", yaml)).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    let next: TracingMMConfig = serde_yaml::from_str(&format!("{}risk: {{max_position: 0.1}}\n", yaml)).unwrap();
+    assert!(current.reload_tunables(&next).is_err());
+    // 省略時と同じhooksを書いただけなら変わらない
+    let next: TracingMMConfig = serde_yaml::from_str(&format!("{}hooks: {{long_only: true, fire_source: orderbook, reserve_limit_orders: true}}\n", yaml)).unwrap();
+    assert!(current.reload_tunables(&next).is_ok());
+}
+
+#[test]
+fn test_parse_config_section() {
+    let yaml = "retry:\n  max_attempts: 3\nendpoints:\n";
+    let x: HashMap<String, i64> = parse_config_section(yaml, "retry").unwrap();
+    assert_eq!(x["max_attempts"], 3);
+    // 節がないか空ならdefault
+    assert!(parse_config_section::<HashMap<String, i64>>(yaml, "endpoints").unwrap().is_empty());
+    assert!(parse_config_section::<HashMap<String, i64>>(yaml, "alert").unwrap().is_empty());
+    assert!(parse_config_section::<HashMap<String, i64>>("", "alert").unwrap().is_empty());
+    // 壊れていればエラー
+    assert!(parse_config_section::<HashMap<String, i64>>("retry:\n  max_attempts: three\n", "retry").is_err());
+    assert!(parse_config_section::<HashMap<String, i64>>("retry: [", "retry").is_err());
+}
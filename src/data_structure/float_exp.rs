@@ -1,5 +1,6 @@
-use std::{ops::{Add, Sub, Mul, AddAssign, SubAssign, Div}, fmt::{Display, Debug}, str::FromStr};
+use std::{ops::{Add, Sub, Mul, AddAssign, SubAssign, Div}, fmt::{Display, Debug}, str::FromStr, cmp::Ordering};
 
+use anyhow::Context;
 use serde::Serialize;
 
 #[derive(Clone, Copy, Eq, Hash)]
@@ -8,6 +9,57 @@ pub struct FloatExp {
     pub exp: i32,
 }
 
+/// 桁を落とすときの丸め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// 負の無限大方向
+    Floor,
+    /// 正の無限大方向
+    Ceil,
+    /// 最近接、ちょうど半分なら偶数側
+    HalfEven,
+    /// 最近接、ちょうど半分なら0から遠い側（f64::roundと同じ）
+    HalfUp,
+}
+
+/// 10^nをi128で。38を超えるとオーバーフローする
+fn pow10(n: u32) -> Option<i128> {
+    10i128.checked_pow(n)
+}
+
+/// n / dをmodeで整数に丸める
+fn div_rounded(n: i128, d: i128, mode: RoundingMode) -> Option<i128> {
+    let q = n.checked_div(d)?;
+    let r = n % d;
+    if r == 0 {
+        return Some(q);
+    }
+    // 真の商の符号。qが0のときも正しく判定するためn, dの符号を見る
+    let positive = (n > 0) == (d > 0);
+    let away = match mode {
+        RoundingMode::Floor => !positive,
+        RoundingMode::Ceil => positive,
+        RoundingMode::HalfEven | RoundingMode::HalfUp => {
+            // |r| * 2 と |d| を比べる。|r| < |d| なのでオーバーフローしないよう引き算で比べる
+            let (r, d) = (r.unsigned_abs(), d.unsigned_abs());
+            match r.cmp(&(d - r)) {
+                Ordering::Less => false,
+                Ordering::Greater => true,
+                Ordering::Equal => mode == RoundingMode::HalfUp || q % 2 != 0,
+            }
+        },
+    };
+    if away {
+        q.checked_add(if positive { 1 } else { -1 })
+    } else {
+        Some(q)
+    }
+}
+
+fn to_i64(x: i128) -> Option<i64> {
+    i64::try_from(x).ok()
+}
+
 impl FloatExp {
     /// x = value * 10^exp
     pub const fn new(value: i64, exp: i32) -> Self {
@@ -22,9 +74,15 @@ impl FloatExp {
     }
 
     pub fn to_f64(&self) -> f64 {
-        self.value as f64 * 10f64.powi(self.exp)
+        // 10^-nを掛けるより10^nで割る方が誤差が小さい
+        if self.exp < 0 {
+            self.value as f64 / 10f64.powi(-self.exp)
+        } else {
+            self.value as f64 * 10f64.powi(self.exp)
+        }
     }
 
+    /// f64は2進なので1.005などはちょうど半分にならず丸めがずれることがある。文字列があるならparseを使う
     pub fn from_f64(raw: f64, exp: i32) -> Self {
         Self::new((raw * 10f64.powi(-exp)).round() as i64, exp)
     }
@@ -33,64 +91,175 @@ impl FloatExp {
         Self::new((raw * 10f64.powi(-exp)).floor() as i64, exp)
     }
 
-    pub fn from_str(raw: String, exp: i32) -> Result<Self, std::num::ParseFloatError> {
-        Ok(Self::new((raw.parse::<f64>()? * 10f64.powi(-exp)).round() as i64, exp))
+    /// 10進の文字列をf64を経由せずに読んでexpに丸める
+    pub fn from_str(raw: String, exp: i32) -> anyhow::Result<Self> {
+        raw.parse::<FloatExp>()?
+            .checked_round_with(exp, RoundingMode::HalfUp)
+            .with_context(|| format!("{} overflows at exp={}", raw, exp))
+    }
+
+    /// exp桁に丸める。i64に収まらなければNone
+    pub fn checked_round_with(&self, exp: i32, mode: RoundingMode) -> Option<Self> {
+        let value = self.value as i128;
+        let value = if exp <= self.exp {
+            value.checked_mul(pow10((self.exp - exp) as u32)?)?
+        } else {
+            match pow10((exp - self.exp) as u32) {
+                Some(d) => div_rounded(value, d, mode)?,
+                // |value| < 10^19なので商は0で、余りだけで丸める
+                None => div_rounded(value.signum(), i128::MAX, mode)?,
+            }
+        };
+        Some(Self::new(to_i64(value)?, exp))
+    }
+
+    pub fn round_with(&self, exp: i32, mode: RoundingMode) -> Self {
+        self.checked_round_with(exp, mode).unwrap_or_else(|| panic!("FloatExp overflow: {:?} at exp={}", self, exp))
     }
 
     /// Round to the specified number of decimal places.
     pub fn round(&self, exp: i32) -> Self {
-        Self::new((self.value as f64 * 10f64.powi(- exp + self.exp)).round() as i64, exp)
+        self.round_with(exp, RoundingMode::HalfUp)
     }
 
     /// Round down to the specified number of decimal places.
     pub fn floor(&self, exp: i32) -> Self {
-        Self::new((self.value as f64 * 10f64.powi(- exp + self.exp)).floor() as i64, exp)
+        self.round_with(exp, RoundingMode::Floor)
+    }
+
+    pub fn ceil(&self, exp: i32) -> Self {
+        self.round_with(exp, RoundingMode::Ceil)
     }
 
     pub fn abs(&self) -> Self {
         Self::new(self.value.abs(), self.exp)
     }
 
-    pub fn min_exp_sub(&self, rhs: Self) -> Self {
+    pub fn checked_add(&self, rhs: Self) -> Option<Self> {
         let exp = self.exp.min(rhs.exp);
-        Self::new(self.round(exp).value - rhs.round(exp).value, exp)
+        let value = self.checked_round_with(exp, RoundingMode::HalfUp)?.value.checked_add(rhs.checked_round_with(exp, RoundingMode::HalfUp)?.value)?;
+        Some(Self::new(value, exp))
     }
 
-    pub fn min_exp_add(&self, rhs: Self) -> Self {
+    pub fn checked_sub(&self, rhs: Self) -> Option<Self> {
         let exp = self.exp.min(rhs.exp);
-        Self::new(self.round(exp).value + rhs.round(exp).value, exp)
+        let value = self.checked_round_with(exp, RoundingMode::HalfUp)?.value.checked_sub(rhs.checked_round_with(exp, RoundingMode::HalfUp)?.value)?;
+        Some(Self::new(value, exp))
+    }
+
+    pub fn checked_mul(&self, rhs: Self) -> Option<Self> {
+        Some(Self::new(self.value.checked_mul(rhs.value)?, self.exp.checked_add(rhs.exp)?))
+    }
+
+    pub fn min_exp_sub(&self, rhs: Self) -> Self {
+        self.checked_sub(rhs).unwrap_or_else(|| panic!("FloatExp overflow: {:?} - {:?}", self, rhs))
+    }
+
+    pub fn min_exp_add(&self, rhs: Self) -> Self {
+        self.checked_add(rhs).unwrap_or_else(|| panic!("FloatExp overflow: {:?} + {:?}", self, rhs))
+    }
+
+    /// self / rhsをnew_exp桁に丸める。0除算かi64に収まらなければNone
+    pub fn checked_div_with(&self, rhs: Self, new_exp: i32, mode: RoundingMode) -> Option<Self> {
+        if rhs.value == 0 {
+            return None;
+        }
+        if self.value == 0 {
+            return Some(Self::new(0, new_exp));
+        }
+        // value = self.value * 10^(self.exp - rhs.exp - new_exp) / rhs.value
+        let k = self.exp as i64 - rhs.exp as i64 - new_exp as i64;
+        let (n, d) = if k >= 0 {
+            ((self.value as i128).checked_mul(pow10(u32::try_from(k).ok()?)?)?, rhs.value as i128)
+        } else {
+            match pow10(u32::try_from(-k).ok()?) {
+                Some(p) => match (rhs.value as i128).checked_mul(p) {
+                    Some(d) => (self.value as i128, d),
+                    None => (self.value.signum() as i128 * rhs.value.signum() as i128, i128::MAX),
+                },
+                None => (self.value.signum() as i128 * rhs.value.signum() as i128, i128::MAX),
+            }
+        };
+        Some(Self::new(to_i64(div_rounded(n, d, mode)?)?, new_exp))
+    }
+
+    pub fn div_with(&self, rhs: Self, new_exp: i32, mode: RoundingMode) -> Self {
+        self.checked_div_with(rhs, new_exp, mode).unwrap_or_else(|| panic!("FloatExp overflow or division by zero: {:?} / {:?}", self, rhs))
     }
 
     /// Divide and round to the specified number of decimal places.
     pub fn div_round(&self, rhs: Self, new_exp: i32) -> Self {
-        let min_exp = self.exp.min(rhs.exp);
-        // f64での割り算なので答えが小さいとこのとき既に誤差が出るが、問題になったら修正する
-        let raw = self.round(min_exp).value as f64 / rhs.round(min_exp).value as f64;   // この時点ではexpは0
-        Self::from_f64(raw, new_exp)
+        self.div_with(rhs, new_exp, RoundingMode::HalfUp)
     }
 
     /// Divide and round down to the specified number of decimal places.
     pub fn div_floor(&self, rhs: Self, new_exp: i32) -> Self {
-        let min_exp = self.exp.min(rhs.exp);
-        // f64での割り算なので答えが小さいとこのとき既に誤差が出るが、問題になったら修正する
-        let raw = self.round(min_exp).value as f64 / rhs.round(min_exp).value as f64;   // この時点ではexpは0
-        Self::from_f64_floor(raw, new_exp)
+        self.div_with(rhs, new_exp, RoundingMode::Floor)
     }
 
     pub const fn is_zero(&self) -> bool {
         self.value == 0
     }
+
+    /// 小数点を入れた10進表記。f64を経由しない
+    fn decimal_string(&self) -> String {
+        let digits = self.value.unsigned_abs().to_string();
+        let sign = if self.value < 0 { "-" } else { "" };
+        if self.exp >= 0 {
+            if self.value == 0 {
+                return "0".to_string();
+            }
+            return format!("{}{}{}", sign, digits, "0".repeat(self.exp as usize));
+        }
+        let scale = (-self.exp) as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        format!("{}{}.{}", sign, int, frac)
+    }
+}
+
+/// "123.4500"はexp=-4、"1.5e-8"はexp=-9になる。桁はそのまま保つ
+impl FromStr for FloatExp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let (mantissa, e) = match s.find(['e', 'E']) {
+            Some(i) => (&s[..i], s[i + 1..].parse::<i32>().with_context(|| format!("invalid exponent: {}", s))?),
+            None => (s, 0),
+        };
+        let (negative, mantissa) = match mantissa.strip_prefix('-') {
+            Some(m) => (true, m),
+            None => (false, mantissa.strip_prefix('+').unwrap_or(mantissa)),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if (int.is_empty() && frac.is_empty()) || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+            anyhow::bail!("invalid decimal: {}", s);
+        }
+        let sign = if negative { -1 } else { 1 };
+        let parse = |frac: &str| format!("{}{}", int, frac).parse::<i128>().ok().and_then(|v| i64::try_from(sign * v).ok());
+        // i64に入らないときだけ末尾の0を落とす
+        let (value, frac) = match parse(frac) {
+            Some(value) => (value, frac),
+            None => {
+                let frac = frac.trim_end_matches('0');
+                (parse(frac).with_context(|| format!("{} overflows i64", s))?, frac)
+            },
+        };
+        let exp = e.checked_sub(frac.len() as i32).with_context(|| format!("{} overflows exp", s))?;
+        Ok(Self::new(value, exp))
+    }
 }
 
 impl Display for FloatExp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{value:.*}", if self.exp<0 {(-self.exp) as usize} else {0}, value=self.to_f64())
+        write!(f, "{}", self.decimal_string())
     }
 }
 
 impl Debug for FloatExp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{value:.*}@exp={exp}", if self.exp<0 {(-self.exp) as usize} else {0}, value=self.to_f64(), exp=self.exp)
+        write!(f, "{}@exp={}", self.decimal_string(), self.exp)
     }
 }
 
@@ -111,7 +280,7 @@ impl Add for FloatExp {
 
     fn add(self, rhs: Self) -> Self::Output {
         assert_eq!(self.exp, rhs.exp);
-        Self::new(self.value + rhs.value, self.exp)
+        Self::new(self.value.checked_add(rhs.value).expect("FloatExp overflow"), self.exp)
     }
 }
 
@@ -120,7 +289,7 @@ impl Sub for FloatExp {
 
     fn sub(self, rhs: Self) -> Self::Output {
         assert_eq!(self.exp, rhs.exp);
-        Self::new(self.value - rhs.value, self.exp)
+        Self::new(self.value.checked_sub(rhs.value).expect("FloatExp overflow"), self.exp)
     }
 }
 
@@ -153,7 +322,7 @@ impl Mul<i64> for FloatExp {
     type Output = Self;
 
     fn mul(self, rhs: i64) -> Self::Output {
-        Self::new(self.value * rhs, self.exp)
+        Self::new(self.value.checked_mul(rhs).expect("FloatExp overflow"), self.exp)
     }
 }
 
@@ -169,45 +338,53 @@ impl Mul<Self> for FloatExp {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        Self::new(self.value * rhs.value, self.exp + rhs.exp)
+        self.checked_mul(rhs).expect("FloatExp overflow")
     }
 }
 
 impl AddAssign for FloatExp {
     fn add_assign(&mut self, rhs: Self) {
         assert_eq!(self.exp, rhs.exp);
-        self.value += rhs.value;
+        self.value = self.value.checked_add(rhs.value).expect("FloatExp overflow");
     }
 }
 
 impl SubAssign for FloatExp {
     fn sub_assign(&mut self, rhs: Self) {
         assert_eq!(self.exp, rhs.exp);
-        self.value -= rhs.value;
+        self.value = self.value.checked_sub(rhs.value).expect("FloatExp overflow");
     }
 }
 
 impl PartialOrd for FloatExp {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
 impl Ord for FloatExp {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    fn cmp(&self, other: &Self) -> Ordering {
         if self.exp == other.exp {
             return self.value.cmp(&other.value);
         }
+        // 符号が違えば桁を揃えるまでもない。揃えてi128に収まらないほど桁が離れていれば大きい方が勝つ
+        let sign = self.value.signum().cmp(&other.value.signum());
+        if sign != Ordering::Equal {
+            return sign;
+        }
         let exp = self.exp.min(other.exp);
-        let a = self.round(exp);
-        let b = other.round(exp);
-        a.value.cmp(&b.value)
+        let scale = |x: &Self| pow10((x.exp - exp) as u32).and_then(|p| (x.value as i128).checked_mul(p));
+        match (scale(self), scale(other)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (None, _) => if self.value > 0 { Ordering::Greater } else { Ordering::Less },
+            (_, None) => if other.value > 0 { Ordering::Less } else { Ordering::Greater },
+        }
     }
 }
 
 impl PartialEq for FloatExp {
     fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == std::cmp::Ordering::Equal
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -247,4 +424,134 @@ fn test_float_exp_serialize() {
         "a": FloatExp::from_f64(1.234, 0),
     });
     assert_eq!(o.to_string(), "{\"a\":1}");
-}
\ No newline at end of file
+}
+#[test]
+fn test_float_exp_rounding_mode() {
+    use RoundingMode::*;
+    let cases = [
+        // value, exp, new_exp, floor, ceil, half_even, half_up
+        (125, -2, -1, 12, 13, 12, 13),
+        (135, -2, -1, 13, 14, 14, 14),
+        (-125, -2, -1, -13, -12, -12, -13),
+        (126, -2, -1, 12, 13, 13, 13),
+        (-124, -2, -1, -13, -12, -12, -12),
+        (5, -1, 0, 0, 1, 0, 1),
+        (-5, -1, 0, -1, 0, 0, -1),
+        (1, -40, 0, 0, 1, 0, 0),
+        (-1, -40, 0, -1, 0, 0, 0),
+    ];
+    for (value, exp, new_exp, floor, ceil, half_even, half_up) in cases {
+        let x = FloatExp::new(value, exp);
+        assert_eq!(x.round_with(new_exp, Floor).value, floor, "floor {:?}", x);
+        assert_eq!(x.round_with(new_exp, Ceil).value, ceil, "ceil {:?}", x);
+        assert_eq!(x.round_with(new_exp, HalfEven).value, half_even, "half_even {:?}", x);
+        assert_eq!(x.round_with(new_exp, HalfUp).value, half_up, "half_up {:?}", x);
+    }
+    // f64では表せない18桁の値も同じ桁への丸めでは変わらない
+    assert_eq!(FloatExp::new(123456789012345678, -8).round(-8).value, 123456789012345678);
+    // 桁を増やしてあふれるならNone
+    assert_eq!(FloatExp::new(i64::MAX, 0).checked_round_with(-1, HalfUp), None);
+}
+
+#[test]
+fn test_float_exp_div() {
+    use RoundingMode::*;
+    let a = FloatExp::new(1, 0);
+    let b = FloatExp::new(3, 0);
+    assert_eq!(a.div_round(b, -8).value, 33333333);
+    assert_eq!(FloatExp::new(2, 0).div_round(b, -8).value, 66666667);
+    assert_eq!(FloatExp::new(2, 0).div_floor(b, -8).value, 66666666);
+    assert_eq!(FloatExp::new(-2, 0).div_floor(b, -8).value, -66666667);
+    assert_eq!(FloatExp::new(5, 0).div_with(FloatExp::new(2, 0), 0, HalfEven).value, 2);
+    // 1億円 / 4,262,466円 を1e-8 BTC単位で
+    assert_eq!(FloatExp::new(100000000, 0).div_floor(FloatExp::new(4262466, 0), -8).value, 2346059769);
+    assert_eq!(a.checked_div_with(FloatExp::new(0, 0), 0, Floor), None);
+}
+
+#[test]
+fn test_float_exp_parse() {
+    let x: FloatExp = "123.4500".parse().unwrap();
+    assert_eq!((x.value, x.exp), (1234500, -4));
+    let x: FloatExp = "-0.00000001".parse().unwrap();
+    assert_eq!((x.value, x.exp), (-1, -8));
+    let x: FloatExp = "1.5e-8".parse().unwrap();
+    assert_eq!((x.value, x.exp), (15, -9));
+    let x: FloatExp = "4262466".parse().unwrap();
+    assert_eq!((x.value, x.exp), (4262466, 0));
+    assert!("abc".parse::<FloatExp>().is_err());
+    assert!("1.2.3".parse::<FloatExp>().is_err());
+    assert!("".parse::<FloatExp>().is_err());
+    assert_eq!("-9223372036854775808".parse::<FloatExp>().unwrap().value, i64::MIN);
+    assert_eq!(FloatExp::from_str("1.005".to_string(), -2).unwrap().value, 101);
+    assert_eq!(FloatExp::new(-5, -3).to_string(), "-0.005");
+    assert_eq!(FloatExp::new(12, 2).to_string(), "1200");
+}
+
+#[cfg(test)]
+mod proptests {
+    use std::str::FromStr;
+
+    use proptest::prelude::*;
+    use rust_decimal::{Decimal, RoundingStrategy};
+
+    use super::{FloatExp, RoundingMode};
+
+    fn to_decimal(x: FloatExp) -> Decimal {
+        Decimal::from_str(&x.to_string()).unwrap()
+    }
+
+    fn strategy(mode: RoundingMode) -> RoundingStrategy {
+        match mode {
+            RoundingMode::Floor => RoundingStrategy::ToNegativeInfinity,
+            RoundingMode::Ceil => RoundingStrategy::ToPositiveInfinity,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+
+    fn mode() -> impl Strategy<Value = RoundingMode> {
+        prop_oneof![
+            Just(RoundingMode::Floor),
+            Just(RoundingMode::Ceil),
+            Just(RoundingMode::HalfEven),
+            Just(RoundingMode::HalfUp),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_matches_decimal(value in -10i64.pow(6)..10i64.pow(6), exp in -12i32..=0, new_exp in -12i32..=0, mode in mode()) {
+            let x = FloatExp::new(value, exp);
+            let expected = to_decimal(x).round_dp_with_strategy((-new_exp) as u32, strategy(mode));
+            let actual = x.round_with(new_exp, mode);
+            prop_assert_eq!(to_decimal(actual), expected);
+            prop_assert_eq!(actual.exp, new_exp);
+        }
+
+        #[test]
+        fn div_matches_decimal(a in -10i64.pow(12)..10i64.pow(12), b in 1i64..10i64.pow(9), a_exp in -8i32..=0, b_exp in -8i32..=0, new_exp in -8i32..=0, mode in mode()) {
+            let x = FloatExp::new(a, a_exp);
+            let y = FloatExp::new(b, b_exp);
+            let expected = (to_decimal(x) / to_decimal(y)).round_dp_with_strategy((-new_exp) as u32, strategy(mode));
+            match x.checked_div_with(y, new_exp, mode) {
+                Some(actual) => prop_assert_eq!(to_decimal(actual), expected),
+                // i64に収まらない商だけNone
+                None => prop_assert!(expected.abs() * Decimal::from(10i64.pow((-new_exp) as u32)) > Decimal::from(i64::MAX)),
+            }
+        }
+
+        #[test]
+        fn parse_roundtrip(value in any::<i64>(), exp in -18i32..=0) {
+            let x = FloatExp::new(value, exp);
+            let parsed: FloatExp = x.to_string().parse().unwrap();
+            prop_assert_eq!((parsed.value, parsed.exp), (value, exp));
+        }
+
+        #[test]
+        fn cmp_matches_decimal(a in any::<i64>(), b in any::<i64>(), a_exp in -10i32..=0, b_exp in -10i32..=0) {
+            let x = FloatExp::new(a, a_exp);
+            let y = FloatExp::new(b, b_exp);
+            prop_assert_eq!(x.cmp(&y), to_decimal(x).cmp(&to_decimal(y)));
+        }
+    }
+}
@@ -1,16 +1,83 @@
 use hyper::StatusCode;
 use thiserror::Error;
 
+/// 各取引所のエラーコードやHTTPのエラーをここにまとめる。
+/// 取引所のエラーで分類できなかったものは`*ClientMessage`のまま返す
 #[derive(Debug, Error)]
 pub enum BotError {
     #[error("Gmo Client message found: {}, {}", .code, .message)]
     GmoClientMessage {code: String, message: String},
     #[error("Bitflyer Client message found: {}, {}, {}", .status, .message, .reqest)]
     BitflyerClientMessage {status: StatusCode, message: String, reqest: String},
+    #[error("Binance Client message found: {}, {}, {}", .status, .code, .message)]
+    BinanceClientMessage {status: StatusCode, code: i64, message: String},
+    #[error("Coincheck Client message found: {}", .message)]
+    CoincheckClientMessage {message: String},
     #[error("Maintenance")]
     Maintenance,
-    #[error("Margin insufficient")]
-    MarginInsufficiency,
+    #[error("Insufficient funds")]
+    InsufficientFunds,
+    #[error("Rate limited")]
+    RateLimited,
+    #[error("Order rejected: {}", .reason)]
+    OrderRejected {reason: String},
+    /// 指値が取引所の許す価格帯から外れている
+    #[error("Price out of range")]
+    PriceOutOfRange,
+    #[error("Authentication failed")]
+    AuthFailed,
+    /// nonce・timestampが古い。署名し直せば通る
+    #[error("Nonce error")]
+    NonceError,
+    /// connectedがfalseならサーバーに届いていない
+    #[error("Network error (connected: {}): {}", .connected, .message)]
+    Network {connected: bool, message: String},
+    #[error("Server error: {}, {}", .status, .message)]
+    ServerError {status: StatusCode, message: String},
     #[error("Too many request in websocket")]
     WsTooManyRequest,
+    #[error("Order rejected by risk limit: {}", .0)]
+    RiskLimit(String),
+    #[error("Kill switch is engaged")]
+    KillSwitch,
+    #[error("New entries are paused")]
+    Paused,
+}
+
+/// CaptureResultでの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// ログだけ出して続ける。次の周期でやり直す
+    Ignore,
+    /// メールで知らせて続ける
+    Alert,
+    /// ログを出し、少し待ってから落ちて再起動に任せる
+    Restart,
+    /// メールで知らせて落ちる
+    Fatal,
+}
+
+impl BotError {
+    pub fn action(&self) -> ErrorAction {
+        match self {
+            BotError::Maintenance | BotError::InsufficientFunds | BotError::PriceOutOfRange | BotError::RateLimited | BotError::NonceError => ErrorAction::Ignore,
+            // kill switchが入ったときはメールを送ってある
+            BotError::KillSwitch => ErrorAction::Ignore,
+            // control APIで止めている
+            BotError::Paused => ErrorAction::Ignore,
+            // 止めた注文は知らせるが、botは動かし続ける
+            BotError::OrderRejected { .. } | BotError::RiskLimit(_) => ErrorAction::Alert,
+            BotError::Network { .. } | BotError::ServerError { .. } | BotError::WsTooManyRequest => ErrorAction::Restart,
+            BotError::AuthFailed
+            | BotError::GmoClientMessage { .. }
+            | BotError::BitflyerClientMessage { .. }
+            | BotError::BinanceClientMessage { .. }
+            | BotError::CoincheckClientMessage { .. } => ErrorAction::Fatal,
+        }
+    }
+}
+
+/// BotErrorでないエラーはFatal
+pub fn error_action(e: &anyhow::Error) -> ErrorAction {
+    e.downcast_ref::<BotError>().map_or(ErrorAction::Fatal, |e| e.action())
 }
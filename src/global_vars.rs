@@ -2,6 +2,8 @@ use once_cell::sync::OnceCell;
 use serde::Deserialize;
 use strum::EnumString;
 
+use crate::client::paper::PaperArgs;
+
 pub static DEBUG: OnceCell<DebugFlag> = OnceCell::new();
 
 #[derive(Debug, Clone, Copy, EnumString, PartialEq, Eq)]
@@ -14,4 +16,11 @@ pub enum DebugFlag {
 
 pub fn get_debug() -> DebugFlag {
     *DEBUG.get().unwrap()
-}
\ No newline at end of file
+}
+
+/// --paperの指定。paper tradingでなければNone
+pub static PAPER: OnceCell<Option<PaperArgs>> = OnceCell::new();
+
+pub fn get_paper() -> Option<&'static PaperArgs> {
+    PAPER.get().and_then(|x| x.as_ref())
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use log::info;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::Deserialize;
+
+use crate::{symbol::{Symbol, Exchange}, data_structure::float_exp::{FloatExp, RoundingMode}, client::{gmo::{GmoClient, GmoClientResponse, SymbolRules}, binance::{BinanceClient, ExchangeInfoRequest}}};
+
+const INSTRUMENTS_PATH: &str = "instruments.yaml";
+
+/// 埋め込みのinstruments.yaml
+const DEFAULT_INSTRUMENTS: &str = include_str!("../instruments.yaml");
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractType {
+    Spot,
+    /// 決済通貨がquoteの先物・CFD
+    Linear,
+    /// 決済通貨がbaseの先物
+    Inverse,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct Instrument {
+    pub symbol: Symbol,
+    pub contract: ContractType,
+    pub price_precision: i32,
+    pub amount_precision: i32,
+    pub settlement_precision: i32,
+    pub tick_size: f64,
+    pub lot_size: f64,
+    pub min_order_amount: f64,
+    pub maker_fee: f64,
+    pub taker_fee: f64,
+}
+
+impl Instrument {
+    pub fn min_order_amount(&self) -> FloatExp {
+        FloatExp::from_f64(self.min_order_amount, self.amount_precision)
+    }
+
+    /// tick_sizeの倍数に丸める
+    pub fn round_price(&self, price: FloatExp, mode: RoundingMode) -> FloatExp {
+        round_to_step(price, self.tick_size, self.price_precision, mode)
+    }
+
+    /// lot_sizeの倍数に切り下げる
+    pub fn round_amount(&self, amount: FloatExp) -> FloatExp {
+        round_to_step(amount, self.lot_size, self.amount_precision, RoundingMode::Floor)
+    }
+}
+
+/// stepが0以下なら精度で丸めるだけ
+fn round_to_step(value: FloatExp, step: f64, exp: i32, mode: RoundingMode) -> FloatExp {
+    let value = value.round_with(exp, mode);
+    let step = FloatExp::from_f64(step, exp);
+    if step.value <= 0 {
+        return value;
+    }
+    (value.div_with(step, 0, mode) * step).round(exp)
+}
+
+static INSTRUMENTS: Lazy<RwLock<HashMap<Symbol, Instrument>>> = Lazy::new(|| {
+    let mut instruments = parse_instruments(DEFAULT_INSTRUMENTS).unwrap();
+    if let Ok(s) = std::fs::read_to_string(INSTRUMENTS_PATH) {
+        instruments.extend(parse_instruments(&s).with_context(|| format!("failed to parse {}", INSTRUMENTS_PATH)).unwrap());
+    }
+    RwLock::new(instruments)
+});
+
+fn parse_instruments(s: &str) -> anyhow::Result<HashMap<Symbol, Instrument>> {
+    let list: Vec<Instrument> = serde_yaml::from_str(s)?;
+    Ok(list.into_iter().map(|i| (i.symbol, i)).collect())
+}
+
+pub fn get_instrument(symbol: &Symbol) -> anyhow::Result<Instrument> {
+    INSTRUMENTS.read().get(symbol).copied()
+        .with_context(|| format!("{} is not registered in {}", symbol.to_file_form(), INSTRUMENTS_PATH))
+}
+
+pub fn register_instrument(instrument: Instrument) {
+    INSTRUMENTS.write().insert(instrument.symbol, instrument);
+}
+
+/// 取引所のAPIから呼値・数量単位・手数料を取得して登録済みの銘柄を更新する。更新した数を返す
+/// APIで取れない取引所はyamlのまま
+pub async fn refresh_instruments(exc: Exchange) -> anyhow::Result<usize> {
+    match exc {
+        Exchange::Gmo => refresh_gmo().await,
+        Exchange::Binance => refresh_binance().await,
+        _ => {
+            info!("refresh_instruments: {} is not supported", exc);
+            Ok(0)
+        },
+    }
+}
+
+async fn refresh_gmo() -> anyhow::Result<usize> {
+    let res: GmoClientResponse<SymbolRules> = GmoClient::new(None).get_public("/v1/symbols", HashMap::<String, String>::new()).await?;
+    let rules = res.into_result()?;
+    let mut updated = 0;
+    let mut instruments = INSTRUMENTS.write();
+    for instrument in instruments.values_mut().filter(|i| i.symbol.exc == Exchange::Gmo) {
+        let Some(rule) = rules.iter().find(|r| r.symbol == instrument.symbol.to_native()) else {
+            continue;
+        };
+        let tick_size = rule.tick_size.parse::<FloatExp>()?;
+        let size_step = rule.size_step.parse::<FloatExp>()?;
+        instrument.price_precision = precision_of(tick_size);
+        instrument.amount_precision = precision_of(size_step);
+        instrument.tick_size = tick_size.to_f64();
+        instrument.lot_size = size_step.to_f64();
+        instrument.min_order_amount = rule.min_order_size.parse()?;
+        instrument.maker_fee = rule.maker_fee.parse()?;
+        instrument.taker_fee = rule.taker_fee.parse()?;
+        info!("refresh instrument: {:?}", instrument);
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// 手数料はexchangeInfoにないのでyamlのまま
+async fn refresh_binance() -> anyhow::Result<usize> {
+    let client = BinanceClient::new(None);
+    let symbols = INSTRUMENTS.read().values().filter(|i| i.symbol.exc == Exchange::Binance && i.contract == ContractType::Spot).map(|i| i.symbol).collect::<Vec<_>>();
+    let mut updated = 0;
+    for symbol in symbols {
+        let res = client.get_public(ExchangeInfoRequest { symbol: symbol.to_native() }).await?;
+        let Some(info) = res.symbols.first() else {
+            continue;
+        };
+        let (Some(tick_size), Some((step_size, min_qty))) = (info.tick_size(), info.lot_size()) else {
+            continue;
+        };
+        let tick_size = tick_size.parse::<FloatExp>()?;
+        let step_size = step_size.parse::<FloatExp>()?;
+        let mut instruments = INSTRUMENTS.write();
+        let Some(instrument) = instruments.get_mut(&symbol) else {
+            continue;
+        };
+        instrument.price_precision = precision_of(tick_size);
+        instrument.amount_precision = precision_of(step_size);
+        instrument.tick_size = tick_size.to_f64();
+        instrument.lot_size = step_size.to_f64();
+        instrument.min_order_amount = min_qty.parse()?;
+        info!("refresh instrument: {:?}", instrument);
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// 0.001なら-3、10なら1。5のように10の累乗でなければその桁
+fn precision_of(step: FloatExp) -> i32 {
+    let mut step = step;
+    while step.value != 0 && step.value % 10 == 0 {
+        step = FloatExp::new(step.value / 10, step.exp + 1);
+    }
+    step.exp
+}
+
+#[test]
+fn test_default_instruments() {
+    use crate::symbol::{Currency, SymbolType};
+    let instruments = parse_instruments(DEFAULT_INSTRUMENTS).unwrap();
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo);
+    assert_eq!(instruments[&symbol].amount_precision, -2);
+    assert_eq!(instruments[&symbol].contract, ContractType::Linear);
+    // lot_sizeとprecisionが揃っている
+    for instrument in instruments.values() {
+        let lot_size = FloatExp::from_f64(instrument.lot_size, instrument.amount_precision);
+        assert_eq!(precision_of(lot_size), instrument.amount_precision, "{:?}", instrument.symbol);
+        let tick_size = FloatExp::from_f64(instrument.tick_size, instrument.price_precision);
+        assert_eq!(precision_of(tick_size), instrument.price_precision, "{:?}", instrument.symbol);
+    }
+}
+
+#[test]
+fn test_precision_of() {
+    assert_eq!(precision_of("0.001".parse().unwrap()), -3);
+    assert_eq!(precision_of("0.0100".parse().unwrap()), -2);
+    assert_eq!(precision_of("10".parse().unwrap()), 1);
+    assert_eq!(precision_of("5".parse().unwrap()), 0);
+}
+
+#[test]
+fn test_round_to_step() {
+    use crate::symbol::{Currency, SymbolType};
+    let mut instrument = Instrument {
+        symbol: Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Gmo),
+        contract: ContractType::Linear,
+        price_precision: 0,
+        amount_precision: -2,
+        settlement_precision: 0,
+        tick_size: 5.,
+        lot_size: 0.05,
+        min_order_amount: 0.05,
+        maker_fee: 0.,
+        taker_fee: 0.,
+    };
+    assert_eq!(instrument.round_price(FloatExp::new(4000003, 0), RoundingMode::Floor), FloatExp::new(4000000, 0));
+    assert_eq!(instrument.round_price(FloatExp::new(4000003, 0), RoundingMode::Ceil), FloatExp::new(4000005, 0));
+    assert_eq!(instrument.round_price(FloatExp::new(40000034, -1), RoundingMode::HalfUp), FloatExp::new(4000005, 0));
+    assert_eq!(instrument.round_amount(FloatExp::new(129, -3)), FloatExp::new(10, -2));
+    instrument.tick_size = 0.;
+    assert_eq!(instrument.round_price(FloatExp::new(40000034, -1), RoundingMode::Floor), FloatExp::new(4000003, 0));
+}
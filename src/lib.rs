@@ -1,4 +1,5 @@
 pub mod symbol;
+pub mod instrument;
 pub mod data_structure;
 pub mod client;
 pub mod order_types;
@@ -8,3 +9,4 @@ pub mod config;
 pub mod logger;
 pub mod utils;
 pub mod global_vars;
+pub mod backtest;
@@ -1,16 +1,16 @@
 use std::collections::HashMap;
 
 use anyhow::Context;
-use chrono::Duration;
-use futures::StreamExt;
-use log::info;
+use chrono::{DateTime, Duration, Utc};
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedSender;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use tokio::{select, spawn};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use url::Url;
+use tokio_tungstenite::tungstenite::Message;
+use log::info;
 
-use crate::{utils::{kline_mmap::KLineMMap, strategy_utils::{show_kline_mmap, start_flush_kline_mmap, CaptureResult}}, config::{CrawlerConfig, KLineBuilderConfig}, symbol::{Symbol, SymbolType}, client::binance::WsAggTrade, global_vars::{get_debug, DebugFlag}};
+use crate::{utils::{kline_mmap::KLineMMap, strategy_utils::{ReconnectingWs, WsHandler, show_kline_mmap, start_flush_kline_mmap, CaptureResult, flush_all_kline_mmap}, shutdown::on_shutdown, metrics, time::floor_time}, config::{CrawlerConfig, KLineBuilderConfig}, symbol::{Symbol, SymbolType}, client::binance::{BinanceClient, WsAggTrade}, global_vars::{get_debug, DebugFlag}};
 
 
 
@@ -32,7 +32,16 @@ pub async fn start_crawler_binance(config: &CrawlerConfig) {
         return;
     }
 
+    // 前回止めてからの分を埋める
+    let since = KLINE_MMAP.get().unwrap().read().values().map(|m| m.mmap_read_header()).min();
+    if let Some(since) = since {
+        if let Err(e) = backfill_klines(symbol, &kline_config, since).await {
+            info!("backfill klines failed: {:?}", e);
+        }
+    }
+
     start_flush_kline_mmap(&KLINE_MMAP, symbol, &kline_config);
+    on_shutdown("flush kline mmap", || async { flush_all_kline_mmap(&KLINE_MMAP) });
 
     select! {
         _ = spawn(async move {
@@ -46,27 +55,55 @@ async fn subscribe_trades(symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>
         SymbolType::Spot => "stream".to_owned(),
         SymbolType::Perp => "fstream".to_owned(),
     };
-    let (socket, _) = connect_async(
-        Url::parse(&format!("wss://{}.binance.com/ws/{}@aggTrade", stream_name, symbol.to_native().to_lowercase())).unwrap()).await?;
-    info!("Connected to websocket");
+    // binanceはサーバーからpingが来る
+    ReconnectingWs::new(&format!("wss://{}.binance.com/ws/{}@aggTrade", stream_name, symbol.to_native().to_lowercase()))
+        .ping_interval(None)
+        .run(&mut CrawlerWsHandler { symbol, kline_config }).await
+}
+
+struct CrawlerWsHandler<'a> {
+    symbol: Symbol,
+    kline_config: &'a Vec<KLineBuilderConfig>,
+}
 
-    let (mut _write, mut read) = socket.split();
+#[async_trait]
+impl WsHandler for CrawlerWsHandler<'_> {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        handle_trades_msg(msg, &self.symbol, self.kline_config)
+    }
 
-    while let Some(msg) = read.next().await {
-        match handle_trades_msg(msg?, &symbol, kline_config) {
-            Ok(_) => (),
-            _ => continue,
+    async fn on_reconnect(&mut self, disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+        backfill_klines(self.symbol, self.kline_config, disconnected_at).await
+    }
+}
+
+/// sinceを含む足から確定済みの足をRESTで取って上書きする
+/// 未確定の足はこのあと届く約定で更新されるので触らない
+async fn backfill_klines(symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>, since: DateTime<Utc>) -> anyhow::Result<()> {
+    let client = BinanceClient::new(None);
+    for conf in kline_config {
+        let timeframe = conf.timeframe.0;
+        let end = floor_time(Utc::now(), timeframe, 0);
+        let start = floor_time(since, timeframe, 0).max(end - timeframe * conf.len as i32);
+        let klines = client.klines(symbol, timeframe, start, end).await?;
+        let mut mmaps = KLINE_MMAP.get().context("KLINE_MMAP is not initialized")?.write();
+        let mmap = mmaps.get_mut(&timeframe).unwrap();
+        for kline in &klines {
+            mmap.fill_kline(kline.opentime(), kline.to_row_data());
         }
+        info!("backfilled {} klines of {}s", klines.len(), timeframe.num_seconds());
     }
-    anyhow::bail!("Websocket disconnected");
+    Ok(())
 }
 
 fn handle_trades_msg(msg: Message, symbol: &Symbol, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
     let msg = msg.to_text()?;
     let ws_agg_trade: WsAggTrade = serde_json::from_str(msg)?;
+    let trade = ws_agg_trade.to_trade_record(*symbol)?;
+    metrics::record_trades(std::slice::from_ref(&trade));
     for conf in kline_config {
         KLINE_MMAP.get().context("KLINE_MMAP is not initialized")?.write()
-            .get_mut(&conf.timeframe.0).unwrap().update_ohlcv(&ws_agg_trade.to_trade_record(*symbol)?)?;
+            .get_mut(&conf.timeframe.0).unwrap().update_ohlcv(&trade)?;
     }
     Ok(())
 }
\ No newline at end of file
@@ -1,16 +1,16 @@
 use std::{collections::HashMap, env};
 
 use chrono::{Duration, DateTime, Utc};
-use futures::{StreamExt, SinkExt, channel::mpsc::{unbounded, UnboundedSender}};
+use async_trait::async_trait;
+use futures::{SinkExt, channel::mpsc::UnboundedSender};
 use log::info;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use serde_json::json;
 use tokio::{select, spawn};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use url::Url;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{config::{KLineBuilderConfig, CrawlerConfig}, utils::{strategy_utils::{start_send_ping, show_kline_mmap, start_flush_kline_mmap, CaptureResult, connect_into_sink}, kline_mmap::KLineMMap, time::{sleep_until_next, ScheduleExpr, UnixTimeUnit, datetime_utc_from_timestamp}, useful_traits::{StaticVarExt, StaticVarVecExt}, orderbook_repository::{OrderbookRepository, OrderbookBest, orderbook_best_time_fn}, record_writer::SerialRecordWriter, status_repository::StatusRepository, draw_orderbook::OrderbookDrawer, draw::init_terminal}, symbol::Symbol, client::{types::{MpackTradeRecord, trades_time_fn}, bitflyer::{WsResponse, ExecutionItem, BoardResult}}, data_structure::float_exp::FloatExp, order_types::Side, global_vars::{DEBUG, get_debug, DebugFlag}};
+use crate::{config::{KLineBuilderConfig, CrawlerConfig}, utils::{strategy_utils::{ReconnectingWs, WsHandler, show_kline_mmap, start_flush_kline_mmap, CaptureResult, flush_all_kline_mmap}, shutdown::on_shutdown, metrics, kline_mmap::KLineMMap, time::{sleep_until_next, ScheduleExpr, UnixTimeUnit, datetime_utc_from_timestamp}, useful_traits::{StaticVarExt, StaticVarVecExt}, orderbook_repository::{OrderbookRepository, OrderbookBest, orderbook_best_time_fn}, record_writer::SerialRecordWriter, status_repository::StatusRepository, draw_orderbook::OrderbookDrawer, draw::init_terminal}, symbol::{Symbol, Exchange}, client::{endpoints::endpoints, types::{MpackTradeRecord, TradeRecord, trades_time_fn}, bitflyer::{BitflyerClient, ExecutionsRequest, WsResponse, ExecutionItem, BoardResult}}, data_structure::float_exp::FloatExp, order_types::Side, global_vars::{DEBUG, get_debug, DebugFlag}};
 
 static KLINE_MMAP: OnceCell<RwLock<HashMap<Duration, KLineMMap>>> = OnceCell::new();
 static ORDERBOOK: OnceCell<RwLock<OrderbookRepository>> = OnceCell::new();
@@ -33,6 +33,7 @@ pub async fn start_crawler_bitflyer(config: &CrawlerConfig) {
         kline_config.iter().map(|c| (c.timeframe.0, KLineMMap::new(symbol, c.timeframe.0, c.len).unwrap())).collect()
     )).unwrap();
     ORDERBOOK.set(RwLock::new(OrderbookRepository::new(Duration::seconds(1)))).unwrap();
+    metrics::register_collector(move || metrics::record_orderbook_depth(symbol, &ORDERBOOK.read()));
     ORDERBOOK_BEST.set(RwLock::new(Vec::new())).unwrap();
     STATUS.set(RwLock::new(StatusRepository::new_init("crawler", &symbol, None).unwrap())).unwrap();
     SERVER_TIME.set(RwLock::new(ServerTimeState {
@@ -47,6 +48,10 @@ pub async fn start_crawler_bitflyer(config: &CrawlerConfig) {
     }
 
     start_flush_kline_mmap(&KLINE_MMAP, symbol, &kline_config);
+    on_shutdown("flush trade records", move || async move { flush_trade_records(symbol) });
+    // server_timeもstatusに書き出す
+    on_shutdown("flush orderbook best", move || async move { flush_orderbook_best(symbol) });
+    on_shutdown("flush kline mmap", || async { flush_all_kline_mmap(&KLINE_MMAP) });
 
     select! {
         _ = spawn(async move {
@@ -84,44 +89,74 @@ impl ServerTimeState {
 }
 
 async fn subscribe_trades(symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
-    let (socket, _) = connect_async(
-    Url::parse("wss://ws.lightstream.bitflyer.com/json-rpc").unwrap()).await?;
-    info!("Connected to websocket");
-    
-    let (mut write, mut read) = socket.split();
-    
-    let channels = vec![
+    // 再接続時もboard_snapshotから購読し直すので板は作り直される
+    let ws = [
         format!("lightning_executions_{}", symbol.to_native()),
         format!("lightning_board_snapshot_{}", symbol.to_native()),
         format!("lightning_board_{}", symbol.to_native()),
-    ];
-    
-    for channel in channels {
-        let op = serde_json::json!({
-            "method": "subscribe",
-            "params": {"channel": channel}
-        });
-
-        write.send(Message::Text(op.to_string())).await?;
-    }
-
-    let (us1, ur1) = unbounded::<Message>();
-    let (mut us2, ur2) = unbounded::<Message>();
+    ].into_iter().fold(ReconnectingWs::new(&endpoints(Exchange::Bitflyer).ws), |ws, channel| ws.subscribe(json!({
+        "method": "subscribe",
+        "params": {"channel": channel}
+    })));
+    ws.run(&mut CrawlerWsHandler {
+        symbol,
+        kline_config,
+        client: BitflyerClient::new(None),
+        last_trade_id: None,
+    }).await
+}
 
-    start_send_ping(symbol, us1).await;
+struct CrawlerWsHandler<'a> {
+    symbol: Symbol,
+    kline_config: &'a Vec<KLineBuilderConfig>,
+    client: BitflyerClient,
+    /// wsで最後に受け取った約定のid。再接続時にこれより新しい約定をRESTで埋める
+    last_trade_id: Option<i64>,
+}
 
-    connect_into_sink(symbol, write, vec![ur1, ur2]);
+#[async_trait]
+impl WsHandler for CrawlerWsHandler<'_> {
+    async fn on_message(&mut self, msg: Message, out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        if let Some(id) = handle_trades_msg(msg, &self.symbol, self.kline_config, out).await? {
+            self.last_trade_id = Some(id);
+        }
+        Ok(())
+    }
 
-    while let Some(msg) = read.next().await {
-        match handle_trades_msg(msg?, &symbol, kline_config, &mut us2).await {
-            Ok(_) => (),
-            _ => continue,
+    async fn on_reconnect(&mut self, disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+        let Some(last_trade_id) = self.last_trade_id else {
+            return Ok(());
+        };
+        let count = 500;
+        let mut items = self.client.get_public(ExecutionsRequest {
+            product_code: self.symbol.to_native(),
+            count,
+            after: Some(last_trade_id),
+        }).await?;
+        if items.len() == count as usize {
+            info!("executions since {} may be partially missing", disconnected_at);
+        }
+        items.sort_by_key(|t| t.id);
+        if let Some(last) = items.last() {
+            self.last_trade_id = Some(last.id);
         }
+        let trades = items.iter().map(|t| t.to_trade_record(self.symbol)).collect::<anyhow::Result<Vec<_>>>()?;
+        info!("backfilled {} executions after reconnect", trades.len());
+        apply_trades(trades, self.kline_config)
     }
-    anyhow::bail!("WebSocket disconnected");
 }
 
-async fn handle_trades_msg(msg: Message, symbol: &Symbol, kline_config: &Vec<KLineBuilderConfig>, write: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+fn apply_trades(trades: Vec<TradeRecord>, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
+    for conf in kline_config {
+        KLINE_MMAP.write()
+        .get_mut(&conf.timeframe.0).unwrap().update_ohlcvs(&trades)?;
+    }
+    TRADE_RECORD.write().extend(trades.into_iter().map(|t| t.mpack()));
+    Ok(())
+}
+
+/// 約定を受け取ったときはその最大のidを返す
+async fn handle_trades_msg(msg: Message, symbol: &Symbol, kline_config: &Vec<KLineBuilderConfig>, write: &mut UnboundedSender<Message>) -> anyhow::Result<Option<i64>> {
     let msg = msg.to_text()?;
     let parsed: WsResponse = serde_json::from_str(msg)?;
     if &parsed.method != "channelMessage" {
@@ -129,15 +164,13 @@ async fn handle_trades_msg(msg: Message, symbol: &Symbol, kline_config: &Vec<KLi
     }
     if parsed.params.channel == format!("lightning_executions_{}", symbol.to_native()) {
         let execution_items = serde_json::from_value::<Vec<ExecutionItem>>(parsed.params.message)?;
-        let trades = execution_items.iter().map(|t| t.to_trade_record(*symbol)).collect();
-        for conf in kline_config {
-            KLINE_MMAP.write()
-            .get_mut(&conf.timeframe.0).unwrap().update_ohlcvs(&trades)?;
-        }
-        TRADE_RECORD.write().extend(trades.into_iter().map(|t| t.mpack()));
+        let trades = execution_items.iter().map(|t| t.to_trade_record(*symbol)).collect::<Vec<_>>();
+        metrics::record_trades(&trades);
+        apply_trades(trades, kline_config)?;
         // サーバー時刻の更新
         // (ticker,)execution,boardが順序通りに受信されることは確認しているのでexecutionの時刻で確認する
         *SERVER_TIME.write() = ServerTimeState::new(execution_items.last().unwrap().exec_date);
+        return Ok(execution_items.iter().map(|t| t.id).max());
     } else if parsed.params.channel == format!("lightning_board_snapshot_{}", symbol.to_native()) {
         info!("Board snapshot received");
         let board_snapshot = serde_json::from_value::<BoardResult>(parsed.params.message)?;
@@ -184,7 +217,7 @@ async fn handle_trades_msg(msg: Message, symbol: &Symbol, kline_config: &Vec<KLi
     } else {
         anyhow::bail!("Unknown channel: {}", parsed.params.channel);
     }
-    Ok(())
+    Ok(None)
 }
 
 /// orderbook_bestをmsgpackで書き出し、stateにサーバー時刻を記録する
@@ -1,16 +1,16 @@
 use anyhow::{Context};
 use chrono::{Duration, DateTime, Utc, format};
 use std::{time::Duration as StdDuration, collections::HashMap};
-use futures::{StreamExt, SinkExt};
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedSender;
 use log::info;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use serde_json::{Value, json};
 use tokio::{select, spawn};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use url::Url;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{symbol::{Symbol, SymbolType, Exchange, Currency}, utils::{time::{sleep_until_next, ScheduleExpr, parse_format_time_utc, now_floor_time}, status_repository::StatusRepository, record_writer::{SerialRecordWriter}, strategy_utils::{start_send_ping, CaptureResult, start_flush_kline_mmap, show_kline_mmap}, useful_traits::{StaticVarExt, StaticVarVecExt}, orderbook_repository::{OrderbookRepository, OrderbookBest, orderbook_best_time_fn, apply_diff_once}, draw_orderbook::OrderbookDrawer, draw::init_terminal, kline_mmap::KLineMMap}, client::{coincheck::{CoincheckClient, KLineRequest, KLineResponse, WsResponse, OrderbookRequest}, types::{MpackTradeRecord, trades_time_fn}}, data_structure::time_queue::TimeQueue, order_types::Side, global_vars::{DEBUG, get_debug, DebugFlag}, config::{CrawlerConfig, KLineBuilderConfig}};
+use crate::{symbol::{Symbol, SymbolType, Exchange, Currency}, utils::{time::{sleep_until_next, ScheduleExpr, parse_format_time_utc, now_floor_time}, status_repository::StatusRepository, record_writer::{SerialRecordWriter}, strategy_utils::{ReconnectingWs, WsHandler, CaptureResult, start_flush_kline_mmap, show_kline_mmap, flush_all_kline_mmap}, shutdown::on_shutdown, metrics, useful_traits::{StaticVarExt, StaticVarVecExt}, orderbook_repository::{OrderbookRepository, OrderbookBest, orderbook_best_time_fn, apply_diff_once}, draw_orderbook::OrderbookDrawer, draw::init_terminal, kline_mmap::KLineMMap}, client::{endpoints::endpoints, coincheck::{CoincheckClient, KLineRequest, KLineResponse, WsResponse, OrderbookRequest, TradesRequest}, types::{MpackTradeRecord, TradeRecord, trades_time_fn}}, data_structure::time_queue::TimeQueue, order_types::Side, global_vars::{DEBUG, get_debug, DebugFlag}, config::{CrawlerConfig, KLineBuilderConfig}};
 
 static STATUS: OnceCell<RwLock<StatusRepository>> = OnceCell::new();
 static KLINE_MMAP: OnceCell<RwLock<HashMap<Duration, KLineMMap>>> = OnceCell::new();
@@ -39,6 +39,7 @@ pub async fn start_crawler_coincheck(config: &CrawlerConfig) {
     
     TRADE_RECORD.set(RwLock::new(Vec::new())).unwrap();
     ORDERBOOK.set(RwLock::new(OrderbookRepository::new(Duration::seconds(1)))).unwrap();
+    metrics::register_collector(move || metrics::record_orderbook_depth(symbol, &ORDERBOOK.read()));
     ORDERBOOK_BEST.set(RwLock::new(Vec::new())).unwrap();
     ORDERBOOK_DIFF.set(RwLock::new([TimeQueue::new(ORDERBOOK_DIFF_DURATION), TimeQueue::new(ORDERBOOK_DIFF_DURATION)])).unwrap();
 
@@ -52,6 +53,10 @@ pub async fn start_crawler_coincheck(config: &CrawlerConfig) {
     }
 
     start_flush_kline_mmap(&KLINE_MMAP, symbol, &kline_config);
+    on_shutdown("flush trade records", move || async move { flush_trade_records(symbol) });
+    on_shutdown("flush orderbook best", move || async move { flush_orderbook_best(symbol) });
+    on_shutdown("flush kline mmap", || async { flush_all_kline_mmap(&KLINE_MMAP) });
+    on_shutdown("flush status", || async { STATUS.read().flush() });
 
     select! {
         // 1min klineの保存
@@ -68,10 +73,10 @@ pub async fn start_crawler_coincheck(config: &CrawlerConfig) {
         }) => {}
         _ = spawn(async move {
             let client = CoincheckClient::new(None);
-            replace_orderbook_state(&client).await.capture_result(symbol).await.unwrap();
+            replace_orderbook_state(&client, symbol).await.capture_result(symbol).await.unwrap();
             loop {
                 sleep_until_next(ScheduleExpr::new(Duration::minutes(1), Duration::minutes(0))).await;
-                replace_orderbook_state(&client).await.capture_result(symbol).await.unwrap();
+                replace_orderbook_state(&client, symbol).await.capture_result(symbol).await.unwrap();
             }
         }) => {}
         // trades,orderbookのファイル出力
@@ -129,8 +134,8 @@ async fn fetch_kline(symbol: Symbol, client: &CoincheckClient) -> anyhow::Result
 }
 
 /// orderbookのsnapshotを取得して直近の差分をすべて適用する
-async fn replace_orderbook_state(client: &CoincheckClient) -> anyhow::Result<()> {
-    let res = client.get_public(OrderbookRequest {}).await?;
+async fn replace_orderbook_state(client: &CoincheckClient, symbol: Symbol) -> anyhow::Result<()> {
+    let res = client.get_public(OrderbookRequest { pair: symbol }).await?;
     let mut snapshot = vec![];
     for &side in &[Side::Buy, Side::Sell] {
         snapshot.push(apply_diff_once(
@@ -143,47 +148,77 @@ async fn replace_orderbook_state(client: &CoincheckClient) -> anyhow::Result<()>
 }
 
 async fn subscribe_ws(symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
-    let (socket, _) =
-        connect_async(Url::parse("wss://ws-api.coincheck.com/").unwrap()).await?;
-    info!("Connected to websocket");
-
-    let (mut write, mut read) = socket.split();
-
-    let channels = vec![
+    let ws = [
         format!("{}-trades", symbol.to_native()),
         format!("{}-orderbook", symbol.to_native()),
-    ];
-    
-    for channel in channels {
-        let op = serde_json::json!({
-            "type": "subscribe",
-            "channel": channel,
-        });
-        write.send(Message::Text(op.to_string())).await?;
-    }
+    ].into_iter().fold(ReconnectingWs::new(&endpoints(Exchange::Coincheck).ws), |ws, channel| ws.subscribe(json!({
+        "type": "subscribe",
+        "channel": channel,
+    })));
+    ws.run(&mut CrawlerWsHandler {
+        symbol,
+        kline_config,
+        client: CoincheckClient::new(None),
+        last_trade_id: None,
+    }).await
+}
 
-    start_send_ping(symbol, write).await;
+struct CrawlerWsHandler<'a> {
+    symbol: Symbol,
+    kline_config: &'a Vec<KLineBuilderConfig>,
+    client: CoincheckClient,
+    /// wsで最後に受け取った約定のid。再接続時にこれより新しい約定をRESTで埋める
+    last_trade_id: Option<i64>,
+}
 
-    while let Some(msg) = read.next().await {
-        match handle_ws_msg(msg?, symbol, kline_config) {
-            Ok(_) => (),
-            Err(_) => continue,
+#[async_trait]
+impl WsHandler for CrawlerWsHandler<'_> {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        if let Some(id) = handle_ws_msg(msg, self.symbol, self.kline_config)? {
+            self.last_trade_id = Some(id);
         }
+        Ok(())
     }
-    anyhow::bail!("WebSocket disconnected");
+
+    async fn on_reconnect(&mut self, disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+        if let Some(last_trade_id) = self.last_trade_id {
+            let res = self.client.get_public(TradesRequest { pair: self.symbol, limit: 100 }).await?;
+            let mut items = res.data.into_iter().filter(|t| t.id > last_trade_id).collect::<Vec<_>>();
+            items.sort_by_key(|t| t.id);
+            if items.len() == 100 && items[0].id > last_trade_id + 1 {
+                info!("trades since {} may be partially missing", disconnected_at);
+            }
+            if let Some(last) = items.last() {
+                self.last_trade_id = Some(last.id);
+            }
+            let trades = items.iter().map(|t| t.to_trade_record(self.symbol)).collect::<Vec<_>>();
+            info!("backfilled {} trades after reconnect", trades.len());
+            apply_trades(&trades, self.kline_config)?;
+        }
+        // 切断中の板の差分は取れないので取り直す
+        replace_orderbook_state(&self.client, self.symbol).await
+    }
+}
+
+fn apply_trades(trades: &Vec<TradeRecord>, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
+    for conf in kline_config {
+        KLINE_MMAP.write()
+        .get_mut(&conf.timeframe.0).unwrap().update_ohlcvs(trades)?;
+    }
+    TRADE_RECORD.write().extend(trades.iter().cloned().map(|x| x.mpack()));
+    Ok(())
 }
 
-fn handle_ws_msg(msg: Message, symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<()> {
+/// 約定を受け取ったときはその最大のidを返す
+fn handle_ws_msg(msg: Message, symbol: Symbol, kline_config: &Vec<KLineBuilderConfig>) -> anyhow::Result<Option<i64>> {
     let msg = msg.to_text()?;
     let parsed = serde_json::from_str::<WsResponse>(msg)?;
     match parsed {
         WsResponse::Trade(trade) => {
             let trades = trade.to_trade_records()?;
-            for conf in kline_config {
-                KLINE_MMAP.write()
-                .get_mut(&conf.timeframe.0).unwrap().update_ohlcvs(&trades)?;
-            }
-            TRADE_RECORD.write().extend(trades.iter().cloned().map(|x| x.mpack()));
+            metrics::record_trades(&trades);
+            apply_trades(&trades, kline_config)?;
+            return Ok(trade.max_id());
         },
         WsResponse::Orderbook(res) => {
             let mut orderbook = ORDERBOOK.write();
@@ -209,7 +244,7 @@ fn handle_ws_msg(msg: Message, symbol: Symbol, kline_config: &Vec<KLineBuilderCo
             }
         }
     }
-    Ok(())
+    Ok(None)
 }
 
 /// msgpackで出力
@@ -1,15 +1,14 @@
 use chrono::Duration;
-use futures::{StreamExt, SinkExt};
-use log::info;
+use async_trait::async_trait;
+use futures::channel::mpsc::UnboundedSender;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use polars::export::ahash::HashMap;
 use serde_json::json;
 use tokio::{select, spawn};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use url::Url;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{config::CrawlerConfig, utils::{orderbook_repository::{OrderbookBest, OrderbookRepository, orderbook_best_time_fn}, strategy_utils::CaptureResult, useful_traits::{TupledResultTranspose, StaticVarExt, StaticVarHashVecExt}, time::{sleep_until_next, ScheduleExpr}, record_writer::SerialRecordWriter, draw_orderbook::OrderbookDrawer, draw::init_terminal}, client::gmo::{WsResponse, OrderbooksResult, WsOkResponse}, symbol::Symbol, data_structure::float_exp::FloatExp, error_types::BotError, global_vars::{get_debug, DebugFlag}};
+use crate::{config::CrawlerConfig, utils::{orderbook_repository::{OrderbookBest, OrderbookRepository, orderbook_best_time_fn}, strategy_utils::{CaptureResult, ReconnectingWs, WsHandler}, useful_traits::{TupledResultTranspose, StaticVarExt, StaticVarHashVecExt}, time::{sleep_until_next, ScheduleExpr}, record_writer::SerialRecordWriter, draw_orderbook::OrderbookDrawer, draw::init_terminal, shutdown::on_shutdown, metrics}, client::{gmo::{WsResponse, OrderbooksResult, WsOkResponse}, endpoints::endpoints}, symbol::{Symbol, Exchange}, data_structure::float_exp::FloatExp, error_types::BotError, global_vars::{get_debug, DebugFlag}};
 
 // HashMap自体はVecへの書き込み時もreadしか要求しないので並列でアクセスできるはず
 // https://stackoverflow.com/questions/50282619/is-it-possible-to-share-a-hashmap-between-threads-without-locking-the-entire-has
@@ -26,6 +25,7 @@ pub async fn start_crawler_gmo(config: &'static CrawlerConfig) {
         ORDERBOOK_DRAWER.set(RwLock::new(OrderbookDrawer::new(0, 0, config.symbols.clone()))).unwrap();
         init_terminal().unwrap();
     }
+    on_shutdown("flush orderbook best", move || async move { flush_orderbook_best(config) });
 
     select! {
         _ = spawn(async move {
@@ -41,30 +41,29 @@ pub async fn start_crawler_gmo(config: &'static CrawlerConfig) {
 }
 
 async fn subscribe_ws(config: &CrawlerConfig) -> anyhow::Result<()> {
-    let (socket, _) = connect_async(Url::parse("wss://api.coin.z.com/ws/public/v1")?).await?;
-    info!("Connected to websocket");
-
-    let (mut write, mut read) = socket.split();
-
-    for &symbol in &config.symbols {
-        write.send(Message::Text(json!({
-            "command": "subscribe",
-            "channel": "orderbooks",
-            "symbol": symbol.to_native(),
-        }).to_string())).await?;
+    // 板は毎回全体が配信されるので再接続時に埋めるものはない
+    let ws = config.symbols.iter().fold(ReconnectingWs::new(&endpoints(Exchange::Gmo).ws), |ws, symbol| ws.subscribe(json!({
+        "command": "subscribe",
+        "channel": "orderbooks",
+        "symbol": symbol.to_native(),
+    })))
         // 連続でsubscribeすると無視されるので少し待つ
-        tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
-    }
+        .subscribe_interval(std::time::Duration::from_millis(2000))
+        // gmoはサーバーからpingが来る
+        .ping_interval(None)
+        .liveness_timeout(std::time::Duration::from_secs(120));
+    ws.run(&mut CrawlerWsHandler { config }).await
+}
 
-    while let Some(msg) = read.next().await {
-        match handle_ws_msg(msg?, config).await {
-            Ok(_) => (),
-            Err(e) if e.is::<BotError>() => return Err(e),
-            _ => continue,
-        }
-    }
+struct CrawlerWsHandler<'a> {
+    config: &'a CrawlerConfig,
+}
 
-    anyhow::bail!("Websocket disconnected");
+#[async_trait]
+impl WsHandler for CrawlerWsHandler<'_> {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        handle_ws_msg(msg, self.config).await
+    }
 }
 
 async fn handle_ws_msg(msg: Message, _config: &CrawlerConfig) -> anyhow::Result<()> {
@@ -85,6 +84,7 @@ async fn handle_ws_msg(msg: Message, _config: &CrawlerConfig) -> anyhow::Result<
                 orderbooks.bids.into_iter().map(|x| (x.price.into(), x.size.into())).collect(),
                 orderbooks.asks.into_iter().map(|x| (x.price.into(), x.size.into())).collect(),
             ]);
+            metrics::record_orderbook_depth(symbol, &repo);
             ORDERBOOK_BEST.push(
                 symbol,
                 OrderbookBest::new(
@@ -97,6 +97,7 @@ async fn handle_ws_msg(msg: Message, _config: &CrawlerConfig) -> anyhow::Result<
                 ORDERBOOK_DRAWER.write().print_orderbook(repo.get_best(), symbol)?;
             }
         }
+        WsOkResponse::Trades(_) => {}
     }
     Ok(())
 }
@@ -3,5 +3,4 @@ pub mod crawler_coincheck;
 pub mod crawler_bitflyer;
 pub mod crawler_binance;
 pub mod crawler_gmo;
-pub mod tracingmm_bitflyer;
-pub mod tracingmm_coincheck;
+pub mod tracingmm;
@@ -1,37 +1,44 @@
+use std::sync::Arc;
+
 use anyhow::Context;
+use async_trait::async_trait;
 use chrono::Duration;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::{FutureExt, StreamExt, future::pending};
 use log::info;
-use maplit::hashmap;
 use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use anyhow::Result;
-use serde_json::Value;
-use serde_json::json;
 use tap::Pipe;
 use tokio::select;
 use tokio::spawn;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::client::credentials::CREDENTIALS;
-use crate::client::gmo::AccountAssets;
-use crate::client::gmo::AccountAssetsRequest;
-use crate::client::gmo::CreateOrderRequest;
-use crate::client::gmo::GmoClient;
-use crate::client::gmo::GmoClientResponse;
-use crate::client::gmo::GmoTimeInForce;
-use crate::client::gmo::Tickers;
-use crate::client::mail::send_mail;
-use crate::client::method::EmptyQueryRequest;
+use crate::client::exchange::ExchangeClient;
+use crate::client::exchange::NewOrder;
+use crate::client::exchange::private_client;
+use crate::client::exchange::subscribe_private_events;
+use crate::client::exchange::{PrivateEvent, OrderStatus};
+use crate::client::endpoints::endpoints;
+use crate::client::gmo;
+use crate::client::paper::PaperClient;
 use crate::config::ShannonConfig;
 use crate::config::VirtualAmount;
 use crate::data_structure::float_exp::FloatExp;
 use crate::data_structure::num_utils::ceil_int;
 use crate::data_structure::num_utils::floor_int;
-use crate::error_types::BotError;
-use crate::order_types::OrderType;
+use crate::error_types::{ErrorAction, error_action};
+use crate::global_vars::get_paper;
 use crate::order_types::Side;
-use crate::symbol::{Symbol};
+use crate::symbol::{Symbol, Exchange};
+use crate::utils::alert::{alert, alert_and_wait, Severity};
+use crate::utils::risk::RiskClient;
+use crate::utils::control;
 use crate::utils::time::ScheduleExpr;
 use crate::utils::time::sleep_until_next;
+use crate::utils::shutdown::on_shutdown;
+use crate::utils::strategy_utils::ReconnectingWs;
+use crate::utils::strategy_utils::WsHandler;
 
 static BALANCE: OnceCell<RwLock<Balance>> = OnceCell::new();
 
@@ -41,35 +48,98 @@ pub async fn start_shannon_gmo(config: &ShannonConfig) {
     let symbol_ref1 = config.symbol.clone();
     let virtual_amount_ref = config.virtual_amount.clone();
 
-    let client = GmoClient::new(Some(CREDENTIALS.gmo.clone()));
+    // paperでは本番の実現損益を上書きしない
+    let risk_name = if get_paper().is_some() { "paper_shannon" } else { "shannon" };
+    let (inner, paper): (Arc<dyn ExchangeClient>, _) = match get_paper() {
+        Some(args) => {
+            let paper = Arc::new(PaperClient::new("shannon", config.symbol, args).unwrap());
+            (paper.clone(), Some(paper))
+        },
+        None => (private_client(config.symbol.exc).unwrap(), None),
+    };
+    // 発注はすべてリスク制限を通す
+    let risk = Arc::new(RiskClient::new(risk_name, inner, config.symbol, config.risk.clone()).unwrap());
+    risk.start_kill_switch();
+    risk.register_metrics();
+    control::register(risk.clone());
+    let client: Arc<dyn ExchangeClient> = risk.clone();
     let virtual_amount = virtual_amount_ref.clone();
+    let shutdown_client = client.clone();
+    let shutdown_symbol = config.symbol.clone();
+    on_shutdown("cancel all orders", move || {
+        let client = shutdown_client.clone();
+        async move { client.cancel_all_orders(shutdown_symbol).await }
+    });
+    let symbol_ref2 = config.symbol.clone();
+    let symbol_ref3 = config.symbol.clone();
     
+    // 約定したらすぐ指値を置き直す。paperでは注文イベントが来ないので定期実行だけ
+    let (tx, rx) = unbounded();
+    let paper_events = paper.clone();
+    let mut fills = rx
+        .inspect(move |event| if let PrivateEvent::Execution(execution) = event { risk.on_execution(execution) })
+        .filter(move |event| futures::future::ready(is_fill(event, &symbol_ref1))).boxed();
+    let symbol = config.symbol.clone();
     select! {
         _ = spawn(async move {
-            let symbol = symbol_ref1.clone();
             loop {
-                sleep_until_next(ScheduleExpr::new(Duration::hours(8), Duration::minutes(0))).await;
-                update_assets(&client, &symbol).await.pipe(capture_result(&symbol));
-                cancel_all_orders(&client, &symbol).await.pipe(capture_result(&symbol));
+                select! {
+                    _ = sleep_until_next(ScheduleExpr::new(Duration::hours(8), Duration::minutes(0))) => {},
+                    Some(event) = fills.next() => {
+                        info!("fill: {:?}", event);
+                        // まとめて来た約定は1回の置き直しで足りる
+                        while let Some(Some(_)) = fills.next().now_or_never() {}
+                    },
+                }
+                capture_result(update_assets(&client, &symbol).await, &symbol).await;
+                capture_result(cancel_all_orders(&client, &symbol).await, &symbol).await;
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                create_order(&client, &symbol, &virtual_amount).await.pipe(capture_result(&symbol));
+                capture_result(create_order(&client, &symbol, &virtual_amount).await, &symbol).await;
+            }
+        }) => {}
+        _ = spawn(async move {
+            // select!の条件では式の評価は止まらないので、paperかどうかはタスクの中で見る
+            // paperの約定もリスク計算に渡す
+            if let Some(paper) = paper_events {
+                paper.subscribe_private(tx);
+                return pending().await;
             }
+            let symbol = symbol_ref3;
+            capture_result(subscribe_private_events(symbol.exc, tx).await, &symbol).await;
+        }) => {}
+        _ = spawn(async move {
+            // paperでは約定履歴で指値を約定させる
+            let Some(paper) = paper else {
+                return pending().await;
+            };
+            let symbol = symbol_ref2.clone();
+            capture_result(subscribe_paper_trades(&paper, &symbol).await, &symbol).await;
         }) => {}
     }
 }
 
-fn capture_result(symbol: &Symbol) -> impl Fn(Result<()>) + '_ {
-        let l =  |result: Result<()>| {
-            match &result {
-                Ok(_) => (),
-                Err(e) if matches!(e.downcast_ref::<BotError>(), Some(BotError::Maintenance)) => info!("Maintenance status found"),
-                Err(e) => {
-                    send_mail(format!("{} - {} {}", e, symbol.exc, symbol.to_native()), format!("{:?}", e)).unwrap();
-                    result.unwrap()
-                },
-            }
-        };
-        l
+/// 指値が全部約定した
+fn is_fill(event: &PrivateEvent, symbol: &Symbol) -> bool {
+    matches!(event, PrivateEvent::Order(order) if order.symbol == *symbol && order.status == OrderStatus::Filled)
+}
+
+/// 落ちる前にメールを送り終えるので、tokioのworkerを止めないようawaitする
+async fn capture_result(result: Result<()>, symbol: &Symbol) {
+    let Err(e) = &result else {
+        return;
+    };
+    match error_action(e) {
+        ErrorAction::Ignore => info!("{}", e),
+        ErrorAction::Alert => alert(Severity::Warning, format!("{} - {} {}", e, symbol.exc, symbol.to_native()), format!("{:?}", e)),
+        ErrorAction::Restart => {
+            info!("{}", e);
+            result.unwrap()
+        },
+        ErrorAction::Fatal => {
+            alert_and_wait(Severity::Error, format!("{} - {} {}", e, symbol.exc, symbol.to_native()), format!("{:?}", e)).await;
+            result.unwrap()
+        },
+    }
 }
 
 #[derive(Debug)]
@@ -87,28 +157,24 @@ impl Balance {
     }
 }
 
-async fn update_assets(client: &GmoClient, symbol: &Symbol) -> Result<()> {
+async fn update_assets(client: &Arc<dyn ExchangeClient>, symbol: &Symbol) -> Result<()> {
     info!("update_assets");
-    let assets: GmoClientResponse<AccountAssets> = client.get_private("/v1/account/assets", AccountAssetsRequest {}).await?;
-    for asset in assets.into_result()? {
-        if asset.symbol == symbol.base.to_string() {
-            BALANCE.get().context("BALANCE failed")?.write().base = asset.amount.parse::<f64>()?.pipe(|x| FloatExp::from_f64(x, symbol.amount_precision()));
-        } else if asset.symbol == symbol.quote.to_string() {
-            BALANCE.get().context("BALANCE failed")?.write().quote = asset.amount.parse::<i64>()?.pipe(|x| FloatExp::new(x, 0));
+    for asset in client.balances().await? {
+        if asset.currency == symbol.base {
+            BALANCE.get().context("BALANCE failed")?.write().base = asset.total.pipe(|x| FloatExp::from_f64(x, symbol.amount_precision()));
+        } else if asset.currency == symbol.quote {
+            BALANCE.get().context("BALANCE failed")?.write().quote = asset.total.pipe(|x| FloatExp::from_f64(x, 0));
         }
     }
     Ok(())
 }
 
-async fn cancel_all_orders(client: &GmoClient, symbol: &Symbol) -> Result<()> {
-    let orders: GmoClientResponse<Value> = client.post("/v1/cancelBulkOrder", &json!({"symbols": [symbol.to_native()]})).await?;
-    orders.into_result()?;
-    Ok(())
+async fn cancel_all_orders(client: &Arc<dyn ExchangeClient>, symbol: &Symbol) -> Result<()> {
+    client.cancel_all_orders(*symbol).await
 }
 
-async fn create_order(client: &GmoClient, symbol: &Symbol, virtual_amount: &VirtualAmount) -> Result<()> {
-    let ticker: GmoClientResponse<Tickers> = client.get_public("/v1/ticker", hashmap! {"symbol".to_owned() => symbol.to_native()}).await?;
-    let last_price = ticker.into_result()?.first().unwrap().last.parse::<i64>()?;
+async fn create_order(client: &Arc<dyn ExchangeClient>, symbol: &Symbol, virtual_amount: &VirtualAmount) -> Result<()> {
+    let last_price = client.ticker(*symbol).await?.last.round(0).to_i64();
     let mut handles = vec![];
     for &side in &[Side::Buy, Side::Sell] {
         let base_amount = BALANCE.get().context("BALANCE failed")?.read().base + virtual_amount.base;
@@ -137,26 +203,13 @@ async fn create_order(client: &GmoClient, symbol: &Symbol, virtual_amount: &Virt
         if amount.value == 0 {
             continue;
         }
-        let order = CreateOrderRequest {
-            symbol: symbol.clone(),
-            side,
-            execution_type: OrderType::Limit,
-            size: format!("{}", amount),
-            price: format!("{}", target_price),
-            time_in_force: Some(GmoTimeInForce::SOK),
-        };
+        let order = NewOrder::limit(symbol.clone(), side, FloatExp::new(target_price, 0), amount).post_only();
         let c = client.clone();
-        info!("send order: {}", serde_json::to_string(&order)?);
+        info!("send order: {:?}", order);
         handles.push(tokio::spawn(async move {
-            let order_id: GmoClientResponse<String> = match c.post("/v1/order", &order).await {
-                Ok(x) => x,
-                Err(e) => return Err(e),
-            };
-            match order_id.into_result() {
-                Ok(x) => info!("order_id: {}", x),
-                Err(e) => return Err(e),
-            }
-            Ok(())
+            let order_id = c.place_order(&order).await?;
+            info!("order_id: {}", order_id);
+            Ok::<_, anyhow::Error>(())
         }));
     }
     for handle in handles {
@@ -165,3 +218,29 @@ async fn create_order(client: &GmoClient, symbol: &Symbol, virtual_amount: &Virt
     
     Ok(())
 }
+
+async fn subscribe_paper_trades(paper: &PaperClient, symbol: &Symbol) -> Result<()> {
+    ReconnectingWs::new(&endpoints(Exchange::Gmo).ws)
+        .subscribe(serde_json::json!({
+            "command": "subscribe",
+            "channel": "trades",
+            "symbol": symbol.to_native(),
+        }))
+        .ping_interval(None)
+        .liveness_timeout(std::time::Duration::from_secs(120))
+        .run(&mut PaperTradesHandler { paper }).await
+}
+
+struct PaperTradesHandler<'a> {
+    paper: &'a PaperClient,
+}
+
+#[async_trait]
+impl WsHandler for PaperTradesHandler<'_> {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> Result<()> {
+        if let gmo::WsResponse::Ok(gmo::WsOkResponse::Trades(trade)) = serde_json::from_str::<gmo::WsResponse>(msg.to_text()?)? {
+            self.paper.on_trades(&[trade.to_trade_record()]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,677 @@
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{Duration, DateTime, Utc};
+use futures::{future::{join_all, pending}, channel::mpsc::{UnboundedSender, unbounded}, StreamExt};
+use log::{info, error};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use tokio::{join, select, spawn, try_join};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{config::{TracingMMConfig, TracingMMHooks, TracingMMTunables, FireSource, Strategy, read_strategy}, utils::{alert::{alert, Severity}, metrics, status_repository::StatusRepository, kline_mmap::KLineMMap, tracingmm_utils::{tracing_price, read_kline, TracingPriceResult, OrderSizing, plan_orders, TracingMMOrder, TracingMMOrderKind, MAPPING_SIZE}, reserved_orders::{ReservedOrdersManager, ReservedOrder}, order_manager::OrderManager, risk::{RiskClient, is_killed, is_paused}, control::{self, Controllable}, config_watcher::{watch_strategy_config, WATCH_INTERVAL}, time::{ScheduleExpr, sleep_until_next, now_floor_time}, useful_traits::StaticVarExt, strategy_utils::{CaptureResult, update_assets_inner, ReconnectingWs, WsHandler}, orderbook_repository::{OrderbookRepository, apply_diff_once}, draw_orderbook::OrderbookDrawer, draw::init_terminal, shutdown::on_shutdown}, client::{exchange::{ExchangeClient, NewOrder, OrderId, private_client, subscribe_private_events, has_private_events, register_tracked_order_ids, PrivateEvent}, types::{KLines, TradeRecord}, paper::PaperClient, endpoints::endpoints, bitflyer, coincheck, gmo}, symbol::{Symbol, Exchange}, data_structure::time_queue::TimeQueue, order_types::{Side, OrderType}, error_types::BotError, global_vars::{get_debug, get_paper, DebugFlag}};
+
+static HOOKS: OnceCell<TracingMMHooks> = OnceCell::new();
+/// 起動時の設定のコピー。調整用の値だけtimeframeの切り替わりで差し替える
+static CONFIG: OnceCell<RwLock<TracingMMConfig>> = OnceCell::new();
+/// 読み直して検証済みの、次のtimeframeから使う値
+static PENDING_TUNABLES: OnceCell<RwLock<Option<TracingMMTunables>>> = OnceCell::new();
+static CLIENT: OnceCell<Arc<dyn ExchangeClient>> = OnceCell::new();
+/// --paperのときだけ
+static PAPER: OnceCell<Arc<PaperClient>> = OnceCell::new();
+/// CLIENTと同じもの。発注はすべてここを通る
+static RISK: OnceCell<Arc<RiskClient>> = OnceCell::new();
+
+static STATUS: OnceCell<RwLock<StatusRepository>> = OnceCell::new();
+static KLINE: OnceCell<RwLock<KLineMMap>> = OnceCell::new();
+static REF_KLINE: OnceCell<RwLock<KLineMMap>> = OnceCell::new();
+static SPOT_KLINE: OnceCell<RwLock<KLineMMap>> = OnceCell::new(); // sfd
+static ORDERS: OnceCell<RwLock<OrderManager>> = OnceCell::new();
+static RESERVED: OnceCell<RwLock<ReservedOrdersManager>> = OnceCell::new();
+
+static ORDERBOOK: OnceCell<RwLock<OrderbookRepository>> = OnceCell::new();
+/// Buy, Sellの順に(価格, 数量の変化)
+type OrderbookDiff = [TimeQueue<(f64, f64)>; 2];
+static ORDERBOOK_DIFF: OnceCell<RwLock<OrderbookDiff>> = OnceCell::new();
+
+// for debug
+static ORDERBOOK_DRAWER: OnceCell<RwLock<OrderbookDrawer>> = OnceCell::new();
+
+const ORDERBOOK_DIFF_DURATION: StdDuration = StdDuration::from_secs(5);
+
+/// orderbook_nthとして指定できる最大値
+const ORDERBOOK_MAX_NTH: usize = 10;
+
+#[inline]
+fn client() -> Arc<dyn ExchangeClient> {
+    CLIENT.get().unwrap().clone()
+}
+
+#[inline]
+fn hooks() -> &'static TracingMMHooks {
+    HOOKS.get().unwrap()
+}
+
+#[inline]
+fn config() -> TracingMMConfig {
+    CONFIG.read().clone()
+}
+
+pub async fn start_tracingmm(config: &'static TracingMMConfig) {
+    let tracing_hooks = config.hooks();
+    if tracing_hooks.fire_source == FireSource::Orderbook && !(1..=ORDERBOOK_MAX_NTH).contains(&tracing_hooks.orderbook_nth) {
+        panic!("orderbook_nth must be in 1..={}", ORDERBOOK_MAX_NTH);
+    }
+    info!("start tracingmm. symbol: {:?}, hooks: {:?}, paper: {:?}", config.symbol, tracing_hooks, get_paper());
+
+    // paperでは本番のステータスを上書きしない
+    let status_name = if get_paper().is_some() { "paper_tracingmm" } else { "tracingmm" };
+    STATUS.set(RwLock::new(StatusRepository::new_init(status_name, &config.symbol, Some(Duration::days(3))).unwrap())).unwrap();
+    KLINE.set(RwLock::new(KLineMMap::new(config.symbol, config.timeframe.0, 300).unwrap())).unwrap();
+    REF_KLINE.set(RwLock::new(KLineMMap::new(config.ref_symbol, config.timeframe.0, 300).unwrap())).unwrap();
+    if let Some(sfd) = &tracing_hooks.sfd {
+        SPOT_KLINE.set(RwLock::new(KLineMMap::new(sfd.spot_symbol, config.timeframe.0, 300).unwrap())).unwrap();
+    }
+    ORDERS.set(RwLock::new(OrderManager::new(config.symbol))).unwrap();
+    register_tracked_order_ids(|_| ORDERS.read().tracked_order_ids());
+    // 予約注文は落ちても残るようにjournalに書き出す
+    RESERVED.set(RwLock::new(ReservedOrdersManager::open(status_name, &config.symbol, config.symbol.price_precision()).unwrap())).unwrap();
+
+    ORDERBOOK.set(RwLock::new(OrderbookRepository::new(Duration::seconds(1)))).unwrap();
+    ORDERBOOK_DIFF.set(RwLock::new([TimeQueue::new(ORDERBOOK_DIFF_DURATION), TimeQueue::new(ORDERBOOK_DIFF_DURATION)])).unwrap();
+
+    if get_debug()==DebugFlag::Orderbook {
+        ORDERBOOK_DRAWER.set(RwLock::new(OrderbookDrawer::new(0, 0, vec![config.symbol]))).unwrap();
+        init_terminal().unwrap();
+    }
+
+    let inner: Arc<dyn ExchangeClient> = match get_paper() {
+        Some(args) => {
+            let paper = Arc::new(PaperClient::new("tracingmm", config.symbol, args).unwrap());
+            PAPER.set(paper.clone()).ok().unwrap();
+            paper
+        },
+        None => private_client(config.symbol.exc).unwrap(),
+    };
+    let risk = Arc::new(RiskClient::new(status_name, inner, config.symbol, config.risk.clone()).unwrap());
+    risk.start_kill_switch();
+    risk.register_metrics();
+    RISK.set(risk.clone()).ok().unwrap();
+    CLIENT.set(risk).ok().unwrap();
+    HOOKS.set(tracing_hooks).unwrap();
+    CONFIG.set(RwLock::new(config.clone())).unwrap();
+    PENDING_TUNABLES.set(RwLock::new(None)).unwrap();
+    control::register(Arc::new(TracingMMControl { symbol: config.symbol }));
+    if let Ok(name) = std::env::var("NAME") {
+        spawn(async move { watch_strategy_config(&name, WATCH_INTERVAL, on_config_change).await });
+    }
+
+    let symbol = config.symbol;
+    metrics::register_collector(move || {
+        metrics::set_symbol_gauge("bot_open_orders", symbol, ORDERS.read().active_orders().count() as f64);
+        metrics::record_orderbook_depth(symbol, &ORDERBOOK.read());
+    });
+    on_shutdown("cancel all orders", move || cancel_all_orders(symbol));
+    on_shutdown("flush status", || async { STATUS.read().flush() });
+
+    reconcile_reserved_orders(symbol).await.capture_result(symbol).await.unwrap();
+
+    let cancel_ahead = Duration::seconds(1);
+
+    select! {
+        _ = spawn(async move {
+            loop {
+                sleep_until_next(ScheduleExpr::new_ahead(config.timeframe.0, cancel_ahead)).await;
+                cancel_all_orders(symbol).await.capture_result(symbol).await.unwrap();
+                tokio::time::sleep(cancel_ahead.to_std().unwrap()).await;
+                apply_pending_tunables().await.capture_result(symbol).await.unwrap();
+                update_order(&self::config()).await.capture_result(symbol).await.unwrap();
+            }
+        }) => {}
+        _ = spawn(async move {
+            reconcile_position_and_assets(&self::config()).await.capture_result(symbol).await.unwrap();
+            loop {
+                // coincheckではupdate_orderとnonceが被らないようにずらす
+                sleep_until_next(ScheduleExpr::new(Duration::hours(1), Duration::minutes(7) + Duration::seconds(15))).await;
+                reconcile_position_and_assets(&self::config()).await.capture_result(symbol).await.unwrap();
+            }
+        }) => {}
+        _ = spawn(async move {
+            // gmoは毎回全体が配信されるのでsnapshotの取り直しは不要
+            if subscribe_orderbook(symbol) && symbol.exc != Exchange::Gmo {
+                start_replace_orderbook_state(symbol);
+            }
+            subscribe_market(symbol).await.capture_result(symbol).await.unwrap();
+        }) => {}
+        _ = spawn(async move {
+            // select!の条件では式の評価は止まらないので、ここで判定する
+            if PAPER.get().is_none() && !is_event_driven(symbol) {
+                return pending().await;
+            }
+            subscribe_private(symbol).await.capture_result(symbol).await.unwrap();
+        }) => {}
+    }
+}
+
+/// config.bot.yamlの変更を検証し、次のtimeframeの切り替わりで反映する。反映できなければメールで知らせる
+fn on_config_change(strategy: Strategy) {
+    let symbol = CONFIG.read().symbol;
+    let res = match strategy {
+        Strategy::TracingMm(next) => stage_tunables(&next).map(|_| ()),
+        _ => Err(anyhow::anyhow!("strategy is no longer tracing_mm")),
+    };
+    if let Err(e) = res {
+        error!("config reload refused: {:?}", e);
+        alert(Severity::Warning, format!("config reload refused - {} {}", symbol.exc, symbol.to_native()), format!("{:?}", e));
+    }
+}
+
+/// 調整用の値を検証して積み、変わる項目を返す。変わらなければ積んであったものも捨てる
+fn stage_tunables(next: &TracingMMConfig) -> anyhow::Result<Vec<String>> {
+    let current = config();
+    let tunables = current.reload_tunables(next)?;
+    let diff = current.tunables().diff(&tunables);
+    if diff.is_empty() {
+        info!("no tunable changes");
+        *PENDING_TUNABLES.write() = None;
+    } else {
+        info!("tunables will change at the next timeframe: {}", diff.join(", "));
+        *PENDING_TUNABLES.write() = Some(tunables);
+    }
+    Ok(diff)
+}
+
+/// timeframeの切り替わりでupdate_orderの前に呼ぶ
+/// 発注量に効く値が変わったら、1時間ごとの更新を待たずに資産状況を計算し直す
+async fn apply_pending_tunables() -> anyhow::Result<()> {
+    let pending = PENDING_TUNABLES.write().take();
+    let Some(tunables) = pending else {
+        return Ok(());
+    };
+    let (config, changes_sizing) = {
+        let mut config = CONFIG.write();
+        info!("apply tunables: {}", config.tunables().diff(&tunables).join(", "));
+        let changes_sizing = config.tunables().changes_sizing(&tunables);
+        *config = config.with_tunables(tunables);
+        (config.clone(), changes_sizing)
+    };
+    if changes_sizing {
+        update_assets(&config).await?;
+    }
+    Ok(())
+}
+
+async fn cancel_all_orders(symbol: Symbol) -> anyhow::Result<()> {
+    RESERVED.write().cancel_all_orders();
+    client().cancel_all_orders(symbol).await?;
+    info!("cancel all orders");
+    Ok(())
+}
+
+/// journalから復元した予約注文を実際の注文・建玉と突き合わせる
+async fn reconcile_reserved_orders(symbol: Symbol) -> anyhow::Result<()> {
+    if RESERVED.read().reserved_orders.is_empty() {
+        return Ok(());
+    }
+    let client = client();
+    let (open_orders, positions) = try_join!(
+        client.open_orders(symbol),
+        client.positions(symbol)
+    )?;
+    let removed = RESERVED.write().reconcile(&open_orders, &positions);
+    info!("reconcile reserved orders. removed: {:?}, remaining: {:?}", removed, RESERVED.read().reserved_orders.values().collect::<Vec<_>>());
+    Ok(())
+}
+
+/// 取引所の注文・建玉と突き合わせる。約定イベントで追えているはずなのにずれていたらメールで知らせる
+async fn reconcile_orders(symbol: Symbol) -> anyhow::Result<()> {
+    let client = client();
+    let requested_at = Utc::now();
+    let (open_orders, positions) = try_join!(
+        client.open_orders(symbol),
+        client.positions(symbol)
+    )?;
+    let divergences = ORDERS.write().reconcile(&open_orders, &positions, requested_at);
+    info!("reconcile orders. position: {:?}", ORDERS.read().positions());
+    if is_event_driven(symbol) && !divergences.is_empty() {
+        alert(
+            Severity::Warning,
+            format!("order state diverged - {} {}", symbol.exc, symbol.to_native()),
+            format!("{:?}", divergences),
+        );
+    }
+    Ok(())
+}
+
+/// ORDERSに登録してから発注する
+async fn place_order(req: &NewOrder) -> anyhow::Result<OrderId> {
+    // ORDERSにも取引所に出す数量で載せる
+    let req = req.round_to_instrument()?;
+    let client_order_id = ORDERS.write().new_order(&req);
+    // 結果がわからなかったときに取引所へ問い合わせられるように同じidを付けて出す
+    match client().place_order(&req.with_client_order_id(&client_order_id)).await {
+        Ok(id) => {
+            ORDERS.write().on_accepted(&client_order_id, &id);
+            Ok(id)
+        },
+        Err(e) => {
+            ORDERS.write().on_rejected(&client_order_id);
+            Err(e)
+        },
+    }
+}
+
+/// 約定をprivate websocketで受け取れるときは、建玉の取得は1時間ごとの照合だけにする
+fn is_event_driven(symbol: Symbol) -> bool {
+    get_paper().is_none() && has_private_events(symbol.exc)
+}
+
+/// 約定・注文のイベントをprivate websocketで受け取ってORDERSと予約注文に反映する
+/// paperではPaperClientの約定を同じ経路で受け取る
+async fn subscribe_private(symbol: Symbol) -> anyhow::Result<()> {
+    let (tx, mut rx) = unbounded();
+    spawn(async move {
+        while let Some(event) = rx.next().await {
+            on_private_event(symbol, event);
+        }
+    });
+    if let Some(paper) = PAPER.get() {
+        paper.subscribe_private(tx);
+        return pending().await;
+    }
+    subscribe_private_events(symbol.exc, tx).await
+}
+
+fn on_private_event(symbol: Symbol, event: PrivateEvent) {
+    let execution = match event {
+        PrivateEvent::Execution(x) if x.symbol == symbol => x,
+        PrivateEvent::Order(x) => {
+            ORDERS.write().on_order_event(&x);
+            return;
+        },
+        _ => return,
+    };
+    ORDERS.write().on_execution(&execution);
+    RISK.get().unwrap().on_execution(&execution);
+    // 決済指値が約定した分だけペアのロスカットを減らす
+    let changed = RESERVED.write().execution_handler(&execution.order_id, execution.amount);
+    info!("execution: {:?}, position: {:?}, changed reserved orders: {:?}", execution, ORDERS.read().positions(), changed);
+}
+
+async fn update_order(config: &TracingMMConfig) -> anyhow::Result<()> {
+    if is_killed() {
+        info!("kill switch is engaged, skip update_order");
+        return Ok(());
+    }
+    if !is_event_driven(config.symbol) {
+        reconcile_orders(config.symbol).await?;
+    }
+    let (klines, ref_klines) = try_join!(
+        read_kline(&KLINE, config.timeframe.into()),
+        read_kline(&REF_KLINE, config.timeframe.into()),
+    )?;
+    let sfd = match hooks().sfd {
+        Some(_) => {
+            let spot_klines = read_kline(&SPOT_KLINE, config.timeframe.into()).await?;
+            Some(get_sfd(&klines, &spot_klines, config.timeframe.into())?)
+        },
+        None => None,
+    };
+    let prices = tracing_price(klines.df, ref_klines.df, MAPPING_SIZE, config.atr_period, &config.beta, &config.gamma)?;
+    info!("update_order prices: {:?}, sfd: {:?}", prices, sfd);
+    send_new_orders(config, &prices, sfd).await?;
+    Ok(())
+}
+
+fn get_sfd(klines: &KLines, spot_klines: &KLines, timeframe: Duration) -> anyhow::Result<f64> {
+    let opentime = now_floor_time(timeframe, -1);
+    let close = klines.at(opentime, "close")?;
+    let spot_close = spot_klines.at(opentime, "close")?;
+    Ok(close.context("close is empty")? / spot_close.context("spot_close is empty")? - 1.0)
+}
+
+async fn send_new_orders(config: &TracingMMConfig, prices: &TracingPriceResult, sfd: Option<f64>) -> anyhow::Result<()> {
+    let pos = ORDERS.read().positions();
+    let sizing = OrderSizing::from_status(&STATUS.read()[&config.symbol]);
+    let mut orders = plan_orders(config, hooks(), &pos, prices, sfd, sizing)?;
+    if is_paused() {
+        info!("new entries are paused, skip open orders");
+        orders.retain(|o| !matches!(o.kind, TracingMMOrderKind::Open));
+    }
+    // into_iter -> collect で anyhow::Result<Vec<()>> になる
+    // https://stackoverflow.com/questions/63798662/how-do-i-convert-a-vecresultt-e-to-resultvect-e
+    join_all(orders.into_iter().map(|o| send_order(config, o))).await
+        .into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(())
+}
+
+async fn send_order(config: &TracingMMConfig, order: TracingMMOrder) -> anyhow::Result<()> {
+    let TracingMMOrder { kind, side, price, amount, losscut_price } = order;
+    let pos_side = match kind {
+        TracingMMOrderKind::Open => side.to_pos(),
+        TracingMMOrderKind::Close => side.inv().to_pos(),
+    };
+
+    let (order_id, rsv_order_id) = if hooks().reserve_limit_orders {
+        let rid = RESERVED.write().add_reserved_order(OrderType::Limit, side, pos_side, price, amount, None);
+        info!("{:?} order(reserved). side: {:?}, price: {}, amount: {}", kind, side, price, amount);
+        (None, Some(rid))
+    } else {
+        let id = place_order(&NewOrder::limit(config.symbol, side, price, amount)).await?;
+        info!("{:?} order. side: {:?}, price: {}, amount: {}, id: {}", kind, side, price, amount, id);
+        (Some(id), None)
+    };
+
+    // ロスカット逆指値
+    if let Some(losscut_price) = losscut_price {
+        let mut reserved = RESERVED.write();
+        let losscut_id = reserved.add_reserved_order(
+            OrderType::Stop, side, pos_side, losscut_price, amount, order_id
+        );
+        reserved.set_pair_rsv_order_id(&losscut_id, rsv_order_id);
+    }
+    Ok(())
+}
+
+async fn reconcile_position_and_assets(config: &TracingMMConfig) -> anyhow::Result<()> {
+    if is_event_driven(config.symbol) {
+        reconcile_orders(config.symbol).await?;
+    }
+    update_assets(config).await
+}
+
+async fn update_assets(config: &TracingMMConfig) -> anyhow::Result<()> {
+    let client = client();
+    let (collateral, ticker) = try_join!(
+        client.collateral(config.symbol),
+        client.ticker(config.symbol)
+    )?;
+    update_assets_inner(&STATUS, config, collateral, ticker.volume)?;
+    Ok(())
+}
+
+fn start_replace_orderbook_state(symbol: Symbol) {
+    spawn(async move {
+        replace_orderbook_state(symbol).await.capture_result(symbol).await.unwrap();
+        loop {
+            sleep_until_next(ScheduleExpr::new(Duration::minutes(1), Duration::seconds(1))).await;
+            replace_orderbook_state(symbol).await.capture_result(symbol).await.unwrap();
+        }
+    });
+}
+
+/// orderbookのsnapshotを取得して直近の差分をすべて適用する
+async fn replace_orderbook_state(symbol: Symbol) -> anyhow::Result<()> {
+    let res = client().orderbook(symbol).await?;
+    let mut snapshot = vec![];
+    for &side in &[Side::Buy, Side::Sell] {
+        snapshot.push(apply_diff_once(
+            res.by_side(side).iter().map(|(price, size)| (price.to_f64().into(), size.to_f64().into())).collect(),
+            ORDERBOOK_DIFF.read()[side as usize].get_data_iter().map(|&(price, size)| (price.into(), size.into())),
+        ))
+    }
+    ORDERBOOK.write().replace_state(snapshot);
+    Ok(())
+}
+
+/// 発火に使うとき、paperで成行を板に当てるときに板を購読する
+fn subscribe_orderbook(symbol: Symbol) -> bool {
+    hooks().fire_source == FireSource::Orderbook || (PAPER.get().is_some() && symbol.exc != Exchange::Bitflyer)
+}
+
+async fn subscribe_market(symbol: Symbol) -> anyhow::Result<()> {
+    let ws = match symbol.exc {
+        Exchange::Bitflyer => {
+            if hooks().fire_source == FireSource::Orderbook {
+                anyhow::bail!("fire_source orderbook is not supported in bitflyer");
+            }
+            ReconnectingWs::new(&endpoints(Exchange::Bitflyer).ws)
+                .subscribe(json!({
+                    "method": "subscribe",
+                    "params": {"channel": format!("lightning_executions_{}", symbol.to_native())}
+                }))
+        },
+        Exchange::Coincheck => {
+            let mut channels = vec![
+                format!("{}-trades", symbol.to_native()),
+            ];
+            if subscribe_orderbook(symbol) {
+                channels.push(format!("{}-orderbook", symbol.to_native()));
+            }
+            channels.into_iter().fold(ReconnectingWs::new(&endpoints(Exchange::Coincheck).ws), |ws, channel| ws.subscribe(json!({
+                "type": "subscribe",
+                "channel": channel,
+            })))
+        },
+        Exchange::Gmo => {
+            let mut channels = vec!["trades"];
+            if subscribe_orderbook(symbol) {
+                channels.push("orderbooks");
+            }
+            channels.into_iter().fold(ReconnectingWs::new(&endpoints(Exchange::Gmo).ws), |ws, channel| ws.subscribe(json!({
+                "command": "subscribe",
+                "channel": channel,
+                "symbol": symbol.to_native(),
+            })))
+                // 連続でsubscribeすると無視されるので少し待つ
+                .subscribe_interval(StdDuration::from_secs(2))
+                // gmoはサーバーからpingが来る
+                .ping_interval(None)
+                .liveness_timeout(StdDuration::from_secs(120))
+        },
+        _ => anyhow::bail!("{} is not supported", symbol.exc),
+    };
+    ws.run(&mut MarketWsHandler { symbol }).await
+}
+
+struct MarketWsHandler {
+    symbol: Symbol,
+}
+
+#[async_trait]
+impl WsHandler for MarketWsHandler {
+    async fn on_message(&mut self, msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+        match self.symbol.exc {
+            Exchange::Bitflyer => handle_bitflyer_msg(msg, self.symbol),
+            Exchange::Coincheck => handle_coincheck_msg(msg, self.symbol),
+            Exchange::Gmo => handle_gmo_msg(msg, self.symbol),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 切断中の差分は取り直せないのでsnapshotから作り直す。約定は古い価格で発火させないように埋めない
+    async fn on_reconnect(&mut self, _disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+        if subscribe_orderbook(self.symbol) && self.symbol.exc != Exchange::Gmo {
+            replace_orderbook_state(self.symbol).await?;
+        }
+        Ok(())
+    }
+}
+
+fn handle_bitflyer_msg(msg: Message, symbol: Symbol) -> anyhow::Result<()> {
+    let msg = msg.to_text()?;
+    let parsed: bitflyer::WsResponse = serde_json::from_str(msg)?;
+    if &parsed.method != "channelMessage" {
+        anyhow::bail!("Not channelMessage");
+    }
+    if parsed.params.channel != format!("lightning_executions_{}", symbol.to_native()) {
+        anyhow::bail!("Not channel for lightning_executions_{}", symbol.to_native());
+    }
+    let trades = serde_json::from_value::<Vec<bitflyer::ExecutionItem>>(parsed.params.message)?;
+    on_trades(symbol, trades.into_iter().map(|t| t.to_trade_record(symbol)).collect());
+    Ok(())
+}
+
+fn handle_coincheck_msg(msg: Message, symbol: Symbol) -> anyhow::Result<()> {
+    let msg = msg.to_text()?;
+    let parsed = serde_json::from_str::<coincheck::WsResponse>(msg)?;
+    match parsed {
+        coincheck::WsResponse::Trade(trade) => {
+            on_trades(symbol, trade.to_trade_records()?);
+        },
+        coincheck::WsResponse::Orderbook(res) => {
+            {
+                let mut orderbook = ORDERBOOK.write();
+                let mut orderbook_diff = ORDERBOOK_DIFF.write();
+                for &side in &[Side::Buy, Side::Sell] {
+                    for item in res.by_side(side) {
+                        if item.size == 0. {
+                            orderbook.remove(side, item.price);
+                        } else {
+                            orderbook.insert(side, item.price, item.size);
+                        }
+                    }
+                    orderbook_diff[side as usize].extend(res.by_side(side).iter().map(|item| (item.price, item.size)));
+                    orderbook_diff[side as usize].retain();
+                }
+            }
+            on_orderbook_update(symbol)?;
+        },
+    }
+    Ok(())
+}
+
+fn handle_gmo_msg(msg: Message, symbol: Symbol) -> anyhow::Result<()> {
+    let msg = msg.to_text()?;
+    let parsed: gmo::WsResponse = serde_json::from_str(msg)?;
+    let res = match parsed {
+        gmo::WsResponse::Ok(x) => x,
+        gmo::WsResponse::Err(x) if x.is_too_many_request() => {
+            anyhow::bail!(BotError::WsTooManyRequest);
+        }
+        gmo::WsResponse::Err(x) => anyhow::bail!("Websocket error response: {}", x.error),
+    };
+    match res {
+        gmo::WsOkResponse::Trades(trade) => {
+            on_trades(symbol, vec![trade.to_trade_record()]);
+        },
+        gmo::WsOkResponse::Orderbooks(orderbooks) => {
+            ORDERBOOK.write().replace_state(vec![
+                orderbooks.bids.into_iter().map(|x| (x.price.into(), x.size.into())).collect(),
+                orderbooks.asks.into_iter().map(|x| (x.price.into(), x.size.into())).collect(),
+            ]);
+            on_orderbook_update(symbol)?;
+        },
+    }
+    Ok(())
+}
+
+fn on_trades(symbol: Symbol, trades: Vec<TradeRecord>) {
+    metrics::record_trades(&trades);
+    // 置いてある指値の約定を先に判定する
+    if let Some(paper) = PAPER.get() {
+        paper.on_trades(&trades);
+    }
+    if let Some(trade) = trades.last() {
+        RISK.get().unwrap().on_mark(trade.price);
+    }
+    // reserved ordersの発火
+    let orders = RESERVED.write().trades_handler(&trades);
+    spawn_fire_reserved_orders(symbol, orders);
+}
+
+fn on_orderbook_update(symbol: Symbol) -> anyhow::Result<()> {
+    let best = ORDERBOOK.read().get_best::<ORDERBOOK_MAX_NTH>();
+    if let Some(paper) = PAPER.get() {
+        paper.on_orderbook(&best);
+    }
+    if hooks().fire_source != FireSource::Orderbook {
+        return Ok(());
+    }
+    // orderbookの描画
+    if get_debug()==DebugFlag::Orderbook {
+        ORDERBOOK_DRAWER.write().print_orderbook(best, symbol)?;
+    }
+    let nth = hooks().orderbook_nth;
+    let best_nth = [best[0][nth-1], best[1][nth-1]];
+    let orders = RESERVED.write().orderbook_handler(best_nth);
+    spawn_fire_reserved_orders(symbol, orders);
+    Ok(())
+}
+
+fn spawn_fire_reserved_orders(symbol: Symbol, orders: Vec<ReservedOrder>) {
+    if orders.is_empty() {
+        return;
+    }
+    // kill switchが入ったら予約注文も出さない
+    if is_killed() {
+        RESERVED.write().cancel_all_orders();
+        return;
+    }
+    spawn(async move {
+        join_all(
+            orders.into_iter().map(|o| fire_reserved_order(symbol, o))
+        ).await.into_iter().collect::<anyhow::Result<()>>()
+        .capture_result(symbol).await.unwrap();
+    });
+}
+
+async fn fire_reserved_order(symbol: Symbol, reserved_order: ReservedOrder) -> anyhow::Result<()> {
+    let req = match reserved_order.order_type {
+        // orderbookが速いとpost_onlyでは間に合わないこともありそうなので無し（post_onlyにする必要もない）
+        OrderType::Limit | OrderType::StopLimit => NewOrder::limit(symbol, reserved_order.side, reserved_order.price, reserved_order.amount),
+        OrderType::Market | OrderType::Stop => NewOrder::market(symbol, reserved_order.side, reserved_order.amount),
+    };
+    if let Some(pair_rsv_order_id) = reserved_order.pair_rsv_order_id {
+        RESERVED.write().remove(&pair_rsv_order_id);
+    }
+    // ペアのキャンセルに失敗しても出した注文は追えるように、発注の結果から先に記録する
+    let (id, cancel_result) = match &reserved_order.pair_order_id {
+        Some(pair_order_id) => {
+            let client = client();
+            let (id, cancel_result) = join!(place_order(&req), client.cancel_order(symbol, pair_order_id));
+            (id?, Some((pair_order_id, cancel_result)))
+        },
+        None => (place_order(&req).await?, None),
+    };
+    RESERVED.write().set_ordered_id(&reserved_order.id, &id);
+    info!("fire_reserved_order. type: {:?}, side: {:?}, price: {}, amount: {}, id: {}", reserved_order.order_type, reserved_order.side, reserved_order.price, reserved_order.amount, id);
+    if let Some((pair_order_id, Err(e))) = cancel_result {
+        error!("failed to cancel the pair order {} of reserved order {}: {:?}", pair_order_id, reserved_order.id, e);
+        // ペアが約定済みで取り消せないのはよくあるので知らせない
+        if !matches!(e.downcast_ref::<BotError>(), Some(BotError::OrderRejected { .. })) {
+            alert(Severity::Warning, format!("failed to cancel pair order - {} {}", symbol.exc, symbol.to_native()), format!("pair order: {}, fired order: {}\n{:?}", pair_order_id, id, e));
+        }
+    }
+    Ok(())
+}
+
+struct TracingMMControl {
+    symbol: Symbol,
+}
+
+#[async_trait]
+impl Controllable for TracingMMControl {
+    fn status(&self) -> serde_json::Value {
+        json!({
+            "config": format!("{:?}", config()),
+            "status": STATUS.read().get(&self.symbol).clone(),
+            "positions": format!("{:?}", ORDERS.read().positions()),
+            "open_orders": ORDERS.read().active_orders().map(|o| format!("{:?}", o)).collect::<Vec<_>>(),
+            "reserved_orders": RESERVED.read().reserved_orders.values().map(|o| format!("{:?}", o)).collect::<Vec<_>>(),
+            "risk": RISK.get().unwrap().status(),
+        })
+    }
+
+    async fn cancel_all(&self) -> anyhow::Result<()> {
+        cancel_all_orders(self.symbol).await
+    }
+
+    /// 予約注文（ロスカット）も消してから閉じる
+    async fn flatten(&self, limit: bool) -> anyhow::Result<Vec<OrderId>> {
+        cancel_all_orders(self.symbol).await?;
+        RISK.get().unwrap().close_positions(limit).await
+    }
+
+    /// 次のtimeframeの切り替わりで反映する
+    async fn reload(&self) -> anyhow::Result<serde_json::Value> {
+        let name = std::env::var("NAME")?;
+        let next = match read_strategy(&name)? {
+            Strategy::TracingMm(c) => c,
+            _ => anyhow::bail!("{} is not tracing_mm", name),
+        };
+        Ok(json!({"changes": stage_tunables(&next)?}))
+    }
+}
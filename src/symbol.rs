@@ -1,8 +1,11 @@
-use std::fmt::Display;
+use std::{fmt::{Display, Debug}, collections::{HashMap, HashSet}, str::FromStr};
 
 use easy_ext::ext;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use strum::EnumString;
+
+use crate::{instrument::{Instrument, get_instrument}, config::read_config_section};
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
@@ -44,23 +47,110 @@ impl Display for SymbolType {
     }
 }
 
-#[derive(Deserialize, Serialize, EnumString, Debug, PartialEq, Eq, Hash, Clone, Copy)]
-pub enum Currency {
-    BTC,
-    XRP,
-    JPY,
-    USDT,
-}
+/// 通貨。名前はinternして`&'static str`で持つのでCopyできる
+/// よく使うものは定数にしてあるが、`Currency::new`やyamlから任意の通貨を作れる
+#[derive(PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Currency(&'static str);
+
+static INTERNED: Lazy<Mutex<HashSet<&'static str>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// config.yamlの`currency_native_names`。取引所での表記が通貨名と違うものだけ書く
+///
+/// ```yaml
+/// currency_native_names:
+///   binance:
+///     USD: USDT
+/// ```
+static NATIVE_NAMES: Lazy<HashMap<Exchange, HashMap<String, String>>> = Lazy::new(|| read_config_section("currency_native_names"));
 
 impl Currency {
+    pub const BTC: Currency = Currency("BTC");
+    pub const ETH: Currency = Currency("ETH");
+    pub const XRP: Currency = Currency("XRP");
+    pub const JPY: Currency = Currency("JPY");
+    pub const USD: Currency = Currency("USD");
+    pub const USDT: Currency = Currency("USDT");
+
+    /// 大文字にそろえてinternする。同じ名前なら何度呼んでもリークは1回
+    pub fn new(name: &str) -> Self {
+        let name = name.to_uppercase();
+        let mut interned = INTERNED.lock();
+        if let Some(&s) = interned.get(name.as_str()) {
+            return Currency(s);
+        }
+        let s: &'static str = Box::leak(name.into_boxed_str());
+        interned.insert(s);
+        Currency(s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
     pub fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap().replace("\"", "")
+        self.0.to_string()
+    }
+
+    /// 取引所での表記。coincheckは小文字
+    pub fn to_native(&self, exc: Exchange) -> String {
+        let name = NATIVE_NAMES.get(&exc)
+            .and_then(|names| names.get(self.0))
+            .map(|s| s.as_str())
+            .unwrap_or(self.0);
+        match exc {
+            Exchange::Coincheck => name.to_lowercase(),
+            _ => name.to_uppercase(),
+        }
+    }
+
+    /// 取引所での表記から通貨にする。to_nativeの逆
+    pub fn from_native(name: &str, exc: Exchange) -> anyhow::Result<Self> {
+        let found = NATIVE_NAMES.get(&exc)
+            .and_then(|names| names.iter().find(|(_, native)| native.eq_ignore_ascii_case(name)))
+            .map(|(currency, _)| currency.as_str());
+        found.unwrap_or(name).parse()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            anyhow::bail!("invalid currency: {:?}", s);
+        }
+        Ok(Currency::new(s))
     }
 }
 
 impl Display for Currency {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Debug for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -86,16 +176,18 @@ impl Symbol {
     }
 
     pub fn to_native(&self) -> String {
+        let base = self.base.to_native(self.exc);
+        let quote = self.quote.to_native(self.exc);
         match self.exc {
             Exchange::Gmo => match self.r#type {
-                SymbolType::Perp => format!("{}_{}", self.base, self.quote),
-                SymbolType::Spot => format!("{}", self.base),
+                SymbolType::Perp => format!("{}_{}", base, quote),
+                SymbolType::Spot => base,
             },
-            Exchange::Coincheck => format!("{}_{}", self.base.to_string().to_lowercase(), self.quote.to_string().to_lowercase()),
-            Exchange::Binance => format!("{}{}", self.base, self.quote),
+            Exchange::Coincheck => format!("{}_{}", base, quote),
+            Exchange::Binance => format!("{}{}", base, quote),
             Exchange::Bitflyer => match self.r#type {
-                SymbolType::Perp => format!("FX_{}_{}", self.base, self.quote),
-                SymbolType::Spot => format!("{}_{}", self.base, self.quote),
+                SymbolType::Perp => format!("FX_{}_{}", base, quote),
+                SymbolType::Spot => format!("{}_{}", base, quote),
             }
         }
     }
@@ -104,42 +196,31 @@ impl Symbol {
         format!("{}-{}-{}-{}", self.exc, self.base, self.quote, self.r#type)
     }
 
+    /// instruments.yamlに登録されていなければエラー
     #[inline]
-    pub const fn settlement_precision(&self) -> i32 {
-        match self.settlement {
-            Currency::JPY => 0,
-            _ => panic!("not implemented"),
-        }
+    pub fn instrument(&self) -> anyhow::Result<Instrument> {
+        get_instrument(self)
     }
 
+    /// 戦略の銘柄は起動時にcheck_instrumentsで確かめているので、ここでは登録済みとみなす
     #[inline]
-    pub const fn amount_precision(&self) -> i32 {
-        match self.exc {
-            Exchange::Gmo => match (self.base, self.r#type) {
-                (Currency::BTC, SymbolType::Perp) => -2,
-                (Currency::BTC, SymbolType::Spot) => -4,
-                (Currency::XRP, SymbolType::Perp) => 1,
-                (Currency::XRP, SymbolType::Spot) => 0,
-                _ => panic!("not implemented"),
-            },
-            Exchange::Bitflyer => -8,
-            Exchange::Coincheck => -8,
-            _ => panic!("not implemented"),
-        }
+    fn registered_instrument(&self) -> Instrument {
+        self.instrument().unwrap()
     }
 
     #[inline]
-    pub const fn price_precision(&self) -> i32 {
-        match self.exc {
-            Exchange::Gmo => match self.base {
-                Currency::BTC => 0,
-                Currency::XRP => -3,
-                _ => panic!("not implemented"),
-            },
-            Exchange::Bitflyer => 0,
-            Exchange::Coincheck => 0,
-            _ => panic!("not implemented"),
-        }
+    pub fn settlement_precision(&self) -> i32 {
+        self.registered_instrument().settlement_precision
+    }
+
+    #[inline]
+    pub fn amount_precision(&self) -> i32 {
+        self.registered_instrument().amount_precision
+    }
+
+    #[inline]
+    pub fn price_precision(&self) -> i32 {
+        self.registered_instrument().price_precision
     }
 }
 
@@ -151,3 +232,28 @@ impl Serialize for Symbol {
         serializer.serialize_str(&self.to_native())
     }
 }
+
+#[test]
+fn test_currency() {
+    let eth: Currency = "eth".parse().unwrap();
+    assert_eq!(eth, Currency::ETH);
+    assert_eq!(eth.as_str().as_ptr(), Currency::new("ETH").as_str().as_ptr());
+    assert!("".parse::<Currency>().is_err());
+    assert!("btc_jpy".parse::<Currency>().is_err());
+    assert_eq!(serde_json::to_string(&Currency::BTC).unwrap(), "\"BTC\"");
+    assert_eq!(serde_json::from_str::<Currency>("\"sol\"").unwrap(), Currency::new("SOL"));
+    match Currency::new("jpy") {
+        Currency::JPY => {},
+        c => panic!("{}", c),
+    }
+}
+
+#[test]
+fn test_symbol_to_native() {
+    let symbol = Symbol::new(Currency::ETH, Currency::JPY, SymbolType::Spot, Exchange::Coincheck);
+    assert_eq!(symbol.to_native(), "eth_jpy");
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    assert_eq!(symbol.to_native(), "FX_BTC_JPY");
+    let symbol = Symbol::new(Currency::BTC, Currency::USDT, SymbolType::Spot, Exchange::Binance);
+    assert_eq!(symbol.to_native(), "BTCUSDT");
+}
@@ -0,0 +1,345 @@
+use std::{collections::HashMap, env, fmt, io::Write};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures::{channel::{mpsc::{unbounded, UnboundedReceiver, UnboundedSender}, oneshot}, StreamExt};
+use log::{info, error};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{client::{mail::send_mail, method::http_client}, config::{read_config_section, try_read_config_section}};
+
+/// 通知の重要度。チャネルごとにmin_severity以上だけ送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+    /// throttleしない
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Severity::Info => "INFO",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+            Severity::Critical => "CRITICAL",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub severity: Severity,
+    pub subject: String,
+    pub body: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Alert {
+    pub fn new(severity: Severity, subject: String, body: String) -> Self {
+        Self { severity, subject, body, timestamp: Utc::now() }
+    }
+}
+
+#[async_trait]
+pub trait Alerter: Send + Sync {
+    async fn send(&self, alert: &Alert) -> anyhow::Result<()>;
+}
+
+/// config.yamlの`mail`で送る。hostは`mail.host`（省略するとgmail）
+pub struct SmtpAlerter;
+
+#[async_trait]
+impl Alerter for SmtpAlerter {
+    async fn send(&self, alert: &Alert) -> anyhow::Result<()> {
+        let (subject, body) = (alert.subject.clone(), alert.body.clone());
+        tokio::task::spawn_blocking(move || send_mail(subject, body)).await?
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// {"text": ...}
+    #[default]
+    Slack,
+    /// {"content": ...}
+    Discord,
+}
+
+pub struct WebhookAlerter {
+    client: reqwest::Client,
+    url: String,
+    format: WebhookFormat,
+}
+
+impl WebhookAlerter {
+    pub fn new(url: &str, format: WebhookFormat) -> Self {
+        Self { client: http_client(), url: url.to_string(), format }
+    }
+}
+
+#[async_trait]
+impl Alerter for WebhookAlerter {
+    async fn send(&self, alert: &Alert) -> anyhow::Result<()> {
+        let text = format!("[{}] {}\n{}", alert.severity, alert.subject, alert.body);
+        let payload = match self.format {
+            WebhookFormat::Slack => json!({"text": text}),
+            WebhookFormat::Discord => json!({"content": text}),
+        };
+        self.client.post(&self.url).json(&payload).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// pathに追記する。pathがなければ標準出力
+pub struct FileAlerter {
+    path: Option<String>,
+}
+
+impl FileAlerter {
+    pub fn new(path: Option<String>) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl Alerter for FileAlerter {
+    async fn send(&self, alert: &Alert) -> anyhow::Result<()> {
+        let line = format!("{} [{}] {}\n{}\n", alert.timestamp.to_rfc3339(), alert.severity, alert.subject, alert.body);
+        match &self.path {
+            Some(path) => std::fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())?,
+            None => print!("{}", line),
+        }
+        Ok(())
+    }
+}
+
+/// config.yamlの`alert`。省略するとSMTPだけに送る
+///
+/// ```yaml
+/// alert:
+///   throttle_secs: 600
+///   channels:
+///     - type: smtp
+///       min_severity: error
+///     - type: webhook
+///       url: https://hooks.slack.com/services/xxx
+///       format: slack
+///     - type: file
+///       path: alerts.log
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AlertConfig {
+    #[serde(default = "default_channels")]
+    pub channels: Vec<ChannelConfig>,
+    /// 同じ件名の通知はこの間1回だけ送る
+    #[serde(default = "default_throttle_secs")]
+    pub throttle_secs: i64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self { channels: default_channels(), throttle_secs: default_throttle_secs() }
+    }
+}
+
+fn default_channels() -> Vec<ChannelConfig> {
+    vec![ChannelConfig::Smtp { min_severity: Severity::Info }]
+}
+
+fn default_throttle_secs() -> i64 {
+    600
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChannelConfig {
+    Smtp {
+        #[serde(default)]
+        min_severity: Severity,
+    },
+    Webhook {
+        url: String,
+        #[serde(default)]
+        format: WebhookFormat,
+        #[serde(default)]
+        min_severity: Severity,
+    },
+    File {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        min_severity: Severity,
+    },
+}
+
+impl ChannelConfig {
+    fn build(&self) -> (Severity, Box<dyn Alerter>) {
+        match self {
+            ChannelConfig::Smtp { min_severity } => (*min_severity, Box::new(SmtpAlerter)),
+            ChannelConfig::Webhook { url, format, min_severity } => (*min_severity, Box::new(WebhookAlerter::new(url, *format))),
+            ChannelConfig::File { path, min_severity } => (*min_severity, Box::new(FileAlerter::new(path.clone()))),
+        }
+    }
+}
+
+/// 件名の数字を除いたものを同じ通知とみなし、window内の2回目以降は送らない。Criticalは常に送る
+#[derive(Debug)]
+pub struct Throttle {
+    window: Duration,
+    /// key -> (最後に送った時刻, それ以降に送らなかった数)
+    last_sent: HashMap<(Severity, String), (DateTime<Utc>, usize)>,
+}
+
+impl Throttle {
+    pub fn new(window: Duration) -> Self {
+        Self { window, last_sent: HashMap::new() }
+    }
+
+    /// 送るならそれまでに送らなかった数を返す
+    pub fn check(&mut self, alert: &Alert) -> Option<usize> {
+        if alert.severity == Severity::Critical {
+            return Some(0);
+        }
+        let key = (alert.severity, alert.subject.chars().filter(|c| !c.is_ascii_digit()).collect());
+        match self.last_sent.get_mut(&key) {
+            Some((last, suppressed)) if alert.timestamp - *last < self.window => {
+                *suppressed += 1;
+                None
+            },
+            Some((last, suppressed)) => {
+                let ret = *suppressed;
+                *last = alert.timestamp;
+                *suppressed = 0;
+                Some(ret)
+            },
+            None => {
+                self.last_sent.insert(key, (alert.timestamp, 0));
+                Some(0)
+            },
+        }
+    }
+}
+
+struct Queued {
+    alert: Alert,
+    done: Option<oneshot::Sender<()>>,
+}
+
+static ALERT_CONFIG: OnceCell<AlertConfig> = OnceCell::new();
+
+/// 起動時に呼び、config.yamlの`alert`が壊れていればエラーにする。呼ばなければ最初の通知のときに読む
+pub fn init() -> anyhow::Result<()> {
+    let config: AlertConfig = try_read_config_section("alert")?;
+    let _ = ALERT_CONFIG.set(config);
+    Lazy::force(&QUEUE);
+    Ok(())
+}
+
+/// 送信は専用スレッドで行い、呼び出し側は待たない
+static QUEUE: Lazy<UnboundedSender<Queued>> = Lazy::new(|| {
+    let config = ALERT_CONFIG.get_or_init(|| read_config_section("alert")).clone();
+    let (tx, rx) = unbounded();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(run_worker(rx, config));
+    });
+    tx
+});
+
+async fn run_worker(mut rx: UnboundedReceiver<Queued>, config: AlertConfig) {
+    let channels = config.channels.iter().map(|c| c.build()).collect::<Vec<_>>();
+    let mut throttle = Throttle::new(Duration::seconds(config.throttle_secs));
+    while let Some(Queued { mut alert, done }) = rx.next().await {
+        match throttle.check(&alert) {
+            Some(suppressed) => {
+                if suppressed > 0 {
+                    alert.body = format!("{}\n\n({} similar alerts were suppressed)", alert.body, suppressed);
+                }
+                for (min_severity, channel) in &channels {
+                    if alert.severity < *min_severity {
+                        continue;
+                    }
+                    if let Err(e) = channel.send(&alert).await {
+                        error!("failed to send alert: {:?}", e);
+                    }
+                }
+            },
+            None => info!("alert throttled: [{}] {}", alert.severity, alert.subject),
+        }
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// 件名の後ろにbot名（NAME）を付ける
+fn with_name(subject: String) -> String {
+    match env::var("NAME") {
+        Ok(name) => format!("{} - {}", subject, name),
+        Err(_) => subject,
+    }
+}
+
+/// キューに積んですぐ返す
+pub fn alert(severity: Severity, subject: impl Into<String>, body: impl Into<String>) {
+    let alert = Alert::new(severity, with_name(subject.into()), body.into());
+    if let Err(e) = QUEUE.unbounded_send(Queued { alert, done: None }) {
+        error!("failed to queue alert: {:?}", e);
+    }
+}
+
+/// 送り終わるまで待つ。落ちる直前に使う
+pub async fn alert_and_wait(severity: Severity, subject: impl Into<String>, body: impl Into<String>) {
+    let alert = Alert::new(severity, with_name(subject.into()), body.into());
+    let (done, rx) = oneshot::channel();
+    if let Err(e) = QUEUE.unbounded_send(Queued { alert, done: Some(done) }) {
+        error!("failed to queue alert: {:?}", e);
+        return;
+    }
+    let _ = rx.await;
+}
+
+#[test]
+fn test_throttle() {
+    let mut throttle = Throttle::new(Duration::seconds(600));
+    let now = Utc::now();
+    let at = |severity, subject: &str, secs| Alert { severity, subject: subject.to_string(), body: String::new(), timestamp: now + Duration::seconds(secs) };
+    assert_eq!(throttle.check(&at(Severity::Error, "gave up reconnecting after 10 attempts", 0)), Some(0));
+    // 数字だけ違うものは同じ通知
+    assert_eq!(throttle.check(&at(Severity::Error, "gave up reconnecting after 11 attempts", 10)), None);
+    assert_eq!(throttle.check(&at(Severity::Error, "gave up reconnecting after 10 attempts", 20)), None);
+    assert_eq!(throttle.check(&at(Severity::Warning, "gave up reconnecting after 10 attempts", 20)), Some(0));
+    assert_eq!(throttle.check(&at(Severity::Critical, "kill switch engaged", 30)), Some(0));
+    assert_eq!(throttle.check(&at(Severity::Critical, "kill switch engaged", 31)), Some(0));
+    assert_eq!(throttle.check(&at(Severity::Error, "gave up reconnecting after 10 attempts", 600)), Some(2));
+}
+
+#[test]
+fn test_alert_config() {
+    let config: AlertConfig = crate::config::parse_config_section(r#"
+alert:
+  channels:
+    - type: smtp
+      min_severity: error
+    - type: webhook
+      url: https://example.com/hook
+      format: discord
+    - type: file
+"#, "alert").unwrap();
+    assert_eq!(config.throttle_secs, 600);
+    assert_eq!(config.channels, vec![
+        ChannelConfig::Smtp { min_severity: Severity::Error },
+        ChannelConfig::Webhook { url: "https://example.com/hook".to_string(), format: WebhookFormat::Discord, min_severity: Severity::Info },
+        ChannelConfig::File { path: None, min_severity: Severity::Info },
+    ]);
+    assert_eq!(AlertConfig::default().channels, default_channels());
+}
@@ -0,0 +1,31 @@
+use std::{time::{Duration, SystemTime}, path::Path};
+
+use log::{info, error};
+
+use crate::config::{Strategy, read_strategy, BOT_CONFIG_PATH};
+
+/// 更新時刻を見る間隔
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(Path::new(path)).and_then(|m| m.modified()).ok()
+}
+
+/// config.bot.yamlの更新時刻をintervalごとに見て、変わったらnameの戦略を読み直してon_changeに渡す
+/// 書きかけなどで読めなければログに出して次の更新を待つ。終了しない
+pub async fn watch_strategy_config<F: FnMut(Strategy)>(name: &str, interval: Duration, mut on_change: F) {
+    let mut last = modified(BOT_CONFIG_PATH);
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = modified(BOT_CONFIG_PATH);
+        if current == last {
+            continue;
+        }
+        last = current;
+        info!("{} is modified", BOT_CONFIG_PATH);
+        match read_strategy(name) {
+            Ok(strategy) => on_change(strategy),
+            Err(e) => error!("failed to reload {}: {:?}", BOT_CONFIG_PATH, e),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::{convert::Infallible, env, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header::{AUTHORIZATION, CONTENT_TYPE}, service::{make_service_fn, service_fn}};
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{client::exchange::OrderId, config::{read_config_section, try_read_config_section}};
+
+use super::risk::{is_killed, is_paused, set_paused};
+
+/// control APIから操作する戦略。登録がなければstatusとpause/resumeだけ使える
+#[async_trait]
+pub trait Controllable: Send + Sync {
+    /// 設定・ステータス・建玉・予約注文など
+    fn status(&self) -> Value;
+    async fn cancel_all(&self) -> anyhow::Result<()>;
+    /// 建玉を閉じる。limitなら指値、そうでなければ成行
+    async fn flatten(&self, limit: bool) -> anyhow::Result<Vec<OrderId>>;
+    /// config.bot.yamlから調整用の値を読み直し、反映した値を返す
+    async fn reload(&self) -> anyhow::Result<Value> {
+        Err(anyhow::anyhow!("reload is not supported by this strategy"))
+    }
+}
+
+static CONTROLLER: OnceCell<Arc<dyn Controllable>> = OnceCell::new();
+
+/// 戦略の起動時に1回だけ呼ぶ
+pub fn register(controller: Arc<dyn Controllable>) {
+    if CONTROLLER.set(controller).is_err() {
+        panic!("controller is already registered");
+    }
+}
+
+/// config.yamlの`control`
+///
+/// ```yaml
+/// control:
+///   token: xxxx
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ControlConfig {
+    /// `Authorization: Bearer {token}`で渡す。なければcontrol APIを立てない
+    pub token: Option<String>,
+}
+
+static CONTROL_CONFIG: OnceCell<ControlConfig> = OnceCell::new();
+
+/// 起動時に呼び、config.yamlの`control`が壊れていればエラーにする。呼ばなければserve_controlで読む
+pub fn init() -> anyhow::Result<()> {
+    let config: ControlConfig = try_read_config_section("control")?;
+    let _ = CONTROL_CONFIG.set(config);
+    Ok(())
+}
+
+fn control_config() -> &'static ControlConfig {
+    CONTROL_CONFIG.get_or_init(|| read_config_section("control"))
+}
+
+type HandlerResult = Result<Value, (StatusCode, String)>;
+
+fn controller() -> Result<&'static Arc<dyn Controllable>, (StatusCode, String)> {
+    CONTROLLER.get().ok_or((StatusCode::BAD_REQUEST, "no controllable strategy is running".to_string()))
+}
+
+fn internal_error(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+}
+
+fn status() -> Value {
+    json!({
+        "name": env::var("NAME").ok(),
+        "paused": is_paused(),
+        "killed": is_killed(),
+        "strategy": CONTROLLER.get().map(|c| c.status()),
+    })
+}
+
+async fn route(method: &Method, path: &str, query: Option<&str>) -> HandlerResult {
+    match (method, path) {
+        (&Method::GET, "/status") => Ok(status()),
+        (&Method::POST, "/pause") => {
+            set_paused(true);
+            Ok(status())
+        },
+        (&Method::POST, "/resume") => {
+            set_paused(false);
+            Ok(status())
+        },
+        (&Method::POST, "/cancel_all") => {
+            controller()?.cancel_all().await.map_err(internal_error)?;
+            Ok(json!({"canceled": true}))
+        },
+        (&Method::POST, "/flatten") => {
+            let limit = match query {
+                None | Some("type=market") => false,
+                Some("type=limit") => true,
+                Some(q) => return Err((StatusCode::BAD_REQUEST, format!("invalid query: {}", q))),
+            };
+            let ids = controller()?.flatten(limit).await.map_err(internal_error)?;
+            Ok(json!({"orders": ids}))
+        },
+        (&Method::POST, "/reload") => controller()?.reload().await.map_err(internal_error),
+        _ => Err((StatusCode::NOT_FOUND, format!("{} {} is not found", method, path))),
+    }
+}
+
+/// `Bearer {token}`か。一致しない位置で比較時間が変わらないように全バイトを見る
+fn is_authorized(header: &str, token: &str) -> bool {
+    let Some(given) = header.strip_prefix("Bearer ") else {
+        return false;
+    };
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    given.len() == token.len() && given.iter().zip(token).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn handle_http(token: Arc<String>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let authorized = req.headers().get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| is_authorized(v, &token));
+    let res = if authorized {
+        info!("control API: {} {}", req.method(), req.uri());
+        let (method, path, query) = (req.method().clone(), req.uri().path().to_string(), req.uri().query().map(|q| q.to_string()));
+        route(&method, &path, query.as_deref()).await
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "unauthorized".to_string()))
+    };
+    let (status, body) = match res {
+        Ok(body) => (StatusCode::OK, body),
+        Err((status, message)) => (status, json!({"error": message})),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+/// ループバックでだけ待ち受ける。終了しない
+pub async fn serve_control(addr: SocketAddr) -> anyhow::Result<()> {
+    if !addr.ip().is_loopback() {
+        anyhow::bail!("control API must listen on a loopback address: {}", addr);
+    }
+    let token = match &control_config().token {
+        Some(token) if !token.is_empty() => Arc::new(token.clone()),
+        _ => anyhow::bail!("control.token is not set in config.yaml"),
+    };
+    let make_svc = make_service_fn(move |_| {
+        let token = token.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_http(token.clone(), req))) }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("serving control API on http://{}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handle_http() {
+    let token = Arc::new("secret".to_string());
+    let request = |method: Method, uri: &str, auth: &str| Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, auth)
+        .body(Body::empty())
+        .unwrap();
+
+    let res = handle_http(token.clone(), request(Method::GET, "/status", "Bearer wrong")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    for auth in ["Bearer secre", "Bearer secrets", "Bearer Secret", "secret", "Basic secret", "Bearer "] {
+        let res = handle_http(token.clone(), request(Method::GET, "/status", auth)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", auth);
+    }
+
+    let res = handle_http(token.clone(), request(Method::GET, "/status", "Bearer secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["strategy"], Value::Null);
+
+    // 戦略が登録されていなければ注文は触れない
+    let res = handle_http(token.clone(), request(Method::POST, "/cancel_all", "Bearer secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = handle_http(token.clone(), request(Method::POST, "/flatten?type=stop", "Bearer secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = handle_http(token.clone(), request(Method::DELETE, "/status", "Bearer secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
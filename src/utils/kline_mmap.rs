@@ -8,7 +8,7 @@ use rmp::Marker;
 
 use crate::{symbol::Symbol, client::types::TradeRecord};
 
-use super::time::{datetime_utc_from_timestamp, UnixTimeUnit, now_floor_time, floor_time};
+use super::{metrics, time::{datetime_utc_from_timestamp, UnixTimeUnit, now_floor_time, floor_time}};
 
 #[derive(Debug, Clone)]
 pub enum KLineRow {
@@ -186,6 +186,11 @@ impl KLineMMap {
         for i in 0..self.len {
             self.mmap_write_row(i, &self.state[i].clone())?;
         }
+        metrics::set_gauge("bot_kline_head_opentime_seconds", &[
+            ("exchange", &self.symbol.exc.to_string()),
+            ("symbol", &self.symbol.to_native()),
+            ("timeframe", &self.timeframe.num_seconds().to_string()),
+        ], self.head_opentime.timestamp() as f64);
         Ok(())
     }
 
@@ -286,6 +291,14 @@ impl KLineMMap {
         Ok(i as usize)
     }
 
+    /// 確定したklineで行を上書きする。切断中の穴埋め用。保持している範囲より古ければ捨てる
+    pub fn fill_kline(&mut self, opentime: DateTime<Utc>, data: KLineRowData) {
+        self.shift_state(opentime);
+        if let Ok(i) = self.index_of(opentime) {
+            self.state[i] = KLineRow::Data(data);
+        }
+    }
+
     pub fn update_ohlcvs(&mut self, records: &Vec<TradeRecord>) -> anyhow::Result<()> {
         for record in records {
             self.update_ohlcv(record)?;
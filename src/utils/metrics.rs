@@ -0,0 +1,222 @@
+use std::{collections::BTreeMap, convert::Infallible, fmt::Write, net::SocketAddr};
+
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header::CONTENT_TYPE, service::{make_service_fn, service_fn}};
+use log::info;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{client::{method::queue_delay_stats, types::TradeRecord}, symbol::Symbol};
+
+use super::orderbook_repository::OrderbookRepository;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Summary,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Summary => "summary",
+        }
+    }
+}
+
+/// 出力するメトリクスの一覧。ここにないものもuntypedで出す
+const METRICS: &[(&str, Kind, &str)] = &[
+    ("bot_ws_messages_total", Kind::Counter, "Websocket messages received"),
+    ("bot_ws_disconnects_total", Kind::Counter, "Websocket disconnections including failed connects"),
+    ("bot_ws_connected", Kind::Gauge, "1 if the websocket is connected"),
+    ("bot_ws_lag_seconds", Kind::Gauge, "Delay between the last trade timestamp and its receipt"),
+    ("bot_last_trade_timestamp_seconds", Kind::Gauge, "Timestamp of the last trade received"),
+    ("bot_orderbook_depth", Kind::Gauge, "Number of price levels in the orderbook"),
+    ("bot_rest_requests_total", Kind::Counter, "REST requests by response status, or error if no response"),
+    ("bot_rest_request_duration_seconds", Kind::Summary, "REST request latency until the response headers"),
+    ("bot_rate_limit_wait_seconds", Kind::Summary, "Time spent waiting for the rate limiter"),
+    ("bot_rate_limit_wait_seconds_max", Kind::Gauge, "Longest wait for the rate limiter"),
+    ("bot_open_orders", Kind::Gauge, "Open orders tracked by the bot"),
+    ("bot_position", Kind::Gauge, "Signed position in base currency"),
+    ("bot_realized_pnl", Kind::Gauge, "Realized PnL of the UTC day including fees"),
+    ("bot_unrealized_pnl", Kind::Gauge, "Unrealized PnL at the last mark price"),
+    ("bot_kline_head_opentime_seconds", Kind::Gauge, "Opentime of the head row written to the kline mmap"),
+];
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// name -> labels -> value
+    values: BTreeMap<String, BTreeMap<String, f64>>,
+    /// name -> labels -> (sum, count)
+    summaries: BTreeMap<String, BTreeMap<String, (f64, u64)>>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+type Collector = Box<dyn Fn() + Send + Sync>;
+
+/// 出力の直前に呼ぶ。状態を持っている側から値を取るときに使う
+static COLLECTORS: Lazy<Mutex<Vec<Collector>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// `{k="v",...}`の形にする
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let inner = labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{{}}}", inner)
+}
+
+pub fn set_gauge(name: &str, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.lock().values.entry(name.to_string()).or_default().insert(format_labels(labels), value);
+}
+
+pub fn inc_counter(name: &str, labels: &[(&str, &str)], by: f64) {
+    *REGISTRY.lock().values.entry(name.to_string()).or_default().entry(format_labels(labels)).or_default() += by;
+}
+
+/// `_sum`と`_count`に足す
+pub fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock();
+    let (sum, count) = registry.summaries.entry(name.to_string()).or_default().entry(format_labels(labels)).or_default();
+    *sum += value;
+    *count += 1;
+}
+
+fn set_summary(name: &str, labels: &[(&str, &str)], sum: f64, count: u64) {
+    REGISTRY.lock().summaries.entry(name.to_string()).or_default().insert(format_labels(labels), (sum, count));
+}
+
+pub fn register_collector(f: impl Fn() + Send + Sync + 'static) {
+    COLLECTORS.lock().push(Box::new(f));
+}
+
+pub fn set_symbol_gauge(name: &str, symbol: Symbol, value: f64) {
+    set_gauge(name, &[("exchange", &symbol.exc.to_string()), ("symbol", &symbol.to_native())], value);
+}
+
+/// websocketで受け取った約定の時刻と遅延を記録する。再接続時の穴埋めでは呼ばない
+pub fn record_trades(trades: &[TradeRecord]) {
+    if let Some(last) = trades.iter().max_by_key(|t| t.timestamp) {
+        let timestamp = last.timestamp as f64 / 1000.;
+        set_symbol_gauge("bot_last_trade_timestamp_seconds", last.symbol, timestamp);
+        set_symbol_gauge("bot_ws_lag_seconds", last.symbol, Utc::now().timestamp_millis() as f64 / 1000. - timestamp);
+    }
+}
+
+pub fn record_orderbook_depth(symbol: Symbol, orderbook: &OrderbookRepository) {
+    for (side, levels) in ["buy", "sell"].iter().zip(&orderbook.state) {
+        set_gauge("bot_orderbook_depth", &[("exchange", &symbol.exc.to_string()), ("symbol", &symbol.to_native()), ("side", side)], levels.len() as f64);
+    }
+}
+
+/// REST・websocketのpathのうち数字だけの部分（注文idなど）を`{id}`にまとめる
+pub fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .map(|s| if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) { "{id}" } else { s })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn collect_rate_limits() {
+    for (exc, class, stats) in queue_delay_stats() {
+        if stats.count == 0 {
+            continue;
+        }
+        let class = format!("{:?}", class).to_lowercase();
+        let labels = [("exchange", exc.to_string()), ("class", class)];
+        let labels = labels.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+        set_summary("bot_rate_limit_wait_seconds", &labels, stats.total.as_secs_f64(), stats.count);
+        set_gauge("bot_rate_limit_wait_seconds_max", &labels, stats.max.as_secs_f64());
+    }
+}
+
+fn write_header(out: &mut String, name: &str, default_kind: Kind) {
+    match METRICS.iter().find(|(n, _, _)| *n == name) {
+        Some((_, kind, help)) => {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind.as_str());
+        },
+        None if default_kind == Kind::Summary => {
+            let _ = writeln!(out, "# TYPE {} summary", name);
+        },
+        None => {
+            let _ = writeln!(out, "# TYPE {} untyped", name);
+        },
+    }
+}
+
+/// Prometheusのtext formatで出力する
+pub fn render() -> String {
+    collect_rate_limits();
+    for collector in COLLECTORS.lock().iter() {
+        collector();
+    }
+    let registry = REGISTRY.lock();
+    let mut out = String::new();
+    for (name, values) in &registry.values {
+        write_header(&mut out, name, Kind::Gauge);
+        for (labels, value) in values {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+    for (name, values) in &registry.summaries {
+        write_header(&mut out, name, Kind::Summary);
+        for (labels, (sum, count)) in values {
+            let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+            let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+        }
+    }
+    out
+}
+
+async fn handle_http(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(res.unwrap())
+}
+
+/// `GET /metrics`で返す。終了しない
+pub async fn serve_metrics(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_http)) });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("serving metrics on http://{}/metrics", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    inc_counter("bot_ws_messages_total", &[("url", "wss://example.com/\"ws\"")], 1.);
+    inc_counter("bot_ws_messages_total", &[("url", "wss://example.com/\"ws\"")], 2.);
+    set_gauge("test_render_gauge", &[], 1.5);
+    observe("bot_rest_request_duration_seconds", &[("path", "/test_render")], 0.25);
+    observe("bot_rest_request_duration_seconds", &[("path", "/test_render")], 0.5);
+    let out = render();
+    assert!(out.contains("# TYPE bot_ws_messages_total counter\n"));
+    assert!(out.contains("bot_ws_messages_total{url=\"wss://example.com/\\\"ws\\\"\"} 3\n"));
+    assert!(out.contains("# TYPE test_render_gauge untyped\ntest_render_gauge 1.5\n"));
+    assert!(out.contains("# TYPE bot_rest_request_duration_seconds summary\n"));
+    assert!(out.contains("bot_rest_request_duration_seconds_sum{path=\"/test_render\"} 0.75\n"));
+    assert!(out.contains("bot_rest_request_duration_seconds_count{path=\"/test_render\"} 2\n"));
+}
+
+#[test]
+fn test_normalize_path() {
+    assert_eq!(normalize_path("/api/exchange/orders/12345"), "/api/exchange/orders/{id}");
+    assert_eq!(normalize_path("/api/v3/order"), "/api/v3/order");
+    assert_eq!(normalize_path("/v1/orders"), "/v1/orders");
+    assert_eq!(normalize_path(""), "");
+}
@@ -6,9 +6,16 @@ pub mod record_writer;
 pub mod strategy_utils;
 pub mod kline_mmap;
 pub mod reserved_orders;
+pub mod order_manager;
+pub mod risk;
+pub mod alert;
+pub mod metrics;
+pub mod control;
+pub mod config_watcher;
 pub mod tracingmm_utils;
 pub mod useful_traits;
 pub mod orderbook_repository;
 pub mod draw_orderbook;
 pub mod draw;
 pub mod serde;
+pub mod shutdown;
@@ -0,0 +1,331 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use uuid::Uuid;
+
+use crate::{client::exchange::{NewOrder, OrderId, OpenOrder, Position, Execution, OrderEvent, OrderStatus}, data_structure::float_exp::FloatExp, order_types::{Side, OrderType, PosSide}, symbol::Symbol};
+
+use super::tracingmm_utils::TracingMMPosition;
+
+/// 約定・キャンセル済みの注文を残しておく時間
+const CLOSED_ORDER_RETENTION_HOURS: i64 = 1;
+
+/// 発注のレスポンスを待つ約定を残しておく時間。過ぎたら手動の発注など知らない注文のものとみなす
+const EARLY_EXECUTION_RETENTION_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderState {
+    /// 発注のレスポンス待ち
+    PendingNew,
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+}
+
+impl OrderState {
+    pub fn is_closed(&self) -> bool {
+        matches!(self, OrderState::Filled | OrderState::Cancelled | OrderState::Rejected)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ManagedOrder {
+    pub client_order_id: String,
+    /// 取引所の注文id。PendingNewの間はNone
+    pub order_id: Option<OrderId>,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub price: Option<FloatExp>,
+    pub amount: FloatExp,
+    pub executed: FloatExp,
+    pub state: OrderState,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ManagedOrder {
+    fn add_executed(&mut self, amount: FloatExp) {
+        let amount = amount.round(self.executed.exp);
+        self.executed += amount;
+        // 取引所の状態イベントで閉じていれば戻さない
+        if !self.state.is_closed() {
+            self.state = if self.amount <= self.executed { OrderState::Filled } else { OrderState::PartiallyFilled };
+        }
+        self.updated_at = Utc::now();
+    }
+}
+
+/// reconcileで見つかった取引所とのずれ
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence {
+    /// 取引所にあるが把握していない注文
+    UnknownOrder(OrderId),
+    /// 未約定のはずが取引所にない注文。約定かキャンセルのイベントを取りこぼしている
+    MissingOrder(OrderId),
+    Position { pos_side: PosSide, local: FloatExp, exchange: FloatExp },
+}
+
+/// 注文ごとの状態と約定から計算した建玉を持つ
+/// 約定・注文イベントで更新し、取引所のRESTのsnapshotとは定期的にreconcileで突き合わせる
+#[derive(Debug, Clone)]
+pub struct OrderManager {
+    symbol: Symbol,
+    /// client_order_id -> 注文
+    orders: HashMap<String, ManagedOrder>,
+    /// 取引所の注文id -> client_order_id
+    ids: HashMap<OrderId, String>,
+    /// 発注のレスポンスより先に来た約定と最初に受け取った時刻。注文idがわかったときに足す
+    early_executions: HashMap<OrderId, (FloatExp, DateTime<Utc>)>,
+    positions: [TracingMMPosition; 2],
+    /// 最後に約定を反映した時刻。これより前に取ったsnapshotで建玉を上書きしない
+    last_execution_at: Option<DateTime<Utc>>,
+}
+
+impl OrderManager {
+    pub fn new(symbol: Symbol) -> Self {
+        let pos = TracingMMPosition::new(symbol.price_precision(), symbol.amount_precision());
+        Self {
+            symbol,
+            orders: HashMap::new(),
+            ids: HashMap::new(),
+            early_executions: HashMap::new(),
+            positions: [pos.clone(), pos],
+            last_execution_at: None,
+        }
+    }
+
+    /// [Long, Short]
+    pub fn positions(&self) -> [TracingMMPosition; 2] {
+        self.positions.clone()
+    }
+
+    pub fn get(&self, client_order_id: &str) -> Option<&ManagedOrder> {
+        self.orders.get(client_order_id)
+    }
+
+    pub fn get_by_order_id(&self, order_id: &str) -> Option<&ManagedOrder> {
+        self.ids.get(order_id).and_then(|cid| self.orders.get(cid))
+    }
+
+    /// 閉じていない注文
+    pub fn active_orders(&self) -> impl Iterator<Item = &ManagedOrder> {
+        self.orders.values().filter(|o| !o.state.is_closed())
+    }
+
+    /// 取引所の注文idがわかっている注文。閉じたものも残っている間は含む
+    pub fn tracked_order_ids(&self) -> HashSet<OrderId> {
+        self.ids.keys().cloned().collect()
+    }
+
+    /// 発注前に呼ぶ。PendingNewで登録してclient_order_idを返す
+    pub fn new_order(&mut self, order: &NewOrder) -> String {
+        let client_order_id = Uuid::new_v4().simple().to_string();
+        self.orders.insert(client_order_id.clone(), ManagedOrder {
+            client_order_id: client_order_id.clone(),
+            order_id: None,
+            side: order.side,
+            order_type: order.order_type.clone(),
+            price: order.price,
+            amount: order.amount,
+            executed: FloatExp::new(0, order.amount.exp),
+            state: OrderState::PendingNew,
+            updated_at: Utc::now(),
+        });
+        client_order_id
+    }
+
+    /// 発注が通って取引所の注文idがわかった
+    pub fn on_accepted(&mut self, client_order_id: &str, order_id: &OrderId) {
+        let Some(order) = self.orders.get_mut(client_order_id) else {
+            return;
+        };
+        order.order_id = Some(order_id.clone());
+        if order.state == OrderState::PendingNew {
+            order.state = OrderState::Open;
+        }
+        order.updated_at = Utc::now();
+        if let Some((executed, _)) = self.early_executions.remove(order_id) {
+            order.add_executed(executed);
+        }
+        self.ids.insert(order_id.clone(), client_order_id.to_string());
+    }
+
+    pub fn on_rejected(&mut self, client_order_id: &str) {
+        if let Some(order) = self.orders.get_mut(client_order_id) {
+            order.state = OrderState::Rejected;
+            order.updated_at = Utc::now();
+        }
+    }
+
+    /// 約定数量はon_executionで数えるので状態だけ見る
+    pub fn on_order_event(&mut self, event: &OrderEvent) {
+        if event.symbol != self.symbol {
+            return;
+        }
+        let Some(order) = self.ids.get(&event.order_id).and_then(|cid| self.orders.get_mut(cid)) else {
+            return;
+        };
+        let next = match event.status {
+            OrderStatus::Ordered => OrderState::Open,
+            OrderStatus::PartiallyFilled => OrderState::PartiallyFilled,
+            OrderStatus::Filled => OrderState::Filled,
+            OrderStatus::Canceled | OrderStatus::Expired => OrderState::Cancelled,
+            OrderStatus::Rejected => OrderState::Rejected,
+        };
+        // 閉じた注文は開き直さない
+        if !order.state.is_closed() {
+            order.state = next;
+            order.updated_at = Utc::now();
+        }
+    }
+
+    /// 約定を注文と建玉に反映する。知らない注文の約定（手動の発注など）も建玉には反映する
+    pub fn on_execution(&mut self, execution: &Execution) {
+        if execution.symbol != self.symbol {
+            return;
+        }
+        match self.ids.get(&execution.order_id).and_then(|cid| self.orders.get_mut(cid)) {
+            Some(order) => order.add_executed(execution.amount),
+            None => {
+                let exp = self.symbol.amount_precision();
+                self.early_executions.entry(execution.order_id.clone()).or_insert((FloatExp::new(0, exp), Utc::now())).0 += execution.amount.round(exp);
+            },
+        }
+        TracingMMPosition::apply_execution(&mut self.positions, execution.side, execution.price, execution.amount);
+        self.last_execution_at = Some(Utc::now());
+    }
+
+    /// 取引所の注文・建玉と突き合わせてずれを返す。建玉は取引所の値に合わせる
+    /// 発注中（PendingNew）の注文と、snapshotを取りに行ったrequested_at以降に更新された注文・建玉は見ない
+    pub fn reconcile(&mut self, open_orders: &[OpenOrder], positions: &[Position], requested_at: DateTime<Utc>) -> Vec<Divergence> {
+        let mut ret = vec![];
+        let now = Utc::now();
+        for o in open_orders.iter().filter(|o| o.symbol == self.symbol) {
+            if self.ids.contains_key(&o.id) {
+                continue;
+            }
+            ret.push(Divergence::UnknownOrder(o.id.clone()));
+            let client_order_id = Uuid::new_v4().simple().to_string();
+            self.ids.insert(o.id.clone(), client_order_id.clone());
+            self.orders.insert(client_order_id.clone(), ManagedOrder {
+                client_order_id,
+                order_id: Some(o.id.clone()),
+                side: o.side,
+                order_type: o.order_type.clone(),
+                price: o.price,
+                amount: o.amount,
+                executed: FloatExp::new(0, o.amount.exp),
+                state: OrderState::Open,
+                updated_at: now,
+            });
+        }
+        for order in self.orders.values_mut().filter(|o| matches!(o.state, OrderState::Open | OrderState::PartiallyFilled) && o.updated_at < requested_at) {
+            let Some(order_id) = &order.order_id else {
+                continue;
+            };
+            if !open_orders.iter().any(|o| &o.id == order_id) {
+                ret.push(Divergence::MissingOrder(order_id.clone()));
+                // 約定かキャンセルかはわからないが、建玉は下で取引所に合わせるので閉じておけばよい
+                order.state = OrderState::Cancelled;
+                order.updated_at = now;
+            }
+        }
+
+        let snapshot = TracingMMPosition::from_positions(&positions.to_vec(), self.symbol.price_precision(), self.symbol.amount_precision());
+        if self.last_execution_at.is_some_and(|t| t >= requested_at) {
+            info!("skip reconciling positions: executions arrived after the snapshot was requested");
+        } else {
+            for pos_side in [PosSide::Long, PosSide::Short] {
+                let idx = pos_side as usize;
+                if self.positions[idx].pos != snapshot[idx].pos {
+                    ret.push(Divergence::Position { pos_side, local: self.positions[idx].pos, exchange: snapshot[idx].pos });
+                    self.positions[idx] = snapshot[idx].clone();
+                }
+            }
+        }
+
+        // 閉じてしばらく経った注文は捨てる
+        let expired = self.orders.values()
+            .filter(|o| o.state.is_closed() && o.updated_at + Duration::hours(CLOSED_ORDER_RETENTION_HOURS) < now)
+            .map(|o| o.client_order_id.clone())
+            .collect::<Vec<_>>();
+        for cid in expired {
+            if let Some(order_id) = self.orders.remove(&cid).and_then(|o| o.order_id) {
+                self.ids.remove(&order_id);
+            }
+        }
+        self.early_executions.retain(|order_id, (_, received_at)| {
+            let keep = *received_at + Duration::minutes(EARLY_EXECUTION_RETENTION_MINUTES) >= now;
+            if !keep {
+                info!("drop executions of unknown order: {}", order_id);
+            }
+            keep
+        });
+        if !ret.is_empty() {
+            info!("order manager diverged from exchange: {:?}", ret);
+        }
+        ret
+    }
+}
+
+#[test]
+fn test_order_manager() {
+    use crate::symbol::{Currency, SymbolType, Exchange};
+
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let mut manager = OrderManager::new(symbol);
+    let execution = |order_id: &str, side: Side, amount: i64| Execution {
+        order_id: order_id.to_string(),
+        symbol,
+        side,
+        price: FloatExp::new(4000000, 0),
+        amount: FloatExp::new(amount, -2),
+        fee: 0.,
+        timestamp: Utc::now(),
+    };
+
+    let buy = manager.new_order(&NewOrder::limit(symbol, Side::Buy, FloatExp::new(4000000, 0), FloatExp::new(3, -2)));
+    assert_eq!(manager.get(&buy).unwrap().state, OrderState::PendingNew);
+    // 発注のレスポンスより先に約定が来る
+    manager.on_execution(&execution("1", Side::Buy, 1));
+    manager.on_accepted(&buy, &"1".to_string());
+    assert_eq!(manager.get(&buy).unwrap().state, OrderState::PartiallyFilled);
+    manager.on_execution(&execution("1", Side::Buy, 2));
+    assert_eq!(manager.get_by_order_id("1").unwrap().state, OrderState::Filled);
+    assert_eq!(manager.positions()[0].pos, FloatExp::new(3, -2));
+    assert_eq!(manager.positions()[0].entry_price, FloatExp::new(4000000, 0));
+
+    let rejected = manager.new_order(&NewOrder::limit(symbol, Side::Sell, FloatExp::new(4100000, 0), FloatExp::new(3, -2)));
+    manager.on_rejected(&rejected);
+    assert_eq!(manager.active_orders().count(), 0);
+
+    let sell = manager.new_order(&NewOrder::limit(symbol, Side::Sell, FloatExp::new(4100000, 0), FloatExp::new(3, -2)));
+    manager.on_accepted(&sell, &"2".to_string());
+
+    // 取引所では2が消えて3があり、建玉も違う
+    let open_orders = vec![OpenOrder { id: "3".to_string(), symbol, side: Side::Buy, order_type: OrderType::Limit, price: None, amount: FloatExp::new(1, -2), created_at: Utc::now() }];
+    let positions = vec![Position { symbol, pos_side: PosSide::Long, amount: FloatExp::new(2, -2), price: FloatExp::new(4000000, 0) }];
+    let divergences = manager.reconcile(&open_orders, &positions, Utc::now());
+    assert_eq!(divergences, vec![
+        Divergence::UnknownOrder("3".to_string()),
+        Divergence::MissingOrder("2".to_string()),
+        Divergence::Position { pos_side: PosSide::Long, local: FloatExp::new(3, -2), exchange: FloatExp::new(2, -2) },
+    ]);
+    assert_eq!(manager.get(&sell).unwrap().state, OrderState::Cancelled);
+    assert_eq!(manager.positions()[0].pos, FloatExp::new(2, -2));
+    assert!(manager.reconcile(&open_orders, &positions, Utc::now()).is_empty());
+
+    // snapshotを取りに行った後に約定が来たら、古いsnapshotで建玉を上書きしない
+    let requested_at = Utc::now();
+    manager.on_execution(&execution("4", Side::Buy, 1));
+    assert!(manager.reconcile(&open_orders, &positions, requested_at).is_empty());
+    assert_eq!(manager.positions()[0].pos, FloatExp::new(3, -2));
+
+    // 知らない注文の約定は時間が経ったら捨てる
+    assert!(manager.early_executions.contains_key("4"));
+    manager.early_executions.get_mut("4").unwrap().1 -= Duration::minutes(EARLY_EXECUTION_RETENTION_MINUTES + 1);
+    manager.reconcile(&open_orders, &positions, requested_at);
+    assert!(manager.early_executions.is_empty());
+}
@@ -1,10 +1,11 @@
-use std::{hash::Hash, collections::HashMap};
+use std::{hash::Hash, collections::HashMap, fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}};
 
-use log::info;
+use log::{info, error};
 use maplit::hashmap;
+use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
-use crate::{order_types::{Side, PosSide, OrderType}, data_structure::float_exp::FloatExp, client::types::TradeRecord};
+use crate::{order_types::{Side, PosSide, OrderType}, data_structure::float_exp::FloatExp, client::{types::TradeRecord, exchange::{OpenOrder, Position}}, symbol::Symbol};
 
 #[derive(Debug, Clone, Eq)]
 pub struct ReservedOrder {
@@ -17,6 +18,8 @@ pub struct ReservedOrder {
     pub pair_order_id: Option<String>,
     pub pair_rsv_order_id: Option<Uuid>,
     pub is_ordered: bool,
+    /// 発火して取引所に受け付けられた注文のid
+    pub ordered_id: Option<String>,
 }
 
 impl PartialEq for ReservedOrder {
@@ -43,6 +46,7 @@ impl ReservedOrder {
             pair_order_id: None,
             pair_rsv_order_id: None,
             is_ordered: false,
+            ordered_id: None,
         }
     }
 
@@ -65,11 +69,80 @@ impl ReservedOrder {
     }
 }
 
+/// journalの1行。FloatExpはDeserializeできないのでexpと分けて持つ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalOrder {
+    id: String,
+    order_type: OrderType,
+    side: Side,
+    pos_side: PosSide,
+    price: f64,
+    price_exp: i32,
+    amount: f64,
+    amount_exp: i32,
+    pair_order_id: Option<String>,
+    pair_rsv_order_id: Option<String>,
+    is_ordered: bool,
+    #[serde(default)]
+    ordered_id: Option<String>,
+}
+
+impl From<&ReservedOrder> for JournalOrder {
+    fn from(o: &ReservedOrder) -> Self {
+        Self {
+            id: o.id.to_string(),
+            order_type: o.order_type.clone(),
+            side: o.side,
+            pos_side: o.pos_side,
+            price: o.price.to_f64(),
+            price_exp: o.price.exp,
+            amount: o.amount.to_f64(),
+            amount_exp: o.amount.exp,
+            pair_order_id: o.pair_order_id.clone(),
+            pair_rsv_order_id: o.pair_rsv_order_id.map(|id| id.to_string()),
+            is_ordered: o.is_ordered,
+            ordered_id: o.ordered_id.clone(),
+        }
+    }
+}
+
+impl TryFrom<JournalOrder> for ReservedOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(o: JournalOrder) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: Uuid::parse_str(&o.id)?,
+            order_type: o.order_type,
+            side: o.side,
+            pos_side: o.pos_side,
+            price: FloatExp::from_f64(o.price, o.price_exp),
+            amount: FloatExp::from_f64(o.amount, o.amount_exp),
+            pair_order_id: o.pair_order_id,
+            pair_rsv_order_id: o.pair_rsv_order_id.map(|id| Uuid::parse_str(&id)).transpose()?,
+            is_ordered: o.is_ordered,
+            ordered_id: o.ordered_id,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JournalEvent {
+    Add { order: JournalOrder },
+    Update { order: JournalOrder },
+    Fire { id: String },
+    Ordered { id: String, order_id: String },
+    Remove { id: String },
+    Clear,
+}
+
 #[derive(Debug, Clone)]
 pub struct ReservedOrdersManager {
     pub reserved_orders: HashMap<Uuid, ReservedOrder>,
     prev_price: Option<FloatExp>,
     price_exp: i32,
+    /// Noneならメモリ上だけで持つ（backtestなど）
+    journal: Option<PathBuf>,
 }
 
 impl ReservedOrdersManager {
@@ -78,11 +151,96 @@ impl ReservedOrdersManager {
             reserved_orders: hashmap! {},
             prev_price: None,
             price_exp,
+            journal: None,
+        }
+    }
+
+    /// 追加・更新・発火・削除を`.reserved_{name}_{symbol}.jsonl`に追記し、起動時はそこから復元する
+    /// 復元した注文は実際の注文・建玉とずれているかもしれないのでreconcileで突き合わせる
+    pub fn open(name: &str, symbol: &Symbol, price_exp: i32) -> anyhow::Result<Self> {
+        let path = PathBuf::from(format!(".reserved_{}_{}.jsonl", name, symbol.to_file_form()));
+        let mut manager = Self::new(price_exp);
+        if path.exists() {
+            manager.replay(&path)?;
+            info!("restored {} reserved orders from {}", manager.reserved_orders.len(), path.display());
+        }
+        manager.journal = Some(path);
+        // 追記し続けると大きくなるので現在の状態だけで書き直す
+        manager.compact()?;
+        Ok(manager)
+    }
+
+    fn replay(&mut self, path: &Path) -> anyhow::Result<()> {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            // 書き込み途中で落ちた最後の行は読めないので捨てる
+            let event = match serde_json::from_str::<JournalEvent>(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    info!("skip broken journal line: {}, {}", line, e);
+                    continue;
+                },
+            };
+            match event {
+                JournalEvent::Add { order } | JournalEvent::Update { order } => {
+                    let order = ReservedOrder::try_from(order)?;
+                    self.reserved_orders.insert(order.id, order);
+                },
+                JournalEvent::Fire { id } => {
+                    if let Some(order) = self.reserved_orders.get_mut(&Uuid::parse_str(&id)?) {
+                        order.is_ordered = true;
+                    }
+                },
+                JournalEvent::Ordered { id, order_id } => {
+                    if let Some(order) = self.reserved_orders.get_mut(&Uuid::parse_str(&id)?) {
+                        order.ordered_id = Some(order_id);
+                    }
+                },
+                JournalEvent::Remove { id } => {
+                    self.reserved_orders.remove(&Uuid::parse_str(&id)?);
+                },
+                JournalEvent::Clear => self.reserved_orders.clear(),
+            }
+        }
+        Ok(())
+    }
+
+    fn compact(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.journal else {
+            return Ok(());
+        };
+        let mut file = File::create(path)?;
+        for order in self.reserved_orders.values() {
+            writeln!(file, "{}", serde_json::to_string(&JournalEvent::Add { order: order.into() })?)?;
+        }
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// 書き込めなくても発注は止めない
+    fn append(&self, event: JournalEvent) {
+        let Some(path) = &self.journal else {
+            return;
+        };
+        let res = (|| -> anyhow::Result<()> {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&event)?)?;
+            file.sync_data()?;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            error!("failed to write reserved orders journal: {:?}", e);
         }
     }
 
     pub fn cancel_all_orders(&mut self) {
         self.reserved_orders.clear();
+        if let Err(e) = self.compact() {
+            error!("failed to write reserved orders journal: {:?}", e);
+        }
     }
 
     pub fn add_reserved_order(&mut self, order_type: OrderType, side: Side, pos_side: PosSide, price: FloatExp, amount: FloatExp, pair_order_id: Option<String>) -> Uuid {
@@ -90,16 +248,102 @@ impl ReservedOrdersManager {
         let ret = reserved_order.id;
         reserved_order.pair_order_id = pair_order_id;
         info!("add_reserved_order: {:?}", reserved_order);
+        self.append(JournalEvent::Add { order: (&reserved_order).into() });
         self.reserved_orders.insert(reserved_order.id, reserved_order);
         ret
     }
 
+    /// journalには残らないので、journalを使うときはset_pair_rsv_order_idなどを使う
     pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut ReservedOrder> {
         self.reserved_orders.get_mut(id)
     }
 
+    pub fn set_pair_rsv_order_id(&mut self, id: &Uuid, pair_rsv_order_id: Option<Uuid>) {
+        if let Some(order) = self.reserved_orders.get_mut(id) {
+            order.pair_rsv_order_id = pair_rsv_order_id;
+            let event = JournalEvent::Update { order: (&*order).into() };
+            self.append(event);
+        }
+    }
+
+    /// 発火した注文が取引所に受け付けられた
+    pub fn set_ordered_id(&mut self, id: &Uuid, order_id: &str) {
+        if let Some(order) = self.reserved_orders.get_mut(id) {
+            order.ordered_id = Some(order_id.to_string());
+            self.append(JournalEvent::Ordered { id: id.to_string(), order_id: order_id.to_string() });
+        }
+    }
+
     pub fn remove(&mut self, id: &Uuid) -> Option<ReservedOrder> {
-        self.reserved_orders.remove(id)
+        let ret = self.reserved_orders.remove(id);
+        if ret.is_some() {
+            self.append(JournalEvent::Remove { id: id.to_string() });
+        }
+        ret
+    }
+
+    /// 復元した予約注文を実際の注文・建玉と突き合わせ、削除したものを返す
+    /// - 発火して取引所に受け付けられたものは取引所の注文・建玉に反映されているので消す
+    /// - 発火したが受け付けられたかわからないものは発火前に戻す
+    /// - 決済側の注文で対応する建玉がないものは消す
+    /// - なくなった注文・予約注文への参照は外す
+    pub fn reconcile(&mut self, open_orders: &[OpenOrder], positions: &[Position]) -> Vec<ReservedOrder> {
+        let has_position = |pos_side: PosSide| positions.iter().any(|p| p.pos_side == pos_side && p.amount.value != 0);
+        let removed_ids = self.reserved_orders.values()
+            .filter(|o| (o.is_ordered && o.ordered_id.is_some()) || (o.side != o.pos_side.to_side() && !has_position(o.pos_side)))
+            .map(|o| o.id)
+            .collect::<Vec<_>>();
+        let removed = removed_ids.iter().filter_map(|id| self.remove(id)).collect::<Vec<_>>();
+
+        let mut updated = vec![];
+        for order in self.reserved_orders.values() {
+            let mut next = order.clone();
+            if order.is_ordered {
+                info!("re-arm reserved order not confirmed by the exchange: {:?}", order);
+                next.is_ordered = false;
+            }
+            if let Some(pair_order_id) = &order.pair_order_id {
+                if !open_orders.iter().any(|o| &o.id == pair_order_id) {
+                    next.pair_order_id = None;
+                }
+            }
+            if let Some(pair_rsv_order_id) = &order.pair_rsv_order_id {
+                if !self.reserved_orders.contains_key(pair_rsv_order_id) {
+                    next.pair_rsv_order_id = None;
+                }
+            }
+            if next.pair_order_id != order.pair_order_id || next.pair_rsv_order_id != order.pair_rsv_order_id || next.is_ordered != order.is_ordered {
+                updated.push(next);
+            }
+        }
+        for order in updated {
+            self.append(JournalEvent::Update { order: (&order).into() });
+            self.reserved_orders.insert(order.id, order);
+        }
+        removed
+    }
+
+    /// 取引所の注文が約定したら、その注文とペアの予約注文（ロスカットなど）を約定した分だけ減らす
+    /// 残りがなくなったものは削除する。変更・削除した予約注文を返す
+    pub fn execution_handler(&mut self, order_id: &str, amount: FloatExp) -> Vec<ReservedOrder> {
+        let ids = self.reserved_orders.values()
+            .filter(|o| !o.is_ordered && o.pair_order_id.as_deref() == Some(order_id))
+            .map(|o| o.id)
+            .collect::<Vec<_>>();
+        let mut changed = vec![];
+        for id in ids {
+            let order = self.reserved_orders.get_mut(&id).unwrap();
+            let executed = amount.round(order.amount.exp);
+            if order.amount <= executed {
+                changed.extend(self.remove(&id));
+                continue;
+            }
+            order.amount -= executed;
+            let order = order.clone();
+            self.append(JournalEvent::Update { order: (&order).into() });
+            changed.push(order);
+        }
+        changed
     }
 
     /// 発火する注文を返す
@@ -121,6 +365,7 @@ impl ReservedOrdersManager {
             }
             self.prev_price = Some(trade_price);
         }
+        self.append_fired(&reserved_orders);
         reserved_orders
     }
 
@@ -135,6 +380,78 @@ impl ReservedOrdersManager {
                 reserved_orders.push(reserved_order.clone());
             }
         }
+        self.append_fired(&reserved_orders);
         reserved_orders
     }
-}
\ No newline at end of file
+
+    fn append_fired(&self, fired: &[ReservedOrder]) {
+        for order in fired {
+            self.append(JournalEvent::Fire { id: order.id.to_string() });
+        }
+    }
+}
+#[test]
+fn test_journal_and_reconcile() {
+    use chrono::Utc;
+    use crate::symbol::{Currency, SymbolType, Exchange};
+
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Spot, Exchange::Coincheck);
+    let path = format!(".reserved_test_{}.jsonl", symbol.to_file_form());
+    let _ = std::fs::remove_file(&path);
+
+    let mut manager = ReservedOrdersManager::open("test", &symbol, 0).unwrap();
+    let close_id = manager.add_reserved_order(OrderType::Limit, Side::Sell, PosSide::Long, FloatExp::new(4000000, 0), FloatExp::new(1, -2), None);
+    let losscut_id = manager.add_reserved_order(OrderType::Stop, Side::Sell, PosSide::Long, FloatExp::new(3900000, 0), FloatExp::new(1, -2), Some("1".to_string()));
+    manager.set_pair_rsv_order_id(&losscut_id, Some(close_id));
+    let open_id = manager.add_reserved_order(OrderType::Limit, Side::Buy, PosSide::Long, FloatExp::new(3950000, 0), FloatExp::new(1, -2), None);
+    manager.remove(&open_id);
+    let fired_id = manager.add_reserved_order(OrderType::Limit, Side::Buy, PosSide::Long, FloatExp::new(3990000, 0), FloatExp::new(1, -2), None);
+    let unconfirmed_id = manager.add_reserved_order(OrderType::Limit, Side::Buy, PosSide::Long, FloatExp::new(3985000, 0), FloatExp::new(1, -2), None);
+    let fired = manager.orderbook_handler([(3980000., 1.), (3990000., 1.)]);
+    let mut fired_ids = fired.iter().map(|o| o.id).collect::<Vec<_>>();
+    fired_ids.sort();
+    let mut expected = vec![fired_id, unconfirmed_id];
+    expected.sort();
+    assert_eq!(fired_ids, expected);
+    manager.set_ordered_id(&fired_id, "3");
+
+    // 再起動
+    let mut manager = ReservedOrdersManager::open("test", &symbol, 0).unwrap();
+    assert_eq!(manager.reserved_orders.len(), 4);
+    assert_eq!(manager.reserved_orders[&losscut_id].pair_rsv_order_id, Some(close_id));
+    assert_eq!(manager.reserved_orders[&losscut_id].price, FloatExp::new(3900000, 0));
+    assert!(manager.reserved_orders[&fired_id].is_ordered);
+    assert_eq!(manager.reserved_orders[&fired_id].ordered_id.as_deref(), Some("3"));
+
+    // 建玉はあるがpairの注文はもうない。受け付けられたかわからない発火は戻す
+    let positions = vec![Position { symbol, pos_side: PosSide::Long, amount: FloatExp::new(1, -2), price: FloatExp::new(0, 0) }];
+    let removed = manager.reconcile(&[], &positions);
+    assert_eq!(removed.iter().map(|o| o.id).collect::<Vec<_>>(), vec![fired_id]);
+    assert_eq!(manager.reserved_orders[&losscut_id].pair_order_id, None);
+    assert!(!manager.reserved_orders[&unconfirmed_id].is_ordered);
+
+    // 建玉がなければ決済側の予約注文は消える
+    let open_orders = vec![OpenOrder { id: "2".to_string(), symbol, side: Side::Buy, order_type: OrderType::Limit, price: None, amount: FloatExp::new(1, -2), created_at: Utc::now() }];
+    let removed = manager.reconcile(&open_orders, &[]);
+    assert_eq!(removed.len(), 2);
+    let manager = ReservedOrdersManager::open("test", &symbol, 0).unwrap();
+    assert_eq!(manager.reserved_orders.keys().collect::<Vec<_>>(), vec![&unconfirmed_id]);
+    assert!(!manager.reserved_orders[&unconfirmed_id].is_ordered);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_execution_handler() {
+    let mut manager = ReservedOrdersManager::new(0);
+    let losscut_id = manager.add_reserved_order(OrderType::Stop, Side::Sell, PosSide::Long, FloatExp::new(3900000, 0), FloatExp::new(3, -2), Some("1".to_string()));
+    let other_id = manager.add_reserved_order(OrderType::Stop, Side::Sell, PosSide::Long, FloatExp::new(3900000, 0), FloatExp::new(3, -2), Some("2".to_string()));
+
+    let changed = manager.execution_handler("1", FloatExp::new(1, -2));
+    assert_eq!(changed.len(), 1);
+    assert_eq!(manager.reserved_orders[&losscut_id].amount, FloatExp::new(2, -2));
+
+    let changed = manager.execution_handler("1", FloatExp::new(2, -2));
+    assert_eq!(changed.iter().map(|o| o.id).collect::<Vec<_>>(), vec![losscut_id]);
+    assert!(!manager.reserved_orders.contains_key(&losscut_id));
+    assert_eq!(manager.reserved_orders[&other_id].amount, FloatExp::new(3, -2));
+}
@@ -0,0 +1,469 @@
+use std::{collections::VecDeque, path::Path, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration as StdDuration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use log::{info, error};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::{select, spawn, signal::unix::{signal, SignalKind}};
+
+use crate::{client::{exchange::{ExchangeClient, NewOrder, OrderId, OpenOrder, Position, AssetBalance, Ticker, OrderbookSnapshot, Execution}}, config::RiskLimits, data_structure::float_exp::FloatExp, error_types::BotError, order_types::Side, symbol::{Symbol, Exchange}};
+
+use super::{alert::{alert, Severity}, control::Controllable, metrics, status_repository::StatusRepository, tracingmm_utils::TracingMMPosition};
+
+/// このファイルがあるか、SIGUSR1を受け取るとkill switchが入る。一度入ったら再起動するまで戻らない
+pub const KILL_SWITCH_PATH: &str = ".kill_switch";
+
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// 基準価格を使い回す秒数。これより古ければtickerを取り直す
+const MARK_TTL_SECS: i64 = 60;
+
+pub fn is_killed() -> bool {
+    KILLED.load(Ordering::SeqCst)
+}
+
+/// 建玉を増やす注文だけ止める。control APIで切り替える
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+    info!("new entries {}", if paused { "paused" } else { "resumed" });
+}
+
+fn sign(side: Side) -> f64 {
+    side.to_pos().sign() as f64
+}
+
+#[derive(Debug, Clone)]
+struct RiskState {
+    /// 直近1分間の発注時刻
+    order_times: VecDeque<DateTime<Utc>>,
+    /// 基準価格と取得時刻
+    mark: Option<(f64, DateTime<Utc>)>,
+    /// [Long, Short]。Noneなら未取得
+    positions: Option<[TracingMMPosition; 2]>,
+    price_exp: i32,
+    amount_exp: i32,
+    /// UTCのその日の実現損益（手数料込み）
+    realized_pnl: f64,
+    day: NaiveDate,
+}
+
+impl RiskState {
+    fn new(symbol: Symbol, now: DateTime<Utc>) -> Self {
+        Self {
+            order_times: VecDeque::new(),
+            mark: None,
+            positions: None,
+            price_exp: symbol.price_precision(),
+            amount_exp: symbol.amount_precision(),
+            realized_pnl: 0.,
+            day: now.date_naive(),
+        }
+    }
+
+    /// 同じ日に保存した実現損益なら引き継ぐ
+    fn restore(&mut self, status: &Value) {
+        if status["day"].as_str() == Some(&self.day.to_string()) {
+            self.realized_pnl = status["realized_pnl"].as_f64().unwrap_or(0.);
+        }
+    }
+
+    fn to_status(&self) -> Value {
+        json!({"day": self.day.to_string(), "realized_pnl": self.realized_pnl})
+    }
+
+    fn roll_day(&mut self, now: DateTime<Utc>) {
+        let day = now.date_naive();
+        if day != self.day {
+            self.day = day;
+            self.realized_pnl = 0.;
+        }
+    }
+
+    /// base建ての符号付き建玉。Noneなら未取得
+    fn position(&self) -> Option<f64> {
+        self.positions.as_ref().map(|p| p[0].pos.to_f64() - p[1].pos.to_f64())
+    }
+
+    /// 多い側の建値
+    fn entry_price(&self) -> f64 {
+        match &self.positions {
+            Some([long, short]) if long.pos >= short.pos => long.entry_price.to_f64(),
+            Some([_, short]) => short.entry_price.to_f64(),
+            None => 0.,
+        }
+    }
+
+    fn unrealized_pnl(&self, mark: f64) -> f64 {
+        self.positions.as_ref()
+            .map(|[long, short]| long.pos.to_f64() * (mark - long.entry_price.to_f64()) + short.pos.to_f64() * (short.entry_price.to_f64() - mark))
+            .unwrap_or(0.)
+    }
+
+    fn set_positions(&mut self, positions: &[Position]) {
+        self.positions = Some(TracingMMPosition::from_positions(&positions.to_vec(), self.price_exp, self.amount_exp));
+    }
+
+    /// 建玉を減らす分は建値との差を実現損益にする
+    fn apply_execution(&mut self, side: Side, price: FloatExp, amount: FloatExp, fee: f64, now: DateTime<Utc>) {
+        self.roll_day(now);
+        // 建玉がわからないうちはsnapshotに含まれるので数えない
+        let Some(positions) = &mut self.positions else {
+            return;
+        };
+        self.realized_pnl += TracingMMPosition::apply_execution(positions, side, price, amount) - fee;
+    }
+
+    fn reduces_position(&self, order: &NewOrder) -> bool {
+        let position = self.position().unwrap_or(0.);
+        let next = position + order.amount.to_f64() * sign(order.side);
+        next.abs() <= position.abs()
+    }
+
+    /// 制限を超えていれば理由を返す
+    fn check(&mut self, limits: &RiskLimits, order: &NewOrder, reference: f64, now: DateTime<Utc>) -> Result<(), String> {
+        self.roll_day(now);
+        while self.order_times.front().is_some_and(|&t| t <= now - Duration::minutes(1)) {
+            self.order_times.pop_front();
+        }
+        // 建玉を減らす注文（発火したロスカットやflatten）は止めない
+        if self.reduces_position(order) {
+            return Ok(());
+        }
+        if let Some(max) = limits.max_orders_per_minute {
+            if self.order_times.len() >= max {
+                return Err(format!("{} orders in the last minute", self.order_times.len()));
+            }
+        }
+        let price = order.price.or(order.trigger_price).map(|p| p.to_f64());
+        if let (Some(rate), Some(price)) = (limits.price_band_rate, price) {
+            if (price / reference - 1.).abs() > rate {
+                return Err(format!("price {} is out of band from {}", price, reference));
+            }
+        }
+        let amount = order.amount.to_f64();
+        let notional = amount * price.unwrap_or(reference);
+        if let Some(max) = limits.max_order_notional {
+            if notional > max {
+                return Err(format!("notional {} exceeds {}", notional, max));
+            }
+        }
+        let next = self.position().unwrap_or(0.) + amount * sign(order.side);
+        if let Some(max) = limits.max_position {
+            if next.abs() > max {
+                return Err(format!("position {} exceeds {}", next, max));
+            }
+        }
+        if let Some(limit) = limits.daily_loss_limit {
+            let pnl = self.realized_pnl + self.unrealized_pnl(reference);
+            if pnl < -limit {
+                return Err(format!("daily pnl {} exceeds loss limit {}", pnl, limit));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 発注の前にリスク制限とkill switchを確かめるExchangeClient
+/// 建玉は初回の発注時とpositionsを呼んだときに取引所から取り、その間はon_executionで追う
+pub struct RiskClient {
+    inner: Arc<dyn ExchangeClient>,
+    symbol: Symbol,
+    limits: RiskLimits,
+    state: Mutex<RiskState>,
+    /// その日の実現損益。再起動しても損失の上限が戻らないように残す
+    status: Mutex<StatusRepository>,
+}
+
+impl RiskClient {
+    /// `.status_risk_{name}_{symbol}.json`に同じ日の実現損益があれば引き継ぐ
+    pub fn new(name: &str, inner: Arc<dyn ExchangeClient>, symbol: Symbol, limits: RiskLimits) -> anyhow::Result<Self> {
+        let status = StatusRepository::new_init(&format!("risk_{}", name), &symbol, None)?;
+        let mut state = RiskState::new(symbol, Utc::now());
+        state.restore(&status[&symbol]);
+        if state.realized_pnl != 0. {
+            info!("risk: restored realized pnl {} of {}", state.realized_pnl, state.day);
+        }
+        Ok(Self {
+            inner,
+            symbol,
+            limits,
+            state: Mutex::new(state),
+            status: Mutex::new(status),
+        })
+    }
+
+    /// 直近の約定価格などを基準価格にする。渡しておけば発注のたびにtickerを取らずに済む
+    pub fn on_mark(&self, price: f64) {
+        self.state.lock().mark = Some((price, Utc::now()));
+    }
+
+    pub fn on_execution(&self, execution: &Execution) {
+        if execution.symbol != self.symbol {
+            return;
+        }
+        let status = {
+            let mut state = self.state.lock();
+            state.apply_execution(execution.side, execution.price, execution.amount, execution.fee, execution.timestamp);
+            state.to_status()
+        };
+        if let Err(e) = self.status.lock().update(self.symbol, status) {
+            error!("risk: failed to save realized pnl: {:?}", e);
+        }
+    }
+
+    async fn reference_price(&self) -> anyhow::Result<f64> {
+        let mark = self.state.lock().mark;
+        if let Some((price, at)) = mark {
+            if Utc::now() - at < Duration::seconds(MARK_TTL_SECS) {
+                return Ok(price);
+            }
+        }
+        let ticker = self.inner.ticker(self.symbol).await?;
+        let mid = ((ticker.bid + ticker.ask).to_f64()) / 2.;
+        self.on_mark(mid);
+        Ok(mid)
+    }
+
+    /// kill switchを監視する。入ったら全注文をキャンセルしてメールで知らせる
+    pub fn start_kill_switch(self: &Arc<Self>) {
+        let client = self.clone();
+        spawn(async move {
+            let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("failed to listen SIGUSR1: {:?}", e);
+                    return;
+                },
+            };
+            let reason = loop {
+                if Path::new(KILL_SWITCH_PATH).exists() {
+                    break KILL_SWITCH_PATH;
+                }
+                select! {
+                    _ = sigusr1.recv() => break "SIGUSR1",
+                    _ = tokio::time::sleep(StdDuration::from_secs(1)) => {},
+                }
+            };
+            client.engage(reason).await;
+        });
+    }
+
+    /// 注文をすべてキャンセルし、取引所の建玉を反対売買で閉じる。以降の新規はpauseする
+    /// limitなら板の反対側の最良価格に指値を出す
+    pub async fn close_positions(&self, limit: bool) -> anyhow::Result<Vec<OrderId>> {
+        set_paused(true);
+        self.inner.cancel_all_orders(self.symbol).await?;
+        let positions = self.positions(self.symbol).await?;
+        let ticker = if limit { Some(self.inner.ticker(self.symbol).await?) } else { None };
+        let mut ids = vec![];
+        for pos in positions.iter().filter(|p| p.symbol == self.symbol && !p.amount.is_zero()) {
+            let side = pos.pos_side.to_side().inv();
+            let order = match &ticker {
+                Some(ticker) => NewOrder::limit(self.symbol, side, if side == Side::Buy { ticker.ask } else { ticker.bid }, pos.amount),
+                None => NewOrder::market(self.symbol, side, pos.amount),
+            };
+            info!("flatten: {:?}", order);
+            ids.push(self.place_order(&order).await?);
+        }
+        Ok(ids)
+    }
+
+    /// 建玉と損益をmetricsに出す
+    pub fn register_metrics(self: &Arc<Self>) {
+        let client = self.clone();
+        metrics::register_collector(move || {
+            let state = client.state.lock().clone();
+            if let Some(position) = state.position() {
+                metrics::set_symbol_gauge("bot_position", client.symbol, position);
+            }
+            metrics::set_symbol_gauge("bot_realized_pnl", client.symbol, state.realized_pnl);
+            if let Some((mark, _)) = state.mark {
+                metrics::set_symbol_gauge("bot_unrealized_pnl", client.symbol, state.unrealized_pnl(mark));
+            }
+        });
+    }
+
+    async fn engage(&self, reason: &str) {
+        KILLED.store(true, Ordering::SeqCst);
+        error!("kill switch engaged by {}", reason);
+        if let Err(e) = self.inner.cancel_all_orders(self.symbol).await {
+            error!("failed to cancel all orders: {:?}", e);
+        }
+        alert(Severity::Critical, format!("kill switch engaged - {} {}", self.symbol.exc, self.symbol.to_native()), format!("engaged by {}", reason));
+    }
+}
+
+/// tracing_mm以外（shannon）はRiskClientをそのままcontrol APIに登録する
+#[async_trait]
+impl Controllable for RiskClient {
+    fn status(&self) -> Value {
+        let state = self.state.lock().clone();
+        json!({
+            "symbol": format!("{} {}", self.symbol.exc, self.symbol.to_native()),
+            "limits": format!("{:?}", self.limits),
+            "position": state.position(),
+            "entry_price": state.entry_price(),
+            "realized_pnl": state.realized_pnl,
+            "mark": state.mark.map(|(price, _)| price),
+        })
+    }
+
+    async fn cancel_all(&self) -> anyhow::Result<()> {
+        self.inner.cancel_all_orders(self.symbol).await
+    }
+
+    async fn flatten(&self, limit: bool) -> anyhow::Result<Vec<OrderId>> {
+        self.close_positions(limit).await
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for RiskClient {
+    fn exchange(&self) -> Exchange {
+        self.inner.exchange()
+    }
+
+    async fn place_order(&self, order: &NewOrder) -> anyhow::Result<OrderId> {
+        if is_killed() {
+            anyhow::bail!(BotError::KillSwitch);
+        }
+        let order = &order.round_to_instrument()?;
+        if order.symbol == self.symbol {
+            if self.state.lock().positions.is_none() {
+                self.positions(self.symbol).await?;
+            }
+            let reference = self.reference_price().await?;
+            let now = Utc::now();
+            let mut state = self.state.lock();
+            if is_paused() && !state.reduces_position(order) {
+                info!("order skipped while paused: {:?}", order);
+                anyhow::bail!(BotError::Paused);
+            }
+            if let Err(reason) = state.check(&self.limits, order, reference, now) {
+                info!("order rejected by risk limit: {}, {:?}", reason, order);
+                anyhow::bail!(BotError::RiskLimit(reason));
+            }
+            state.order_times.push_back(now);
+        }
+        self.inner.place_order(order).await
+    }
+
+    async fn cancel_order(&self, symbol: Symbol, id: &OrderId) -> anyhow::Result<()> {
+        self.inner.cancel_order(symbol, id).await
+    }
+
+    async fn cancel_all_orders(&self, symbol: Symbol) -> anyhow::Result<()> {
+        self.inner.cancel_all_orders(symbol).await
+    }
+
+    async fn open_orders(&self, symbol: Symbol) -> anyhow::Result<Vec<OpenOrder>> {
+        self.inner.open_orders(symbol).await
+    }
+
+    /// 取得した建玉でリスク計算の建玉も置き換える
+    async fn positions(&self, symbol: Symbol) -> anyhow::Result<Vec<Position>> {
+        let ret = self.inner.positions(symbol).await?;
+        if symbol == self.symbol {
+            self.state.lock().set_positions(&ret);
+        }
+        Ok(ret)
+    }
+
+    async fn balances(&self) -> anyhow::Result<Vec<AssetBalance>> {
+        self.inner.balances().await
+    }
+
+    async fn collateral(&self, symbol: Symbol) -> anyhow::Result<f64> {
+        self.inner.collateral(symbol).await
+    }
+
+    async fn ticker(&self, symbol: Symbol) -> anyhow::Result<Ticker> {
+        self.inner.ticker(symbol).await
+    }
+
+    async fn orderbook(&self, symbol: Symbol) -> anyhow::Result<OrderbookSnapshot> {
+        self.inner.orderbook(symbol).await
+    }
+}
+
+#[test]
+fn test_risk_check() {
+    use crate::{symbol::{Currency, SymbolType}, order_types::PosSide};
+
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let limits = RiskLimits {
+        max_order_notional: Some(100_000.),
+        max_position: Some(0.03),
+        max_orders_per_minute: Some(3),
+        price_band_rate: Some(0.05),
+        daily_loss_limit: Some(10_000.),
+    };
+    let now = Utc::now();
+    let mut state = RiskState::new(symbol, now);
+    state.set_positions(&[Position { symbol, pos_side: PosSide::Long, amount: FloatExp::new(2, -2), price: FloatExp::new(4_000_000, 0) }]);
+    let buy = |price: i64, amount: i64| NewOrder::limit(symbol, Side::Buy, FloatExp::new(price, 0), FloatExp::new(amount, -2));
+
+    assert!(state.check(&limits, &buy(4_000_000, 1), 4_000_000., now).is_ok());
+    // 基準価格から離れすぎ
+    assert!(state.check(&limits, &buy(3_000_000, 1), 4_000_000., now).is_err());
+    // 1注文の金額
+    assert!(state.check(&limits, &buy(4_000_000, 3), 4_000_000., now).is_err());
+    // 建玉の上限。減らす注文は通す
+    assert!(state.check(&limits, &buy(4_000_000, 2), 4_000_000., now).is_err());
+    let sell = NewOrder::market(symbol, Side::Sell, FloatExp::new(2, -2));
+    assert!(state.check(&limits, &sell, 4_000_000., now).is_ok());
+
+    // 評価損が上限を超えたら建玉を増やす注文は止める
+    assert!(state.check(&limits, &buy(3_800_000, 1), 3_800_000., now).is_ok());
+    assert!(state.check(&limits, &buy(3_400_000, 1), 3_400_000., now).is_err());
+    assert!(state.check(&limits, &sell, 3_400_000., now).is_ok());
+
+    // 発注回数。使い切っても建玉を減らす成行は通す
+    state.order_times.extend([now, now, now]);
+    assert!(state.check(&limits, &buy(4_000_000, 1), 4_000_000., now).is_err());
+    assert!(state.check(&limits, &sell, 4_000_000., now).is_ok());
+    assert!(state.check(&limits, &buy(4_000_000, 1), 4_000_000., now + Duration::minutes(1)).is_ok());
+}
+
+#[test]
+fn test_risk_apply_execution() {
+    use crate::symbol::{Currency, SymbolType};
+
+    let symbol = Symbol::new(Currency::BTC, Currency::JPY, SymbolType::Perp, Exchange::Bitflyer);
+    let price = |p: i64| FloatExp::new(p, 0);
+    let amount = |a: i64| FloatExp::new(a, -2);
+    let now = Utc::now();
+    let mut state = RiskState::new(symbol, now);
+    // 建玉がわかる前の約定は数えない
+    state.apply_execution(Side::Buy, price(4_000_000), amount(1), 0., now);
+    assert_eq!(state.position(), None);
+
+    state.set_positions(&[]);
+    state.apply_execution(Side::Buy, price(4_000_000), amount(1), 0., now);
+    state.apply_execution(Side::Buy, price(3_800_000), amount(1), 0., now);
+    assert!((state.entry_price() - 3_900_000.).abs() < 1e-6);
+    state.apply_execution(Side::Sell, price(4_000_000), amount(3), 10., now);
+    assert!((state.realized_pnl - (2_000. - 10.)).abs() < 1e-6);
+    assert!((state.position().unwrap() + 0.01).abs() < 1e-9);
+    assert_eq!(state.entry_price(), 4_000_000.);
+
+    // 同じ日なら再起動しても実現損益を引き継ぐ
+    let status = state.to_status();
+    let mut restored = RiskState::new(symbol, now);
+    restored.restore(&status);
+    assert_eq!(restored.realized_pnl, state.realized_pnl);
+    let mut restored = RiskState::new(symbol, now + Duration::days(1));
+    restored.restore(&status);
+    assert_eq!(restored.realized_pnl, 0.);
+
+    // 日付が変わると実現損益は0から
+    state.roll_day(now + Duration::days(1));
+    assert_eq!(state.realized_pnl, 0.);
+}
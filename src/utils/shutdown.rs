@@ -0,0 +1,71 @@
+use std::time::Duration as StdDuration;
+
+use futures::{future::BoxFuture, Future, FutureExt};
+use log::{info, error};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+type ShutdownHook = Box<dyn Fn() -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+/// 登録順に実行する
+static HOOKS: Lazy<Mutex<Vec<(String, ShutdownHook)>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// 1つのhookにかける最大の時間
+const HOOK_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// 終了時の処理を登録する。注文のキャンセルはflushより先に登録する
+pub fn on_shutdown<F, Fut>(name: &str, f: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    HOOKS.lock().push((name.to_string(), Box::new(move || f().boxed())));
+}
+
+/// SIGTERMかSIGINTを受け取るまで待つ
+pub async fn wait_signal() -> anyhow::Result<&'static str> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => Ok("SIGTERM"),
+        res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT").map_err(|e| e.into()),
+    }
+}
+
+/// 登録されたhookをすべて実行する。失敗しても残りは実行する
+pub async fn run_shutdown_hooks() -> usize {
+    let hooks = std::mem::take(&mut *HOOKS.lock());
+    let mut failed = 0;
+    for (name, hook) in hooks {
+        match tokio::time::timeout(HOOK_TIMEOUT, hook()).await {
+            Ok(Ok(())) => info!("shutdown: {} done", name),
+            Ok(Err(e)) => {
+                failed += 1;
+                error!("shutdown: {} failed: {:?}", name, e);
+            },
+            Err(_) => {
+                failed += 1;
+                error!("shutdown: {} timed out", name);
+            },
+        }
+    }
+    failed
+}
+
+#[tokio::test]
+async fn test_run_shutdown_hooks() {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    let count = Arc::new(AtomicUsize::new(0));
+    let c = count.clone();
+    on_shutdown("ok", move || {
+        let c = c.clone();
+        async move {
+            c.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    });
+    on_shutdown("err", || async { anyhow::bail!("failed") });
+    assert_eq!(run_shutdown_hooks().await, 1);
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    // 2回目は何もしない
+    assert_eq!(run_shutdown_hooks().await, 0);
+}
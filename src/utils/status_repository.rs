@@ -67,6 +67,15 @@ impl StatusRepository {
         self.data.insert(symbol, next);
         Ok(())
     }
+
+    /// 現在の状態をすべてファイルに書き出す
+    pub fn flush(&self) -> anyhow::Result<()> {
+        for (symbol, data) in &self.data {
+            let mut file = File::create(self.file_name(symbol))?;
+            serde_json::to_writer_pretty(&mut file, data)?;
+        }
+        Ok(())
+    }
 }
 
 impl Index<&Symbol> for StatusRepository {
@@ -1,22 +1,22 @@
-use std::{collections::HashMap, env, process::exit};
+use std::{collections::HashMap, process::exit, time::Duration as StdDuration};
 
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use chrono::Duration;
-use futures::{stream::SplitSink, SinkExt, Sink, channel::mpsc::UnboundedReceiver, StreamExt};
+use chrono::{Duration, DateTime, Utc};
+use futures::{SinkExt, StreamExt, channel::mpsc::{unbounded, UnboundedSender}};
 use hyper::StatusCode;
 use log::info;
 use once_cell::sync::OnceCell;
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use serde_json::json;
+use serde_json::{json, Value};
 use tap::Pipe;
-use tokio::{spawn, net::TcpStream};
-use tokio_stream::StreamMap;
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream, tungstenite::Message};
+use tokio::{spawn, select, time::Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use url::Url;
 
-use crate::{symbol::{Symbol, Exchange}, client::{mail::send_mail, types::KLines}, error_types::BotError, utils::time::{UnixTimeUnit, now_floor_time}, config::{KLineBuilderConfig, TracingMMConfig}, data_structure::float_exp::FloatExp, order_types::{PosSide, Side}};
+use crate::{symbol::Symbol, client::types::KLines, error_types::{BotError, ErrorAction, error_action}, utils::time::{UnixTimeUnit, now_floor_time}, config::{KLineBuilderConfig, TracingMMConfig}, data_structure::float_exp::FloatExp, order_types::{PosSide, Side}};
 
-use super::{kline_mmap::KLineMMap, time::{sleep_until_next, ScheduleExpr}, status_repository::StatusRepository, useful_traits::StaticVarExt};
+use super::{alert::{alert, alert_and_wait, Severity}, metrics, kline_mmap::KLineMMap, time::{sleep_until_next, ScheduleExpr}, status_repository::StatusRepository, useful_traits::StaticVarExt};
 
 #[async_trait]
 pub trait CaptureResult {
@@ -26,64 +26,231 @@ pub trait CaptureResult {
 #[async_trait]
 impl CaptureResult for anyhow::Result<()> {
     async fn capture_result(self, symbol: Symbol) -> anyhow::Result<()> {
-        match self {
-            Err(e) => {
-                match e.downcast_ref::<BotError>() {
-                    Some(BotError::Maintenance) => {
-                        info!("Maintenance status found");
-                        return Ok(());
-                    },
-                    Some(BotError::MarginInsufficiency) => {
-                        info!("Margin insufficiency found");
-                        return Ok(());
-                    },
-                    _ => {},
-                };
-                match e.downcast_ref::<tokio_tungstenite::tungstenite::Error>() {
-                    Some(wse) if symbol.exc == Exchange::Bitflyer || symbol.exc == Exchange::Gmo => {
-                        match wse {
-                            tokio_tungstenite::tungstenite::Error::Http(res) => {
-                                match res.status() {
-                                    StatusCode::SERVICE_UNAVAILABLE | StatusCode::BAD_GATEWAY => {
-                                        info!("{} websocket disconnected (5xx), wait 60s", symbol.exc);
-                                        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                                        std::env::remove_var("RUST_BACKTRACE");
-                                        return Err(e);
-                                    },
-                                    _ => {},
-                                }
-                            },
-                            _ => {},
-                        }
-                    },
-                    _ => {},
-                }
-                send_mail(format!("{} - {}", e, env::var("NAME").unwrap()), format!("{:?}", e)).unwrap();
-                return Err(e);
-            }
-            _ => return Ok(()),
+        let Err(e) = self else {
+            return Ok(());
+        };
+        match error_action(&e) {
+            ErrorAction::Ignore => {
+                info!("{} {}", symbol.exc, e);
+                Ok(())
+            },
+            ErrorAction::Alert => {
+                alert(Severity::Warning, e.to_string(), format!("{:?}", e));
+                Ok(())
+            },
+            // 取引所が落ちているときはメールを送らず、少し待ってから再起動させる
+            ErrorAction::Restart => {
+                info!("{} {}, wait 60s", symbol.exc, e);
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                std::env::remove_var("RUST_BACKTRACE");
+                Err(e)
+            },
+            // 落ちる前に送り終える
+            ErrorAction::Fatal => {
+                alert_and_wait(Severity::Error, e.to_string(), format!("{:?}", e)).await;
+                Err(e)
+            },
+        }
+    }
+}
+
+/// 再接続までの待ち時間。initialから倍々にしてmaxで頭打ちにする
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: StdDuration,
+    pub max: StdDuration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: StdDuration::from_secs(1),
+            max: StdDuration::from_secs(60),
         }
     }
 }
 
-/// aiohttpのheartbeat相当。こちらからpingを送信する。pong確認で接続検査が必要かも
-pub async fn start_send_ping<E: Into<anyhow::Error> + Send, T: Sink<Message, Error = E> + Unpin + Send + 'static>(symbol: Symbol, mut sink: T) {
-    spawn(async move {
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> StdDuration {
+        self.initial.checked_mul(2u32.saturating_pow(attempt)).unwrap_or(self.max).min(self.max)
+    }
+}
+
+#[async_trait]
+pub trait WsHandler: Send {
+    /// Ping, Pong, Closeは渡さない。outに送ったメッセージはそのままwebsocketに書き込まれる
+    /// BotError::WsTooManyRequestを返すと再接続し、BotError::AuthFailedを返すとrunがそのエラーで終わる
+    /// それ以外のエラーはログに出して読み続ける
+    async fn on_message(&mut self, msg: Message, out: &mut UnboundedSender<Message>) -> anyhow::Result<()>;
+
+    /// 再接続してsubscribeする前に呼ぶ。切断中に取りこぼした約定などをRESTで埋める
+    async fn on_reconnect(&mut self, _disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// 接続するたびにsubscribeより先に送るメッセージ。認証など毎回作り直すもの
+    async fn on_connect(&mut self) -> anyhow::Result<Vec<Message>> {
+        Ok(vec![])
+    }
+}
+
+/// 切断されると再接続してsubscribeをやり直すwebsocket
+/// pingを送り、liveness_timeoutの間pongも含めて何も受信しなければ切断とみなす
+#[derive(Debug, Clone)]
+pub struct ReconnectingWs {
+    url: String,
+    subscribes: Vec<Message>,
+    subscribe_interval: StdDuration,
+    ping_interval: Option<StdDuration>,
+    liveness_timeout: StdDuration,
+    backoff: Backoff,
+    /// 連続でこの回数失敗したらエラーを返す
+    max_attempts: u32,
+    /// ログ・エラー・metricsに出すurl。省略するとurl
+    metrics_label: String,
+}
+
+impl ReconnectingWs {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            subscribes: vec![],
+            subscribe_interval: StdDuration::ZERO,
+            ping_interval: Some(StdDuration::from_secs(10)),
+            liveness_timeout: StdDuration::from_secs(60),
+            backoff: Backoff::default(),
+            max_attempts: 10,
+            metrics_label: url.to_string(),
+        }
+    }
+
+    pub fn subscribe(mut self, msg: Value) -> Self {
+        self.subscribes.push(Message::Text(msg.to_string()));
+        self
+    }
+
+    /// gmoは連続でsubscribeすると無視されるので間隔を空ける
+    pub fn subscribe_interval(mut self, interval: StdDuration) -> Self {
+        self.subscribe_interval = interval;
+        self
+    }
+
+    /// Noneならこちらからpingを送らない（サーバーからpingが来る取引所向け）
+    pub fn ping_interval(mut self, interval: Option<StdDuration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn liveness_timeout(mut self, timeout: StdDuration) -> Self {
+        self.liveness_timeout = timeout;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// urlにトークンを含むときはログ・エラー・metricsに出さないよう置き換える
+    pub fn metrics_label(mut self, label: &str) -> Self {
+        self.metrics_label = label.to_string();
+        self
+    }
+
+    /// 切断されるたびに再接続する。max_attempts回連続で失敗したときだけ返る
+    pub async fn run<H: WsHandler>(&self, handler: &mut H) -> anyhow::Result<()> {
+        let mut attempt = 0;
+        let mut disconnected_at = None;
+        loop {
+            let err = match self.connect_and_read(handler, &mut disconnected_at, &mut attempt).await {
+                Ok(()) => anyhow!("WebSocket disconnected"),
+                Err(e) => e,
+            };
+            disconnected_at.get_or_insert_with(Utc::now);
+            metrics::set_gauge("bot_ws_connected", &[("url", &self.metrics_label)], 0.);
+            metrics::inc_counter("bot_ws_disconnects_total", &[("url", &self.metrics_label)], 1.);
+            // 認証が通らなければつなぎ直しても同じ
+            if matches!(err.downcast_ref::<BotError>(), Some(BotError::AuthFailed)) {
+                return Err(err.context(format!("websocket auth failed: {}", self.metrics_label)));
+            }
+            if attempt >= self.max_attempts {
+                return Err(err.context(format!("gave up reconnecting to {} after {} attempts", self.metrics_label, attempt)));
+            }
+            let delay = self.backoff.delay(attempt);
+            attempt += 1;
+            info!("{} disconnected: {}. reconnect in {:?} (attempt {})", self.metrics_label, err, delay, attempt);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn connect_and_read<H: WsHandler>(&self, handler: &mut H, disconnected_at: &mut Option<DateTime<Utc>>, attempt: &mut u32) -> anyhow::Result<()> {
+        let (socket, _) = connect_async(Url::parse(&self.url)?).await.map_err(classify_ws_error)?;
+        info!("Connected to websocket: {}", self.metrics_label);
+        metrics::set_gauge("bot_ws_connected", &[("url", &self.metrics_label)], 1.);
+        let (mut write, mut read) = socket.split();
+
+        if let Some(since) = disconnected_at.take() {
+            if let Err(e) = handler.on_reconnect(since).await {
+                info!("backfill after reconnect failed: {:?}", e);
+            }
+        }
+        for msg in handler.on_connect().await? {
+            write.send(msg).await?;
+        }
+        for (i, msg) in self.subscribes.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(self.subscribe_interval).await;
+            }
+            write.send(msg.clone()).await?;
+        }
+
+        let (mut out, mut out_rx) = unbounded::<Message>();
+        let mut ping = tokio::time::interval(self.ping_interval.unwrap_or(self.liveness_timeout));
+        let mut last_received = Instant::now();
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-            sink.send(Message::Ping(vec![])).await.map_err(|e| anyhow!(e)).capture_result(symbol).await.unwrap();
+            select! {
+                msg = read.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        None => return Ok(()),
+                    };
+                    last_received = Instant::now();
+                    // 1件でも受信できたら接続できたとみなす
+                    *attempt = 0;
+                    metrics::inc_counter("bot_ws_messages_total", &[("url", &self.metrics_label)], 1.);
+                    match msg {
+                        Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {},
+                        Message::Close(frame) => anyhow::bail!("closed by server: {:?}", frame),
+                        msg => match handler.on_message(msg, &mut out).await {
+                            Ok(()) => {},
+                            Err(e) if matches!(e.downcast_ref::<BotError>(), Some(BotError::WsTooManyRequest | BotError::AuthFailed)) => return Err(e),
+                            Err(e) => info!("catched error in handle_ws_msg: {}", e),
+                        },
+                    }
+                },
+                Some(msg) = out_rx.next() => write.send(msg).await?,
+                _ = ping.tick(), if self.ping_interval.is_some() => write.send(Message::Ping(vec![])).await?,
+                _ = tokio::time::sleep_until(last_received + self.liveness_timeout) => {
+                    anyhow::bail!("no message within {:?}", self.liveness_timeout);
+                },
+            }
         }
-    });
+    }
 }
 
-pub fn connect_into_sink<E: Into<anyhow::Error> + Send, T: Sink<Message, Error = E> + Unpin + Send + 'static>(symbol: Symbol, mut sink: T, streams: Vec<UnboundedReceiver<Message>>) {
-    let mut all = StreamMap::new();
-    for (i, rdr) in streams.into_iter().enumerate() {
-        all.insert(i, rdr);
+/// handshakeの5xx・429と接続できないときはBotErrorで包む。元のtungstenite::Errorもdowncastできる
+fn classify_ws_error(e: tokio_tungstenite::tungstenite::Error) -> anyhow::Error {
+    use tokio_tungstenite::tungstenite::Error;
+    let bot_error = match &e {
+        Error::Http(res) if res.status().is_server_error() => Some(BotError::ServerError { status: res.status(), message: e.to_string() }),
+        Error::Http(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => Some(BotError::RateLimited),
+        Error::Io(_) => Some(BotError::Network { connected: false, message: e.to_string() }),
+        _ => None,
+    };
+    match bot_error {
+        Some(bot_error) => anyhow::Error::new(e).context(bot_error),
+        None => e.into(),
     }
-    spawn(async move {
-        sink.send_all(&mut all.map(|(_, x)| Ok(x))).await.map_err(|e| anyhow!(e)).capture_result(symbol).await.unwrap();
-    });
 }
 
 /// timeframeおきにkline_mmapをflushする
@@ -111,6 +278,15 @@ pub fn show_kline_mmap(kline_mmap: &OnceCell<RwLock<HashMap<Duration, KLineMMap>
     Ok(())
 }
 
+/// 終了時にすべてのtimeframeのmmapを書き出す
+pub fn flush_all_kline_mmap(kline_mmap: &OnceCell<RwLock<HashMap<Duration, KLineMMap>>>) -> anyhow::Result<()> {
+    for mmap in kline_mmap.get().context("KLINE_MMAP is not initialized")?.write().values_mut() {
+        mmap.update_mmap()?;
+    }
+    info!("Flushed all kline mmap");
+    Ok(())
+}
+
 fn flush_kline_mmap(kline_mmap: &OnceCell<RwLock<HashMap<Duration, KLineMMap>>>, timeframe: Duration) -> anyhow::Result<()> {
     let head_opentime = now_floor_time(timeframe, -1);
     kline_mmap.get().context("KLINE_MMAP is not initialized")?.write().get_mut(&timeframe).unwrap().update_mmap_with_shift(head_opentime)?;
@@ -130,9 +306,16 @@ pub fn get_liquidity_limited_base(base_volume_1d: f64, exit_mean_period: Duratio
     base_volume_1d * contract_size / daily_trial / (unit_count as f64 + doten as i64 as f64) * 0.01
 }
 
-pub fn update_assets_inner(status: &OnceCell<RwLock<StatusRepository>>, config: &TracingMMConfig, current_margin: f64, base_volume_1d: f64) -> anyhow::Result<()> {
-    let mut fixed_margin = status.read()[&config.symbol]["fixed_margin"].as_f64().unwrap_or(0.0);
-    fixed_margin = fixed_margin.max(current_margin * 0.8);
+#[derive(Debug, Clone, Copy)]
+pub struct AssetStatus {
+    pub fixed_margin: f64,
+    pub available_quote: f64,
+    pub liquidity_limited_base: f64,
+}
+
+/// 証拠金と出来高から注文に使える量を計算する
+pub fn next_asset_status(prev_fixed_margin: f64, config: &TracingMMConfig, current_margin: f64, base_volume_1d: f64) -> AssetStatus {
+    let fixed_margin = prev_fixed_margin.max(current_margin * 0.8);
     let available_quote = fixed_margin * config.leverage;
     let liquidity_limited_base = get_liquidity_limited_base(
         base_volume_1d,
@@ -141,6 +324,16 @@ pub fn update_assets_inner(status: &OnceCell<RwLock<StatusRepository>>, config:
         1.0, 
         config.beta.r#in==config.beta.out && config.gamma.r#in==config.gamma.out
     );
+    AssetStatus {
+        fixed_margin,
+        available_quote,
+        liquidity_limited_base,
+    }
+}
+
+pub fn update_assets_inner(status: &OnceCell<RwLock<StatusRepository>>, config: &TracingMMConfig, current_margin: f64, base_volume_1d: f64) -> anyhow::Result<()> {
+    let fixed_margin = status.read()[&config.symbol]["fixed_margin"].as_f64().unwrap_or(0.0);
+    let AssetStatus { fixed_margin, available_quote, liquidity_limited_base } = next_asset_status(fixed_margin, config, current_margin, base_volume_1d);
 
     status.write().update(config.symbol, json!({
         "fixed_margin": fixed_margin,
@@ -150,4 +343,55 @@ pub fn update_assets_inner(status: &OnceCell<RwLock<StatusRepository>>, config:
 
     info!("update_assets. fixed_margin: {}, available_quote: {}, liquidity_limited_base: {}", fixed_margin, available_quote, liquidity_limited_base);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_backoff() {
+    let backoff = Backoff::default();
+    assert_eq!(backoff.delay(0), StdDuration::from_secs(1));
+    assert_eq!(backoff.delay(3), StdDuration::from_secs(8));
+    assert_eq!(backoff.delay(6), StdDuration::from_secs(60));
+    assert_eq!(backoff.delay(100), StdDuration::from_secs(60));
+}
+
+#[tokio::test]
+async fn test_reconnecting_ws() {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use crate::client::mock_server::MockServer;
+
+    struct Handler {
+        messages: Arc<AtomicUsize>,
+        reconnects: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl WsHandler for Handler {
+        async fn on_message(&mut self, _msg: Message, _out: &mut UnboundedSender<Message>) -> anyhow::Result<()> {
+            self.messages.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn on_reconnect(&mut self, _disconnected_at: DateTime<Utc>) -> anyhow::Result<()> {
+            self.reconnects.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let server = MockServer::start().await.unwrap();
+    let messages = Arc::new(AtomicUsize::new(0));
+    let reconnects = Arc::new(AtomicUsize::new(0));
+    let mut handler = Handler { messages: messages.clone(), reconnects: reconnects.clone() };
+    let ws = ReconnectingWs::new(&server.ws_url())
+        .subscribe(json!({"channel": "trades"}))
+        .backoff(Backoff { initial: StdDuration::from_millis(10), max: StdDuration::from_millis(10) });
+    spawn(async move { ws.run(&mut handler).await });
+
+    server.wait_ws_received(1).await.unwrap();
+    server.ws_send(json!({"channel": "trades"}));
+    server.ws_disconnect();
+    // 再接続してsubscribeし直す
+    let received = server.wait_ws_received(2).await.unwrap();
+    assert_eq!(received[0], received[1]);
+    assert_eq!(messages.load(Ordering::SeqCst), 1);
+    assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+}
@@ -5,11 +5,14 @@ use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use polars::{prelude::{DataFrame, IntoLazy, RollingOptions, EWMOptions}, lazy::dsl::{col, lit}};
 use serde::Deserialize;
+use serde_json::Value;
 
-use crate::{order_types::{PosSide, Side}, data_structure::float_exp::FloatExp, utils::{time::now_floor_time, useful_traits::StaticVarExt}, client::types::KLines, symbol::Symbol};
+use crate::{order_types::Side, data_structure::float_exp::FloatExp, utils::{time::now_floor_time, useful_traits::StaticVarExt, strategy_utils::is_logical_postonly}, client::{types::KLines, exchange::{Position, aggregate_positions}}, symbol::Symbol, config::{TracingMMConfig, TracingMMHooks}};
 
-use super::{kline_mmap::KLineMMap, status_repository::StatusRepository};
+use super::kline_mmap::KLineMMap;
 
+/// klinesとref_klinesの比率を計算する足の数
+pub const MAPPING_SIZE: i64 = 100;
 
 #[derive(Debug, Clone)]
 pub struct TracingMMPosition {
@@ -26,9 +29,53 @@ impl TracingMMPosition {
             init_notional: FloatExp::new(0, price_exp + amount_exp),
         }
     }
+
+    /// 取引所のポジション一覧から[Long, Short]を作る
+    pub fn from_positions(positions: &Vec<Position>, price_exp: i32, amount_exp: i32) -> [Self; 2] {
+        let agg = aggregate_positions(positions, price_exp, amount_exp);
+        let mut ret = [Self::new(price_exp, amount_exp), Self::new(price_exp, amount_exp)];
+        for idx in 0..2 {
+            ret[idx].pos = agg[idx].0;
+            ret[idx].init_notional = agg[idx].1;
+            if !ret[idx].pos.is_zero() {
+                ret[idx].entry_price = ret[idx].init_notional.div_round(ret[idx].pos, price_exp);
+            }
+        }
+        ret
+    }
+
+    /// 約定を[Long, Short]に反映し、決済した分の実現損益（手数料は含まない）を返す
+    /// 反対側の建玉を先に減らし、残りを同じ側に足す。backtest・RiskClientも同じ計算を使う
+    pub fn apply_execution(pos: &mut [Self; 2], side: Side, price: FloatExp, amount: FloatExp) -> f64 {
+        let close_side = side.inv().to_pos();
+        let opposite = &mut pos[close_side as usize];
+        let amount = amount.round(opposite.pos.exp);
+        let closed = amount.min(opposite.pos);
+        let mut realized_pnl = 0.;
+        if !closed.is_zero() {
+            realized_pnl = (price.to_f64() - opposite.entry_price.to_f64()) * closed.to_f64() * close_side.sign() as f64;
+            opposite.pos -= closed;
+            if opposite.pos.is_zero() {
+                opposite.init_notional = FloatExp::new(0, opposite.init_notional.exp);
+                opposite.entry_price = FloatExp::new(0, opposite.entry_price.exp);
+            } else {
+                // 建値は変わらない
+                opposite.init_notional = (opposite.entry_price * opposite.pos).round(opposite.init_notional.exp);
+            }
+        }
+        let rest = amount - closed;
+        if rest.is_zero() {
+            return realized_pnl;
+        }
+        let same = &mut pos[side.to_pos() as usize];
+        same.pos += rest;
+        same.init_notional += (price.round(same.entry_price.exp) * rest).round(same.init_notional.exp);
+        same.entry_price = same.init_notional.div_round(same.pos, same.entry_price.exp);
+        realized_pnl
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct PriceInOut {
     pub r#in: f64,
     pub out: f64,
@@ -77,7 +124,7 @@ pub async fn read_kline(kline: &'static OnceCell<RwLock<KLineMMap>>, timeframe:
     anyhow::bail!("failed to update kline. curr: {:?}, header: {:?}", prev_opentime, header_opentime);
 }
 
-fn tracing_price_df(df: DataFrame, ref_df: DataFrame, mapping_size: i64, atr_period: i64, beta: &PriceInOut, gamma: &PriceInOut) -> anyhow::Result<DataFrame> {
+pub fn tracing_price_df(df: DataFrame, ref_df: DataFrame, mapping_size: i64, atr_period: i64, beta: &PriceInOut, gamma: &PriceInOut) -> anyhow::Result<DataFrame> {
     if df.column("opentime")? != &df.column("opentime")?.sort(false) {
         anyhow::bail!("opentime must be sorted");
     }
@@ -114,24 +161,49 @@ fn tracing_price_df(df: DataFrame, ref_df: DataFrame, mapping_size: i64, atr_per
 pub fn tracing_price(df: DataFrame, ref_df: DataFrame, mapping_size: i64, atr_period: i64, beta: &PriceInOut, gamma: &PriceInOut) -> anyhow::Result<TracingPriceResult> {
     let df = tracing_price_df(df, ref_df, mapping_size, atr_period, beta, gamma)?;
     let len = df.height();
-    Ok(TracingPriceResult {
-        buy_price: PriceInOut {
-            r#in: df["buy_price"].f64()?.to_vec()[len - 1].context("buy_price is empty")?,
-            out: df["buy_exit"].f64()?.to_vec()[len - 1].context("buy_exit is empty")?,
-        },
-        sell_price: PriceInOut {
-            r#in: df["sell_price"].f64()?.to_vec()[len - 1].context("sell_price is empty")?,
-            out: df["sell_exit"].f64()?.to_vec()[len - 1].context("sell_exit is empty")?,
-        },
-        last_close: df["close"].f64()?.to_vec()[len - 1].context("close is empty")?,
-    })
+    TracingPriceResult::from_row(&df, len - 1)
+}
+
+impl TracingPriceResult {
+    /// tracing_price_dfのidx行目の価格
+    pub fn from_row(df: &DataFrame, idx: usize) -> anyhow::Result<Self> {
+        let value = |column: &str| -> anyhow::Result<f64> {
+            df[column].f64()?.into_iter().nth(idx).flatten().with_context(|| format!("{} is empty", column))
+        };
+        Ok(Self {
+            buy_price: PriceInOut {
+                r#in: value("buy_price")?,
+                out: value("buy_exit")?,
+            },
+            sell_price: PriceInOut {
+                r#in: value("sell_price")?,
+                out: value("sell_exit")?,
+            },
+            last_close: value("close")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OrderSizing {
+    pub available_quote: f64,
+    pub liquidity_limited_base: f64,
+}
+
+impl OrderSizing {
+    /// update_assetsが書き込んだstatusから読む
+    pub fn from_status(status: &Value) -> Option<Self> {
+        Some(Self {
+            available_quote: status["available_quote"].as_f64()?,
+            liquidity_limited_base: status["liquidity_limited_base"].as_f64()?,
+        })
+    }
 }
 
 /// 使用可能な注文量を計算する
-pub fn next_open_amount(status: &'static OnceCell<RwLock<StatusRepository>>, pos: &'static OnceCell<RwLock<[TracingMMPosition; 2]>>, max_side_positions: i64, symbol: &Symbol, side: Side, price: FloatExp) -> Option<FloatExp> {
-    let status = status.read()[symbol].clone();
-    let quote_for_order = status["available_quote"].as_f64()?.min(status["liquidity_limited_base"].as_f64()? * max_side_positions as f64 * price.to_f64());
-    let init_notional = pos.read()[side as usize].init_notional.to_f64();
+pub fn open_amount(sizing: &OrderSizing, max_side_positions: i64, symbol: &Symbol, side: Side, price: FloatExp, init_notional: FloatExp) -> Option<FloatExp> {
+    let quote_for_order = sizing.available_quote.min(sizing.liquidity_limited_base * max_side_positions as f64 * price.to_f64());
+    let init_notional = init_notional.to_f64();
     let amount = open_amount_for_quote(
         FloatExp::from_f64(quote_for_order, symbol.settlement_precision()),
         max_side_positions,
@@ -140,11 +212,100 @@ pub fn next_open_amount(status: &'static OnceCell<RwLock<StatusRepository>>, pos
         symbol
     );
     if amount.is_none() {
-        info!("next_open_amount not enough quote. side: {:?}, quote_for_order: {}, init_notional: {}, price: {}", side, quote_for_order, init_notional, price);
+        info!("open_amount not enough quote. side: {:?}, quote_for_order: {}, init_notional: {}, price: {}", side, quote_for_order, init_notional, price);
     }
     amount
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracingMMOrderKind {
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct TracingMMOrder {
+    pub kind: TracingMMOrderKind,
+    pub side: Side,
+    pub price: FloatExp,
+    pub amount: FloatExp,
+    /// closeのロスカット逆指値
+    pub losscut_price: Option<FloatExp>,
+}
+
+/// timeframeの切り替わりで出す注文を決める
+/// 実運用とバックテストで共通
+pub fn plan_orders(config: &TracingMMConfig, hooks: &TracingMMHooks, pos: &[TracingMMPosition; 2], prices: &TracingPriceResult, sfd: Option<f64>, sizing: Option<OrderSizing>) -> anyhow::Result<Vec<TracingMMOrder>> {
+    let symbol = config.symbol;
+    let last_close = FloatExp::from_f64(prices.last_close, symbol.price_precision());
+    let min_amount = hooks.order_min_amount(&symbol)?;
+    let (close_sides, open_sides): (&[Side], &[Side]) = if hooks.long_only {
+        (&[Side::Sell], &[Side::Buy])
+    } else {
+        (&[Side::Buy, Side::Sell], &[Side::Buy, Side::Sell])
+    };
+    let mut ret = vec![];
+    // close order
+    for &side in close_sides {
+        let pos_side = side.inv().to_pos();
+        let amount = pos[pos_side as usize].pos;
+        if amount.is_zero() {
+            continue;
+        }
+        let price = FloatExp::from_f64(prices.by_side(side).out, symbol.price_precision());
+        if !order_filter("close_order", side, price, amount, min_amount, last_close) {
+            continue;
+        }
+        let losscut_price = config.losscut_rate.map(|losscut_rate|
+            pos[pos_side as usize].entry_price * (1.0 - losscut_rate * pos_side.sign() as f64)
+        );
+        ret.push(TracingMMOrder { kind: TracingMMOrderKind::Close, side, price, amount, losscut_price });
+    }
+    // open order
+    for &side in open_sides {
+        if !sfd_filter(hooks, side, sfd) {
+            continue;
+        }
+        let Some(sizing) = &sizing else {
+            info!("open_order sizing is not ready");
+            break;
+        };
+        let price = FloatExp::from_f64(prices.by_side(side).r#in, symbol.price_precision());
+        let Some(amount) = open_amount(sizing, config.max_side_positions, &symbol, side, price, pos[side as usize].init_notional) else {
+            continue;
+        };
+        if !order_filter("open_order", side, price, amount, min_amount, last_close) {
+            continue;
+        }
+        ret.push(TracingMMOrder { kind: TracingMMOrderKind::Open, side, price, amount, losscut_price: None });
+    }
+    Ok(ret)
+}
+
+fn order_filter(label: &str, side: Side, price: FloatExp, amount: FloatExp, min_amount: FloatExp, last_close: FloatExp) -> bool {
+    if amount < min_amount {
+        info!("{} amount too small: {}", label, amount);
+        return false;
+    }
+    if !is_logical_postonly(side, price, last_close) {
+        info!("{} not logical postonly, side: {:?}", label, side);
+        return false;
+    }
+    true
+}
+
+/// SFDが閾値を超える方向には新規注文を出さない
+fn sfd_filter(hooks: &TracingMMHooks, side: Side, sfd: Option<f64>) -> bool {
+    if let (Some(sfd_config), Some(sfd)) = (&hooks.sfd, sfd) {
+        let sfd_cond = [sfd < sfd_config.limit_rate, -sfd_config.limit_rate < sfd];
+        if !sfd_cond[side as usize] {
+            info!("SFD is out of range. side: {:?}, SFD: {}", side, sfd);
+            return false;
+        }
+    }
+    true
+}
+
 fn open_amount_for_quote(quote_for_order: FloatExp, max_side_positions: i64, init_notional: FloatExp, price: FloatExp, symbol: &Symbol) -> Option<FloatExp> {
     let order_amount = (quote_for_order / max_side_positions).div_floor(price, symbol.amount_precision());
     if init_notional + (order_amount * price).round(symbol.settlement_precision()) <= quote_for_order {
@@ -208,4 +369,67 @@ fn test_tracing_price() {
     // f64の値を取り出すと一致していることがわかる
     let prices = tracing_price(df, ref_df, mapping_size, atr_period,& beta, &gamma).unwrap();
     println!("{:?}", prices);
-}
\ No newline at end of file
+}
+#[test]
+fn test_plan_orders() {
+    let config: TracingMMConfig = serde_yaml::from_str(r#"
+symbol: {base: BTC, quote: JPY, settlement: JPY, type: spot, exc: coincheck}
+timeframe: 150s
+leverage: 1
+ref_symbol: {base: BTC, quote: USDT, settlement: USDT, type: spot, exc: binance}
+atr_period: 25
+beta: {in: 1.0, out: 1.0}
+gamma: {in: 1.0, out: 1.0}
+losscut_rate: 0.05
+exit_mean_frame: 80
+"#).unwrap();
+    let hooks = config.hooks();
+    let prices = TracingPriceResult {
+        buy_price: PriceInOut::new(3_900_000., 3_950_000.),
+        sell_price: PriceInOut::new(4_100_000., 4_050_000.),
+        last_close: 4_000_000.,
+    };
+    let sizing = OrderSizing { available_quote: 300_000., liquidity_limited_base: 1. };
+    let mut pos = [TracingMMPosition::new(0, -8), TracingMMPosition::new(0, -8)];
+
+    // ポジションがなければ買いのみ
+    let orders = plan_orders(&config, &hooks, &pos, &prices, None, Some(sizing)).unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].kind, TracingMMOrderKind::Open);
+    assert_eq!(orders[0].side, Side::Buy);
+    assert_eq!(orders[0].price, FloatExp::new(3_900_000, 0));
+
+    pos[0].pos = FloatExp::new(1_000_000, -8);
+    pos[0].entry_price = FloatExp::new(4_000_000, 0);
+    pos[0].init_notional = FloatExp::new(40_000, 0).round(-8);
+    let orders = plan_orders(&config, &hooks, &pos, &prices, None, None).unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].kind, TracingMMOrderKind::Close);
+    assert_eq!(orders[0].side, Side::Sell);
+    assert_eq!(orders[0].price, FloatExp::new(4_050_000, 0));
+    assert_eq!(orders[0].losscut_price, Some(FloatExp::new(3_800_000, 0)));
+}
+
+#[test]
+fn test_apply_execution() {
+    let mut pos = [TracingMMPosition::new(0, -8), TracingMMPosition::new(0, -8)];
+    TracingMMPosition::apply_execution(&mut pos, Side::Buy, FloatExp::new(4_000_000, 0), FloatExp::new(1_000_000, -8));
+    TracingMMPosition::apply_execution(&mut pos, Side::Buy, FloatExp::new(3_900_000, 0), FloatExp::new(1_000_000, -8));
+    assert_eq!(pos[0].pos, FloatExp::new(2_000_000, -8));
+    assert_eq!(pos[0].entry_price, FloatExp::new(3_950_000, 0));
+
+    // 一部決済しても建値は変わらない
+    let pnl = TracingMMPosition::apply_execution(&mut pos, Side::Sell, FloatExp::new(4_100_000, 0), FloatExp::new(500_000, -8));
+    assert!((pnl - 750.).abs() < 1e-6);
+    assert_eq!(pos[0].pos, FloatExp::new(1_500_000, -8));
+    assert_eq!(pos[0].entry_price, FloatExp::new(3_950_000, 0));
+    assert_eq!(pos[0].init_notional, FloatExp::new(59_250, 0));
+
+    // 決済しきれなかった分はドテン
+    let pnl = TracingMMPosition::apply_execution(&mut pos, Side::Sell, FloatExp::new(4_000_000, 0), FloatExp::new(2_000_000, -8));
+    assert!((pnl - 750.).abs() < 1e-6);
+    assert!(pos[0].pos.is_zero());
+    assert!(pos[0].entry_price.is_zero());
+    assert_eq!(pos[1].pos, FloatExp::new(500_000, -8));
+    assert_eq!(pos[1].entry_price, FloatExp::new(4_000_000, 0));
+}